@@ -0,0 +1,305 @@
+use crate::ArchiveTimestamp;
+use chrono::{DateTime, Local, Utc};
+
+/// The template archives were named with before it was configurable.
+pub const DEFAULT_TEMPLATE: &str = "{hostname}-{job}-{timestamp}";
+
+const PLACEHOLDERS: [&str; 6] = ["hostname", "job", "date", "time", "timestamp", "user"];
+/// Placeholders that change from one cycle to the next
+const TIME_PLACEHOLDERS: [&str; 3] = ["date", "time", "timestamp"];
+
+/// Archive names built from `archive_name_template`. Archives of the main
+/// series hold every job at once, so there `{job}` is left out along with
+/// a separator next to it; `paths_from` jobs get a series of their own
+/// with the job name filled in. Prune globs come from the same template,
+/// so names and retention always agree.
+pub struct NameTemplate<'a> {
+    pub template: &'a str,
+    pub hostname: &'a str,
+    pub user: &'a str,
+}
+
+impl NameTemplate<'_> {
+    /// The name of an archive of `job`'s series (the main series for
+    /// `None`) created at `now`.
+    pub fn name(&self, job: Option<&str>, now: DateTime<Utc>, style: ArchiveTimestamp) -> String {
+        let format = |format: &str| match style {
+            ArchiveTimestamp::Local => now.with_timezone(&Local).format(format).to_string(),
+            ArchiveTimestamp::Utc => now.format(format).to_string(),
+        };
+        let (date, time) = (format("%Y-%m-%d"), format("%H%M%S"));
+        let timestamp = style.format(now);
+        self.expand(job, |placeholder| match placeholder {
+            "date" => date.clone(),
+            "time" => time.clone(),
+            _ => timestamp.clone(),
+        })
+    }
+
+    /// Glob matching exactly the archives of `job`'s series. Times become
+    /// `[0-9]*`, so the main series of host `web` matches neither the
+    /// `paths_from` series of `web` nor the archives of host `web-1`.
+    pub fn glob(&self, job: Option<&str>) -> String {
+        self.expand(job, |_| "[0-9]*".to_string())
+    }
+
+    /// Glob matching the archives of every series of this host.
+    pub fn any_glob(&self) -> String {
+        let main = remove_job(self.template, "*");
+        let glob = NameTemplate {
+            template: &main,
+            ..*self
+        }
+        .expand(None, |_| "*".to_string());
+        let mut collapsed = String::with_capacity(glob.len());
+        for c in glob.chars() {
+            if !(c == '*' && collapsed.ends_with('*')) {
+                collapsed.push(c);
+            }
+        }
+        collapsed
+    }
+
+    /// Whether `name` is an archive of one of this host's series: the main
+    /// one or that of one of `jobs`. Unlike the globs, times must have the
+    /// shape this template gives them, so host `web` doesn't claim
+    /// `web-2-2024-03-31-011500Z` of host `web-2`.
+    pub fn owns(&self, name: &str, jobs: &[&str]) -> bool {
+        std::iter::once(None)
+            .chain(jobs.iter().map(|job| Some(*job)))
+            .any(|job| matches_times(&self.expand(job, |p| format!("{{{}}}", p)), name))
+    }
+
+    fn expand(&self, job: Option<&str>, time: impl Fn(&str) -> String) -> String {
+        let template = match job {
+            Some(_) => self.template.to_string(),
+            None => remove_job(self.template, ""),
+        };
+        let mut name = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let placeholder = &rest[start + 1..start + end];
+            match placeholder {
+                "hostname" => name.push_str(self.hostname),
+                "user" => name.push_str(self.user),
+                "job" => name.push_str(job.unwrap_or_default()),
+                p if TIME_PLACEHOLDERS.contains(&p) => name.push_str(&time(p)),
+                _ => name.push_str(&rest[start..=start + end]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        name.push_str(rest);
+        name
+    }
+}
+
+/// Shapes each time placeholder is written in: `d` a digit, `s` a sign,
+/// anything else itself. Timestamps end in `Z` or a UTC offset.
+fn time_shapes(placeholder: &str) -> &'static [&'static str] {
+    match placeholder {
+        "date" => &["dddd-dd-dd"],
+        "time" => &["dddddd"],
+        _ => &["dddd-dd-dd-ddddddZ", "dddd-dd-dd-ddddddsdddd"],
+    }
+}
+
+/// Whether `name` is `pattern` with each time placeholder in it written
+/// out in one of its shapes.
+fn matches_times(pattern: &str, name: &str) -> bool {
+    let Some(start) = pattern.find('{') else {
+        return pattern == name;
+    };
+    let Some(end) = pattern[start..].find('}').map(|end| start + end) else {
+        return pattern == name;
+    };
+    let Some(rest) = name.strip_prefix(&pattern[..start]) else {
+        return false;
+    };
+    time_shapes(&pattern[start + 1..end]).iter().any(|shape| {
+        rest.len() >= shape.len()
+            && rest.is_char_boundary(shape.len())
+            && rest.bytes().zip(shape.bytes()).all(|(c, s)| match s {
+                b'd' => c.is_ascii_digit(),
+                b's' => c == b'+' || c == b'-',
+                _ => c == s,
+            })
+            && matches_times(&pattern[end + 1..], &rest[shape.len()..])
+    })
+}
+
+/// `template` with `{job}` and one separator next to it replaced by
+/// `replacement`.
+fn remove_job(template: &str, replacement: &str) -> String {
+    const SEPARATORS: [char; 3] = ['-', '_', '.'];
+    let Some(start) = template.find("{job}") else {
+        return template.to_string();
+    };
+    let end = start + "{job}".len();
+    let (mut before, mut after) = (&template[..start], &template[end..]);
+    if let Some(rest) = after.strip_prefix(SEPARATORS) {
+        after = rest;
+    } else if let Some(rest) = before.strip_suffix(SEPARATORS) {
+        before = rest;
+    }
+    format!("{}{}{}", before, replacement, after)
+}
+
+/// Check `archive_name_template`: known placeholders only, one that
+/// changes each cycle, and `{job}` when `paths_from` jobs need series of
+/// their own.
+pub fn lint(template: &str, has_job_series: bool) -> Result<(), String> {
+    let mut found = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("archive_name_template: unclosed {{ in '{}'", template))?;
+        let placeholder = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "archive_name_template: unknown placeholder {{{}}} (expected {})",
+                placeholder,
+                PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+            ));
+        }
+        found.push(placeholder);
+        rest = &rest[start + end + 1..];
+    }
+    if !found.iter().any(|p| TIME_PLACEHOLDERS.contains(p)) {
+        return Err(format!(
+            "archive_name_template '{}' needs {{timestamp}}, {{date}} or {{time}} to tell cycles apart",
+            template
+        ));
+    }
+    if has_job_series {
+        // The main series' prune glob tells its archives apart by the
+        // digit where the job name would be
+        let job = found.iter().position(|p| *p == "job");
+        let last_time = found.iter().rposition(|p| TIME_PLACEHOLDERS.contains(p));
+        match job {
+            None => {
+                return Err(format!(
+                    "archive_name_template '{}' needs {{job}}: jobs with paths_from are archived and pruned separately",
+                    template
+                ))
+            }
+            Some(job) if Some(job) > last_time => {
+                return Err(format!(
+                    "archive_name_template '{}': {{job}} must come before {{timestamp}}, {{date}} or {{time}} so each series can be pruned separately",
+                    template
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    if template.contains(['/', ':']) || template.trim() != template {
+        return Err(format!(
+            "archive_name_template '{}': archive names cannot contain '/', ':' or surrounding spaces",
+            template
+        ));
+    }
+    Ok(())
+}
+
+/// Name of the user running us, for `{user}`.
+pub fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| unsafe { libc::getuid() }.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(template: &str) -> NameTemplate<'_> {
+        NameTemplate {
+            template,
+            hostname: "web",
+            user: "root",
+        }
+    }
+
+    #[test]
+    fn test_default_template_keeps_old_names() {
+        let now = DateTime::parse_from_rfc3339("2024-03-31T01:15:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let default = template(DEFAULT_TEMPLATE);
+        assert_eq!(
+            default.name(None, now, ArchiveTimestamp::Utc),
+            "web-2024-03-31-011500Z"
+        );
+        assert_eq!(
+            default.name(Some("media"), now, ArchiveTimestamp::Utc),
+            "web-media-2024-03-31-011500Z"
+        );
+        assert_eq!(default.glob(None), "web-[0-9]*");
+        assert_eq!(default.glob(Some("media")), "web-media-[0-9]*");
+        assert_eq!(default.any_glob(), "web-*");
+    }
+
+    #[test]
+    fn test_custom_template() {
+        let now = DateTime::parse_from_rfc3339("2024-03-31T01:15:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let custom = template("{date}_{user}@{hostname}_{job}_{time}");
+        assert_eq!(
+            custom.name(None, now, ArchiveTimestamp::Utc),
+            "2024-03-31_root@web_011500"
+        );
+        assert_eq!(
+            custom.name(Some("db"), now, ArchiveTimestamp::Utc),
+            "2024-03-31_root@web_db_011500"
+        );
+        assert_eq!(custom.glob(None), "[0-9]*_root@web_[0-9]*");
+        assert_eq!(custom.any_glob(), "*_root@web_*");
+
+        let dotted = template("{hostname}.{job}.{timestamp}");
+        assert_eq!(dotted.glob(None), "web.[0-9]*");
+        assert_eq!(dotted.glob(Some("db")), "web.db.[0-9]*");
+        assert_eq!(dotted.any_glob(), "web.*");
+    }
+
+    #[test]
+    fn test_owns() {
+        let default = template(DEFAULT_TEMPLATE);
+        assert!(default.owns("web-2024-03-31-011500Z", &[]));
+        assert!(default.owns("web-2024-03-31-021500+0100", &[]));
+        assert!(default.owns("web-media-2024-03-31-011500Z", &["media"]));
+        assert!(!default.owns("web-media-2024-03-31-011500Z", &[]));
+        // The glob web-[0-9]* would take these
+        assert!(!default.owns("web-2-2024-03-31-011500Z", &[]));
+        assert!(!default.owns("web-2024-03-31-011500Z-old", &[]));
+
+        let custom = template("{date}_{user}@{hostname}_{time}");
+        assert!(custom.owns("2024-03-31_root@web_011500", &[]));
+        assert!(!custom.owns("2024-03-31_root@web-2_011500", &[]));
+    }
+
+    #[test]
+    fn test_lint() {
+        assert!(lint(DEFAULT_TEMPLATE, true).is_ok());
+        assert!(lint("{hostname}-{date}", false).is_ok());
+        assert!(lint("{hostname}-{date}", true)
+            .unwrap_err()
+            .contains("needs {job}"));
+        assert!(lint("{hostname}-{timestamp}-{job}", true)
+            .unwrap_err()
+            .contains("before"));
+        assert!(lint("{hostname}-{timestamp}-{job}", false).is_ok());
+        assert!(lint("{hostname}", false)
+            .unwrap_err()
+            .contains("tell cycles apart"));
+        assert!(lint("{host}-{date}", false)
+            .unwrap_err()
+            .contains("unknown placeholder {host}"));
+        assert!(lint("{hostname-{date}", false).is_err());
+        assert!(lint("{hostname}/{date}", false).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// One restore, restore test, export, mount, delete or key operation
+/// performed through the tool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// Who ran the command, including the sudo caller when there is one
+    pub user: String,
+    pub host: String,
+    /// e.g. `restore`, `verify`, `export`, `mount`, `delete`, `key-export`
+    pub action: String,
+    /// Archive acted on; `None` when the whole repository was used
+    #[serde(default)]
+    pub archive: Option<String>,
+    /// Paths inside the archive; empty means all of them
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Where the data went: a directory, mount point or new archive name
+    #[serde(default)]
+    pub target: Option<String>,
+    pub success: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        host: &str,
+        action: &str,
+        archive: Option<&str>,
+        result: &Result<(), String>,
+    ) -> Self {
+        Self {
+            at: Utc::now(),
+            user: current_user(),
+            host: host.to_string(),
+            action: action.to_string(),
+            archive: archive.map(str::to_string),
+            paths: Vec::new(),
+            target: None,
+            success: result.is_ok(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+
+    pub fn with_paths(mut self, paths: &[&str]) -> Self {
+        self.paths = paths.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn with_target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+}
+
+/// The invoking user, e.g. `alice (sudo as root)`.
+pub fn current_user() -> String {
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    let user = std::env::var("USER").unwrap_or_else(|_| format!("uid {}", uid));
+    match std::env::var("SUDO_USER") {
+        Ok(caller) if caller != user => format!("{} (sudo as {})", caller, user),
+        _ => user,
+    }
+}
+
+/// Append `entry` as one JSON line. The file is only ever appended to.
+pub fn append(path: &str, entry: &AuditEntry) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    let line = serde_json::to_string(entry)
+        .map_err(|e| format!("Failed to serialize audit entry: {}", e))?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open audit log {}: {}", path, e))?;
+    writeln!(file, "{}", line).map_err(|e| format!("Failed to write audit log {}: {}", path, e))
+}
@@ -0,0 +1,548 @@
+use crate::display::Output;
+use crate::report::{ArchiveEntry, FileEntry};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::Style;
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Rows moved by PageUp and PageDown
+const PAGE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    Archives,
+    Files,
+}
+
+/// A row of the file pane: a directory derived from the paths below it,
+/// or a file or symlink.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Shown in the pane: the last path component, or the whole path in
+    /// search results
+    pub name: String,
+    /// Path in the archive, without the leading slash
+    pub path: String,
+    pub dir: bool,
+    /// Total of the files below a directory
+    pub size: u64,
+}
+
+/// What the caller has to do after a key press.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// List the files of this archive and hand them to `Browser::load`
+    Load(String),
+    /// Leave and restore the paths from the archive
+    Restore(Selection),
+    Quit,
+}
+
+/// Paths marked for restoring, without the leading slash.
+#[derive(Debug, PartialEq)]
+pub struct Selection {
+    pub archive: String,
+    pub paths: Vec<String>,
+    /// The archive listing, to check the restored files against
+    pub files: Vec<FileEntry>,
+}
+
+/// State of the `browse` screen, kept apart from the terminal so it can be
+/// tested.
+pub struct Browser {
+    archives: Vec<ArchiveEntry>,
+    archive_cursor: usize,
+    /// Index of the archive whose files are loaded
+    loaded: Option<usize>,
+    files: Vec<FileEntry>,
+    dir: String,
+    entries: Vec<Entry>,
+    cursor: usize,
+    query: String,
+    searching: bool,
+    marked: BTreeSet<String>,
+    focus: Focus,
+    status: String,
+}
+
+impl Browser {
+    /// `archives` oldest first, as borg lists them; the newest is selected.
+    pub fn new(archives: Vec<ArchiveEntry>) -> Self {
+        Self {
+            archive_cursor: archives.len().saturating_sub(1),
+            archives,
+            loaded: None,
+            files: Vec::new(),
+            dir: String::new(),
+            entries: Vec::new(),
+            cursor: 0,
+            query: String::new(),
+            searching: false,
+            marked: BTreeSet::new(),
+            focus: Focus::Archives,
+            status: String::new(),
+        }
+    }
+
+    pub fn focus(&self) -> Focus {
+        self.focus
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn marked(&self) -> &BTreeSet<String> {
+        &self.marked
+    }
+
+    pub fn set_status(&mut self, status: &str) {
+        self.status = status.to_string();
+    }
+
+    /// Show `files` as the contents of the selected archive.
+    pub fn load(&mut self, files: Vec<FileEntry>) {
+        self.loaded = Some(self.archive_cursor);
+        self.files = files;
+        self.dir.clear();
+        self.query.clear();
+        self.marked.clear();
+        self.focus = Focus::Files;
+        self.refresh();
+    }
+
+    fn selected_archive(&self) -> Option<&ArchiveEntry> {
+        self.archives.get(self.archive_cursor)
+    }
+
+    /// Rebuild the file pane for the current directory or search.
+    fn refresh(&mut self) {
+        self.entries = if self.query.is_empty() {
+            children(&self.files, &self.dir)
+        } else {
+            search(&self.files, &self.query)
+        };
+        self.cursor = self.cursor.min(self.entries.len().saturating_sub(1));
+    }
+
+    fn enter(&mut self, dir: &str) {
+        self.dir = dir.to_string();
+        self.query.clear();
+        self.cursor = 0;
+        self.refresh();
+    }
+
+    fn up(&mut self) {
+        if self.dir.is_empty() {
+            self.focus = Focus::Archives;
+            return;
+        }
+        let left = self.dir.clone();
+        let parent = left.rsplit_once('/').map_or("", |(parent, _)| parent);
+        self.enter(parent);
+        // Keep the cursor on the directory we came from
+        self.cursor = self
+            .entries
+            .iter()
+            .position(|e| e.path == left)
+            .unwrap_or(0);
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        let (cursor, len) = match self.focus {
+            Focus::Archives => (&mut self.archive_cursor, self.archives.len()),
+            Focus::Files => (&mut self.cursor, self.entries.len()),
+        };
+        if len > 0 {
+            *cursor = cursor.saturating_add_signed(by).min(len - 1);
+        }
+    }
+
+    /// Open the selected archive, listing it first unless it already is.
+    fn open_archive(&mut self) -> Option<Action> {
+        if self.loaded == Some(self.archive_cursor) {
+            self.focus = Focus::Files;
+            return None;
+        }
+        let archive = self.selected_archive()?.name.clone();
+        self.status = format!("Listing {}...", archive);
+        Some(Action::Load(archive))
+    }
+
+    /// The marked paths, or the one under the cursor when none are.
+    fn selection(&mut self) -> Option<Action> {
+        let archive = self.archives.get(self.loaded?)?.name.clone();
+        let mut paths: Vec<String> = if self.marked.is_empty() {
+            vec![self.entries.get(self.cursor)?.path.clone()]
+        } else {
+            self.marked.iter().cloned().collect()
+        };
+        // A marked directory already restores everything below it
+        let all = paths.clone();
+        paths.retain(|path| {
+            !all.iter()
+                .any(|other| path.starts_with(&format!("{}/", other)))
+        });
+        Some(Action::Restore(Selection {
+            archive,
+            paths,
+            files: std::mem::take(&mut self.files),
+        }))
+    }
+
+    pub fn key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+        if self.searching {
+            match key.code {
+                KeyCode::Enter => self.searching = false,
+                KeyCode::Esc => {
+                    self.searching = false;
+                    self.query.clear();
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                }
+                KeyCode::Char(c) => self.query.push(c),
+                _ => return None,
+            }
+            self.cursor = 0;
+            self.refresh();
+            return None;
+        }
+
+        self.status.clear();
+        match key.code {
+            KeyCode::Char('q') => return Some(Action::Quit),
+            KeyCode::Esc if !self.query.is_empty() => {
+                self.query.clear();
+                self.refresh();
+            }
+            KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::PageUp => self.move_cursor(-(PAGE as isize)),
+            KeyCode::PageDown => self.move_cursor(PAGE as isize),
+            KeyCode::Tab if self.focus == Focus::Files => self.focus = Focus::Archives,
+            KeyCode::Tab | KeyCode::Enter | KeyCode::Right | KeyCode::Char('l')
+                if self.focus == Focus::Archives =>
+            {
+                return self.open_archive()
+            }
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                if let Some(entry) = self.entries.get(self.cursor).filter(|e| e.dir) {
+                    let path = entry.path.clone();
+                    self.enter(&path);
+                }
+            }
+            KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h')
+                if self.focus == Focus::Files =>
+            {
+                self.up()
+            }
+            KeyCode::Char('/') if self.loaded.is_some() => {
+                self.focus = Focus::Files;
+                self.searching = true;
+                self.query.clear();
+                self.refresh();
+            }
+            KeyCode::Char(' ') if self.focus == Focus::Files => {
+                if let Some(entry) = self.entries.get(self.cursor) {
+                    if !self.marked.remove(&entry.path) {
+                        self.marked.insert(entry.path.clone());
+                    }
+                    self.move_cursor(1);
+                }
+            }
+            KeyCode::Char('r') if self.focus == Focus::Files => return self.selection(),
+            _ => {}
+        }
+        None
+    }
+
+    pub fn render(&self, frame: &mut Frame, output: &Output) {
+        let [panes, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                .areas(panes);
+        let highlight = |focus| {
+            if self.focus == focus {
+                Style::new().reversed()
+            } else {
+                Style::new().bold()
+            }
+        };
+
+        let archives: Vec<ListItem> = self
+            .archives
+            .iter()
+            .map(|a| ListItem::new(format!("{}  {}", a.name, output.time(a.time))))
+            .collect();
+        frame.render_stateful_widget(
+            List::new(archives)
+                .block(Block::bordered().title(" Archives "))
+                .highlight_style(highlight(Focus::Archives)),
+            left,
+            &mut ListState::default().with_selected(Some(self.archive_cursor)),
+        );
+
+        let title = if !self.query.is_empty() || self.searching {
+            format!(" Search: {} ", self.query)
+        } else {
+            format!(" /{} ", self.dir)
+        };
+        let files: Vec<ListItem> = match self.loaded {
+            None => vec![ListItem::new("Press Enter to list the archive")],
+            Some(_) => self
+                .entries
+                .iter()
+                .map(|e| {
+                    let mark = if self.marked.contains(&e.path) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    let slash = if e.dir { "/" } else { "" };
+                    ListItem::new(format!(
+                        "{} {}{}  {}",
+                        mark,
+                        e.name,
+                        slash,
+                        output.bytes(e.size)
+                    ))
+                })
+                .collect(),
+        };
+        frame.render_stateful_widget(
+            List::new(files)
+                .block(Block::bordered().title(title))
+                .highlight_style(highlight(Focus::Files)),
+            right,
+            &mut ListState::default().with_selected(self.loaded.map(|_| self.cursor)),
+        );
+
+        let help = if self.searching {
+            "type to search, Enter: done, Esc: clear".to_string()
+        } else if !self.status.is_empty() {
+            self.status.clone()
+        } else {
+            format!(
+                "Enter: open  Backspace: up  /: search  Space: mark  r: restore{}  q: quit",
+                match self.marked.len() {
+                    0 => String::new(),
+                    n => format!(" {} marked", n),
+                }
+            )
+        };
+        frame.render_widget(Paragraph::new(Line::from(help)), status);
+    }
+}
+
+/// Directories and files directly below `dir` ("" for the root),
+/// directories first.
+pub fn children(files: &[FileEntry], dir: &str) -> Vec<Entry> {
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", dir)
+    };
+    let mut dirs: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut entries = Vec::new();
+    for file in files {
+        let Some(rest) = file.path.strip_prefix(&prefix) else {
+            continue;
+        };
+        match rest.split_once('/') {
+            Some((name, _)) => *dirs.entry(name).or_default() += file.size,
+            None if !rest.is_empty() => entries.push(Entry {
+                name: rest.to_string(),
+                path: file.path.clone(),
+                dir: false,
+                size: file.size,
+            }),
+            None => {}
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    dirs.into_iter()
+        .map(|(name, size)| Entry {
+            name: name.to_string(),
+            path: format!("{}{}", prefix, name),
+            dir: true,
+            size,
+        })
+        .chain(entries)
+        .collect()
+}
+
+/// Files whose path contains `query`, ignoring case.
+pub fn search(files: &[FileEntry], query: &str) -> Vec<Entry> {
+    let query = query.to_lowercase();
+    files
+        .iter()
+        .filter(|f| f.path.to_lowercase().contains(&query))
+        .map(|f| Entry {
+            name: format!("/{}", f.path),
+            path: f.path.clone(),
+            dir: false,
+            size: f.size,
+        })
+        .collect()
+}
+
+/// Run the browser until the user quits or picks files to restore.
+/// `list` lists an archive's files when it is opened.
+pub fn run(
+    archives: Vec<ArchiveEntry>,
+    output: &Output,
+    mut list: impl FnMut(&str) -> Result<Vec<FileEntry>, String>,
+) -> Result<Option<Selection>, String> {
+    if archives.is_empty() {
+        return Err("The repository has no archives yet".to_string());
+    }
+    let mut browser = Browser::new(archives);
+    let mut terminal =
+        ratatui::try_init().map_err(|e| format!("Failed to set up the terminal: {}", e))?;
+    let result = (|| loop {
+        terminal.draw(|frame| browser.render(frame, output))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match browser.key(key) {
+            Some(Action::Load(archive)) => {
+                // Show "Listing..." while borg works
+                terminal.draw(|frame| browser.render(frame, output))?;
+                match list(&archive) {
+                    Ok(files) => browser.load(files),
+                    Err(e) => browser.set_status(&e),
+                }
+            }
+            Some(Action::Restore(selection)) => return Ok(Some(selection)),
+            Some(Action::Quit) => return Ok(None),
+            None => {}
+        }
+    })();
+    ratatui::restore();
+    result.map_err(|e: std::io::Error| format!("Terminal error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn file(path: &str, size: u64) -> FileEntry {
+        FileEntry {
+            path: path.to_string(),
+            size,
+            mtime: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    fn press(browser: &mut Browser, code: KeyCode) -> Option<Action> {
+        browser.key(KeyEvent::from(code))
+    }
+
+    #[test]
+    fn test_children_and_search() {
+        let files = [
+            file("etc/hosts", 10),
+            file("etc/nginx/nginx.conf", 20),
+            file("etc/nginx/sites/default", 5),
+            file("home/alice/Notes.txt", 1),
+        ];
+        let root = children(&files, "");
+        assert_eq!(
+            root.iter().map(|e| (&*e.name, e.dir)).collect::<Vec<_>>(),
+            [("etc", true), ("home", true)]
+        );
+        let etc = children(&files, "etc");
+        assert_eq!(
+            etc.iter()
+                .map(|e| (&*e.path, e.dir, e.size))
+                .collect::<Vec<_>>(),
+            [("etc/nginx", true, 25), ("etc/hosts", false, 10)]
+        );
+        assert!(children(&files, "et").is_empty());
+
+        let found = search(&files, "notes");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "/home/alice/Notes.txt");
+    }
+
+    #[test]
+    fn test_navigate_mark_and_restore() {
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let archives = ["host-1", "host-2"].map(|name| ArchiveEntry {
+            name: name.to_string(),
+            time,
+            comment: String::new(),
+            hostname: String::new(),
+        });
+        let mut browser = Browser::new(archives.to_vec());
+        assert_eq!(
+            press(&mut browser, KeyCode::Enter),
+            Some(Action::Load("host-2".to_string()))
+        );
+        browser.load(vec![
+            file("etc/hosts", 10),
+            file("etc/nginx/nginx.conf", 20),
+            file("srv/data", 1),
+        ]);
+        assert_eq!(browser.focus(), Focus::Files);
+
+        // Into etc, mark nginx/ and everything below it, then hosts
+        press(&mut browser, KeyCode::Enter);
+        assert_eq!(browser.entries()[0].path, "etc/nginx");
+        press(&mut browser, KeyCode::Char(' '));
+        press(&mut browser, KeyCode::Char(' '));
+        press(&mut browser, KeyCode::Up);
+        press(&mut browser, KeyCode::Enter);
+        press(&mut browser, KeyCode::Char(' '));
+        assert_eq!(browser.marked().len(), 3);
+
+        // Back at the root the cursor is on etc
+        press(&mut browser, KeyCode::Backspace);
+        press(&mut browser, KeyCode::Backspace);
+        assert_eq!(browser.entries()[browser.cursor].path, "etc");
+
+        // Search narrows the pane, Space toggles the mark off again
+        press(&mut browser, KeyCode::Char('/'));
+        for c in "HOSTS".chars() {
+            press(&mut browser, KeyCode::Char(c));
+        }
+        press(&mut browser, KeyCode::Enter);
+        assert_eq!(browser.entries().len(), 1);
+        press(&mut browser, KeyCode::Char(' '));
+        assert_eq!(browser.marked().len(), 2);
+
+        let Some(Action::Restore(selection)) = press(&mut browser, KeyCode::Char('r')) else {
+            panic!("expected a restore");
+        };
+        assert_eq!(selection.archive, "host-2");
+        assert_eq!(selection.paths, ["etc/nginx"]);
+        assert_eq!(selection.files.len(), 3);
+
+        // Going left from the root returns to the archives
+        let mut browser = Browser::new(archives.to_vec());
+        press(&mut browser, KeyCode::Up);
+        assert_eq!(
+            press(&mut browser, KeyCode::Right),
+            Some(Action::Load("host-1".to_string()))
+        );
+        browser.load(vec![file("etc/hosts", 10)]);
+        press(&mut browser, KeyCode::Left);
+        assert_eq!(browser.focus(), Focus::Archives);
+        // Already listed, so no second borg list
+        assert_eq!(press(&mut browser, KeyCode::Enter), None);
+        assert_eq!(press(&mut browser, KeyCode::Char('q')), Some(Action::Quit));
+    }
+}
@@ -0,0 +1,137 @@
+use serde_yaml::Value;
+use std::fmt;
+
+/// One difference between a user config and the bundled default.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigDifference {
+    /// Present in both files with a different value
+    Customized {
+        key: String,
+        default: String,
+        value: String,
+    },
+    /// Present in the default but missing from the user config
+    NotSet { key: String, default: String },
+    /// Present in the user config but not in the default
+    Extra { key: String, value: String },
+}
+
+impl fmt::Display for ConfigDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigDifference::Customized {
+                key,
+                default,
+                value,
+            } => write!(f, "  ~ {}: {} -> {}", key, default, value),
+            ConfigDifference::NotSet { key, default } => {
+                write!(f, "  + {} (default: {})", key, default)
+            }
+            ConfigDifference::Extra { key, value } => write!(f, "  * {}: {}", key, value),
+        }
+    }
+}
+
+/// Compare two YAML documents key by key.
+pub fn diff(default_yaml: &str, user_yaml: &str) -> Result<Vec<ConfigDifference>, String> {
+    let default: Value = serde_yaml::from_str(default_yaml)
+        .map_err(|e| format!("Failed to parse default config: {}", e))?;
+    let user: Value = serde_yaml::from_str(user_yaml)
+        .map_err(|e| format!("Failed to parse config file: {}", e))?;
+
+    let mut differences = Vec::new();
+    diff_values("", &default, &user, &mut differences);
+    Ok(differences)
+}
+
+fn diff_values(key: &str, default: &Value, user: &Value, out: &mut Vec<ConfigDifference>) {
+    match (default, user) {
+        (Value::Mapping(d), Value::Mapping(u)) => {
+            for (k, dv) in d {
+                let child = join_key(key, k);
+                match u.get(k) {
+                    Some(uv) => diff_values(&child, dv, uv, out),
+                    None => out.push(ConfigDifference::NotSet {
+                        key: child,
+                        default: render(dv),
+                    }),
+                }
+            }
+            for (k, uv) in u {
+                if !d.contains_key(k) {
+                    out.push(ConfigDifference::Extra {
+                        key: join_key(key, k),
+                        value: render(uv),
+                    });
+                }
+            }
+        }
+        _ if default == user => {}
+        _ => out.push(ConfigDifference::Customized {
+            key: key.to_string(),
+            default: render(default),
+            value: render(user),
+        }),
+    }
+}
+
+fn join_key(prefix: &str, key: &Value) -> String {
+    let key = match key {
+        Value::String(s) => s.clone(),
+        other => render(other),
+    };
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Short single-line rendering; collections are summarized.
+fn render(value: &Value) -> String {
+    match value {
+        Value::Null => "~".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        Value::Sequence(items) => format!("[{} items]", items.len()),
+        Value::Mapping(map) => format!("{{{} keys}}", map.len()),
+        Value::Tagged(tagged) => render(&tagged.value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_configs_have_no_differences() {
+        let yaml = "a: 1\nb:\n  c: x\n";
+        assert!(diff(yaml, yaml).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_reports_each_kind() {
+        let default = "compression: lz4\noptions:\n  show_stats: true\n  show_progress: true\n";
+        let user = "compression: zstd\noptions:\n  show_stats: true\nextra: 1\n";
+        let differences = diff(default, user).unwrap();
+        assert_eq!(
+            differences,
+            vec![
+                ConfigDifference::Customized {
+                    key: "compression".to_string(),
+                    default: "lz4".to_string(),
+                    value: "zstd".to_string(),
+                },
+                ConfigDifference::NotSet {
+                    key: "options.show_progress".to_string(),
+                    default: "true".to_string(),
+                },
+                ConfigDifference::Extra {
+                    key: "extra".to_string(),
+                    value: "1".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use serde_yaml::Value;
+use std::path::Path;
+
+/// Syntax of a config file. Either way it is read into the same YAML
+/// document, so includes, overrides and `config diff` work on both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConfigFormat {
+    #[default]
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// TOML for a `.toml` file, YAML otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+
+    pub fn default_file_name(self) -> &'static str {
+        match self {
+            Self::Yaml => "borg-config.yaml",
+            Self::Toml => "borg-config.toml",
+        }
+    }
+
+    pub fn parse(self, contents: &str) -> Result<Value, String> {
+        match self {
+            Self::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+            Self::Toml => {
+                let table: toml::Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+                serde_yaml::to_value(table).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Write `doc` in this format. TOML has no null, so empty keys are left
+    /// out, as are the comments of a YAML original.
+    pub fn render(self, doc: &Value) -> Result<String, String> {
+        match self {
+            Self::Yaml => serde_yaml::to_string(doc).map_err(|e| e.to_string()),
+            Self::Toml => {
+                let mut doc = doc.clone();
+                strip_nulls(&mut doc);
+                let table = toml::Table::try_from(doc).map_err(|e| e.to_string())?;
+                toml::to_string(&table).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            mapping.retain(|_, value| !value.is_null());
+            mapping.values_mut().for_each(strip_nulls);
+        }
+        Value::Sequence(items) => {
+            items.retain(|value| !value.is_null());
+            items.iter_mut().for_each(strip_nulls);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("/etc/borg/c.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("/etc/borg/c.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(ConfigFormat::from_path(Path::new("c")), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let yaml: Value = serde_yaml::from_str(
+            "repository:\n  path: /srv/borg\n  remote:\njobs:\n  - name: home\n    exclude: ['*.tmp']\nretention:\n  daily: 7\n",
+        )
+        .unwrap();
+        let toml = ConfigFormat::Toml.render(&yaml).unwrap();
+        assert!(toml.contains("[[jobs]]"), "{}", toml);
+        assert!(!toml.contains("remote"), "{}", toml);
+
+        let parsed = ConfigFormat::Toml.parse(&toml).unwrap();
+        assert_eq!(parsed["repository"]["path"], Value::from("/srv/borg"));
+        assert_eq!(parsed["jobs"][0]["exclude"][0], Value::from("*.tmp"));
+        assert_eq!(parsed["retention"]["daily"], Value::from(7));
+        assert!(ConfigFormat::Toml.parse("jobs = [").is_err());
+    }
+}
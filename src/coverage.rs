@@ -0,0 +1,246 @@
+use crate::glob_match;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Filesystem types holding local data worth backing up. Network shares
+/// are left out; they are usually some other machine's to back up.
+const DATA_FILESYSTEMS: [&str; 14] = [
+    "ext2", "ext3", "ext4", "xfs", "btrfs", "zfs", "f2fs", "jfs", "reiserfs", "bcachefs", "vfat",
+    "exfat", "ntfs3", "fuseblk",
+];
+
+/// Directories the OS, its package manager or the kernel recreate; left
+/// out of the report unless everything is asked for.
+pub const SYSTEM_DIRS: [&str; 15] = [
+    "/bin",
+    "/boot",
+    "/dev",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/proc",
+    "/run",
+    "/sbin",
+    "/snap",
+    "/sys",
+    "/tmp",
+    "/usr",
+    "/var/cache",
+    "/var/tmp",
+];
+
+/// A directory no job backs up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gap {
+    pub path: PathBuf,
+    /// Bytes on disk, not counting other filesystems mounted below it
+    pub size: u64,
+}
+
+/// What the jobs protect, for finding what they don't.
+pub struct Coverage {
+    /// Job sources and the extra filesystems they include
+    pub covered: Vec<PathBuf>,
+    /// Whether borg stays on the filesystem of each covered path
+    pub one_file_system: bool,
+    /// Exclude patterns; excluded directories are left out on purpose
+    pub excludes: Vec<String>,
+    /// Directories not to report, such as the repository itself
+    pub skip: Vec<PathBuf>,
+    /// Smallest gap worth reporting
+    pub min_size: u64,
+}
+
+impl Coverage {
+    /// Directories on `mounts` that no job backs up, largest first. A
+    /// directory is reported whole unless something covered or skipped
+    /// lies inside it, in which case its contents are looked at instead.
+    pub fn gaps(&self, mounts: &[PathBuf]) -> Vec<Gap> {
+        let covered: Vec<(PathBuf, Option<u64>)> = self
+            .covered
+            .iter()
+            .map(|path| (path.clone(), fs::metadata(path).ok().map(|m| m.dev())))
+            .collect();
+        let mut gaps = Vec::new();
+        for mount in mounts {
+            if let Ok(meta) = fs::metadata(mount) {
+                self.walk(mount, meta.dev(), &covered, &mut gaps);
+            }
+        }
+        gaps.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+        gaps
+    }
+
+    fn walk(&self, dir: &Path, dev: u64, covered: &[(PathBuf, Option<u64>)], gaps: &mut Vec<Gap>) {
+        if self.skip.iter().any(|skip| skip == dir)
+            || dir.file_name().is_some_and(|name| name == "lost+found")
+            || excluded(dir, &self.excludes)
+        {
+            return;
+        }
+        let covers = |(path, path_dev): &(PathBuf, Option<u64>)| {
+            dir.starts_with(path) && (!self.one_file_system || *path_dev == Some(dev))
+        };
+        if covered.iter().any(covers) {
+            return;
+        }
+
+        let inside = |path: &PathBuf| path != dir && path.starts_with(dir);
+        if covered.iter().any(|(path, _)| inside(path)) || self.skip.iter().any(inside) {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                match fs::symlink_metadata(&path) {
+                    Ok(meta) if meta.is_dir() && meta.dev() == dev => {
+                        self.walk(&path, dev, covered, gaps)
+                    }
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let size = disk_usage(dir, dev);
+        if size >= self.min_size {
+            gaps.push(Gap {
+                path: dir.to_path_buf(),
+                size,
+            });
+        }
+    }
+}
+
+/// Whether an exclude pattern matches `path`. Handles borg's default
+/// fnmatch style (`*` also matches `/`, patterns are relative to `/`) and
+/// path prefixes (`pp:`); regular expressions are not evaluated.
+fn excluded(path: &Path, patterns: &[String]) -> bool {
+    let path = path.to_string_lossy();
+    let relative = path.trim_start_matches('/');
+    patterns.iter().any(|pattern| {
+        if let Some(prefix) = pattern.strip_prefix("pp:").or(pattern.strip_prefix("pf:")) {
+            return Path::new(&*path).starts_with(prefix);
+        }
+        if pattern.starts_with("re:") {
+            return false;
+        }
+        let pattern = pattern
+            .strip_prefix("fm:")
+            .or(pattern.strip_prefix("sh:"))
+            .unwrap_or(pattern);
+        glob_match(pattern.trim_start_matches('/'), relative)
+    })
+}
+
+/// Bytes allocated below `dir`, staying on device `dev`.
+fn disk_usage(dir: &Path, dev: u64) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let meta = fs::symlink_metadata(entry.path()).ok()?;
+            if meta.dev() != dev {
+                return None;
+            }
+            let own = meta.blocks() * 512;
+            Some(if meta.is_dir() {
+                own + disk_usage(&entry.path(), dev)
+            } else {
+                own
+            })
+        })
+        .sum()
+}
+
+/// Mount points of local data filesystems in `/proc/self/mounts` format.
+pub fn data_mounts(mounts: &str) -> Vec<PathBuf> {
+    let mut points: Vec<PathBuf> = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fstype = fields.next()?;
+            // /proc/mounts escapes spaces as \040
+            DATA_FILESYSTEMS
+                .contains(&fstype)
+                .then(|| PathBuf::from(mount_point.replace("\\040", " ")))
+        })
+        .collect();
+    points.sort();
+    points.dedup();
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_mounts() {
+        let mounts = "\
+/dev/sda1 / ext4 rw 0 0
+proc /proc proc rw 0 0
+/dev/sdb1 /mnt/data\\040disk xfs rw 0 0
+tmpfs /tmp tmpfs rw 0 0
+nas:/export /mnt/nas nfs4 rw 0 0
+/dev/sda1 / ext4 rw 0 0
+";
+        assert_eq!(
+            data_mounts(mounts),
+            vec![PathBuf::from("/"), PathBuf::from("/mnt/data disk")]
+        );
+    }
+
+    #[test]
+    fn test_excluded() {
+        let patterns = vec![
+            "/home/*/.cache".to_string(),
+            "**/node_modules".to_string(),
+            "pp:/srv/scratch".to_string(),
+        ];
+        assert!(excluded(Path::new("/home/ann/.cache"), &patterns));
+        assert!(excluded(Path::new("/opt/app/node_modules"), &patterns));
+        assert!(excluded(Path::new("/srv/scratch/tmp"), &patterns));
+        assert!(!excluded(Path::new("/srv/scratchpad"), &patterns));
+        assert!(!excluded(Path::new("/home/ann"), &patterns));
+    }
+
+    #[test]
+    fn test_gaps() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = |path: &str| {
+            let path = root.path().join(path);
+            fs::create_dir_all(&path).unwrap();
+            path
+        };
+        let home = dir("home");
+        dir("home/ann");
+        fs::write(dir("data/photos").join("a.jpg"), vec![1u8; 64 * 1024]).unwrap();
+        fs::write(
+            dir("scratch/node_modules").join("x.js"),
+            vec![1u8; 64 * 1024],
+        )
+        .unwrap();
+        fs::write(dir("var/log").join("syslog"), vec![1u8; 64 * 1024]).unwrap();
+        fs::write(dir("var/small").join("f"), b"x").unwrap();
+
+        let coverage = Coverage {
+            covered: vec![home],
+            one_file_system: true,
+            excludes: vec!["**/scratch".to_string()],
+            skip: vec![root.path().join("var/log")],
+            min_size: 16 * 1024,
+        };
+        let gaps: Vec<PathBuf> = coverage
+            .gaps(&[root.path().to_path_buf()])
+            .into_iter()
+            .map(|gap| gap.path.strip_prefix(root.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(gaps, vec![PathBuf::from("data")]);
+    }
+}
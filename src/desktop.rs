@@ -0,0 +1,147 @@
+use crate::privileges::{self, Account};
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+
+/// Pop-up notifications on the desktop of a workstation, through
+/// `notify-send` (freedesktop, over D-Bus) or `osascript` on macOS.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Desktop {
+    /// Whose desktop shows them when we run as root, e.g. from the system
+    /// timer; otherwise our own session's
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default = "default_true")]
+    pub on_start: bool,
+    #[serde(default = "default_true")]
+    pub on_warning: bool,
+    #[serde(default = "default_true")]
+    pub on_failure: bool,
+    #[serde(default)]
+    pub on_success: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Low,
+    Normal,
+    /// Stays on screen until dismissed
+    Critical,
+}
+
+impl Urgency {
+    fn name(self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+}
+
+impl Desktop {
+    pub fn show(&self, title: &str, body: &str, urgency: Urgency) -> Result<(), String> {
+        let mut cmd = if cfg!(target_os = "macos") {
+            let mut cmd = Command::new("osascript");
+            cmd.args(["-e", &applescript(title, body)]);
+            cmd
+        } else {
+            let mut cmd = Command::new("notify-send");
+            cmd.args(notify_send_args(title, body, urgency));
+            cmd
+        };
+        if let Some(account) = self.session_owner()? {
+            // Run in the user's session, where their notification daemon
+            // listens on the session bus
+            let runtime_dir = format!("/run/user/{}", account.uid);
+            let mut wrapped = Command::new("setpriv");
+            wrapped
+                .args(privileges::setpriv_args(&account, false))
+                .arg(cmd.get_program())
+                .args(cmd.get_args())
+                .env("HOME", &account.home)
+                .env("USER", &account.name)
+                .env(
+                    "DBUS_SESSION_BUS_ADDRESS",
+                    format!("unix:path={}/bus", runtime_dir),
+                )
+                .env("XDG_RUNTIME_DIR", runtime_dir);
+            cmd = wrapped;
+        }
+
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        let output = cmd
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The account to switch to: `user` when we run as root. Root has no
+    /// desktop of its own to notify.
+    fn session_owner(&self) -> Result<Option<Account>, String> {
+        if !privileges::is_root() || cfg!(target_os = "macos") {
+            return Ok(None);
+        }
+        match &self.user {
+            Some(name) => Account::lookup(name).map(Some),
+            None => Err(
+                "running as root; set notifications.desktop.user to the user whose desktop shows notifications"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+fn notify_send_args(title: &str, body: &str, urgency: Urgency) -> Vec<String> {
+    vec![
+        "--app-name=borg-timemachine".to_string(),
+        format!("--urgency={}", urgency.name()),
+        "--icon=drive-harddisk".to_string(),
+        title.to_string(),
+        body.to_string(),
+    ]
+}
+
+/// AppleScript for a notification, with the strings quoted for it.
+fn applescript(title: &str, body: &str) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    format!(
+        "display notification {} with title {}",
+        quote(body),
+        quote(title)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            notify_send_args("Backup failed", "disk full", Urgency::Critical),
+            [
+                "--app-name=borg-timemachine",
+                "--urgency=critical",
+                "--icon=drive-harddisk",
+                "Backup failed",
+                "disk full"
+            ]
+        );
+        assert_eq!(
+            applescript("Backup", r#"say "hi" \o/"#),
+            r#"display notification "say \"hi\" \\o/" with title "Backup""#
+        );
+    }
+}
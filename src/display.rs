@@ -0,0 +1,160 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, Utc};
+use serde::Deserialize;
+
+/// Unit system for sizes shown to people.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// KiB, MiB, GiB (powers of 1024)
+    #[default]
+    Binary,
+    /// kB, MB, GB (powers of 1000)
+    Decimal,
+}
+
+/// How sizes and times are rendered in `list`, `history` and `audit`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Output {
+    #[serde(default)]
+    pub units: Units,
+    /// strftime format for absolute times, in local time
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    /// Show times as "3 hours ago" instead of a date
+    #[serde(default)]
+    pub relative_times: bool,
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            units: Units::default(),
+            date_format: default_date_format(),
+            relative_times: false,
+        }
+    }
+}
+
+impl Output {
+    /// Reject date formats chrono can't render, which would otherwise panic
+    /// mid-output.
+    pub fn validate(&self) -> Result<(), String> {
+        if StrftimeItems::new(&self.date_format).any(|item| item == Item::Error) {
+            return Err(format!("Invalid output.date_format: {}", self.date_format));
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&self, bytes: u64) -> String {
+        match self.units {
+            Units::Binary => crate::format_bytes(bytes),
+            Units::Decimal => format_bytes_decimal(bytes),
+        }
+    }
+
+    pub fn time(&self, time: DateTime<Utc>) -> String {
+        self.time_at(time, Utc::now())
+    }
+
+    /// Render `time` as seen at `now`.
+    pub fn time_at(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+        if self.relative_times {
+            relative_time(time, now)
+        } else {
+            time.with_timezone(&Local)
+                .format(&self.date_format)
+                .to_string()
+        }
+    }
+}
+
+/// Human-readable size using SI units, e.g. `1.5 GB`.
+pub fn format_bytes_decimal(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "kB", "MB", "GB", "TB", "PB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Coarse distance between two times, e.g. `3 hours ago` or `in 2 days`.
+pub fn relative_time(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - time).num_seconds();
+    let magnitude = seconds.unsigned_abs();
+    if magnitude < 60 {
+        return "just now".to_string();
+    }
+
+    let (count, unit) = match magnitude {
+        0..=3_599 => (magnitude / 60, "minute"),
+        3_600..=86_399 => (magnitude / 3_600, "hour"),
+        86_400..=2_591_999 => (magnitude / 86_400, "day"),
+        2_592_000..=31_535_999 => (magnitude / 2_592_000, "month"),
+        _ => (magnitude / 31_536_000, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    if seconds >= 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal_units() {
+        assert_eq!(format_bytes_decimal(999), "999 B");
+        assert_eq!(format_bytes_decimal(1_500), "1.5 kB");
+        assert_eq!(format_bytes_decimal(3_000_000_000), "3.0 GB");
+
+        let output = Output {
+            units: Units::Decimal,
+            ..Output::default()
+        };
+        assert_eq!(output.bytes(2_000_000), "2.0 MB");
+        assert_eq!(Output::default().bytes(2_097_152), "2.0 MiB");
+    }
+
+    #[test]
+    fn test_relative_time() {
+        let now = Utc::now();
+        assert_eq!(relative_time(now, now), "just now");
+        assert_eq!(
+            relative_time(now - chrono::Duration::minutes(1), now),
+            "1 minute ago"
+        );
+        assert_eq!(
+            relative_time(now - chrono::Duration::hours(5), now),
+            "5 hours ago"
+        );
+        assert_eq!(
+            relative_time(now + chrono::Duration::days(2), now),
+            "in 2 days"
+        );
+    }
+
+    #[test]
+    fn test_date_format_validation() {
+        assert!(Output::default().validate().is_ok());
+        let output = Output {
+            date_format: "%Y-%Q".to_string(),
+            ..Output::default()
+        };
+        assert!(output.validate().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::config_format::ConfigFormat;
+use crate::migrate;
+use serde_yaml::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Key listing the files a config file builds on.
+pub const INCLUDE_KEY: &str = "include";
+
+/// Replace the `include:` list in `doc`, read from `path`, with the merged
+/// contents of the files it names. Included files are layered in order,
+/// each over the previous one, and `doc` itself goes on top. Relative
+/// paths are resolved against the including file's directory. Each
+/// included file is upgraded from its own `version` first.
+pub fn resolve(doc: &mut Value, path: &Path) -> Result<(), String> {
+    let canonical = canonical(path)?;
+    let mut stack = vec![canonical];
+    resolve_in(doc, path, &mut stack)
+}
+
+fn resolve_in(doc: &mut Value, path: &Path, stack: &mut Vec<PathBuf>) -> Result<(), String> {
+    let includes = match doc.as_mapping_mut().and_then(|m| m.remove(INCLUDE_KEY)) {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::String(file)) => vec![file],
+        Some(Value::Sequence(files)) => files
+            .into_iter()
+            .map(|file| match file {
+                Value::String(file) => Ok(file),
+                other => Err(format!(
+                    "{}: include entries must be file names, got {:?}",
+                    path.display(),
+                    other
+                )),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(format!(
+                "{}: include must be a file name or a list of them",
+                path.display()
+            ))
+        }
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = Value::Null;
+    for file in includes {
+        let included = dir.join(&file);
+        let canonical = canonical(&included)?;
+        if let Some(start) = stack.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = stack[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("Config include cycle: {}", cycle.join(" -> ")));
+        }
+
+        let contents = fs::read_to_string(&included).map_err(|e| {
+            format!(
+                "Failed to read included config {}: {}",
+                included.display(),
+                e
+            )
+        })?;
+        let mut layer = ConfigFormat::from_path(&included)
+            .parse(&contents)
+            .map_err(|e| {
+                format!(
+                    "Failed to parse included config {}: {}",
+                    included.display(),
+                    e
+                )
+            })?;
+        migrate::upgrade(&mut layer)
+            .map_err(|e| format!("Included config {}: {}", included.display(), e))?;
+        // The including file's version is the one that counts
+        if let Some(layer) = layer.as_mapping_mut() {
+            layer.remove(migrate::VERSION_KEY);
+        }
+        stack.push(canonical);
+        resolve_in(&mut layer, &included, stack)?;
+        stack.pop();
+        merge(&mut merged, layer);
+    }
+
+    let own = std::mem::replace(doc, merged);
+    merge(doc, own);
+    Ok(())
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, String> {
+    path.canonicalize()
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))
+}
+
+/// Lay `top` over `base`: mappings merge key by key, recursively; anything
+/// else in `top` (lists included) replaces what `base` had. A null in
+/// `top`, such as a section with only comments, leaves `base` alone.
+pub fn merge(base: &mut Value, top: Value) {
+    match (base, top) {
+        (Value::Mapping(base), Value::Mapping(top)) => {
+            for (key, value) in top {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (base, top) => *base = top,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = yaml("retention:\n  daily: 7\n  weekly: 4\nexclusions: [a, b]\n");
+        merge(
+            &mut base,
+            yaml("retention:\n  daily: 3\nexclusions: [c]\nhealthcheck:\n"),
+        );
+        assert_eq!(
+            base,
+            yaml("retention:\n  daily: 3\n  weekly: 4\nexclusions: [c]\nhealthcheck:\n")
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("common.yaml"),
+            "retention:\n  daily: 7\n  weekly: 4\ncompression: lz4\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("site")).unwrap();
+        fs::write(
+            dir.path().join("site/overrides.yaml"),
+            "include: ../common.yaml\ncompression: zstd\n",
+        )
+        .unwrap();
+        let host = dir.path().join("host.yaml");
+        fs::write(
+            &host,
+            "include:\n  - common.yaml\n  - site/overrides.yaml\nretention:\n  daily: 3\n",
+        )
+        .unwrap();
+
+        let mut doc = yaml(&fs::read_to_string(&host).unwrap());
+        resolve(&mut doc, &host).unwrap();
+        assert_eq!(
+            doc,
+            yaml("retention:\n  daily: 3\n  weekly: 4\ncompression: zstd\n")
+        );
+    }
+
+    #[test]
+    fn test_included_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("old.yaml"),
+            "security:\n  key_backup_path: /root/key.txt\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("new.yaml"), "version: 99\n").unwrap();
+        let host = dir.path().join("host.yaml");
+        fs::write(&host, "").unwrap();
+
+        // Upgraded from version 0 though the including file is current
+        let mut doc = yaml("version: 1\ninclude: old.yaml\n");
+        resolve(&mut doc, &host).unwrap();
+        assert_eq!(
+            doc,
+            yaml("version: 1\nsecurity:\n  key_backup: /root/key.txt\n")
+        );
+
+        let mut doc = yaml("version: 1\ninclude: new.yaml\n");
+        let error = resolve(&mut doc, &host).unwrap_err();
+        assert!(
+            error.contains("new.yaml") && error.contains("newer"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.yaml");
+        fs::write(&a, "include: b.yaml\n").unwrap();
+        fs::write(dir.path().join("b.yaml"), "include: [a.yaml]\n").unwrap();
+
+        let mut doc = yaml(&fs::read_to_string(&a).unwrap());
+        let error = resolve(&mut doc, &a).unwrap_err();
+        assert!(error.starts_with("Config include cycle:"), "{}", error);
+        assert!(error.ends_with("a.yaml"), "{}", error);
+
+        let missing = dir.path().join("c.yaml");
+        fs::write(&missing, "include: nope.yaml\n").unwrap();
+        let mut doc = yaml("include: nope.yaml\n");
+        assert!(resolve(&mut doc, &missing).is_err());
+    }
+}
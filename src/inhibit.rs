@@ -0,0 +1,99 @@
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+/// A logind inhibitor lock keeping the machine from suspending or shutting
+/// down until dropped. It is held by `systemd-inhibit` running `cat` on a
+/// pipe from us, so it goes away as soon as we close the pipe, or die.
+pub struct Inhibitor {
+    child: Child,
+}
+
+impl Inhibitor {
+    /// Whether systemd is the init system, so logind can take the lock.
+    pub fn available() -> bool {
+        Path::new("/run/systemd/system").exists()
+    }
+
+    pub fn acquire(why: &str) -> Result<Self, String> {
+        Self::hold(Self::command(why))
+    }
+
+    fn command(why: &str) -> Command {
+        let mut command = Command::new("systemd-inhibit");
+        command.args([
+            "--what=sleep:shutdown",
+            "--who=borg-timemachine",
+            &format!("--why={}", why),
+            "--mode=block",
+            "cat",
+        ]);
+        command
+    }
+
+    fn hold(mut command: Command) -> Result<Self, String> {
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // Keep a terminal Ctrl-C from releasing the lock before we
+            // have cleaned up
+            .process_group(0)
+            .spawn()
+            .map_err(|e| {
+                format!(
+                    "Failed to run {}: {}",
+                    command.get_program().to_string_lossy(),
+                    e
+                )
+            })?;
+        Ok(Self { child })
+    }
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        // EOF on stdin ends cat, and with it the lock
+        drop(self.child.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inhibitor_command() {
+        let command = Inhibitor::command("Backup in progress");
+        assert_eq!(command.get_program(), "systemd-inhibit");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "--what=sleep:shutdown",
+                "--who=borg-timemachine",
+                "--why=Backup in progress",
+                "--mode=block",
+                "cat"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lock_is_held_until_dropped() {
+        // cat stands in for systemd-inhibit, which holds the lock as long
+        // as its own cat runs
+        let mut inhibitor = Inhibitor::hold(Command::new("cat")).unwrap();
+        assert!(inhibitor.child.try_wait().unwrap().is_none());
+        let pid = inhibitor.child.id() as libc::pid_t;
+        drop(inhibitor);
+        // SAFETY: signal 0 only checks that the process exists
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+
+        assert!(Inhibitor::hold(Command::new("no-such-inhibit"))
+            .err()
+            .unwrap()
+            .contains("Failed to run no-such-inhibit"));
+    }
+}
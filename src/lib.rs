@@ -1,31 +1,280 @@
-use chrono::{Datelike, Local};
+pub mod archive_name;
+pub mod audit;
+pub mod browse;
+pub mod config_diff;
+pub mod config_format;
+pub mod coverage;
+pub mod desktop;
+pub mod display;
+pub mod include;
+pub mod inhibit;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod notify;
+pub mod overrides;
+pub mod passphrase;
+pub mod policy;
+pub mod preflight;
+pub mod privileges;
+pub mod replication;
+pub mod report;
+pub mod reporter;
+pub mod restore_test;
+pub mod retention;
+pub mod retry;
+pub mod runner;
+pub mod sd_notify;
+pub mod signals;
+pub mod snapshot;
+pub mod state;
+pub mod summary;
+pub mod systemd;
+pub mod targets;
+pub mod validate;
+pub mod version;
+pub mod wizard;
+
+use archive_name::NameTemplate;
+use audit::AuditEntry;
+use chrono::{DateTime, Datelike, Local, Utc};
 use serde::Deserialize;
 use std::fs;
-use std::io::{self, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Instant;
+
+use config_format::ConfigFormat;
+use inhibit::Inhibitor;
+use lock::LockGuard;
+use metrics::Metrics;
+use notify::{Channel, Healthcheck, Notification, Smtp};
+use passphrase::{BorgPassphrase, PassphraseSource};
+use policy::{
+    OnCheckOverdue, OnCheckpoints, OnMissingRepository, OnMissingSource, OnWarning, Policies,
+};
+use preflight::{CheckResult, CheckStatus, Policy};
+use report::{
+    ArchiveDetails, ArchiveEntry, ArchiveStats, CycleOutcome, CycleReport, DiskStatus, FileEntry,
+    HostStatus, InitOutcome, JobStats, RepositoryInfo, RepositoryReport, RepositoryStats,
+    StageOutcome, StageReport, Status,
+};
+use reporter::{Reporter, Silent};
+use runner::{BorgCommand, BorgRunner, SystemRunner};
+use sd_notify::{SdNotify, Watchdog};
+use state::{DiskRecord, HistoryEntry, StateStore};
+use summary::SummaryReport;
+use version::BorgVersion;
 
 const DEFAULT_CONFIG: &str = include_str!("../borg-config.yaml");
 
+const TRASH_PREFIX: &str = "trash-";
+const TRASH_TIMESTAMP: &str = "%Y-%m-%d-%H%M%SZ";
+
+/// Archive name that stands for this host's newest archive.
+pub const LATEST: &str = "latest";
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    /// Schema version of the file
+    #[serde(default)]
+    pub version: u32,
+    /// Name for this machine in archive names, instead of its short hostname
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub borg: Borg,
     pub repository: Repository,
     pub jobs: Vec<BackupJob>,
     #[serde(default)]
     pub exclusions: Vec<String>,
+    /// Files of exclude patterns, one per line (`--exclude-from`)
+    #[serde(default)]
+    pub exclusions_from: Vec<String>,
+    /// borg pattern files with include and exclude rules (`--patterns-from`)
+    #[serde(default)]
+    pub patterns_from: Vec<String>,
     pub compression: String,
+    #[serde(default)]
+    pub archive_timestamp: ArchiveTimestamp,
+    /// Archive names, e.g. `{hostname}-{job}-{timestamp}`
+    #[serde(default = "default_archive_name_template")]
+    pub archive_name_template: String,
     pub options: Options,
     pub retention: Retention,
     pub notifications: Notifications,
     pub logging: Logging,
     pub maintenance: Maintenance,
     pub security: Security,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub healthcheck: Option<Healthcheck>,
+    #[serde(default)]
+    pub output: display::Output,
+    #[serde(default)]
+    pub policy: Policies,
+    #[serde(default)]
+    pub schedule: systemd::Schedule,
+    #[serde(default)]
+    pub retry: retry::Retry,
+    #[serde(default)]
+    pub replication: Option<replication::Replication>,
+    /// More repositories `backup` writes to alongside `repository`
+    #[serde(default)]
+    pub targets: Vec<targets::Target>,
+    #[serde(default)]
+    pub run: privileges::Run,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Borg {
+    /// borg executable, a name on PATH or an absolute path
+    #[serde(default = "default_borg_binary")]
+    pub binary: String,
+    /// Seconds borg waits for a repository lock held by someone else
+    #[serde(default = "default_lock_wait")]
+    pub lock_wait: u64,
+    /// Tell borg this hostname is unique, letting it remove its own stale locks
+    #[serde(default)]
+    pub hostname_is_unique: Option<bool>,
+}
+
+fn default_borg_binary() -> String {
+    "borg".to_string()
+}
+
+fn default_lock_wait() -> u64 {
+    600
+}
+
+impl Default for Borg {
+    fn default() -> Self {
+        Self {
+            binary: default_borg_binary(),
+            lock_wait: default_lock_wait(),
+            hostname_is_unique: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Repository {
     pub path: String,
     pub encryption: String,
+    #[serde(default)]
+    pub remote: Option<Remote>,
+    /// Filesystem UUID the local repository must be on, for removable disks
+    #[serde(default)]
+    pub expect_uuid: Option<String>,
+    /// Filesystem label the local repository must be on
+    #[serde(default)]
+    pub expect_label: Option<String>,
+    /// Interchangeable disks; whichever is attached gets the backup
+    #[serde(default)]
+    pub rotation: Vec<RotationDisk>,
+    /// Created with `--append-only`; only `admin prune` removes data from it
+    #[serde(default)]
+    pub append_only: bool,
+    /// `status` warns about rotation disks not backed up within this age
+    #[serde(default = "default_rotation_max_age")]
+    pub rotation_max_age: String,
+    /// Other machines back up here too
+    #[serde(default)]
+    pub shared: bool,
+    /// `status` warns about hosts without an archive within this age
+    #[serde(default = "default_host_max_age")]
+    pub host_max_age: String,
+}
+
+fn default_rotation_max_age() -> String {
+    "14d".to_string()
+}
+
+fn default_host_max_age() -> String {
+    "7d".to_string()
+}
+
+/// One disk of a rotation, each holding its own repository.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RotationDisk {
+    pub name: String,
+    /// Filesystem UUID, as shown by `lsblk -f`
+    pub uuid: String,
+    /// Repository path while the disk is mounted
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Remote {
+    #[serde(default)]
+    pub user: Option<String>,
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+}
+
+impl Repository {
+    /// Location passed to borg: the local path or an SSH URL.
+    pub fn url(&self) -> String {
+        let remote = match &self.remote {
+            Some(r) => r,
+            None => return self.path.clone(),
+        };
+
+        let login = match &remote.user {
+            Some(user) => format!("{}@{}", user, remote.host),
+            None => remote.host.clone(),
+        };
+
+        match remote.port {
+            Some(port) => {
+                let path = if self.path.starts_with('/') {
+                    self.path.clone()
+                } else {
+                    format!("/./{}", self.path)
+                };
+                format!("ssh://{}:{}{}", login, port, path)
+            }
+            None => format!("{}:{}", login, self.path),
+        }
+    }
+
+    fn lock_location(&self) -> String {
+        if self.rotation.is_empty() {
+            return self.url();
+        }
+        let paths: Vec<&str> = self.rotation.iter().map(|d| d.path.as_str()).collect();
+        format!("rotation:{}", paths.join(","))
+    }
+
+    /// Value for `BORG_RSH`, if the remote needs a non-default ssh invocation.
+    pub fn ssh_command(&self) -> Option<String> {
+        let remote = self.remote.as_ref()?;
+        if remote.ssh_key.is_none() && remote.ssh_options.is_empty() {
+            return None;
+        }
+
+        let mut parts = vec!["ssh".to_string()];
+        if let Some(key) = &remote.ssh_key {
+            parts.push("-i".to_string());
+            parts.push(key.clone());
+        }
+        for opt in &remote.ssh_options {
+            parts.push("-o".to_string());
+            parts.push(opt.clone());
+        }
+        let quoted: Vec<String> = parts.iter().map(|p| passphrase::shell_quote(p)).collect();
+        Some(quoted.join(" "))
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -37,18 +286,198 @@ pub struct BackupJob {
     pub enabled: bool,
     #[serde(default)]
     pub exclude: Vec<String>,
+    #[serde(default)]
+    pub pre_command: Option<String>,
+    #[serde(default)]
+    pub post_command: Option<String>,
+    /// Back up exactly the paths listed by a file or command
+    #[serde(default)]
+    pub paths_from: Option<PathsFrom>,
+    #[serde(default)]
+    pub paths_delimiter: PathsDelimiter,
+    /// Mount points below `source` to back up despite `one_file_system`
+    #[serde(default)]
+    pub also_include_filesystems: Vec<String>,
+    /// Back up from a read-only LVM, btrfs or ZFS snapshot of the source
+    #[serde(default)]
+    pub snapshot: Option<snapshot::Snapshot>,
+    /// The source needs root to read, e.g. `/etc` or all of `/home`
+    #[serde(default)]
+    pub sudo: bool,
+}
+
+/// Source of an explicit path list: `{file: <path>}` or `{command: <shell>}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PathsFrom {
+    File { file: String },
+    Command { command: String },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PathsDelimiter {
+    #[default]
+    Newline,
+    Nul,
 }
 
 fn default_true() -> bool {
     true
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveTimestamp {
+    /// Local time with the UTC offset appended, e.g. `2024-03-31-021500+0100`
+    #[default]
+    Local,
+    /// UTC with a `Z` suffix, e.g. `2024-03-31-011500Z`
+    Utc,
+}
+
+impl ArchiveTimestamp {
+    pub fn format(self, now: DateTime<Utc>) -> String {
+        match self {
+            ArchiveTimestamp::Local => now
+                .with_timezone(&Local)
+                .format("%Y-%m-%d-%H%M%S%z")
+                .to_string(),
+            ArchiveTimestamp::Utc => now.format("%Y-%m-%d-%H%M%SZ").to_string(),
+        }
+    }
+}
+
+fn default_archive_name_template() -> String {
+    archive_name::DEFAULT_TEMPLATE.to_string()
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Options {
     pub one_file_system: bool,
     pub exclude_caches: bool,
     pub show_progress: bool,
     pub show_stats: bool,
+    /// What to do when a local repository shares a device with a job source
+    #[serde(default)]
+    pub same_device: Policy,
+    /// Keep the machine from suspending or shutting down during a cycle (systemd only)
+    #[serde(default = "default_true")]
+    pub inhibit_sleep: bool,
+    /// Leave out directories containing a file of one of these names, e.g. `.nobackup`
+    #[serde(default)]
+    pub exclude_if_present: Vec<String>,
+    /// Back up the tag files of excluded directories
+    #[serde(default)]
+    pub keep_exclude_tags: bool,
+    /// Seconds between checkpoints during a long `borg create`
+    #[serde(default)]
+    pub checkpoint_interval: Option<u64>,
+    /// Don't store access times; unset leaves borg's default
+    #[serde(default)]
+    pub noatime: Option<bool>,
+    /// Store numeric user and group IDs only
+    #[serde(default)]
+    pub numeric_ids: bool,
+    /// How borg spots unchanged files, e.g. `ctime,size,inode`
+    #[serde(default)]
+    pub files_cache: Option<String>,
+    /// Upload limit to a remote repository, in KiB/s
+    #[serde(default, alias = "remote_ratelimit")]
+    pub upload_ratelimit: Option<u64>,
+    /// Shorthand for `nice: 19` and `ionice: idle`
+    #[serde(default)]
+    pub low_priority: bool,
+    /// CPU priority borg runs at during a cycle, -20 to 19
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// I/O scheduling class during a cycle, e.g. `idle` or `best-effort:7`
+    #[serde(default)]
+    pub ionice: Option<String>,
+    /// Extra arguments for borg subcommands, for flags without an option
+    #[serde(default)]
+    pub extra_args: ExtraArgs,
+    /// How many `targets` `backup` runs at once
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+}
+
+fn default_max_parallel() -> usize {
+    2
+}
+
+/// Arguments appended to `borg create` and `borg prune` as given.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ExtraArgs {
+    #[serde(default)]
+    pub create: Vec<String>,
+    #[serde(default)]
+    pub prune: Vec<String>,
+}
+
+const FILES_CACHE_MODES: [&str; 6] = ["ctime", "mtime", "size", "inode", "rechunk", "disabled"];
+
+impl Options {
+    /// Checks on the options serde can't express.
+    pub fn lint(&self) -> Result<(), String> {
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("options.nice must be -20 to 19, got {}", nice));
+            }
+        }
+        if let Some(files_cache) = &self.files_cache {
+            if let Some(mode) = files_cache
+                .split(',')
+                .find(|mode| !FILES_CACHE_MODES.contains(&mode.trim()))
+            {
+                return Err(format!(
+                    "options.files_cache: unknown mode '{}' (expected {})",
+                    mode,
+                    FILES_CACHE_MODES.join(", ")
+                ));
+            }
+        }
+        self.ionice_args()?;
+        if self.max_parallel == 0 {
+            return Err("options.max_parallel must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// `ionice` arguments for the configured class.
+    pub fn ionice_args(&self) -> Result<Option<Vec<String>>, String> {
+        let Some(spec) = &self.ionice else {
+            return Ok(None);
+        };
+        let (class, level) = match spec.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (spec.as_str(), None),
+        };
+        let class = match class {
+            "idle" if level.is_none() => "3",
+            "best-effort" => "2",
+            "realtime" => "1",
+            _ => {
+                return Err(format!(
+                    "options.ionice: expected idle, best-effort[:0-7] or realtime[:0-7], got {}",
+                    spec
+                ))
+            }
+        };
+        let mut args = vec!["-c".to_string(), class.to_string()];
+        if let Some(level) = level {
+            match level.parse::<u8>() {
+                Ok(level) if level <= 7 => args.extend(["-n".to_string(), level.to_string()]),
+                _ => {
+                    return Err(format!(
+                        "options.ionice: level must be 0 to 7, got {}",
+                        level
+                    ))
+                }
+            }
+        }
+        Ok(Some(args))
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -59,37 +488,501 @@ pub struct Retention {
     pub weekly: u32,
     pub monthly: u32,
     pub yearly: u32,
+    /// Days a deleted archive stays in the trash before it is really deleted; 0 deletes immediately
+    #[serde(default = "default_trash_days")]
+    pub trash_days: u32,
+    /// Archives tagged with this label are never pruned; empty protects none
+    #[serde(default = "default_keep_tag")]
+    pub keep_tag: String,
+}
+
+fn default_trash_days() -> u32 {
+    7
+}
+
+fn default_keep_tag() -> String {
+    "keep".to_string()
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Notifications {
     pub enabled: bool,
-    pub email: String,
+    /// Shorthand for a single email channel
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Also notify when a cycle completes successfully
+    #[serde(default)]
+    pub on_success: bool,
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+    /// Deliver email channels over SMTP instead of the `mail` command
+    #[serde(default)]
+    pub smtp: Option<Smtp>,
+    /// Layout of the summary sent with `on_success`
+    #[serde(default)]
+    pub report: SummaryReport,
+    /// Pop-ups on this machine's desktop as cycles start and end
+    #[serde(default)]
+    pub desktop: Option<desktop::Desktop>,
+}
+
+impl Notifications {
+    /// Configured channels, including the `email` shorthand.
+    pub fn all_channels(&self) -> Vec<Channel> {
+        let mut channels = Vec::new();
+        if let Some(to) = &self.email {
+            channels.push(Channel::Email { to: to.clone() });
+        }
+        channels.extend(self.channels.iter().cloned());
+        channels
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Logging {
     pub log_file: String,
     pub lock_file: String,
+    /// Minimum level: error, warn, info, debug or trace
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: logging::LogFormat,
+    /// Rotate the log file once it reaches this size, e.g. `10M`
+    #[serde(default)]
+    pub max_size: Option<String>,
+    /// Number of rotated log files to keep
+    #[serde(default = "default_keep_files")]
+    pub keep_files: usize,
+    /// Gzip rotated log files
+    #[serde(default)]
+    pub compress_rotated: bool,
+    /// JSON file recording the history of backup cycles
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    /// Append-only JSON lines log of restores, mounts and deletions
+    #[serde(default = "default_audit_log")]
+    pub audit_log: String,
+}
+
+fn default_keep_files() -> usize {
+    5
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_state_file() -> String {
+    "/var/lib/borg-timemachine/state.json".to_string()
+}
+
+fn default_audit_log() -> String {
+    "/var/log/borg-timemachine-audit.log".to_string()
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Maintenance {
     pub check_day: u32,
+    #[serde(default)]
+    pub check_mode: CheckMode,
     pub auto_compact: bool,
+    /// Compact at most this often, e.g. `1d`; `0` compacts every cycle
+    #[serde(default = "default_compact_interval")]
+    pub compact_interval: String,
+    /// Only compact segments with at least this percentage of unused space
+    #[serde(default)]
+    pub compact_threshold: Option<u8>,
+    /// Run the check and compact at the end of backup cycles.
+    #[serde(default = "default_true")]
+    pub in_cycle: bool,
+    /// Check the new archive's metadata right after it is written
+    #[serde(default)]
+    pub verify_new_archive: bool,
+    /// Day of week to restore-test a sample of the new archive (1=Mon, 0=disabled)
+    #[serde(default)]
+    pub restore_test_day: u32,
+    /// Files in that sample
+    #[serde(default = "default_restore_test_files")]
+    pub restore_test_files: usize,
+    /// Warn once the repository stores more than this, e.g. `500G`
+    #[serde(default)]
+    pub max_repo_size: Option<String>,
+    /// Warn once a local repository's filesystem has less than this left
+    #[serde(default)]
+    pub min_free_space: Option<String>,
+    /// Stricter retention applied when either limit is crossed
+    #[serde(default)]
+    pub quota_retention: Option<Retention>,
+}
+
+fn default_restore_test_files() -> usize {
+    20
+}
+
+fn default_compact_interval() -> String {
+    "1d".to_string()
+}
+
+impl Maintenance {
+    /// How long `borg compact` waits after the last compact.
+    pub fn compact_interval(&self) -> Result<std::time::Duration, String> {
+        if self.compact_interval == "0" {
+            return Ok(std::time::Duration::ZERO);
+        }
+        parse_timeout(&self.compact_interval)
+            .map_err(|e| format!("maintenance.compact_interval: {}", e))
+    }
+
+    pub fn lint(&self) -> Result<(), String> {
+        self.compact_interval()?;
+        if self.compact_threshold.is_some_and(|t| t > 99) {
+            return Err("maintenance.compact_threshold is a percentage below 100".to_string());
+        }
+        self.quota()?;
+        if self.quota_retention.is_some()
+            && self.max_repo_size.is_none()
+            && self.min_free_space.is_none()
+        {
+            return Err(
+                "maintenance.quota_retention needs max_repo_size or min_free_space".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn quota(&self) -> Result<(Option<u64>, Option<u64>), String> {
+        let parse = |name: &str, size: &Option<String>| {
+            size.as_deref()
+                .map(logging::parse_size)
+                .transpose()
+                .map_err(|e| format!("maintenance.{}: {}", name, e))
+        };
+        Ok((
+            parse("max_repo_size", &self.max_repo_size)?,
+            parse("min_free_space", &self.min_free_space)?,
+        ))
+    }
+
+    /// The limits a repository of `size` bytes with `free` bytes left has crossed.
+    pub fn quota_problems(
+        &self,
+        size: u64,
+        free: Option<u64>,
+        output: &display::Output,
+    ) -> Result<Vec<String>, String> {
+        let (max_size, min_free) = self.quota()?;
+        let mut problems = Vec::new();
+        if let Some(max_size) = max_size.filter(|max| size > *max) {
+            problems.push(format!(
+                "repository stores {}, more than maintenance.max_repo_size {}",
+                output.bytes(size),
+                output.bytes(max_size)
+            ));
+        }
+        if let (Some(min_free), Some(free)) = (min_free, free) {
+            if free < min_free {
+                problems.push(format!(
+                    "only {} free on the repository's filesystem, less than maintenance.min_free_space {}",
+                    output.bytes(free),
+                    output.bytes(min_free)
+                ));
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// How thorough the scheduled `borg check` is.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMode {
+    /// Repository and archive metadata only
+    #[default]
+    Quick,
+    /// Also verify every data chunk on the first check of each month
+    Deep,
+}
+
+/// Order of the archives shown by `list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ListSort {
+    /// Oldest first
+    #[default]
+    Time,
+    Name,
+    /// Smallest original size first
+    Size,
+    /// Quickest backup first
+    Duration,
+}
+
+/// Which archives `list` shows, and how.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListOptions {
+    /// Only trashed archives, instead of only the others
+    pub trash: bool,
+    /// Only archives holding this job
+    pub job: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only the newest N left after the other filters
+    pub last: Option<usize>,
+    pub sort: ListSort,
+    pub reverse: bool,
+    /// Every host's archives of a shared repository, not only this host's
+    pub all_hosts: bool,
+}
+
+/// How `mount` mounts the repository.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MountOptions {
+    /// Only this archive, a name or `latest`
+    pub archive: Option<String>,
+    /// Stay in the foreground until unmounted
+    pub foreground: bool,
+    /// FUSE and borg mount options, e.g. `versions` or `uid=1000`
+    pub fuse_options: Vec<String>,
+}
+
+/// What a `borg check` run looks at, and whether it repairs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckOptions {
+    /// Read and verify every data chunk, not just the metadata
+    pub verify_data: bool,
+    pub repair: bool,
+    pub archives_only: bool,
+    pub repository_only: bool,
+    /// Only the newest N archives
+    pub last: Option<u32>,
+}
+
+impl CheckOptions {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.repair {
+            args.push("--repair".to_string());
+        }
+        if self.archives_only {
+            args.push("--archives-only".to_string());
+        }
+        if self.repository_only {
+            args.push("--repository-only".to_string());
+        }
+        if self.verify_data {
+            args.push("--verify-data".to_string());
+        }
+        if let Some(last) = self.last {
+            args.push(format!("--last={}", last));
+        }
+        args
+    }
+
+    fn is_full(&self) -> bool {
+        !self.archives_only && !self.repository_only && self.last.is_none()
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Security {
+    /// Read when no other `passphrase` source is configured
+    #[serde(default = "default_passphrase_file")]
     pub passphrase_file: String,
+    #[serde(default)]
+    pub passphrase: Option<PassphraseSource>,
+    /// Where the exported repository key is escrowed (`borg key export`).
+    #[serde(default, alias = "key_backup_path")]
+    pub key_backup: Option<String>,
+}
+
+fn default_passphrase_file() -> String {
+    "/root/.borg-passphrase".to_string()
+}
+
+impl Security {
+    pub fn passphrase_source(&self) -> PassphraseSource {
+        self.passphrase
+            .clone()
+            .unwrap_or_else(|| PassphraseSource::File {
+                path: self.passphrase_file.clone(),
+            })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Hooks {
+    #[serde(default)]
+    pub pre_cycle: Option<String>,
+    #[serde(default)]
+    pub post_cycle: Option<String>,
+    #[serde(default)]
+    pub on_failure: Option<String>,
+    /// Abort the cycle when a hook exits non-zero; otherwise only log a warning
+    #[serde(default = "default_true")]
+    pub fail_on_error: bool,
+    /// Held while a hook runs, so concurrent targets take turns
+    #[serde(skip)]
+    pub lock_file: Option<String>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre_cycle: None,
+            post_cycle: None,
+            on_failure: None,
+            fail_on_error: true,
+            lock_file: None,
+        }
+    }
 }
 
 impl Config {
+    /// Lock file of the repository, from `logging.lock_file`.
+    pub fn lock_file(&self) -> String {
+        lock::repository_lock_file(&self.logging.lock_file, &self.repository.lock_location())
+    }
+
     pub fn load(path: &str) -> Result<Self, String> {
-        let contents = fs::read_to_string(path)
+        Self::load_with_overrides(Some(path), &[])
+    }
+
+    /// Load the config file with its includes and `overrides` applied.
+    pub fn load_with_overrides(
+        path: Option<&str>,
+        overrides: &[overrides::Override],
+    ) -> Result<Self, String> {
+        let config = Self::read(path, overrides)?;
+        config.lint()?;
+        Ok(config)
+    }
+
+    /// Like `load_with_overrides`, without the `lint` checks.
+    pub fn read(path: Option<&str>, overrides: &[overrides::Override]) -> Result<Self, String> {
+        let contents = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read config file {}: {}", path, e))?,
+            None => DEFAULT_CONFIG.to_string(),
+        };
+
+        let format = path.map_or(ConfigFormat::Yaml, |p| {
+            ConfigFormat::from_path(Path::new(p))
+        });
+        let mut doc = format
+            .parse(&contents)
+            .map_err(|e| format!("Failed to parse config file: {}", e))?;
+        let includes = doc.get(include::INCLUDE_KEY).is_some();
+        let outdated = !migrate::upgrade(&mut doc)?.is_empty();
+
+        // Without includes, overrides or old option names parse YAML text
+        // directly, keeping line numbers in error messages
+        let config: Self =
+            if format == ConfigFormat::Yaml && !includes && overrides.is_empty() && !outdated {
+                serde_yaml::from_str(&contents)
+            } else {
+                if let Some(path) = path.filter(|_| includes) {
+                    include::resolve(&mut doc, Path::new(path))?;
+                }
+                let known = overrides::known_keys(DEFAULT_CONFIG);
+                for setting in overrides {
+                    overrides::apply(&mut doc, &known, setting)?;
+                }
+                serde_yaml::from_value(doc)
+            }
+            .map_err(|e| format!("Failed to parse config file: {}", e))?;
+        Ok(config)
+    }
+
+    /// Checks serde can't express, run on every load.
+    pub fn lint(&self) -> Result<(), String> {
+        retention::lint(&self.retention)?;
+        self.options.lint()?;
+        self.retry.lint()?;
+        self.maintenance.lint()?;
+        if let Some(replication) = &self.replication {
+            replication.lint(&self.repository.path)?;
+        }
+        targets::lint(self)?;
+        let mut snapshot_root = None;
+        let mut mounted: Vec<(&str, PathBuf)> = Vec::new();
+        for job in &self.jobs {
+            let Some(snapshot) = &job.snapshot else {
+                continue;
+            };
+            snapshot.lint(&job.name, &job.source)?;
+            if job.paths_from.is_some() {
+                return Err(format!(
+                    "job {}: snapshot cannot be used with paths_from",
+                    job.name
+                ));
+            }
+            // All jobs go into one borg create, run from one directory
+            if *snapshot_root.get_or_insert(&snapshot.mountpoint) != &snapshot.mountpoint {
+                return Err(format!(
+                    "job {}: all snapshots must share one mountpoint",
+                    job.name
+                ));
+            }
+            // One snapshot would be mounted over the other
+            let path = snapshot.path(&job.source);
+            if let Some((other, _)) = mounted
+                .iter()
+                .find(|(_, other)| path.starts_with(other) || other.starts_with(&path))
+            {
+                return Err(format!(
+                    "job {}: snapshot would be mounted at {}, overlapping job {}'s",
+                    job.name,
+                    path.display(),
+                    other
+                ));
+            }
+            mounted.push((&job.name, path));
+        }
+        if !self.repository.rotation.is_empty() {
+            parse_age(&self.repository.rotation_max_age)
+                .map_err(|e| format!("repository.rotation_max_age: {}", e))?;
+        }
+        if self.repository.shared {
+            parse_age(&self.repository.host_max_age)
+                .map_err(|e| format!("repository.host_max_age: {}", e))?;
+            // Without the hostname every host would write the same series
+            if !self.archive_name_template.contains("{hostname}") {
+                return Err(
+                    "repository.shared needs {hostname} in archive_name_template".to_string(),
+                );
+            }
+        }
+        let job_series = self.jobs.iter().any(|job| job.paths_from.is_some());
+        archive_name::lint(&self.archive_name_template, job_series)
+    }
+
+    /// Compare a config file with the bundled default config.
+    pub fn diff_against_default(path: &str) -> Result<Vec<config_diff::ConfigDifference>, String> {
+        let mut contents = fs::read_to_string(path)
             .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        let format = ConfigFormat::from_path(Path::new(path));
+        if format != ConfigFormat::Yaml {
+            contents = format
+                .parse(&contents)
+                .and_then(|doc| ConfigFormat::Yaml.render(&doc))
+                .map_err(|e| format!("Failed to parse config file: {}", e))?;
+        }
+        config_diff::diff(DEFAULT_CONFIG, &contents)
+    }
 
-        serde_yaml::from_str(&contents).map_err(|e| format!("Failed to parse config file: {}", e))
+    /// The bundled default config in `format`.
+    pub fn example(format: ConfigFormat) -> Result<String, String> {
+        match format {
+            ConfigFormat::Yaml => Ok(DEFAULT_CONFIG.to_string()),
+            ConfigFormat::Toml => {
+                let doc = ConfigFormat::Yaml.parse(DEFAULT_CONFIG)?;
+                let body = ConfigFormat::Toml.render(&doc)?;
+                Ok(format!(
+                    "# Borg Time Machine Configuration\n\
+                     # Every option is documented in `generate-config` (YAML) output.\n\n{}",
+                    body
+                ))
+            }
+        }
     }
 
     pub fn load_or_default(path: Option<&str>) -> Result<Self, String> {
@@ -104,399 +997,7033 @@ impl Config {
 
 pub struct BorgBackup {
     config: Config,
-    log_handle: Option<fs::File>,
+    runner: Box<dyn BorgRunner>,
+    version: BorgVersion,
+    disk: Option<String>,
     hostname: String,
+    user: String,
+    passphrase: Option<BorgPassphrase>,
+    dry_run: bool,
+    interactive: bool,
+    admin: bool,
+    run_as: Option<privileges::Account>,
+    comment: Option<String>,
+    reporter: Box<dyn Reporter>,
+    report: CycleReport,
+    cycle_log: Vec<String>,
+    sd_notify: Option<SdNotify>,
+    watchdog: Option<Watchdog>,
 }
 
 impl BorgBackup {
     pub fn new(config: Config) -> Result<Self, String> {
-        let hostname = Self::get_hostname()?;
+        let runner = SystemRunner::new(&config.borg.binary);
+        Self::with_runner(config, Box::new(runner))
+    }
 
-        Ok(Self {
+    /// Like `new`, but run borg through `runner`.
+    pub fn with_runner(config: Config, runner: Box<dyn BorgRunner>) -> Result<Self, String> {
+        let hostname = get_hostname(config.hostname.as_deref())?;
+        let version = Self::detect_version(&config.borg.binary, runner.as_ref())?;
+        let run_as = privileges::run_as(&config)?;
+
+        let mut backup = Self {
             config,
-            log_handle: None,
+            runner,
+            version,
+            disk: None,
             hostname,
-        })
+            user: archive_name::current_user(),
+            passphrase: None,
+            dry_run: false,
+            interactive: false,
+            admin: false,
+            run_as,
+            comment: None,
+            reporter: Box::new(Silent),
+            report: CycleReport::new(Utc::now()),
+            cycle_log: Vec::new(),
+            sd_notify: SdNotify::from_env(),
+            watchdog: None,
+        };
+        backup.select_rotation_disk();
+        Ok(backup)
     }
 
-    pub fn get_repo_path(&self) -> String {
-        self.config.repository.path.clone()
+    fn select_rotation_disk(&mut self) {
+        let attached = self.config.repository.rotation.iter().find(|disk| {
+            preflight::mounted_device(Path::new(&disk.path))
+                .and_then(|device| device.uuid)
+                .is_some_and(|uuid| uuid.eq_ignore_ascii_case(&disk.uuid))
+        });
+        if let Some(disk) = attached.cloned() {
+            self.config.repository.path = disk.path;
+            self.config.repository.expect_uuid = Some(disk.uuid);
+            self.disk = Some(disk.name);
+        }
     }
 
-    fn get_hostname() -> Result<String, String> {
-        let output = Command::new("hostname")
-            .arg("-s")
-            .output()
-            .map_err(|e| format!("Failed to get hostname: {}", e))?;
+    /// Name of the attached rotation disk in use.
+    pub fn rotation_disk(&self) -> Option<&str> {
+        self.disk.as_deref()
+    }
 
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    /// Simulate create/prune without modifying the repository.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
     }
 
-    pub fn init_repository(&self) -> Result<(), String> {
-        println!(
-            "Initializing Borg repository at: {}",
-            self.config.repository.path
-        );
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
 
-        // Check if repository already exists
-        let check = Command::new("borg")
-            .args(["info", &self.config.repository.path])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
+    /// Act as the trusted machine of an append-only repository.
+    pub fn set_admin(&mut self, admin: bool) {
+        self.admin = admin;
+    }
 
-        if check.is_ok() && check.unwrap().success() {
-            return Err(format!(
-                "Repository already exists at {}. Remove it first or use a different path.",
-                self.config.repository.path
-            ));
-        }
-
-        let status = Command::new("borg")
-            .args([
-                "init",
-                &format!("--encryption={}", self.config.repository.encryption),
-                &self.config.repository.path,
-            ])
-            .status()
-            .map_err(|e| format!("Failed to run borg init: {}", e))?;
+    /// Store `comment` with the archives `create_backup` creates.
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
 
-        if !status.success() {
-            return Err("borg init failed".to_string());
-        }
+    /// Send messages and questions to `reporter` instead of dropping them.
+    pub fn set_reporter(&mut self, reporter: impl Reporter + 'static) {
+        self.reporter = Box::new(reporter);
+    }
 
-        println!("Repository initialized successfully!");
-        println!("\nIMPORTANT: Export and backup your encryption key:");
-        println!(
-            "  borg key export {} ~/borg-key-backup.txt",
-            self.config.repository.path
-        );
-        println!(
-            "  borg key export --paper {} borg-key-qr.html",
-            self.config.repository.path
-        );
+    /// Allow asking questions on the terminal.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
 
-        Ok(())
+    pub fn config(&self) -> &Config {
+        &self.config
     }
 
-    pub fn check_lock(&self) -> Result<(), String> {
-        if Path::new(&self.config.logging.lock_file).exists() {
+    fn detect_version(binary: &str, runner: &dyn BorgRunner) -> Result<BorgVersion, String> {
+        let mut cmd = BorgCommand::new();
+        cmd.arg("--version");
+        let output = runner.capture(cmd).map_err(|e| {
+            format!(
+                "Failed to run {}: {} (install borg or set borg.binary)",
+                binary, e
+            )
+        })?;
+        if !output.status.success() {
             return Err(format!(
-                "Lock file exists at {}. Another backup may be running.",
-                self.config.logging.lock_file
+                "{} --version failed: {}",
+                binary,
+                String::from_utf8_lossy(&output.stderr).trim()
             ));
         }
-        Ok(())
+
+        let version = BorgVersion::parse(&String::from_utf8_lossy(&output.stdout))?;
+        version.check_supported()?;
+        Ok(version)
     }
 
-    pub fn create_lock(&self) -> Result<(), String> {
-        fs::write(&self.config.logging.lock_file, "")
-            .map_err(|e| format!("Failed to create lock file: {}", e))
+    /// Version of the borg binary in use.
+    pub fn borg_version(&self) -> BorgVersion {
+        self.version
     }
 
-    pub fn remove_lock(&self) {
-        let _ = fs::remove_file(&self.config.logging.lock_file);
+    pub fn get_repo_path(&self) -> String {
+        self.config.repository.url()
     }
 
-    fn log(&mut self, message: &str) {
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-        let log_line = format!("[{}] {}\n", timestamp, message);
+    fn borg(&self) -> BorgCommand {
+        self.borg_reading(false)
+    }
 
-        // Print to stdout
-        print!("{}", log_line);
-        let _ = io::stdout().flush();
+    fn borg_reading(&self, read_all: bool) -> BorgCommand {
+        let mut cmd = self.borg_as(self.run_as.as_ref(), read_all);
+        if let Some(account) = &self.run_as {
+            cmd.env("HOME", &account.home).env("USER", &account.name);
+        }
+        cmd
+    }
 
-        // Write to log file if handle exists
-        if let Some(ref mut handle) = self.log_handle {
-            let _ = handle.write_all(log_line.as_bytes());
+    fn borg_as_root(&self) -> BorgCommand {
+        let mut cmd = self.borg_as(None, false);
+        if let Some(account) = &self.run_as {
+            if std::env::var_os("BORG_KEYS_DIR").is_none() {
+                cmd.env(
+                    "BORG_KEYS_DIR",
+                    Path::new(&account.home).join(".config/borg/keys"),
+                );
+            }
         }
+        cmd
     }
 
-    pub fn open_log(&mut self) -> Result<(), String> {
-        let log_file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.config.logging.log_file)
-            .map_err(|e| format!("Failed to open log file: {}", e))?;
+    fn borg_as(&self, account: Option<&privileges::Account>, read_all: bool) -> BorgCommand {
+        let mut cmd = BorgCommand::new();
+        if let Some(account) = account {
+            cmd.wrap("setpriv", privileges::setpriv_args(account, read_all));
+        }
+        cmd.common_arg(format!("--lock-wait={}", self.config.borg.lock_wait));
+        if let Some(rsh) = self.config.repository.ssh_command() {
+            cmd.env("BORG_RSH", rsh);
+        }
+        if let Some(passphrase) = &self.passphrase {
+            cmd.env(passphrase.variable, &passphrase.value);
+        }
+        if let Some(unique) = self.config.borg.hostname_is_unique {
+            cmd.env("BORG_HOSTNAME_IS_UNIQUE", if unique { "yes" } else { "no" });
+        }
+        cmd
+    }
 
-        self.log_handle = Some(log_file);
-        Ok(())
+    fn borg_in_background(&self) -> BorgCommand {
+        self.borg_in_background_reading(false)
     }
 
-    pub fn load_passphrase(&self) -> Result<String, String> {
-        fs::read_to_string(&self.config.security.passphrase_file)
-            .map(|s| s.trim().to_string())
-            .map_err(|e| format!("Failed to read passphrase file: {}", e))
+    fn borg_in_background_reading(&self, read_all: bool) -> BorgCommand {
+        let options = &self.config.options;
+        let mut cmd = self.borg_reading(read_all);
+        let nice = options.nice.or(options.low_priority.then_some(19));
+        if let Some(nice) = nice {
+            cmd.wrap("nice", ["-n".to_string(), nice.to_string()]);
+        }
+        // Checked when the config is loaded
+        let ionice = match options.ionice_args() {
+            Ok(Some(args)) => Some(args),
+            // ionice is Linux only; low_priority does without it elsewhere
+            _ if options.low_priority && on_path("ionice") => {
+                Some(vec!["-c".to_string(), "3".to_string()])
+            }
+            _ => None,
+        };
+        if let Some(args) = ionice {
+            cmd.wrap("ionice", args);
+        }
+        cmd
     }
 
-    pub fn create_backup(&mut self) -> Result<(), String> {
-        let archive_name = format!(
-            "{}-{}",
-            self.hostname,
-            Local::now().format("%Y-%m-%d-%H%M%S")
+    fn effective_compression(&mut self) -> String {
+        if self.config.compression != "auto" {
+            return self.config.compression.clone();
+        }
+
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let remote = self.config.repository.remote.is_some();
+        let compression = auto_compression(cpus, remote);
+        self.log(&format!(
+            "Using compression {} (auto: {} CPUs, {} repository)",
+            compression,
+            cpus,
+            if remote { "remote" } else { "local" }
+        ));
+        compression
+    }
+
+    fn name_template(&self) -> NameTemplate<'_> {
+        NameTemplate {
+            template: &self.config.archive_name_template,
+            hostname: &self.hostname,
+            user: &self.user,
+        }
+    }
+
+    fn archive_globs(&self) -> Vec<String> {
+        let template = self.name_template();
+        let mut globs = vec![template.glob(None)];
+        globs.extend(
+            self.config
+                .jobs
+                .iter()
+                .filter(|job| job.paths_from.is_some())
+                .map(|job| template.glob(Some(&job.name))),
         );
+        globs
+    }
 
-        self.log(&format!("Starting backup: {}", archive_name));
+    /// Create the repository.
+    pub fn init_repository(&mut self, export_key: Option<&str>) -> Result<InitOutcome, String> {
+        self.check_repository_device()?;
+        self.reporter.message(&format!(
+            "Initializing Borg repository at: {}",
+            self.get_repo_path()
+        ));
 
-        // Build borg create command
-        let mut cmd = Command::new("borg");
-        cmd.arg("create");
+        // Check if repository already exists
+        let mut check = self.borg();
+        check.args(["info", &self.get_repo_path()]);
+        let check = self.runner.capture(check);
 
-        if self.config.options.show_stats {
-            cmd.arg("--stats");
-        }
-        if self.config.options.show_progress {
-            cmd.arg("--progress");
-        }
-        if self.config.options.one_file_system {
-            cmd.arg("--one-file-system");
+        if check.is_ok() && check.unwrap().status.success() {
+            return Err(format!(
+                "Repository already exists at {}. Remove it first or use a different path.",
+                self.get_repo_path()
+            ));
         }
-        if self.config.options.exclude_caches {
-            cmd.arg("--exclude-caches");
+
+        let mut cmd = self.borg();
+        cmd.args([
+            "init",
+            &format!("--encryption={}", self.config.repository.encryption),
+        ]);
+        if self.config.repository.append_only {
+            cmd.arg("--append-only");
         }
+        cmd.arg(self.get_repo_path());
+        let status = self
+            .runner
+            .execute(cmd)
+            .map_err(|e| format!("Failed to run borg init: {}", e))?;
 
-        cmd.arg(format!("--compression={}", self.config.compression));
+        if !status.success() {
+            return Err("borg init failed".to_string());
+        }
 
-        // Add global exclusions
-        for pattern in &self.config.exclusions {
-            cmd.arg("--exclude").arg(pattern);
+        if let Some(path) = export_key {
+            self.export_key(Some(path), false)?;
         }
+        Ok(InitOutcome {
+            location: self.get_repo_path(),
+            key_exported_to: export_key.map(str::to_string),
+        })
+    }
 
-        // Build archive path with jobs
-        let archive_path = format!("{}::{}", self.config.repository.path, archive_name);
-        cmd.arg(&archive_path);
+    /// The `authorized_keys` line restricting `public_key` to an append-only `borg serve`.
+    pub fn authorized_keys_line(&self, public_key: &str) -> Result<String, String> {
+        let repo = &self.config.repository;
+        if repo.remote.is_none() {
+            return Err("authorized_keys lines are for remote repositories".to_string());
+        }
+        let key = public_key.trim();
+        if key.is_empty() || key.lines().count() != 1 {
+            return Err("Expected a single SSH public key".to_string());
+        }
+        Ok(format!(
+            "command=\"borg serve --append-only --restrict-to-repository {}\",restrict {}",
+            repo.path, key
+        ))
+    }
 
-        // Add all enabled job sources
-        for job in &self.config.jobs {
-            if job.enabled {
-                cmd.arg(&job.source);
+    /// Whether the repository has been created.
+    pub fn repository_exists(&self) -> Result<bool, String> {
+        let mut cmd = self.borg();
+        cmd.args(["info", &self.get_repo_path()]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg info: {}", e))?;
+        if output.status.success() {
+            return Ok(true);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stderr.contains("does not exist") || stdout.contains("does not exist") {
+            return Ok(false);
+        }
+        Err(format!(
+            "borg info failed with exit code {}: {}",
+            output.status.code().unwrap_or(-1),
+            stderr.trim()
+        ))
+    }
 
-                // Add job-specific exclusions
-                for pattern in &job.exclude {
-                    cmd.arg("--exclude").arg(pattern);
-                }
+    fn ensure_repository(&mut self) -> Result<(), String> {
+        if self.repository_exists()? {
+            return Ok(());
+        }
+        let repo = self.get_repo_path();
+        let init = match self.config.policy.on_missing_repository {
+            OnMissingRepository::Init => true,
+            OnMissingRepository::Fail => false,
+            OnMissingRepository::Ask => {
+                self.interactive
+                    && self.reporter.confirm(&format!(
+                        "Repository {} does not exist. Initialize it now?",
+                        repo
+                    ))?
             }
+        };
+        if !init {
+            return Err(format!(
+                "Repository {} does not exist. Create it with \
+                 `borg-timemachine init --export-key <file>`, or set \
+                 policy.on_missing_repository: init",
+                repo
+            ));
         }
-
-        let status = cmd
-            .status()
-            .map_err(|e| format!("Failed to run borg create: {}", e))?;
-
-        // Borg exit codes:
-        // 0 = success
-        // 1 = warning (backup completed but some files couldn't be read)
-        // 2+ = error (backup failed)
-        let exit_code = status.code().unwrap_or(2);
-        if exit_code >= 2 {
-            return Err(format!("borg create failed with exit code {}", exit_code));
+        if self.dry_run {
+            return Err(format!(
+                "Repository {} does not exist; a real run would initialize it",
+                repo
+            ));
         }
 
-        if exit_code == 1 {
-            self.log("Backup created with warnings (some files may have been skipped)");
-        } else {
-            self.log("Backup created successfully");
+        self.log(&format!(
+            "Repository {} does not exist, initializing it",
+            repo
+        ));
+        let key_backup = self.config.security.key_backup.clone();
+        let outcome = self.init_repository(key_backup.as_deref())?;
+        if let Some(path) = outcome.key_exported_to {
+            self.log(&format!("Key exported to {}", path));
         }
         Ok(())
     }
 
-    pub fn prune_backups(&mut self) -> Result<(), String> {
-        self.log("Pruning old backups...");
+    /// Export the repository key to `path`, or print it when `path` is `None`.
+    pub fn export_key(&mut self, path: Option<&str>, paper: bool) -> Result<(), String> {
+        let mut cmd = self.borg();
+        cmd.args(["key", "export"]);
+        if paper {
+            cmd.arg("--paper");
+        }
+        cmd.arg(self.get_repo_path());
+        if let Some(path) = path {
+            cmd.arg(path);
+        }
+        let result = self.run_key_command(cmd, "export");
+        let result = result.and_then(|()| match path {
+            Some(path) => restrict_permissions(path),
+            None => Ok(()),
+        });
+        self.audit(
+            AuditEntry::new(&self.hostname, "key-export", None, &result)
+                .with_target(path.unwrap_or("stdout")),
+        );
+        result
+    }
 
-        let mut cmd = Command::new("borg");
-        cmd.arg("prune")
-            .arg("--list")
-            .arg(format!("--prefix={}-", self.hostname))
-            .arg(format!("--keep-within={}", self.config.retention.within))
-            .arg(format!("--keep-hourly={}", self.config.retention.hourly))
-            .arg(format!("--keep-daily={}", self.config.retention.daily))
-            .arg(format!("--keep-weekly={}", self.config.retention.weekly))
-            .arg(format!("--keep-monthly={}", self.config.retention.monthly))
-            .arg(format!("--keep-yearly={}", self.config.retention.yearly))
-            .arg(&self.config.repository.path);
-
-        let status = cmd
-            .status()
-            .map_err(|e| format!("Failed to run borg prune: {}", e))?;
+    /// Restore the repository key from a `key export` file.
+    pub fn import_key(&mut self, path: &str) -> Result<(), String> {
+        if self.dry_run {
+            self.log(&format!("Would import the key from {}", path));
+            return Ok(());
+        }
 
-        let exit_code = status.code().unwrap_or(2);
-        if exit_code >= 2 {
-            return Err(format!("borg prune failed with exit code {}", exit_code));
+        let mut cmd = self.borg();
+        cmd.args(["key", "import", &self.get_repo_path(), path]);
+        let result = self.run_key_command(cmd, "import");
+        self.audit(AuditEntry::new(&self.hostname, "key-import", None, &result).with_target(path));
+        result
+    }
+
+    /// Change the repository passphrase.
+    pub fn change_passphrase(
+        &mut self,
+        new_passphrase: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        if self.dry_run {
+            self.log("Would change the repository passphrase");
+            return Ok(None);
         }
 
-        self.log("Prune completed");
-        Ok(())
+        let mut cmd = self.borg();
+        cmd.args(["key", "change-passphrase", &self.get_repo_path()]);
+        if let Some(passphrase) = new_passphrase {
+            cmd.env("BORG_NEW_PASSPHRASE", passphrase);
+        }
+        let mut result = self.run_key_command(cmd, "change-passphrase");
+        let source = self.config.security.passphrase_source();
+        let file = match &source {
+            PassphraseSource::File { path } if new_passphrase.is_some() => Some(path.clone()),
+            _ => None,
+        };
+        if let (Ok(()), Some(path), Some(passphrase)) = (&result, &file, new_passphrase) {
+            result = write_secret(path, passphrase);
+        }
+        self.audit(AuditEntry::new(
+            &self.hostname,
+            "key-change-passphrase",
+            None,
+            &result,
+        ));
+        result.map(|()| file)
     }
 
-    pub fn compact_repository(&mut self) -> Result<(), String> {
-        if !self.config.maintenance.auto_compact {
-            return Ok(());
+    fn run_key_command(&self, cmd: BorgCommand, name: &str) -> Result<(), String> {
+        match self.runner.execute(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            Ok(_) => Err(format!("borg key {} failed", name)),
+            Err(e) => Err(format!("Failed to run borg key {}: {}", name, e)),
         }
+    }
 
-        self.log("Compacting repository...");
+    /// Take the repository's lock, reporting any stale lock left by a dead process.
+    pub fn acquire_lock(&mut self) -> Result<LockGuard, String> {
+        let (guard, previous) = LockGuard::acquire(&self.config.lock_file())?;
+        if let Some(owner) = previous {
+            self.log(&format!(
+                "Cleaned up stale lock left by PID {} (started {})",
+                owner.pid, owner.started
+            ));
+        }
+        Ok(guard)
+    }
 
-        let status = Command::new("borg")
-            .args(["compact", &self.config.repository.path])
-            .status()
-            .map_err(|e| format!("Failed to run borg compact: {}", e))?;
+    /// Remove our lock file and run `borg break-lock` on the repository.
+    pub fn break_lock(&mut self) -> Result<(), String> {
+        let path = self.config.lock_file();
+        let owner = lock::read_owner(&path);
+        lock::break_lock(&path)?;
+        if let Some(owner) = owner {
+            self.warn(&format!(
+                "Broke lock left by PID {} (started {})",
+                owner.pid, owner.started
+            ));
+        }
 
-        let exit_code = status.code().unwrap_or(2);
-        if exit_code >= 2 {
-            return Err(format!("borg compact failed with exit code {}", exit_code));
+        let mut cmd = self.borg();
+        cmd.args(["break-lock", &self.get_repo_path()]);
+        let status = self
+            .runner
+            .execute(cmd)
+            .map_err(|e| format!("Failed to run borg break-lock: {}", e))?;
+        if !status.success() {
+            return Err("borg break-lock failed".to_string());
         }
 
-        self.log("Compact completed");
+        self.log("Locks removed");
         Ok(())
     }
 
-    pub fn check_repository(&mut self) -> Result<(), String> {
-        // Only run on the configured day
-        let today = Local::now().weekday().num_days_from_monday() + 1;
-        if self.config.maintenance.check_day == 0 || today != self.config.maintenance.check_day {
-            return Ok(());
-        }
+    fn log(&mut self, message: &str) {
+        let message = self.decorate(message);
+        tracing::info!("{}", message);
+        self.remember(&message);
+    }
 
-        self.log("Running weekly integrity check...");
+    fn warn(&mut self, message: &str) {
+        let decorated = self.decorate(message);
+        tracing::warn!("{}", decorated);
+        self.remember(&format!("WARNING: {}", decorated));
+        self.report.warnings.push(message.to_string());
+    }
 
-        let status = Command::new("borg")
-            .args(["check", &self.config.repository.path])
-            .status()
-            .map_err(|e| format!("Failed to run borg check: {}", e))?;
+    fn error(&mut self, message: &str) {
+        let message = self.decorate(message);
+        tracing::error!("{}", message);
+        self.remember(&format!("ERROR: {}", message));
+    }
 
-        let exit_code = status.code().unwrap_or(2);
-        if exit_code >= 2 {
-            return Err(format!(
-                "Repository integrity check failed with exit code {}",
-                exit_code
-            ));
+    fn decorate(&self, message: &str) -> String {
+        if self.dry_run {
+            format!("[DRY RUN] {}", message)
+        } else {
+            message.to_string()
         }
+    }
 
-        self.log("Integrity check passed");
-        Ok(())
+    fn remember(&mut self, message: &str) {
+        self.cycle_log.push(timestamped(message));
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.ping();
+        }
     }
 
-    pub fn send_failure_notification(&self, error: &str) {
-        if !self.config.notifications.enabled {
-            return;
+    fn run_logged(&mut self, cmd: BorgCommand) -> io::Result<ExitStatus> {
+        let cycle_log = &mut self.cycle_log;
+        let watchdog = self.watchdog.as_ref();
+        let mut lock_failed = false;
+        let status = self.runner.stream(cmd, &mut |progress, line| {
+            if let Some(watchdog) = watchdog {
+                watchdog.ping();
+            }
+            if progress {
+                tracing::debug!(target: "borg", "{}", line);
+            } else {
+                lock_failed |= line.contains(lock::BORG_LOCK_FAILED);
+                tracing::info!(target: "borg", "{}", line);
+                cycle_log.push(timestamped(&line));
+            }
+        });
+
+        if let Some(signal) = signals::interrupted() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("interrupted by {}", signals::name(signal)),
+            ));
+        }
+        if lock_failed && !status.as_ref().is_ok_and(|s| s.success()) {
+            return Err(io::Error::other(format!(
+                "the repository is locked by {}; gave up after {}s (borg.lock_wait)",
+                self.describe_borg_lock(),
+                self.config.borg.lock_wait
+            )));
         }
+        status
+    }
 
-        let subject = format!("Backup Failure on {}", self.hostname);
-        let body = format!("Borg backup failed: {}", error);
+    fn run_with_retry(
+        &mut self,
+        what: &str,
+        build: impl Fn(&mut Self) -> BorgCommand,
+    ) -> Result<i32, String> {
+        let retry = self.config.retry.clone();
+        let mut attempt = 1;
+        loop {
+            let cmd = build(self);
+            let status = self
+                .run_logged(cmd)
+                .map_err(|e| format!("Failed to run borg {}: {}", what, e))?;
 
-        let _ = Command::new("mail")
-            .args(["-s", &subject, &self.config.notifications.email])
-            .stdin(Stdio::piped())
-            .spawn()
-            .and_then(|mut child| {
-                if let Some(ref mut stdin) = child.stdin {
-                    stdin.write_all(body.as_bytes())?;
+            // Borg exit codes:
+            // 0 = success
+            // 1 = warning (completed but e.g. some files couldn't be read)
+            // 2+ = error
+            let exit_code = status.code().unwrap_or(2);
+            if exit_code < 2 {
+                if attempt > 1 {
+                    self.log(&format!("borg {} succeeded on attempt {}", what, attempt));
                 }
-                child.wait()
-            });
+                return Ok(exit_code);
+            }
+            if !retry.should_retry(attempt, exit_code) {
+                return Err(match attempt {
+                    1 => format!("borg {} failed with exit code {}", what, exit_code),
+                    _ => format!(
+                        "borg {} failed with exit code {} after {} attempts",
+                        what, exit_code, attempt
+                    ),
+                });
+            }
+
+            let delay = retry.delay(attempt);
+            self.log(&format!(
+                "borg {} attempt {}/{} failed with exit code {}; retrying in {}s",
+                what,
+                attempt,
+                retry.max_attempts,
+                exit_code,
+                delay.as_secs()
+            ));
+            let until = std::time::Instant::now() + delay;
+            while let Some(left) = until.checked_duration_since(std::time::Instant::now()) {
+                if let Some(signal) = signals::interrupted() {
+                    return Err(format!(
+                        "Failed to run borg {}: interrupted by {}",
+                        what,
+                        signals::name(signal)
+                    ));
+                }
+                std::thread::sleep(left.min(std::time::Duration::from_secs(1)));
+            }
+            attempt += 1;
+        }
     }
 
-    pub fn run_backup_cycle(&mut self) -> Result<(), String> {
-        self.check_lock()?;
-        self.create_lock()?;
+    /// Processes holding borg's lock on the repository.
+    pub fn borg_lock_holders(&self) -> Vec<lock::BorgLockHolder> {
+        if self.config.repository.remote.is_some() {
+            return Vec::new();
+        }
+        lock::borg_lock_holders(Path::new(&self.config.repository.path), &self.hostname)
+    }
 
-        let result = self.run_backup_cycle_inner();
+    fn describe_borg_lock(&self) -> String {
+        let holders = self.borg_lock_holders();
+        if holders.is_empty() {
+            return match &self.config.repository.remote {
+                Some(remote) => format!("another borg process using {}", remote.host),
+                None => "another borg process".to_string(),
+            };
+        }
+        holders
+            .iter()
+            .map(|holder| holder.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 
-        if let Err(ref e) = result {
-            self.log(&format!("ERROR: {}", e));
-            self.send_failure_notification(e);
+    fn log_borg_lock(&mut self) {
+        if self.borg_lock_holders().is_empty() {
+            return;
         }
+        let message = format!(
+            "Repository is locked by {}; borg will wait up to {}s for it (borg.lock_wait)",
+            self.describe_borg_lock(),
+            self.config.borg.lock_wait
+        );
+        self.log(&message);
+    }
 
-        self.remove_lock();
-        result
+    fn log_tail(&self, lines: usize) -> String {
+        let start = self.cycle_log.len().saturating_sub(lines);
+        self.cycle_log[start..].join("\n")
     }
 
-    fn run_backup_cycle_inner(&mut self) -> Result<(), String> {
-        self.open_log()?;
+    fn shell(&self, command: &str) -> Command {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        self.config.security.passphrase_source().scrub(&mut shell);
+        shell
+    }
 
-        // Run backup
-        self.create_backup()?;
+    fn run_hook(&mut self, label: &str, command: &str, env: &[(&str, &str)]) -> Result<(), String> {
+        let _turn = match self.config.hooks.lock_file.clone() {
+            Some(path) => Some(LockGuard::wait(&path)?),
+            None => None,
+        };
+        self.log(&format!("Running {} hook: {}", label, command));
 
-        // Prune old backups
-        self.prune_backups()?;
+        let output = self
+            .shell(command)
+            .env("DRY_RUN", if self.dry_run { "1" } else { "0" })
+            .envs(env.iter().copied())
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Failed to run {} hook: {}", label, e))?;
 
-        // Compact repository
-        self.compact_repository()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        for line in stdout.lines().chain(stderr.lines()) {
+            self.log(&format!("  [{}] {}", label, line));
+        }
 
-        // Check repository (if scheduled)
-        self.check_repository()?;
+        if output.status.success() {
+            return Ok(());
+        }
 
-        self.log("Backup cycle complete");
-        Ok(())
+        let error = format!(
+            "{} hook failed with exit code {}",
+            label,
+            output.status.code().unwrap_or(-1)
+        );
+        if self.config.hooks.fail_on_error {
+            Err(error)
+        } else {
+            self.warn(&error);
+            Ok(())
+        }
     }
 
-    pub fn list_archives(&self) -> Result<(), String> {
-        let status = Command::new("borg")
-            .args(["list", &self.config.repository.path])
-            .status()
-            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+    fn run_job_hooks(&mut self, post: bool) -> Result<(), String> {
+        let label = if post { "post_command" } else { "pre_command" };
+        let hooks: Vec<(String, String)> = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.enabled)
+            .filter_map(|job| {
+                let command = if post {
+                    &job.post_command
+                } else {
+                    &job.pre_command
+                };
+                command.clone().map(|c| (job.name.clone(), c))
+            })
+            .collect();
 
-        if !status.success() {
-            return Err("borg list failed".to_string());
+        for (name, command) in hooks {
+            self.run_hook(
+                &format!("{} {}", name, label),
+                &command,
+                &[("BORG_TIMEMACHINE_JOB", &name)],
+            )?;
         }
-
         Ok(())
     }
 
-    pub fn mount_repository(&self, mount_point: &str) -> Result<(), String> {
-        println!("Mounting repository to {}", mount_point);
-
-        let status = Command::new("borg")
-            .args(["mount", &self.config.repository.path, mount_point])
-            .status()
-            .map_err(|e| format!("Failed to run borg mount: {}", e))?;
-
-        if !status.success() {
-            return Err("borg mount failed".to_string());
+    pub fn open_log(&mut self) -> Result<(), String> {
+        let logging = self.config.logging.clone();
+        if let Some(max_size) = &logging.max_size {
+            let max_size = logging::parse_size(max_size)?;
+            if let Err(e) = logging::rotate(
+                &logging.log_file,
+                max_size,
+                logging.keep_files,
+                logging.compress_rotated,
+            ) {
+                self.warn(&format!("log rotation failed: {}", e));
+            }
         }
 
-        println!("Mounted successfully!");
-        println!("Browse backups: ls {}", mount_point);
-        println!("Unmount with: fusermount -u {}", mount_point);
+        logging::open_file(&logging.log_file)
+    }
 
+    /// Resolve the configured passphrase source.
+    pub fn load_passphrase(&mut self) -> Result<(), String> {
+        self.passphrase = Some(self.config.security.passphrase_source().resolve()?);
         Ok(())
     }
 
-    pub fn generate_example_config(output_path: &str) -> Result<(), String> {
-        fs::write(output_path, DEFAULT_CONFIG)
-            .map_err(|e| format!("Failed to write example config: {}", e))?;
+    pub fn create_backup(&mut self) -> Result<(), String> {
+        let now = Utc::now();
+        let archive_name = self
+            .name_template()
+            .name(None, now, self.config.archive_timestamp);
+        let archive_name = self.unique_name(&archive_name);
 
-        println!("Example configuration written to: {}", output_path);
-        Ok(())
+        self.log(&format!("Starting backup: {}", archive_name));
+
+        self.run_job_hooks(false)?;
+        let result = self
+            .run_borg_create(&archive_name)
+            .and_then(|_| self.create_paths_from_archives(now));
+        // Post commands run even if borg failed so dumps get cleaned up
+        let post_result = self.run_job_hooks(true);
+        result.and(post_result)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn unique_name(&mut self, base: &str) -> String {
+        let name = match self.archive_names() {
+            Ok(existing) => unique_archive_name(base, &existing),
+            Err(e) => {
+                self.warn(&format!(
+                    "could not check for duplicate archive names: {}",
+                    e
+                ));
+                base.to_string()
+            }
+        };
+        if name != base {
+            self.log(&format!(
+                "Archive {} already exists, using {} instead",
+                base, name
+            ));
+        }
+        name
+    }
 
-    #[test]
-    fn test_load_default_config() {
-        let config = Config::load_or_default(None);
-        assert!(config.is_ok());
+    fn create_command(&mut self, read_all: bool) -> BorgCommand {
+        let mut cmd = self.borg_in_background_reading(read_all);
+        cmd.arg("create");
 
-        let config = config.unwrap();
-        assert_eq!(config.repository.path, "/tmp/borg");
-        assert_eq!(config.compression, "lz4");
-        assert!(!config.jobs.is_empty());
+        if self.dry_run {
+            // borg refuses --stats together with --dry-run
+            cmd.arg("--dry-run").arg("--list");
+        } else if self.config.options.show_stats {
+            cmd.arg("--stats");
+        }
+        if self.config.options.show_progress {
+            cmd.arg("--progress");
+        }
+        if self.config.options.one_file_system {
+            cmd.arg("--one-file-system");
+        }
+        if self.config.options.exclude_caches {
+            cmd.arg("--exclude-caches");
+        }
+        for marker in &self.config.options.exclude_if_present {
+            cmd.arg("--exclude-if-present").arg(marker);
+        }
+        if self.config.options.keep_exclude_tags {
+            cmd.arg("--keep-exclude-tags");
+        }
+        let renamed = self.version.has_renamed_create_flags();
+        if let Some(interval) = self.config.options.checkpoint_interval {
+            cmd.arg(format!("--checkpoint-interval={}", interval));
+        }
+        match self.config.options.noatime {
+            Some(true) if !renamed => {
+                cmd.arg("--noatime");
+            }
+            Some(false) if renamed => {
+                cmd.arg("--atime");
+            }
+            _ => {}
+        }
+        if self.config.options.numeric_ids {
+            cmd.arg(if renamed {
+                "--numeric-ids"
+            } else {
+                "--numeric-owner"
+            });
+        }
+        if let Some(mode) = &self.config.options.files_cache {
+            cmd.arg(format!("--files-cache={}", mode));
+        }
+        if let Some(limit) = self.config.options.upload_ratelimit {
+            let flag = if renamed {
+                "--upload-ratelimit"
+            } else {
+                "--remote-ratelimit"
+            };
+            cmd.arg(format!("{}={}", flag, limit));
+        }
+
+        let compression = self.effective_compression();
+        self.report.compression = Some(compression.clone());
+        cmd.arg(format!("--compression={}", compression));
+        if let Some(comment) = &self.comment {
+            cmd.arg(format!("--comment={}", comment));
+        }
+
+        cmd.args(self.pattern_args());
+        cmd.args(&self.config.options.extra_args.create);
+        cmd
     }
 
-    #[test]
-    fn test_config_jobs() {
-        let config = Config::load_or_default(None).unwrap();
-        assert!(config.jobs.iter().any(|j| j.name == "system-config"));
-        assert!(config.jobs.iter().any(|j| j.name == "user-homes"));
+    fn pattern_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for pattern in &self.config.exclusions {
+            args.extend(["--exclude".to_string(), pattern.clone()]);
+        }
+        for file in &self.config.exclusions_from {
+            args.extend(["--exclude-from".to_string(), file.clone()]);
+        }
+        for file in &self.config.patterns_from {
+            args.extend(["--patterns-from".to_string(), file.clone()]);
+        }
+
+        // Keep our own files out of the archive
+        for pattern in self.auto_exclusions() {
+            args.extend(["--exclude".to_string(), pattern]);
+        }
+        args
     }
 
-    #[test]
-    fn test_retention_policy() {
+    fn run_borg_create(&mut self, archive_name: &str) -> Result<(), String> {
+        let jobs: Vec<BackupJob> = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.enabled && job.paths_from.is_none())
+            .cloned()
+            .collect();
+        let jobs = self.present_sources(jobs)?;
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        let snapshots = self.take_snapshots(&jobs)?;
+        let snapshot_root = jobs
+            .iter()
+            .find_map(|job| job.snapshot.as_ref())
+            .filter(|_| !snapshots.is_empty())
+            .map(|snapshot| snapshot.mountpoint.clone());
+
+        // Build archive path with jobs
+        let mut args = vec![format!("{}::{}", self.get_repo_path(), archive_name)];
+
+        // Add all enabled job sources
+        for job in &jobs {
+            match &job.snapshot {
+                // Relative to the snapshot mountpoint, so the archive has
+                // the same paths as when backing up the live source
+                Some(_) if snapshot_root.is_some() => {
+                    let relative = job.source.trim_start_matches('/');
+                    args.push(if relative.is_empty() { "." } else { relative }.to_string());
+                }
+                _ => args.push(job.source.clone()),
+            }
+            args.extend(self.extra_filesystems(job));
+
+            // Add job-specific exclusions
+            for pattern in &job.exclude {
+                args.push("--exclude".to_string());
+                args.push(pattern.clone());
+            }
+        }
+
+        let read_all = jobs.iter().any(|job| job.sudo);
+        let logged = self.cycle_log.len();
+        let result = self.run_with_retry("create", |backup| {
+            let mut cmd = backup.create_command(read_all);
+            if let Some(root) = &snapshot_root {
+                cmd.current_dir(root);
+            }
+            cmd.args(&args);
+            cmd
+        });
+        for snapshot in snapshots {
+            if let Err(e) = snapshot.release() {
+                self.warn(&format!("Failed to remove snapshot: {}", e));
+            }
+        }
+        let exit_code = result?;
+
+        if !self.dry_run {
+            self.report.archive = Some(archive_name.to_string());
+            self.report.created.push(archive_name.to_string());
+            let names = jobs.iter().map(|job| job.name.clone()).collect();
+            self.report.stats = self.record_job_stats(names, archive_name);
+        }
+
+        self.log_create_result(exit_code, logged);
+        Ok(())
+    }
+
+    fn take_snapshots(&mut self, jobs: &[BackupJob]) -> Result<Vec<snapshot::Taken>, String> {
+        let mut taken = Vec::new();
+        for job in jobs {
+            let Some(snapshot) = &job.snapshot else {
+                continue;
+            };
+            if self.dry_run {
+                self.log(&format!(
+                    "Skipping snapshot of {} for job {}, reading the live source",
+                    snapshot.volume, job.name
+                ));
+                continue;
+            }
+            self.log(&format!(
+                "Snapshotting {} for job {}",
+                snapshot.volume, job.name
+            ));
+            let snapshot = snapshot
+                .take(&job.name, &job.source)
+                .map_err(|e| format!("Failed to snapshot job {}: {}", job.name, e))?;
+            taken.push(snapshot);
+        }
+        Ok(taken)
+    }
+
+    fn extra_filesystems(&mut self, job: &BackupJob) -> Vec<String> {
+        if !self.config.options.one_file_system {
+            return Vec::new();
+        }
+
+        let source_mount =
+            preflight::mounted_device(Path::new(&job.source)).map(|device| device.mount_point);
+        let mut extra = Vec::new();
+        for path in &job.also_include_filesystems {
+            if !Path::new(path).exists() {
+                self.warn(&format!(
+                    "job {}: {} in also_include_filesystems does not exist",
+                    job.name, path
+                ));
+                continue;
+            }
+            if !preflight::is_under(Path::new(path), Path::new(&job.source)) {
+                self.warn(&format!(
+                    "job {}: {} in also_include_filesystems is not below {}",
+                    job.name, path, job.source
+                ));
+                continue;
+            }
+            let mount = preflight::mounted_device(Path::new(path)).map(|device| device.mount_point);
+            if mount.is_some() && mount == source_mount {
+                tracing::debug!("{} is on the same filesystem as {}", path, job.source);
+                continue;
+            }
+            extra.push(path.clone());
+        }
+        extra
+    }
+
+    fn present_sources(&mut self, jobs: Vec<BackupJob>) -> Result<Vec<BackupJob>, String> {
+        let mut present = Vec::new();
+        for job in jobs {
+            if Path::new(&job.source).exists() {
+                present.push(job);
+                continue;
+            }
+            match self.config.policy.on_missing_source {
+                OnMissingSource::Skip => self.warn(&format!(
+                    "skipping job {}: source {} does not exist",
+                    job.name, job.source
+                )),
+                OnMissingSource::Fail => {
+                    return Err(format!(
+                        "Source {} of job {} does not exist",
+                        job.source, job.name
+                    ))
+                }
+            }
+        }
+        Ok(present)
+    }
+
+    fn log_create_result(&mut self, exit_code: i32, logged: usize) {
+        if self.dry_run {
+            self.log("Backup simulated, no archive was written");
+        } else if exit_code == 1 {
+            let skipped = summary::skipped_files(&self.cycle_log[logged..]);
+            if skipped.is_empty() {
+                self.warn("Backup created with warnings (some files may have been skipped)");
+            } else {
+                self.warn(&format!(
+                    "Backup created with warnings, {} file(s) skipped",
+                    skipped.len()
+                ));
+                for entry in &skipped {
+                    self.log(&format!("Skipped {}", entry));
+                }
+                self.report.skipped.extend(skipped);
+            }
+        } else {
+            self.log("Backup created successfully");
+        }
+    }
+
+    fn create_paths_from_archives(&mut self, now: DateTime<Utc>) -> Result<(), String> {
+        let jobs: Vec<BackupJob> = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.enabled && job.paths_from.is_some())
+            .cloned()
+            .collect();
+        if !jobs.is_empty() && !self.version.has_paths_from_stdin() {
+            return Err(format!(
+                "paths_from needs borg 1.2 or newer, but {} is {}",
+                self.config.borg.binary, self.version
+            ));
+        }
+
+        for job in jobs {
+            let archive_name =
+                self.name_template()
+                    .name(Some(&job.name), now, self.config.archive_timestamp);
+            let archive_name = self.unique_name(&archive_name);
+            self.log(&format!(
+                "Starting backup of {} from path list: {}",
+                job.name, archive_name
+            ));
+
+            let mut cmd = self.create_command(job.sudo);
+            cmd.arg("--paths-from-stdin");
+            if job.paths_delimiter == PathsDelimiter::Nul {
+                // borg decodes the escape; a NUL byte can't be in an argument
+                cmd.arg("--paths-delimiter").arg("\\0");
+            }
+            for pattern in &job.exclude {
+                cmd.arg("--exclude").arg(pattern);
+            }
+            cmd.arg(format!("{}::{}", self.get_repo_path(), archive_name));
+
+            let mut producer = None;
+            match job.paths_from.as_ref() {
+                Some(PathsFrom::File { file: path }) => {
+                    let file = fs::File::open(path)
+                        .map_err(|e| format!("Failed to open path list {}: {}", path, e))?;
+                    cmd.stdin(file);
+                }
+                Some(PathsFrom::Command { command }) => {
+                    let mut child = self
+                        .shell(command)
+                        .stdin(Stdio::null())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .map_err(|e| format!("Failed to run path list command: {}", e))?;
+                    if let Some(stdout) = child.stdout.take() {
+                        cmd.stdin(stdout);
+                    }
+                    producer = Some(child);
+                }
+                None => continue,
+            }
+
+            let logged = self.cycle_log.len();
+            let status = self.run_logged(cmd);
+            if let Some(mut child) = producer {
+                let produced = child
+                    .wait()
+                    .map_err(|e| format!("Failed to run path list command: {}", e))?;
+                if !produced.success() {
+                    return Err(format!(
+                        "Path list command for job {} failed with {}",
+                        job.name, produced
+                    ));
+                }
+            }
+
+            let status = status.map_err(|e| format!("Failed to run borg create: {}", e))?;
+            let exit_code = status.code().unwrap_or(2);
+            if exit_code >= 2 {
+                return Err(format!(
+                    "borg create for job {} failed with exit code {}",
+                    job.name, exit_code
+                ));
+            }
+            if !self.dry_run {
+                self.report.created.push(archive_name.clone());
+                self.record_job_stats(vec![job.name.clone()], &archive_name);
+            }
+            self.log_create_result(exit_code, logged);
+        }
+        Ok(())
+    }
+
+    fn record_job_stats(&mut self, jobs: Vec<String>, archive: &str) -> Option<ArchiveStats> {
+        // borg reports the repository's totals along with the archive
+        let info = self.info_json(Some(archive)).and_then(|info| {
+            Ok((
+                ArchiveStats::from_info_json(&info)?,
+                RepositoryStats::from_info_json(&info)?,
+            ))
+        });
+        let stats = match info {
+            Ok((stats, repository)) => {
+                if repository.is_some() {
+                    self.report.repository = repository;
+                }
+                stats
+            }
+            Err(e) => {
+                self.warn(&format!("could not read archive stats: {}", e));
+                return None;
+            }
+        };
+
+        let output = &self.config.output;
+        let message = format!(
+            "{}: {} original, {} compressed, {} new ({:.0}% saved)",
+            jobs.join(", "),
+            output.bytes(stats.original_size),
+            output.bytes(stats.compressed_size),
+            output.bytes(stats.deduplicated_size),
+            stats.savings() * 100.0
+        );
+        self.log(&message);
+        self.report.job_stats.push(JobStats {
+            jobs,
+            archive: archive.to_string(),
+            stats: stats.clone(),
+        });
+        Some(stats)
+    }
+
+    pub fn prune_backups(&mut self) -> Result<(), String> {
+        self.refuse_if_append_only("prune")?;
+        self.log("Pruning old backups...");
+
+        self.prune_with(&self.config.retention.clone())?;
+
+        self.purge_trash(Some(chrono::Duration::days(i64::from(
+            self.config.retention.trash_days,
+        ))))?;
+
+        self.log("Prune completed");
+        Ok(())
+    }
+
+    fn scheduled_prune(&mut self) -> Result<(), String> {
+        if self.append_only() {
+            self.log("Not pruning: the repository is append-only from this host");
+            return Ok(());
+        }
+        self.prune_backups()
+    }
+
+    /// Prune and compact the archives of `hostname` in an append-only repository.
+    pub fn admin_prune(&mut self, hostname: Option<&str>) -> Result<(), String> {
+        match hostname.map(str::trim) {
+            Some(name) if !name.is_empty() => self.hostname = name.to_string(),
+            _ if self.config.hostname.is_some() => {}
+            _ => {
+                return Err(
+                    "Give the client whose archives to prune with --hostname, or set hostname in the config"
+                        .to_string(),
+                )
+            }
+        }
+        self.admin = true;
+        let flagged = !self.dry_run && self.repository_append_only()?;
+        if flagged {
+            self.log("Lifting the repository's append_only flag to prune");
+            self.set_repository_append_only(false)?;
+        }
+        let result = self.prune_backups().and_then(|_| self.compact_repository());
+        if !flagged {
+            return result;
+        }
+        let restored = self.set_repository_append_only(true);
+        result.and(restored)
+    }
+
+    fn repository_append_only(&self) -> Result<bool, String> {
+        let mut cmd = self.borg();
+        cmd.args(["config", &self.get_repo_path(), "append_only"]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg config: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to read the repository's append_only setting: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let value = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .to_lowercase();
+        Ok(value == "1" || value == "true")
+    }
+
+    fn set_repository_append_only(&self, on: bool) -> Result<(), String> {
+        let mut cmd = self.borg();
+        cmd.args([
+            "config",
+            &self.get_repo_path(),
+            "append_only",
+            if on { "1" } else { "0" },
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg config: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to set the repository's append_only to {}: {}",
+                u8::from(on),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    fn append_only(&self) -> bool {
+        self.config.repository.append_only && !self.admin
+    }
+
+    fn refuse_if_append_only(&self, what: &str) -> Result<(), String> {
+        if self.append_only() {
+            return Err(format!(
+                "Refusing to {}: repository.append_only is set; run `admin prune` from a trusted machine",
+                what
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_own_archive(&self, archive: &str) -> bool {
+        let name = parse_trash_name(archive).map_or(archive, |(_, original)| original);
+        let name = match name.rsplit_once(".checkpoint") {
+            Some((original, _)) if report::is_checkpoint(name) => original,
+            _ => name,
+        };
+        let jobs: Vec<&str> = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.paths_from.is_some())
+            .map(|job| job.name.as_str())
+            .collect();
+        self.name_template().owns(name, &jobs)
+    }
+
+    fn foreign_archives(&self, glob: &str) -> Result<Vec<String>, String> {
+        if !self.config.repository.shared {
+            return Ok(Vec::new());
+        }
+        let mut cmd = self.borg();
+        cmd.args(["list", "--short"]);
+        if self.version.has_consider_checkpoints() {
+            cmd.arg("--consider-checkpoints");
+        }
+        cmd.args([&format!("--glob-archives={}", glob), &self.get_repo_path()]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && !self.is_own_archive(name))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn refuse_foreign(&self, what: &str, archives: &[String]) -> Result<(), String> {
+        if !self.config.repository.shared {
+            return Ok(());
+        }
+        match archives.iter().find(|name| !self.is_own_archive(name)) {
+            Some(name) => Err(format!(
+                "Refusing to {} {}: it is not one of this host's archives in the shared repository",
+                what, name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn prune_with(&mut self, retention: &Retention) -> Result<(), String> {
+        for glob in self.archive_globs() {
+            // borg prune goes by name alone, and another host's name may
+            // start with ours (web and web-2)
+            if let Some(name) = self.foreign_archives(&glob)?.first() {
+                return Err(format!(
+                    "Refusing to prune {}: it also matches {}, which is not this host's, in the shared repository; change hostname or archive_name_template so no host's names start with another's",
+                    glob, name
+                ));
+            }
+            let protected = self.tagged_archives(&glob, &retention.keep_tag)?;
+            // borg prune can't leave archives out, so with tagged archives
+            // in the series it only says what to prune and we delete
+            let dry_run = self.dry_run || !protected.is_empty();
+            let logged = self.cycle_log.len();
+            self.run_with_retry("prune", |backup| {
+                let mut cmd = backup.borg_in_background();
+                cmd.arg("prune").arg("--list");
+                if dry_run {
+                    cmd.arg("--dry-run");
+                }
+                cmd.arg(format!("--glob-archives={}", glob))
+                    .arg(format!("--keep-within={}", retention.within))
+                    .arg(format!("--keep-hourly={}", retention.hourly))
+                    .arg(format!("--keep-daily={}", retention.daily))
+                    .arg(format!("--keep-weekly={}", retention.weekly))
+                    .arg(format!("--keep-monthly={}", retention.monthly))
+                    .arg(format!("--keep-yearly={}", retention.yearly))
+                    .args(&backup.config.options.extra_args.prune)
+                    .arg(backup.get_repo_path());
+                cmd
+            })?;
+            let mut pruned = summary::pruned_archives(&self.cycle_log[logged..]);
+            if !protected.is_empty() {
+                pruned.retain(|archive| {
+                    let keep = protected.contains(archive);
+                    if keep {
+                        self.log(&format!(
+                            "Keeping {}: tagged {}",
+                            archive, retention.keep_tag
+                        ));
+                    }
+                    !keep
+                });
+                self.borg_delete(&pruned)?;
+            }
+            self.report.pruned.extend(pruned);
+        }
+        Ok(())
+    }
+
+    fn tagged_archives(&self, glob: &str, tag: &str) -> Result<Vec<String>, String> {
+        if tag.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut cmd = self.borg();
+        cmd.args([
+            "list",
+            "--json",
+            "--format={comment}",
+            &format!("--glob-archives={}", glob),
+            &self.get_repo_path(),
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+        Ok(
+            ArchiveEntry::from_list_json(&String::from_utf8_lossy(&output.stdout))?
+                .into_iter()
+                .filter(|a| has_tag(&a.comment, tag))
+                .map(|a| a.name)
+                .collect(),
+        )
+    }
+
+    /// Add `label` to the comment of `archive`, or take it off with `remove`.
+    pub fn tag_archive(&mut self, archive: &str, label: &str, remove: bool) -> Result<(), String> {
+        if label.is_empty() || label.contains(char::is_whitespace) {
+            return Err(format!("Invalid tag '{}': use a single word", label));
+        }
+        let archive = self.resolve_archive(archive)?;
+        self.refuse_foreign("tag", std::slice::from_ref(&archive))?;
+        let _lock = self.acquire_lock()?;
+        let current = ArchiveDetails::from_info_json(&self.info_json(Some(&archive))?)?
+            .pop()
+            .map(|a| a.comment)
+            .unwrap_or_default();
+        let comment = retag(&current, label, remove);
+        if comment == current {
+            self.log(&format!(
+                "{} is {} tagged {}",
+                archive,
+                if remove { "not" } else { "already" },
+                label
+            ));
+            return Ok(());
+        }
+        if self.dry_run {
+            self.log(&format!(
+                "Would set the comment of {} to '{}'",
+                archive, comment
+            ));
+            return Ok(());
+        }
+
+        let mut cmd = self.borg();
+        cmd.arg("recreate")
+            .arg(format!("--comment={}", comment))
+            .arg(format!("{}::{}", self.get_repo_path(), archive));
+        let result = match self.run_logged(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!(
+                "borg recreate failed with exit code {}",
+                status.code().unwrap_or(-1)
+            )),
+            Err(e) => Err(format!("Failed to run borg recreate: {}", e)),
+        };
+        let action = if remove { "untag" } else { "tag" };
+        self.audit(
+            AuditEntry::new(&self.hostname, action, Some(&archive), &result).with_target(label),
+        );
+        result?;
+        self.log(&format!("Comment of {} is now '{}'", archive, comment));
+        Ok(())
+    }
+
+    fn check_quota(&mut self) -> Result<(), String> {
+        let maintenance = &self.config.maintenance;
+        if maintenance.max_repo_size.is_none() && maintenance.min_free_space.is_none()
+            || self.dry_run
+        {
+            return Ok(());
+        }
+        let mut problems = self.quota_problems()?;
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let retention = self.config.maintenance.quota_retention.clone();
+        if let Some(retention) = retention.filter(|_| !self.append_only()) {
+            self.log(&format!(
+                "{}; pruning with maintenance.quota_retention and emptying the trash",
+                problems.join("; ")
+            ));
+            self.prune_with(&retention)?;
+            self.purge_trash(None)?;
+            self.compact_repository()?;
+            problems = self.quota_problems()?;
+            if problems.is_empty() {
+                self.log("The repository is within its limits again");
+                return Ok(());
+            }
+        }
+
+        for problem in &problems {
+            self.warn(problem);
+        }
+        self.notify(Notification {
+            subject: format!("Backup repository running out of space on {}", self.hostname),
+            message: format!(
+                "{}\n\nFree up space, prune harder or raise the limits before a full disk fails the next backup.",
+                problems.join("\n")
+            ),
+            hostname: self.hostname.clone(),
+            success: false,
+            html: None,
+        });
+        Ok(())
+    }
+
+    fn quota_problems(&self) -> Result<Vec<String>, String> {
+        let size = RepositoryInfo::from_info_json(&self.info_json(None)?)?
+            .stats
+            .unique_csize;
+        let free = match self.config.repository.remote {
+            Some(_) => None,
+            None => Some(free_space(&self.config.repository.path)?),
+        };
+        self.config
+            .maintenance
+            .quota_problems(size, free, &self.config.output)
+    }
+
+    /// Archives matching `pattern` and older than `older_than`.
+    pub fn select_archives(
+        &self,
+        pattern: Option<&str>,
+        older_than: Option<&str>,
+    ) -> Result<Vec<String>, String> {
+        let cutoff = older_than
+            .map(parse_age)
+            .transpose()?
+            .map(|age| Utc::now() - age);
+        let pattern = pattern
+            .map(str::to_string)
+            .unwrap_or_else(|| self.name_template().any_glob());
+
+        let archives = self.archive_entries()?;
+
+        let is_glob = pattern.contains(['*', '?']);
+        if !is_glob && !archives.iter().any(|a| a.name == pattern) {
+            return Err(format!("Archive {} not found", pattern));
+        }
+        if parse_trash_name(&pattern).is_some() {
+            return Err(format!(
+                "{} is already in the trash; use empty-trash to delete it",
+                pattern
+            ));
+        }
+
+        Ok(archives
+            .into_iter()
+            .filter(|a| !a.name.starts_with(TRASH_PREFIX))
+            .filter(|a| glob_match(&pattern, &a.name))
+            .filter(|a| cutoff.is_none_or(|cutoff| a.time < cutoff))
+            .map(|a| a.name)
+            .collect())
+    }
+
+    fn archive_entries(&self) -> Result<Vec<ArchiveEntry>, String> {
+        let mut cmd = self.borg();
+        cmd.args(["list", "--json", &self.get_repo_path()]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+        ArchiveEntry::from_list_json(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Name of this host's newest archive.
+    pub fn latest_archive(&self) -> Result<String, String> {
+        let mut cmd = self.borg();
+        cmd.args([
+            "list",
+            "--json",
+            "--last",
+            "1",
+            &format!("--glob-archives={}", self.name_template().any_glob()),
+            &self.get_repo_path(),
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+        ArchiveEntry::from_list_json(&String::from_utf8_lossy(&output.stdout))?
+            .pop()
+            .map(|a| a.name)
+            .ok_or_else(|| format!("No archives of {} yet", self.hostname))
+    }
+
+    /// An archive name given by the user, with `latest` resolved.
+    pub fn resolve_archive(&self, name: &str) -> Result<String, String> {
+        if name == LATEST {
+            self.latest_archive()
+        } else {
+            Ok(name.to_string())
+        }
+    }
+
+    /// The newest of this host's archives created at or before `as_of`.
+    pub fn archive_as_of(&self, as_of: DateTime<Utc>) -> Result<ArchiveEntry, String> {
+        let own = self.name_template().any_glob();
+        self.archive_entries()?
+            .into_iter()
+            .filter(|a| !a.name.starts_with(TRASH_PREFIX))
+            .filter(|a| glob_match(&own, &a.name) && a.time <= as_of)
+            .max_by_key(|a| a.time)
+            .ok_or_else(|| {
+                format!(
+                    "No archive of {} at or before {}",
+                    self.hostname,
+                    self.config.output.time(as_of)
+                )
+            })
+    }
+
+    /// Delete archives.
+    pub fn delete_archives(&mut self, archives: &[String]) -> Result<(), String> {
+        self.refuse_if_append_only("delete archives")?;
+        self.refuse_foreign("delete", archives)?;
+        if self.config.retention.trash_days == 0 {
+            self.borg_delete(archives)?;
+            return self.compact_repository();
+        }
+
+        let now = Utc::now();
+        for archive in archives {
+            let trashed = trash_name(archive, now);
+            self.log(&format!(
+                "Moving {} to the trash as {} (kept for {} days)",
+                archive, trashed, self.config.retention.trash_days
+            ));
+            let result = self.rename_archive(archive, &trashed);
+            self.audit(
+                AuditEntry::new(&self.hostname, "trash", Some(archive), &result)
+                    .with_target(&trashed),
+            );
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Restore an archive from the trash, by its original or trashed name.
+    pub fn undelete_archive(&mut self, archive: &str) -> Result<(), String> {
+        let names = self.archive_names()?;
+        let (trashed, original) = names
+            .iter()
+            .filter_map(|name| parse_trash_name(name).map(|(_, original)| (name, original)))
+            .filter(|(name, original)| *name == archive || *original == archive)
+            .max()
+            .ok_or_else(|| format!("{} is not in the trash", archive))?;
+
+        if names.iter().any(|name| name == original) {
+            return Err(format!(
+                "Cannot restore {}: an archive named {} already exists",
+                trashed, original
+            ));
+        }
+
+        let (trashed, original) = (trashed.clone(), original.to_string());
+        self.log(&format!("Restoring {} from the trash", original));
+        let result = self.rename_archive(&trashed, &original);
+        self.audit(
+            AuditEntry::new(&self.hostname, "undelete", Some(&trashed), &result)
+                .with_target(&original),
+        );
+        result
+    }
+
+    /// Delete everything in the trash now, regardless of age.
+    pub fn empty_trash(&mut self) -> Result<(), String> {
+        self.refuse_if_append_only("empty the trash")?;
+        if self.purge_trash(None)? > 0 {
+            self.compact_repository()?;
+        }
+        Ok(())
+    }
+
+    fn purge_trash(&mut self, max_age: Option<chrono::Duration>) -> Result<usize, String> {
+        let now = Utc::now();
+        let expired: Vec<String> = self
+            .archive_names()?
+            .into_iter()
+            .filter(|name| match (parse_trash_name(name), max_age) {
+                (Some(_), None) => true,
+                (Some((trashed_at, _)), Some(max_age)) => now - trashed_at >= max_age,
+                (None, _) => false,
+            })
+            .filter(|name| !self.config.repository.shared || self.is_own_archive(name))
+            .collect();
+
+        if !expired.is_empty() {
+            self.log(&format!(
+                "Deleting {} archive(s) from the trash",
+                expired.len()
+            ));
+            self.borg_delete(&expired)?;
+        }
+        Ok(expired.len())
+    }
+
+    fn borg_delete(&mut self, archives: &[String]) -> Result<(), String> {
+        for archive in archives {
+            self.log(&format!("Deleting archive {}", archive));
+            if self.dry_run {
+                continue;
+            }
+
+            let mut cmd = self.borg();
+            cmd.args(["delete", &format!("{}::{}", self.get_repo_path(), archive)]);
+            let result = match self.run_logged(cmd) {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format!(
+                    "borg delete of {} failed with exit code {}",
+                    archive,
+                    status.code().unwrap_or(-1)
+                )),
+                Err(e) => Err(format!("Failed to run borg delete: {}", e)),
+            };
+            self.audit(AuditEntry::new(
+                &self.hostname,
+                "delete",
+                Some(archive),
+                &result,
+            ));
+            result?;
+        }
+        Ok(())
+    }
+
+    fn rename_archive(&mut self, from: &str, to: &str) -> Result<(), String> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let mut cmd = self.borg();
+        cmd.args(["rename", &format!("{}::{}", self.get_repo_path(), from), to]);
+        let status = self
+            .run_logged(cmd)
+            .map_err(|e| format!("Failed to run borg rename: {}", e))?;
+        if !status.success() {
+            return Err(format!(
+                "borg rename of {} failed with exit code {}",
+                from,
+                status.code().unwrap_or(-1)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rewrite `archives` with the current compression and exclusions.
+    pub fn recreate_archives(
+        &mut self,
+        archives: &[String],
+        confirm: impl FnOnce() -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.refuse_if_append_only("recreate archives")?;
+        self.refuse_foreign("recreate", archives)?;
+        let _lock = self.acquire_lock()?;
+        let compression = self.effective_compression();
+
+        for archive in archives {
+            let cmd = self.recreate_command(archive, &compression, true);
+            let output = self
+                .runner
+                .capture(cmd)
+                .map_err(|e| format!("Failed to run borg recreate: {}", e))?;
+            if output.status.code().unwrap_or(2) >= 2 {
+                return Err(format!(
+                    "borg recreate --dry-run of {} failed: {}",
+                    archive,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            // --list marks the items the patterns leave out with `x`
+            let listed = [output.stdout, output.stderr].concat();
+            let excluded: Vec<String> = String::from_utf8_lossy(&listed)
+                .lines()
+                .filter_map(|line| line.strip_prefix("x "))
+                .map(str::to_string)
+                .collect();
+            for path in &excluded {
+                tracing::debug!("{}: would exclude {}", archive, path);
+            }
+            self.reporter.message(&format!(
+                "{}: {} path(s) would be excluded",
+                archive,
+                excluded.len()
+            ));
+        }
+        self.reporter.message(&format!(
+            "{} archive(s) would be recompressed with {} where it differs",
+            archives.len(),
+            compression
+        ));
+        if self.dry_run {
+            return Ok(());
+        }
+
+        confirm()?;
+        for (index, archive) in archives.iter().enumerate() {
+            self.log(&format!(
+                "Recreating archive {}/{}: {}",
+                index + 1,
+                archives.len(),
+                archive
+            ));
+            let cmd = self.recreate_command(archive, &compression, false);
+            let result = match self.run_logged(cmd) {
+                Ok(status) if status.code().unwrap_or(2) < 2 => Ok(()),
+                Ok(status) => Err(format!(
+                    "borg recreate of {} failed with exit code {}",
+                    archive,
+                    status.code().unwrap_or(-1)
+                )),
+                Err(e) => Err(format!("Failed to run borg recreate: {}", e)),
+            };
+            self.audit(AuditEntry::new(
+                &self.hostname,
+                "recreate",
+                Some(archive),
+                &result,
+            ));
+            result?;
+        }
+        self.log(&format!("Recreated {} archive(s)", archives.len()));
+
+        // The old chunks only free space once compacted
+        self.compact_repository()
+    }
+
+    fn recreate_command(&self, archive: &str, compression: &str, dry_run: bool) -> BorgCommand {
+        let mut cmd = self.borg_in_background();
+        cmd.arg("recreate");
+        if dry_run {
+            cmd.arg("--dry-run").arg("--list");
+        } else if self.config.options.show_progress {
+            cmd.arg("--progress");
+        }
+        cmd.arg(format!("--compression={}", compression));
+        cmd.arg("--recompress=if-different");
+        if self.config.options.exclude_caches {
+            cmd.arg("--exclude-caches");
+        }
+        for marker in &self.config.options.exclude_if_present {
+            cmd.arg("--exclude-if-present").arg(marker);
+        }
+        if self.config.options.keep_exclude_tags {
+            cmd.arg("--keep-exclude-tags");
+        }
+        cmd.args(self.pattern_args());
+        for job in self.config.jobs.iter().filter(|job| job.enabled) {
+            for pattern in &job.exclude {
+                cmd.arg("--exclude").arg(pattern);
+            }
+        }
+        cmd.arg(format!("{}::{}", self.get_repo_path(), archive));
+        cmd
+    }
+
+    /// Run `borg check` and `borg compact` if they are due, or both with `force`.
+    pub fn run_maintenance(&mut self, force: bool) -> Result<(), String> {
+        let _lock = self.acquire_lock()?;
+        if force {
+            self.run_scheduled_check()?;
+            self.compact_repository()
+        } else {
+            self.check_if_due()?;
+            self.compact_if_due()
+        }
+    }
+
+    fn scheduled_compact(&mut self) -> Result<(), String> {
+        if !self.config.maintenance.in_cycle {
+            return Ok(());
+        }
+        self.compact_if_due()
+    }
+
+    fn compact_if_due(&mut self) -> Result<(), String> {
+        if self.append_only() {
+            return Ok(());
+        }
+        let interval = self.config.maintenance.compact_interval()?;
+        let last = self.state().last_compact()?;
+        if !policy::compact_due(interval, last, Utc::now()) {
+            tracing::debug!("Compact not due until {:?} after the last one", interval);
+            return Ok(());
+        }
+        self.compact_repository()
+    }
+
+    pub fn compact_repository(&mut self) -> Result<(), String> {
+        if !self.config.maintenance.auto_compact {
+            return Ok(());
+        }
+        self.refuse_if_append_only("compact")?;
+        if self.dry_run {
+            self.log("Skipping compact");
+            return Ok(());
+        }
+        if !self.version.has_compact() {
+            // Borg before 1.2 compacts segments as part of every commit
+            return Ok(());
+        }
+
+        self.log("Compacting repository...");
+
+        let mut cmd = self.borg_in_background();
+        cmd.arg("compact");
+        if let Some(threshold) = self.config.maintenance.compact_threshold {
+            cmd.arg(format!("--threshold={}", threshold));
+        }
+        cmd.arg(self.get_repo_path());
+        let status = self
+            .run_logged(cmd)
+            .map_err(|e| format!("Failed to run borg compact: {}", e))?;
+
+        let exit_code = status.code().unwrap_or(2);
+        if exit_code >= 2 {
+            return Err(format!("borg compact failed with exit code {}", exit_code));
+        }
+
+        self.log("Compact completed");
+        if let Err(e) = self.state().record_compact(Utc::now()) {
+            self.warn(&e);
+        }
+        Ok(())
+    }
+
+    /// Sync the repository to `replication.target`, unless a backup cycle is running.
+    pub fn replicate(&mut self) -> Result<(), String> {
+        let _lock = self.acquire_lock()?;
+        self.replicate_repository()
+    }
+
+    fn replicate_after_cycle(&mut self) -> Result<(), String> {
+        match &self.config.replication {
+            Some(replication) if replication.after_cycle => self.replicate_repository(),
+            _ => Ok(()),
+        }
+    }
+
+    fn replicate_repository(&mut self) -> Result<(), String> {
+        let replication = self
+            .config
+            .replication
+            .clone()
+            .ok_or("No replication target configured")?;
+        if self.config.repository.remote.is_some() {
+            return Err("Replication needs a local repository to copy from".to_string());
+        }
+        let program = replication.tool.program();
+        if self.dry_run {
+            self.log(&format!(
+                "Skipping replication to {} with {}",
+                replication.target, program
+            ));
+            return Ok(());
+        }
+
+        self.log(&format!(
+            "Replicating repository to {} with {}...",
+            replication.target, program
+        ));
+        let repo = self.get_repo_path();
+        let mut cmd = self.borg_in_background();
+        cmd.args(["with-lock", &repo, program])
+            .args(replication.args(&repo));
+        let status = self
+            .run_logged(cmd)
+            .map_err(|e| format!("Failed to replicate repository: {}", e))?;
+        if !status.success() {
+            return Err(format!(
+                "Replication to {} failed: {} exited with code {}",
+                replication.target,
+                program,
+                status.code().unwrap_or(-1)
+            ));
+        }
+
+        self.log("Replication completed");
+        Ok(())
+    }
+
+    /// The cycle's check stage.
+    pub fn check_repository(&mut self) -> Result<(), String> {
+        if !self.config.maintenance.in_cycle {
+            // Still notice when the separate maintenance runs stop
+            if self.config.maintenance.check_day == 0 {
+                return Ok(());
+            }
+            return self.check_overdue();
+        }
+        self.check_if_due()
+    }
+
+    fn check_if_due(&mut self) -> Result<(), String> {
+        let state = self.state();
+        let last_check = state.last_check()?;
+        let first_cycle = state.history()?.first().map(|entry| entry.started);
+        let check_day = self.config.maintenance.check_day;
+        if !policy::check_due(check_day, last_check, first_cycle, Local::now()) {
+            if check_day == 0 {
+                return Ok(());
+            }
+            return self.check_overdue();
+        }
+        self.run_scheduled_check()
+    }
+
+    fn run_scheduled_check(&mut self) -> Result<(), String> {
+        let deep = self.config.maintenance.check_mode == CheckMode::Deep && Local::now().day() <= 7;
+        if deep {
+            self.log("Running monthly deep integrity check (verifying all data)...");
+        } else {
+            self.log("Running weekly integrity check...");
+        }
+
+        self.run_check(&CheckOptions {
+            verify_data: deep,
+            ..CheckOptions::default()
+        })?;
+        self.verify_key_backup();
+        Ok(())
+    }
+
+    /// The `check` subcommand: check (or repair) now, whatever the day.
+    pub fn check_now(&mut self, options: &CheckOptions) -> Result<(), String> {
+        let _lock = if options.repair {
+            Some(self.acquire_lock()?)
+        } else {
+            None
+        };
+        if options.repair {
+            self.log("Repairing repository...");
+        } else {
+            self.log("Checking repository...");
+        }
+        self.run_check(options)?;
+        if options.is_full() {
+            self.verify_key_backup();
+        }
+        Ok(())
+    }
+
+    fn run_check(&mut self, options: &CheckOptions) -> Result<(), String> {
+        if options.repair && self.dry_run {
+            self.log("Skipping repair");
+            return Ok(());
+        }
+
+        let mut cmd = self.borg_in_background();
+        cmd.arg("check").args(options.args());
+        if options.repair {
+            // Asked for confirmation already; borg would ask again
+            cmd.env("BORG_CHECK_I_KNOW_WHAT_I_AM_DOING", "YES");
+        }
+        cmd.arg(self.get_repo_path());
+        let status = self
+            .run_logged(cmd)
+            .map_err(|e| format!("Failed to run borg check: {}", e))?;
+
+        let exit_code = status.code().unwrap_or(2);
+        if exit_code >= 2 {
+            return Err(format!(
+                "Repository integrity check failed with exit code {}",
+                exit_code
+            ));
+        }
+
+        if options.repair {
+            self.log("Repair completed");
+        } else {
+            self.log("Integrity check passed");
+        }
+        // Partial checks don't vouch for the whole repository
+        if !self.dry_run && options.is_full() {
+            if let Err(e) = self.state().record_check(Utc::now()) {
+                self.warn(&e);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_encrypted(&self) -> bool {
+        !matches!(
+            self.config.repository.encryption.as_str(),
+            "none" | "authenticated" | "authenticated-blake2"
+        )
+    }
+
+    fn verify_key_backup(&mut self) {
+        let path = match &self.config.security.key_backup {
+            Some(path) if self.is_encrypted() => path.clone(),
+            _ => return,
+        };
+        let problem = match self.key_backup_problem(&path) {
+            Ok(None) => {
+                self.log(&format!("Key backup {} matches the repository key", path));
+                return;
+            }
+            Ok(Some(problem)) => problem,
+            Err(e) => {
+                self.warn(&format!("could not verify the key backup: {}", e));
+                return;
+            }
+        };
+
+        self.warn(&problem);
+        self.notify(Notification {
+            subject: format!("Key backup problem on {}", self.hostname),
+            message: format!(
+                "{}\n\nWithout the repository key the backups cannot be restored. \
+                 Export it again with `borg-timemachine key export {}`.",
+                problem, path
+            ),
+            hostname: self.hostname.clone(),
+            success: false,
+            html: None,
+        });
+    }
+
+    /// What is wrong with the key backup at `path`, if anything.
+    pub fn key_backup_problem(&self, path: &str) -> Result<Option<String>, String> {
+        let saved = match fs::read_to_string(path) {
+            Ok(saved) => saved,
+            Err(e) => return Ok(Some(format!("key backup {} is unreadable: {}", path, e))),
+        };
+        if saved.trim().is_empty() {
+            return Ok(Some(format!("key backup {} is empty", path)));
+        }
+
+        let mut cmd = self.borg();
+        cmd.args(["key", "export"]);
+        if !saved.starts_with("BORG_KEY") {
+            cmd.arg("--paper");
+        }
+        cmd.arg(self.get_repo_path());
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg key export: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "borg key export failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        if String::from_utf8_lossy(&output.stdout).trim() == saved.trim() {
+            Ok(None)
+        } else {
+            Ok(Some(format!(
+                "key backup {} does not match the repository key",
+                path
+            )))
+        }
+    }
+
+    fn check_overdue(&mut self) -> Result<(), String> {
+        let state = self.state();
+        let last_check = state.last_check()?;
+        let first_cycle = state.history()?.first().map(|entry| entry.started);
+        if !policy::check_overdue(last_check, first_cycle, Utc::now()) {
+            return Ok(());
+        }
+
+        let message = match last_check {
+            Some(at) => format!(
+                "repository check is overdue; the last one passed {}",
+                self.config.output.time(at)
+            ),
+            None => "repository check is overdue; none has run yet".to_string(),
+        };
+        match self.config.policy.on_check_overdue {
+            OnCheckOverdue::Warn => {
+                self.warn(&message);
+                Ok(())
+            }
+            OnCheckOverdue::Fail => Err(message),
+        }
+    }
+
+    /// Read back the archives written by this cycle with an archives-only check.
+    pub fn verify_new_archive(&mut self) -> Result<(), String> {
+        if !self.config.maintenance.verify_new_archive {
+            return Ok(());
+        }
+
+        for archive in self.report.created.clone() {
+            self.log(&format!("Verifying new archive {}...", archive));
+
+            let mut cmd = self.borg();
+            cmd.args([
+                "check",
+                "--archives-only",
+                "--glob-archives",
+                &archive,
+                &self.get_repo_path(),
+            ]);
+            let status = self
+                .run_logged(cmd)
+                .map_err(|e| format!("Failed to run borg check: {}", e))?;
+
+            let exit_code = status.code().unwrap_or(2);
+            if exit_code >= 2 {
+                return Err(format!(
+                    "Verification of archive {} failed with exit code {}",
+                    archive, exit_code
+                ));
+            }
+            if exit_code == 1 {
+                self.warn(&format!(
+                    "borg check of archive {} reported warnings",
+                    archive
+                ));
+            } else {
+                self.log("Archive verified");
+            }
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        if !self.config.notifications.enabled {
+            return;
+        }
+
+        let notification = self.as_sent(notification);
+        let smtp = self.config.notifications.smtp.clone();
+        for channel in self.config.notifications.all_channels() {
+            if let Err(e) = channel.send(&notification, smtp.as_ref()) {
+                self.warn(&format!("{} notification failed: {}", channel.name(), e));
+            }
+        }
+    }
+
+    fn as_sent(&self, mut notification: Notification) -> Notification {
+        if self.dry_run {
+            notification.subject = format!("[DRY RUN] {}", notification.subject);
+            notification
+                .message
+                .push_str("\n\nThis was a simulated run; the repository was not modified.");
+        }
+        notification
+    }
+
+    pub fn send_failure_notification(&mut self, error: &str) {
+        self.notify(Notification {
+            subject: format!("Backup Failure on {}", self.hostname),
+            message: format!("Borg backup failed: {}", error),
+            hostname: self.hostname.clone(),
+            success: false,
+            html: None,
+        });
+    }
+
+    pub fn send_success_notification(&mut self) {
+        if !self.config.notifications.on_success {
+            return;
+        }
+
+        let summary = &self.config.notifications.report;
+        let output = &self.config.output;
+        let message = summary.text(&self.report, &self.hostname, output);
+        let html = summary.html(&self.report, &self.hostname, output);
+        self.notify(Notification {
+            subject: format!("Backup Succeeded on {}", self.hostname),
+            message,
+            hostname: self.hostname.clone(),
+            success: true,
+            html,
+        });
+    }
+
+    fn notify_desktop(&mut self, title: &str, body: &str, urgency: desktop::Urgency) {
+        let Some(desktop) = &self.config.notifications.desktop else {
+            return;
+        };
+        if !self.config.notifications.enabled {
+            return;
+        }
+        let title = if self.dry_run {
+            format!("[DRY RUN] {}", title)
+        } else {
+            title.to_string()
+        };
+        // Not a warning: headless hosts and sessions that aren't logged in
+        // would otherwise turn every cycle into one
+        if let Err(e) = desktop.show(&title, body, urgency) {
+            self.log(&format!("Desktop notification failed: {}", e));
+        }
+    }
+
+    fn notify_desktop_started(&mut self) {
+        if self
+            .config
+            .notifications
+            .desktop
+            .as_ref()
+            .is_some_and(|d| d.on_start)
+        {
+            let jobs = self.config.jobs.iter().filter(|job| job.enabled).count();
+            self.notify_desktop(
+                "Backup started",
+                &format!("Backing up {} job(s) to {}", jobs, self.get_repo_path()),
+                desktop::Urgency::Low,
+            );
+        }
+    }
+
+    fn notify_desktop_finished(&mut self) {
+        let Some(desktop) = self.config.notifications.desktop.clone() else {
+            return;
+        };
+        let outcome = self.report.outcome();
+        let wanted = match outcome {
+            CycleOutcome::Success => desktop.on_success,
+            CycleOutcome::SuccessWithWarnings => desktop.on_warning,
+            CycleOutcome::Failed(_) => desktop.on_failure,
+        };
+        if wanted {
+            let (title, body, urgency) = desktop_summary(&outcome, &self.report);
+            self.notify_desktop(title, &body, urgency);
+        }
+    }
+
+    /// Run every stage of a backup cycle, report it and tell how it ended.
+    pub fn run_backup_cycle(&mut self) -> CycleOutcome {
+        let lock = match self.acquire_lock() {
+            Ok(lock) => lock,
+            Err(e) => return CycleOutcome::Failed(e),
+        };
+        if let Err(e) = signals::install() {
+            return CycleOutcome::Failed(e);
+        }
+        self.notify_systemd("READY=1\nSTATUS=Starting backup cycle");
+        self.watchdog = self.sd_notify.as_ref().and_then(SdNotify::watchdog);
+
+        self.report = CycleReport::new(Utc::now());
+        self.cycle_log.clear();
+        self.ping_healthcheck_start();
+        self.notify_desktop_started();
+        let started = Instant::now();
+        let inhibitor = self.inhibit_sleep();
+        let result = self.run_backup_cycle_inner();
+        drop(inhibitor);
+        self.report.duration = started.elapsed();
+        self.report.success = result.is_ok();
+        self.report.error = result.as_ref().err().cloned();
+
+        if let Err(ref e) = result {
+            self.error(e);
+            if let Some(signal) = signals::interrupted() {
+                self.warn(&format!(
+                    "Backup cycle interrupted by {}, cleaning up",
+                    signals::name(signal)
+                ));
+            }
+            if let Some(hook) = self.config.hooks.on_failure.clone() {
+                if let Err(hook_err) =
+                    self.run_hook("on_failure", &hook, &[("BORG_TIMEMACHINE_ERROR", e)])
+                {
+                    self.error(&hook_err);
+                }
+            }
+            self.send_failure_notification(e);
+        } else {
+            self.send_success_notification();
+        }
+        self.notify_desktop_finished();
+
+        self.write_metrics();
+        self.record_history();
+        self.ping_healthcheck_finish(result.is_ok());
+
+        self.watchdog = None;
+        match &result {
+            Ok(()) => self.notify_systemd("STOPPING=1\nSTATUS=Backup cycle complete"),
+            Err(e) => {
+                self.notify_systemd(&format!("STOPPING=1\nSTATUS=Backup cycle failed: {}", e))
+            }
+        }
+        drop(lock);
+        self.report.outcome()
+    }
+
+    fn notify_systemd(&self, state: &str) {
+        if let Some(notify) = &self.sd_notify {
+            if let Err(e) = notify.send(state) {
+                tracing::debug!("sd_notify failed: {}", e);
+            }
+        }
+    }
+
+    fn inhibit_sleep(&mut self) -> Option<Inhibitor> {
+        if !self.config.options.inhibit_sleep || self.dry_run || !Inhibitor::available() {
+            return None;
+        }
+        match Inhibitor::acquire("Backup in progress") {
+            Ok(inhibitor) => Some(inhibitor),
+            Err(e) => {
+                self.log(&format!("Not inhibiting sleep: {}", e));
+                None
+            }
+        }
+    }
+
+    /// Make sure a local repository is on the expected filesystem.
+    pub fn check_repository_device(&self) -> Result<(), String> {
+        let repo = &self.config.repository;
+        if !repo.rotation.is_empty() && self.disk.is_none() {
+            let names: Vec<&str> = repo.rotation.iter().map(|d| d.name.as_str()).collect();
+            return Err(format!(
+                "None of the rotation disks ({}) is attached",
+                names.join(", ")
+            ));
+        }
+        if repo.remote.is_some() || (repo.expect_uuid.is_none() && repo.expect_label.is_none()) {
+            return Ok(());
+        }
+
+        let device = preflight::mounted_device(Path::new(&repo.path))
+            .ok_or_else(|| format!("Cannot find the filesystem holding {}", repo.path))?;
+        let describe = |id: &Option<String>| id.as_deref().unwrap_or("none").to_string();
+
+        let uuid_ok = repo.expect_uuid.as_ref().is_none_or(|expected| {
+            device
+                .uuid
+                .as_ref()
+                .is_some_and(|uuid| uuid.eq_ignore_ascii_case(expected))
+        });
+        let label_ok = repo
+            .expect_label
+            .as_ref()
+            .is_none_or(|expected| device.label.as_ref() == Some(expected));
+
+        if uuid_ok && label_ok {
+            return Ok(());
+        }
+        Err(format!(
+            "Repository {} is on {} mounted at {} (UUID {}, label {}), not the expected disk (UUID {}, label {}); is the right drive attached and mounted?",
+            repo.path,
+            device.device,
+            device.mount_point.display(),
+            describe(&device.uuid),
+            describe(&device.label),
+            describe(&repo.expect_uuid),
+            describe(&repo.expect_label),
+        ))
+    }
+
+    /// Warn or fail when a local repository sits on or inside a job source.
+    pub fn check_repository_placement(&mut self) -> Result<(), String> {
+        let policy = self.config.options.same_device;
+        if policy == Policy::Ignore || self.config.repository.remote.is_some() {
+            return Ok(());
+        }
+
+        let repo = Path::new(&self.config.repository.path).to_path_buf();
+        let overlaps: Vec<String> = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.enabled)
+            .filter_map(|job| {
+                preflight::repository_overlap(&repo, Path::new(&job.source)).map(|overlap| {
+                    format!(
+                        "repository {} {} source {} (job {})",
+                        repo.display(),
+                        overlap,
+                        job.source,
+                        job.name
+                    )
+                })
+            })
+            .collect();
+
+        for overlap in &overlaps {
+            self.warn(overlap);
+        }
+
+        if policy == Policy::Fail && !overlaps.is_empty() {
+            return Err(format!(
+                "Refusing to back up: {} (set options.same_device to warn to allow)",
+                overlaps.join("; ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Exclude patterns for this tool's own files that fall under a job source.
+    pub fn auto_exclusions(&self) -> Vec<String> {
+        let mut paths: Vec<PathBuf> = vec![
+            PathBuf::from(&self.config.logging.log_file),
+            PathBuf::from(self.config.lock_file()),
+            PathBuf::from(&self.config.logging.state_file),
+            PathBuf::from(&self.config.logging.audit_log),
+        ];
+        if self.config.repository.remote.is_none() {
+            paths.push(PathBuf::from(&self.config.repository.path));
+        }
+        paths.extend(preflight::borg_mount_points());
+
+        paths
+            .iter()
+            .filter(|path| {
+                self.config
+                    .jobs
+                    .iter()
+                    .filter(|job| job.enabled)
+                    .any(|job| preflight::is_under(path, Path::new(&job.source)))
+            })
+            .map(|path| format!("pp:{}", path.display()))
+            .collect()
+    }
+
+    /// Health checks for the repository, key and configuration.
+    pub fn doctor(&mut self) -> Vec<CheckResult> {
+        let mut results = vec![CheckResult::new(
+            "borg",
+            CheckStatus::Pass,
+            format!("{} is version {}", self.config.borg.binary, self.version),
+        )];
+
+        // Unlike validate, actually run the command or ask the keyring
+        let source = self.config.security.passphrase_source();
+        results.push(match &source {
+            PassphraseSource::Command { .. } | PassphraseSource::Keyring { .. } => {
+                match source.resolve() {
+                    Ok(_) => CheckResult::new(
+                        "passphrase",
+                        CheckStatus::Pass,
+                        format!("from {}", source.describe()),
+                    ),
+                    Err(e) => CheckResult::new("passphrase", CheckStatus::Fail, e),
+                }
+            }
+            _ => validate::passphrase(&source),
+        });
+
+        results.push(match privileges::check(&self.config) {
+            Ok(()) => CheckResult::new(
+                "privileges",
+                CheckStatus::Pass,
+                match &self.run_as {
+                    Some(account) => format!("borg runs as {}", account.name),
+                    None => "borg can read every job's source".to_string(),
+                },
+            ),
+            Err(e) => CheckResult::new("privileges", CheckStatus::Fail, e),
+        });
+
+        // A trivial list proves the key decrypts and surfaces TAM warnings
+        let mut cmd = self.borg();
+        cmd.args(["list", "--short", "--last", "1", &self.get_repo_path()])
+            .stdin(Stdio::null());
+        match self.runner.capture(cmd) {
+            Ok(output) => results.extend(preflight::key_checks(
+                output.status.success(),
+                &String::from_utf8_lossy(&output.stderr),
+            )),
+            Err(e) => results.push(CheckResult::new(
+                "key",
+                CheckStatus::Fail,
+                format!("Failed to run borg: {}", e),
+            )),
+        }
+
+        // Without an exported key, losing a keyfile repository is fatal
+        if self.is_encrypted() {
+            results.push(match &self.config.security.key_backup {
+                None => CheckResult::new(
+                    "key backup",
+                    CheckStatus::Warn,
+                    "security.key_backup is not set; export the key with `key export`",
+                ),
+                Some(path) => match self.key_backup_problem(path) {
+                    Ok(None) => CheckResult::new(
+                        "key backup",
+                        CheckStatus::Pass,
+                        format!("{} matches the repository key", path),
+                    ),
+                    Ok(Some(problem)) => CheckResult::new("key backup", CheckStatus::Fail, problem),
+                    Err(e) => CheckResult::new("key backup", CheckStatus::Warn, e),
+                },
+            });
+        }
+
+        if self.config.repository.expect_uuid.is_some()
+            || self.config.repository.expect_label.is_some()
+        {
+            results.push(match self.check_repository_device() {
+                Ok(()) => CheckResult::new("device", CheckStatus::Pass, "expected disk is mounted"),
+                Err(e) => CheckResult::new("device", CheckStatus::Fail, e),
+            });
+        }
+
+        // Repository placement relative to the sources
+        if self.config.repository.remote.is_none() {
+            let repo = PathBuf::from(&self.config.repository.path);
+            let overlaps: Vec<String> = self
+                .config
+                .jobs
+                .iter()
+                .filter(|job| job.enabled)
+                .filter_map(|job| {
+                    preflight::repository_overlap(&repo, Path::new(&job.source))
+                        .map(|overlap| format!("{} source {}", overlap, job.source))
+                })
+                .collect();
+            results.push(if overlaps.is_empty() {
+                CheckResult::new(
+                    "placement",
+                    CheckStatus::Pass,
+                    "repository is on a separate device from all sources",
+                )
+            } else {
+                CheckResult::new("placement", CheckStatus::Warn, overlaps.join("; "))
+            });
+
+            if !self.borg_lock_holders().is_empty() {
+                results.push(CheckResult::new(
+                    "borg lock",
+                    CheckStatus::Warn,
+                    format!("repository is locked by {}", self.describe_borg_lock()),
+                ));
+            }
+        }
+
+        results
+    }
+
+    /// Report of the most recent backup cycle.
+    pub fn last_report(&self) -> &CycleReport {
+        &self.report
+    }
+
+    fn ping_healthcheck_start(&mut self) {
+        let healthcheck = match &self.config.healthcheck {
+            Some(h) if !self.dry_run => h.clone(),
+            _ => return,
+        };
+        if let Err(e) = healthcheck.ping_start() {
+            self.warn(&format!("healthcheck start ping failed: {}", e));
+        }
+    }
+
+    fn ping_healthcheck_finish(&mut self, success: bool) {
+        let healthcheck = match &self.config.healthcheck {
+            Some(h) if !self.dry_run => h.clone(),
+            _ => return,
+        };
+        let tail = self.log_tail(healthcheck.log_lines);
+        if let Err(e) = healthcheck.ping_finish(success, &tail) {
+            self.warn(&format!("healthcheck ping failed: {}", e));
+        }
+    }
+
+    fn record_history(&mut self) {
+        if self.dry_run {
+            return;
+        }
+
+        let jobs = self
+            .config
+            .jobs
+            .iter()
+            .filter(|job| job.enabled)
+            .map(|job| job.name.clone())
+            .collect();
+        let entry = HistoryEntry::from_report(&self.report, jobs);
+        if let Err(e) = self.state().record(entry) {
+            self.warn(&e);
+        }
+
+        if let (Some(disk), true) = (self.disk.clone(), self.report.success) {
+            if let Err(e) = self.state().record_disk_backup(&disk, self.report.started) {
+                self.warn(&e);
+            }
+        }
+    }
+
+    /// Directories of at least `min_size` bytes that no enabled job backs up or excludes.
+    pub fn coverage_gaps(&self, min_size: u64, all: bool) -> Result<Vec<coverage::Gap>, String> {
+        let mounts = fs::read_to_string("/proc/self/mounts")
+            .map_err(|e| format!("Failed to read /proc/self/mounts: {}", e))?;
+
+        let mut covered = Vec::new();
+        let mut excludes = self.config.exclusions.clone();
+        for file in &self.config.exclusions_from {
+            excludes.extend(read_pattern_file(file)?);
+        }
+        for job in self.config.jobs.iter().filter(|job| job.enabled) {
+            let source = Path::new(&job.source);
+            covered.push(
+                source
+                    .canonicalize()
+                    .unwrap_or_else(|_| source.to_path_buf()),
+            );
+            covered.extend(job.also_include_filesystems.iter().map(PathBuf::from));
+            excludes.extend(job.exclude.iter().cloned());
+        }
+
+        let mut skip: Vec<PathBuf> = if all {
+            Vec::new()
+        } else {
+            coverage::SYSTEM_DIRS.iter().map(PathBuf::from).collect()
+        };
+        if self.config.repository.remote.is_none() {
+            skip.push(PathBuf::from(&self.config.repository.path));
+        }
+        skip.extend(preflight::borg_mount_points());
+
+        let coverage = coverage::Coverage {
+            covered,
+            one_file_system: self.config.options.one_file_system,
+            excludes,
+            skip,
+            min_size,
+        };
+        Ok(coverage.gaps(&coverage::data_mounts(&mounts)))
+    }
+
+    /// The last cycle and, with a disk rotation, when each disk last had a backup.
+    pub fn status(&self) -> Result<Status, String> {
+        let history = self.state().history()?;
+        let rotation = &self.config.repository.rotation;
+        let max_age = parse_age(&self.config.repository.rotation_max_age)?;
+        let records = self.state().disk_backups()?;
+        let stale = stale_disks(rotation, &records, Utc::now(), max_age);
+        let disks = rotation
+            .iter()
+            .map(|disk| DiskStatus {
+                name: disk.name.clone(),
+                uuid: disk.uuid.clone(),
+                attached: self.disk.as_deref() == Some(disk.name.as_str()),
+                last_backup: records
+                    .iter()
+                    .find(|r| r.name == disk.name)
+                    .map(|r| r.last_backup),
+                stale: stale.contains(&disk.name),
+            })
+            .collect();
+        Ok(Status {
+            last_cycle: history.last().cloned(),
+            disks,
+            rotation_max_age: self.config.repository.rotation_max_age.clone(),
+            hosts: Vec::new(),
+            hosts_unavailable: None,
+            host_max_age: self.config.repository.host_max_age.clone(),
+        })
+    }
+
+    /// When each host of the shared repository last backed up.
+    pub fn host_status(&self) -> Result<Vec<HostStatus>, String> {
+        let mut cmd = self.borg();
+        if self.version.has_bypass_lock() {
+            cmd.common_arg("--bypass-lock");
+        }
+        cmd.args([
+            "list",
+            "--json",
+            "--format={hostname}",
+            &self.get_repo_path(),
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "borg list failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(host_statuses(
+            &ArchiveEntry::from_list_json(&String::from_utf8_lossy(&output.stdout))?,
+            Utc::now(),
+            parse_age(&self.config.repository.host_max_age)?,
+        ))
+    }
+
+    fn write_metrics(&mut self) {
+        if !self.config.metrics.enabled || self.dry_run {
+            return;
+        }
+
+        let contents = metrics::render(&self.report, &self.hostname);
+        if let Err(e) = metrics::write_textfile(&self.config.metrics.textfile, &contents) {
+            self.warn(&e);
+        }
+    }
+
+    fn timed_stage(
+        &mut self,
+        stage: &str,
+        run: impl FnOnce(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        if let Some(signal) = signals::interrupted() {
+            return Err(format!(
+                "Interrupted by {} before {}",
+                signals::name(signal),
+                stage
+            ));
+        }
+
+        self.notify_systemd(&format!("STATUS=Backup cycle: {}", stage));
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let warnings = self.report.warnings.len();
+        let result = run(self);
+        let outcome = if result.is_err() {
+            StageOutcome::Failed
+        } else if self.report.warnings.len() > warnings {
+            StageOutcome::Warning
+        } else {
+            StageOutcome::Success
+        };
+        self.report.stages.push(StageReport {
+            name: stage.to_string(),
+            started: started_at,
+            duration: started.elapsed(),
+            outcome,
+        });
+        result?;
+        self.enforce_warning_policy(warnings)
+    }
+
+    fn enforce_warning_policy(&self, since: usize) -> Result<(), String> {
+        match (
+            self.config.policy.on_warning,
+            self.report.warnings.get(since),
+        ) {
+            (OnWarning::Fail, Some(warning)) => Err(format!(
+                "Failing on warning (policy.on_warning is fail): {}",
+                warning
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn run_backup_cycle_inner(&mut self) -> Result<(), String> {
+        self.open_log()?;
+
+        if let Some(hook) = self.config.hooks.pre_cycle.clone() {
+            self.run_hook("pre_cycle", &hook, &[])?;
+        }
+
+        // After pre_cycle, which may be what mounts the disk
+        self.select_rotation_disk();
+        self.check_repository_device()?;
+        // Rather than EPERM on random files halfway through
+        privileges::check(&self.config)?;
+        self.ensure_repository()?;
+        if let Some(disk) = self.disk.clone() {
+            self.log(&format!(
+                "Using rotation disk {} ({})",
+                disk, self.config.repository.path
+            ));
+        }
+        self.log_borg_lock();
+        let checkpoints = self.find_checkpoints();
+        let warnings = self.report.warnings.len();
+        self.check_repository_placement()?;
+        self.enforce_warning_policy(warnings)?;
+
+        // Run backup
+        self.timed_stage("create", Self::create_backup)?;
+        self.clean_up_checkpoints(&checkpoints);
+
+        // Read back the new archive
+        self.timed_stage("verify", Self::verify_new_archive)?;
+
+        // Prune old backups
+        self.timed_stage("prune", Self::scheduled_prune)?;
+
+        // Compact repository
+        self.timed_stage("compact", Self::scheduled_compact)?;
+
+        // Warn before the disk fills up
+        self.timed_stage("quota", Self::check_quota)?;
+
+        // Check repository (if scheduled)
+        self.timed_stage("check", Self::check_repository)?;
+
+        // Restore a sample of files (if scheduled)
+        self.timed_stage("restore-test", Self::scheduled_restore_test)?;
+
+        // Copy the repository to the replica
+        self.timed_stage("replicate", Self::replicate_after_cycle)?;
+
+        if let Some(hook) = self.config.hooks.post_cycle.clone() {
+            self.run_hook("post_cycle", &hook, &[])?;
+        }
+
+        self.log("Backup cycle complete");
+        Ok(())
+    }
+
+    /// Statistics for a single archive.
+    pub fn archive_stats(&self, archive: &str) -> Result<ArchiveStats, String> {
+        ArchiveStats::from_info_json(&self.info_json(Some(archive))?)
+    }
+
+    fn info_json(&self, archive: Option<&str>) -> Result<String, String> {
+        let target = match archive {
+            Some(archive) => format!("{}::{}", self.get_repo_path(), archive),
+            None => self.get_repo_path(),
+        };
+        let mut cmd = self.borg();
+        cmd.args(["info", "--json", &target]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg info: {}", e))?;
+
+        if !output.status.success() {
+            return Err("borg info failed".to_string());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Names of all archives in the repository.
+    pub fn archive_names(&self) -> Result<Vec<String>, String> {
+        let mut cmd = self.borg();
+        cmd.args(["list", "--short", &self.get_repo_path()]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty() && !report::is_checkpoint(l))
+            .collect())
+    }
+
+    /// Checkpoints of this host's archives left by interrupted `borg create` runs.
+    pub fn checkpoint_archives(&self) -> Result<Vec<String>, String> {
+        let mut cmd = self.borg();
+        cmd.args(["list", "--short"]);
+        if self.version.has_consider_checkpoints() {
+            cmd.arg("--consider-checkpoints");
+        }
+        cmd.args([
+            &format!(
+                "--glob-archives={}.checkpoint*",
+                self.name_template().any_glob()
+            ),
+            &self.get_repo_path(),
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err("borg list failed".to_string());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|l| report::is_checkpoint(l))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn find_checkpoints(&mut self) -> Vec<String> {
+        // Only needed to clean up afterwards; the backup goes ahead
+        let checkpoints = match self.checkpoint_archives() {
+            Ok(checkpoints) => checkpoints,
+            Err(e) => {
+                self.warn(&format!("Failed to look for checkpoints: {}", e));
+                Vec::new()
+            }
+        };
+        if !checkpoints.is_empty() {
+            self.log(&format!(
+                "The previous backup was interrupted and left {}; this one reuses the data it uploaded",
+                checkpoints.join(", ")
+            ));
+        }
+        checkpoints
+    }
+
+    fn clean_up_checkpoints(&mut self, checkpoints: &[String]) {
+        if checkpoints.is_empty() || self.config.policy.on_checkpoints != OnCheckpoints::Delete {
+            return;
+        }
+        if self.append_only() {
+            self.log("Keeping the checkpoints: the repository is append-only");
+            return;
+        }
+        self.log(&format!(
+            "Deleting {} checkpoint(s) of the interrupted backup",
+            checkpoints.len()
+        ));
+        if let Err(e) = self
+            .refuse_foreign("delete", checkpoints)
+            .and_then(|_| self.borg_delete(checkpoints))
+        {
+            self.warn(&e);
+        }
+    }
+
+    /// The archives `options` selects, with their statistics, in the order they ask for.
+    pub fn archive_details(&self, options: &ListOptions) -> Result<Vec<ArchiveDetails>, String> {
+        let template = self.name_template();
+        let job_glob = match &options.job {
+            None => None,
+            Some(name) => {
+                let job = self
+                    .config
+                    .jobs
+                    .iter()
+                    .find(|job| job.name == *name)
+                    .ok_or_else(|| format!("No job named {}", name))?;
+                // Jobs without paths_from share the main series
+                Some(template.glob(job.paths_from.as_ref().map(|_| job.name.as_str())))
+            }
+        };
+        // Let borg skip the archives we'd filter out anyway
+        let glob = match (&job_glob, options.trash) {
+            (_, true) => format!("{}*", TRASH_PREFIX),
+            (Some(glob), false) => glob.clone(),
+            (None, false) => "*".to_string(),
+        };
+
+        let mut cmd = self.borg();
+        cmd.args([
+            "info",
+            "--json",
+            &format!("--glob-archives={}", glob),
+            &self.get_repo_path(),
+        ]);
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg info: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "borg info failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let verified: Vec<String> = self
+            .state()
+            .verified_archives()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| v.archive)
+            .collect();
+        let mut archives: Vec<ArchiveDetails> =
+            ArchiveDetails::from_info_json(&String::from_utf8_lossy(&output.stdout))?
+                .into_iter()
+                .filter(|a| a.name.starts_with(TRASH_PREFIX) == options.trash)
+                .filter(|a| {
+                    options.all_hosts
+                        || !self.config.repository.shared
+                        || self.is_own_archive(&a.name)
+                })
+                .filter(|a| {
+                    let original = parse_trash_name(&a.name).map_or(a.name.as_str(), |(_, n)| n);
+                    job_glob
+                        .as_ref()
+                        .is_none_or(|glob| glob_match(glob, original))
+                })
+                .filter(|a| options.since.is_none_or(|since| a.time >= since))
+                .filter(|a| options.until.is_none_or(|until| a.time <= until))
+                .map(|a| ArchiveDetails {
+                    verified: verified.contains(&a.name),
+                    ..a
+                })
+                .collect();
+
+        archives.sort_by_key(|a| a.time);
+        if let Some(last) = options.last {
+            archives.drain(..archives.len().saturating_sub(last));
+        }
+        match options.sort {
+            ListSort::Time => {}
+            ListSort::Name => archives.sort_by(|a, b| a.name.cmp(&b.name)),
+            ListSort::Size => archives.sort_by_key(|a| a.stats.original_size),
+            ListSort::Duration => {
+                archives.sort_by(|a, b| a.duration_secs.total_cmp(&b.duration_secs))
+            }
+        }
+        if options.reverse {
+            archives.reverse();
+        }
+        Ok(archives)
+    }
+
+    /// Every version of files matching `pattern` across this host's archives.
+    pub fn find_files(&self, pattern: &str) -> Result<Vec<(String, FileEntry)>, String> {
+        // Archives store paths without the leading slash
+        let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+        let is_glob = pattern.contains(['*', '?']);
+
+        let mut found = Vec::new();
+        for archive in self.select_archives(None, None)? {
+            // Let borg narrow the listing to the subtree
+            let files = self.archive_files(&archive, (!is_glob).then_some(pattern))?;
+            found.extend(
+                files
+                    .into_iter()
+                    .filter(|file| {
+                        if is_glob {
+                            glob_match(pattern, &file.path)
+                        } else {
+                            file.path == pattern || file.path.starts_with(&format!("{}/", pattern))
+                        }
+                    })
+                    .map(|file| (archive.clone(), file)),
+            );
+        }
+        Ok(found)
+    }
+
+    fn archive_files(&self, archive: &str, path: Option<&str>) -> Result<Vec<FileEntry>, String> {
+        let mut cmd = self.borg();
+        cmd.args([
+            "list",
+            "--json-lines",
+            &format!("{}::{}", self.get_repo_path(), archive),
+        ]);
+        if let Some(path) = path {
+            cmd.arg(path);
+        }
+        let output = self
+            .runner
+            .capture(cmd)
+            .map_err(|e| format!("Failed to run borg list: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("borg list of {} failed", archive));
+        }
+        FileEntry::from_json_lines(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Restore `path` as it was at `as_of`.
+    pub fn restore_file(
+        &mut self,
+        path: &str,
+        as_of: DateTime<Utc>,
+        target: Option<&str>,
+    ) -> Result<(), String> {
+        let path = path.trim_start_matches('/').trim_end_matches('/');
+        let archive = self.archive_as_of(as_of)?;
+        let files: Vec<FileEntry> = self
+            .archive_files(&archive.name, Some(path))?
+            .into_iter()
+            .filter(|f| f.path == path || f.path.starts_with(&format!("{}/", path)))
+            .collect();
+        if files.is_empty() {
+            return Err(format!("/{} is not in archive {}", path, archive.name));
+        }
+
+        self.log(&format!(
+            "Restoring /{} from {} ({})",
+            path,
+            archive.name,
+            self.config.output.time(archive.time)
+        ));
+        let result = match target {
+            None => self.extract_to_stdout(&archive.name, path, &files),
+            Some(target) => self.extract_to(&archive.name, path, target, &files),
+        };
+        self.audit(
+            AuditEntry::new(&self.hostname, "restore", Some(&archive.name), &result)
+                .with_paths(&[path])
+                .with_target(target.unwrap_or("stdout")),
+        );
+        result?;
+
+        if let Some(target) = target {
+            self.log(&format!(
+                "Restored {} file(s) to {}",
+                files.len(),
+                Path::new(target).join(path).display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Browse the archives in a terminal UI and restore the files picked there.
+    pub fn browse(&mut self, target: &str) -> Result<(), String> {
+        let archives: Vec<ArchiveEntry> = self
+            .archive_entries()?
+            .into_iter()
+            .filter(|a| !a.name.starts_with(TRASH_PREFIX))
+            .collect();
+        let selection = browse::run(archives, &self.config.output, |archive| {
+            self.archive_files(archive, None)
+        })?;
+        match selection {
+            Some(selection) => self.restore_paths(
+                &selection.archive,
+                &selection.paths,
+                target,
+                &selection.files,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Restore `paths` from `archive` below `target`.
+    pub fn restore_paths(
+        &mut self,
+        archive: &str,
+        paths: &[String],
+        target: &str,
+        files: &[FileEntry],
+    ) -> Result<(), String> {
+        let mut result = Ok(());
+        let mut restored = 0;
+        for path in paths {
+            let below: Vec<FileEntry> = files
+                .iter()
+                .filter(|f| f.path == *path || f.path.starts_with(&format!("{}/", path)))
+                .cloned()
+                .collect();
+            self.log(&format!("Restoring /{} from {}", path, archive));
+            match self.extract_to(archive, path, target, &below) {
+                Ok(()) => restored += below.len(),
+                Err(e) => {
+                    self.error(&format!("Failed to restore /{}: {}", path, e));
+                    result = Err(format!("Failed to restore /{}", path));
+                }
+            }
+        }
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        self.audit(
+            AuditEntry::new(&self.hostname, "restore", Some(archive), &result)
+                .with_paths(&paths)
+                .with_target(target),
+        );
+        result?;
+        self.log(&format!("Restored {} file(s) to {}", restored, target));
+        Ok(())
+    }
+
+    fn extract_to_stdout(
+        &mut self,
+        archive: &str,
+        path: &str,
+        files: &[FileEntry],
+    ) -> Result<(), String> {
+        if !files.iter().any(|f| f.path == path) {
+            return Err(format!(
+                "/{} is a directory; restore it with --target instead of --stdout",
+                path
+            ));
+        }
+
+        let mut cmd = self.borg();
+        cmd.args([
+            "extract",
+            "--stdout",
+            &format!("{}::{}", self.get_repo_path(), archive),
+            path,
+        ]);
+        match self.runner.execute(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!(
+                "borg extract failed with exit code {}",
+                status.code().unwrap_or(-1)
+            )),
+            Err(e) => Err(format!("Failed to run borg extract: {}", e)),
+        }
+    }
+
+    fn extract_to(
+        &mut self,
+        archive: &str,
+        path: &str,
+        target: &str,
+        files: &[FileEntry],
+    ) -> Result<(), String> {
+        let destination = Path::new(target).join(path);
+        // Never overwrite: restoring into / by mistake would clobber the
+        // live file
+        if destination.symlink_metadata().is_ok() {
+            return Err(format!(
+                "{} already exists; choose an empty --target",
+                destination.display()
+            ));
+        }
+        if !self.dry_run {
+            fs::create_dir_all(target)
+                .map_err(|e| format!("Failed to create {}: {}", target, e))?;
+        }
+
+        let mut cmd = self.borg_as_root();
+        cmd.current_dir(target).arg("extract");
+        if self.dry_run {
+            cmd.arg("--dry-run");
+        }
+        cmd.args([&format!("{}::{}", self.get_repo_path(), archive), path]);
+        match self.run_logged(cmd) {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                return Err(format!(
+                    "borg extract failed with exit code {}",
+                    status.code().unwrap_or(-1)
+                ))
+            }
+            Err(e) => return Err(format!("Failed to run borg extract: {}", e)),
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+        verify_extracted(Path::new(target), files)
+    }
+
+    /// Run the Prometheus exporter on `listen`, e.g. `:9861`.
+    pub fn run_exporter(&self, listen: &str) -> Result<(), String> {
+        metrics::serve(listen, &self.state(), &self.hostname)
+    }
+
+    fn audit(&mut self, entry: AuditEntry) {
+        if self.dry_run {
+            return;
+        }
+        if let Err(e) = audit::append(&self.config.logging.audit_log, &entry) {
+            self.warn(&e);
+        }
+        if let Err(e) = self.state().record_audit(entry) {
+            self.warn(&e);
+        }
+    }
+
+    fn state(&self) -> StateStore {
+        StateStore::new(&self.config.logging.state_file)
+    }
+
+    /// Restore-test an archive by extracting it with `--dry-run`.
+    pub fn verify_restore(&mut self, archive: &str, manual: bool) -> Result<(), String> {
+        let archive = &self.resolve_archive(archive)?;
+        let method = if manual {
+            "manual"
+        } else {
+            self.log(&format!("Restore-testing archive {}...", archive));
+
+            let mut cmd = self.borg();
+            cmd.args([
+                "extract",
+                "--dry-run",
+                &format!("{}::{}", self.get_repo_path(), archive),
+            ]);
+            let result = match self.run_logged(cmd) {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format!(
+                    "Restore test of {} failed with exit code {}",
+                    archive,
+                    status.code().unwrap_or(-1)
+                )),
+                Err(e) => Err(format!("Failed to run borg extract: {}", e)),
+            };
+            self.audit(AuditEntry::new(
+                &self.hostname,
+                "verify",
+                Some(archive),
+                &result,
+            ));
+            result?;
+            "extract-dry-run"
+        };
+
+        if self.dry_run {
+            return Ok(());
+        }
+        self.state().mark_verified(archive, method)?;
+        self.log(&format!(
+            "Archive {} marked as verified ({})",
+            archive, method
+        ));
+        Ok(())
+    }
+
+    /// Restore `count` random files of `archive` and compare them with the live ones.
+    pub fn verify_sample(&mut self, archive: &str, count: usize) -> Result<String, String> {
+        let archive = &self.resolve_archive(archive)?;
+        let files = restore_test::sample(&self.archive_files(archive, None)?, count);
+        if files.is_empty() {
+            return Err(format!("Archive {} holds no files to restore", archive));
+        }
+        if self.dry_run {
+            self.log(&format!(
+                "Would restore {} random file(s) from {}",
+                files.len(),
+                archive
+            ));
+            return Ok(String::new());
+        }
+        self.log(&format!(
+            "Restore-testing {} random file(s) from {}...",
+            files.len(),
+            archive
+        ));
+
+        // Private to us (mode 0700) and unguessable
+        let dir = tempfile::Builder::new()
+            .prefix("borg-timemachine-restore-test-")
+            .tempdir()
+            .map_err(|e| format!("Failed to create restore directory: {}", e))?;
+
+        let mut cmd = self.borg_as_root();
+        cmd.current_dir(dir.path())
+            .arg("extract")
+            .arg(format!("{}::{}", self.get_repo_path(), archive))
+            .args(files.iter().map(|file| &file.path));
+        let result = match self.run_logged(cmd) {
+            Ok(status) if status.success() => {
+                let comparison = restore_test::compare(dir.path(), Path::new("/"), &files);
+                match comparison.failures.first() {
+                    None => Ok(comparison.summary()),
+                    Some(failure) => Err(format!(
+                        "Restore test of {} failed: {} ({})",
+                        archive,
+                        failure,
+                        comparison.summary()
+                    )),
+                }
+            }
+            Ok(status) => Err(format!(
+                "Restore test of {} failed: borg extract exited with code {}",
+                archive,
+                status.code().unwrap_or(-1)
+            )),
+            Err(e) => Err(format!("Failed to run borg extract: {}", e)),
+        };
+        drop(dir);
+
+        self.audit(AuditEntry::new(
+            &self.hostname,
+            "verify",
+            Some(archive),
+            &result.clone().map(|_| ()),
+        ));
+        let summary = result?;
+        self.state().mark_verified(archive, "sample")?;
+        self.log(&format!("Archive {} restore-tested: {}", archive, summary));
+        Ok(summary)
+    }
+
+    /// `verify --sample`, reported through the notification channels.
+    pub fn verify_sample_and_notify(&mut self, archive: &str, count: usize) -> Result<(), String> {
+        let result = self.verify_sample(archive, count);
+        if self.dry_run {
+            return result.map(|_| ());
+        }
+        match &result {
+            Ok(summary) if self.config.notifications.on_success => self.notify(Notification {
+                subject: format!("Restore Test Passed on {}", self.hostname),
+                message: summary.clone(),
+                hostname: self.hostname.clone(),
+                success: true,
+                html: None,
+            }),
+            Ok(_) => {}
+            Err(e) => self.notify(Notification {
+                subject: format!("Restore Test Failure on {}", self.hostname),
+                message: e.clone(),
+                hostname: self.hostname.clone(),
+                success: false,
+                html: None,
+            }),
+        }
+        result.map(|_| ())
+    }
+
+    fn scheduled_restore_test(&mut self) -> Result<(), String> {
+        let maintenance = &self.config.maintenance;
+        let today = Local::now().weekday().num_days_from_monday() + 1;
+        if maintenance.restore_test_day == 0
+            || maintenance.restore_test_day != today
+            || maintenance.restore_test_files == 0
+        {
+            return Ok(());
+        }
+        let Some(archive) = self.report.archive.clone() else {
+            return Ok(());
+        };
+        let tested_today = self.state().verified_archives()?.iter().any(|v| {
+            v.method == "sample"
+                && v.verified_at.with_timezone(&Local).date_naive() == Local::now().date_naive()
+        });
+        if tested_today {
+            return Ok(());
+        }
+        let count = self.config.maintenance.restore_test_files;
+        self.verify_sample(&archive, count).map(|_| ())
+    }
+
+    /// Import a tarball as an archive of this host's main series.
+    pub fn import_tar(
+        &mut self,
+        tarball: &str,
+        time: Option<DateTime<Utc>>,
+    ) -> Result<String, String> {
+        if !self.version.has_import_tar() {
+            return Err(format!(
+                "borg {} has no import-tar; it needs borg 1.2 or later",
+                self.version
+            ));
+        }
+        let filter = tar_filter(tarball)?;
+        let time = match time {
+            Some(time) => time,
+            None => fs::metadata(tarball)
+                .and_then(|m| m.modified())
+                .map(DateTime::<Utc>::from)
+                .map_err(|e| format!("Failed to read {}: {}", tarball, e))?,
+        };
+        let _lock = self.acquire_lock()?;
+        let name = self
+            .name_template()
+            .name(None, time, self.config.archive_timestamp);
+        let archive = self.unique_name(&name);
+
+        if self.dry_run {
+            self.log(&format!("Would import {} as {}", tarball, archive));
+            return Ok(archive);
+        }
+        self.log(&format!("Importing {} as {}...", tarball, archive));
+
+        let compression = self.effective_compression();
+        let mut cmd = self.borg_in_background();
+        cmd.arg("import-tar");
+        if self.config.options.show_stats {
+            cmd.arg("--stats");
+        }
+        if self.config.options.show_progress {
+            cmd.arg("--progress");
+        }
+        if let Some(filter) = filter {
+            cmd.arg(format!("--tar-filter={} -d", filter));
+        }
+        cmd.arg(format!("--compression={}", compression))
+            .arg(format!(
+                "--timestamp={}",
+                time.with_timezone(&Local).format("%Y-%m-%dT%H:%M:%S")
+            ))
+            .arg(format!("{}::{}", self.get_repo_path(), archive))
+            .arg(tarball);
+        let result = match self.run_logged(cmd) {
+            Ok(status) if status.code().unwrap_or(2) < 2 => Ok(()),
+            Ok(status) => Err(format!(
+                "borg import-tar failed with exit code {}",
+                status.code().unwrap_or(-1)
+            )),
+            Err(e) => Err(format!("Failed to run borg import-tar: {}", e)),
+        };
+        self.audit(
+            AuditEntry::new(&self.hostname, "import", Some(&archive), &result).with_target(tarball),
+        );
+        result?;
+        self.log(&format!("Imported {} as {}", tarball, archive));
+        Ok(archive)
+    }
+
+    /// Write `archive` as a tarball, limited to `paths` when any are given.
+    pub fn export_tar(
+        &mut self,
+        archive: &str,
+        output: &str,
+        paths: &[String],
+    ) -> Result<(), String> {
+        let filter = tar_filter(output)?;
+        if Path::new(output).exists() {
+            return Err(format!("{} already exists", output));
+        }
+        let archive = self.resolve_archive(archive)?;
+        let paths: Vec<&str> = paths
+            .iter()
+            .map(|p| p.trim_start_matches('/').trim_end_matches('/'))
+            .collect();
+
+        if self.dry_run {
+            self.log(&format!("Would export {} to {}", archive, output));
+            return Ok(());
+        }
+        self.log(&format!("Exporting {} to {}...", archive, output));
+
+        let mut cmd = self.borg();
+        cmd.arg("export-tar");
+        if let Some(filter) = filter {
+            cmd.arg(format!("--tar-filter={}", filter));
+        }
+        cmd.arg(format!("{}::{}", self.get_repo_path(), archive))
+            .arg(output)
+            .args(&paths);
+        let result = match self.run_logged(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!(
+                "borg export-tar failed with exit code {}",
+                status.code().unwrap_or(-1)
+            )),
+            Err(e) => Err(format!("Failed to run borg export-tar: {}", e)),
+        };
+        if result.is_err() {
+            // Don't leave a truncated tarball that looks complete
+            let _ = fs::remove_file(output);
+        }
+        self.audit(
+            AuditEntry::new(&self.hostname, "export", Some(&archive), &result)
+                .with_paths(&paths)
+                .with_target(output),
+        );
+        result?;
+
+        self.log(&format!("Exported {} to {}", archive, output));
+        Ok(())
+    }
+
+    /// The repository's size, chunks, cache and growth, or one archive's statistics.
+    pub fn repository_info(&self, archive: Option<&str>) -> Result<RepositoryReport, String> {
+        let archive = archive.map(|a| self.resolve_archive(a)).transpose()?;
+        let info = RepositoryInfo::from_info_json(&self.info_json(archive.as_deref())?)?;
+        let growth = match info.archive {
+            Some(_) => Vec::new(),
+            None => self.growth_trends(info.stats.unique_csize, Utc::now()),
+        };
+        Ok(RepositoryReport { info, growth })
+    }
+
+    fn growth_trends(&self, current: u64, now: DateTime<Utc>) -> Vec<String> {
+        let samples: Vec<(DateTime<Utc>, u64)> = self
+            .state()
+            .history()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|entry| Some((entry.started, entry.repository?.unique_csize)))
+            .collect();
+        let output = &self.config.output;
+        let mut trends = Vec::new();
+        for (days, period) in [(7, "this week"), (30, "in 30 days")] {
+            let Some((since, change)) =
+                report::growth(&samples, current, now, chrono::Duration::days(days))
+            else {
+                break;
+            };
+            let verb = if change < 0 { "shrank" } else { "grew" };
+            let bytes = output.bytes(change.unsigned_abs());
+            if since > now - chrono::Duration::days(days) {
+                // Not enough history for the whole period
+                trends.push(format!("{} {} since {}", verb, bytes, output.time(since)));
+                break;
+            }
+            trends.push(format!("{} {} {}", verb, bytes, period));
+        }
+        trends
+    }
+
+    /// Mount the repository, or one archive of it, at `mount_point`.
+    pub fn mount_repository(
+        &mut self,
+        mount_point: &str,
+        options: &MountOptions,
+    ) -> Result<(), String> {
+        let archive = options
+            .archive
+            .as_deref()
+            .map(|a| self.resolve_archive(a))
+            .transpose()?;
+        let source = match &archive {
+            Some(archive) => {
+                self.reporter
+                    .message(&format!("Mounting archive {} to {}", archive, mount_point));
+                format!("{}::{}", self.get_repo_path(), archive)
+            }
+            None => {
+                self.reporter
+                    .message(&format!("Mounting repository to {}", mount_point));
+                self.get_repo_path()
+            }
+        };
+
+        let created = !Path::new(mount_point).exists();
+        if created {
+            fs::create_dir_all(mount_point)
+                .map_err(|e| format!("Failed to create {}: {}", mount_point, e))?;
+            if let Err(e) = self.state().record_created_mount_point(mount_point) {
+                self.warn(&e);
+            }
+        }
+
+        let mut cmd = self.borg_as_root();
+        cmd.arg("mount");
+        if options.foreground {
+            cmd.arg("--foreground");
+            // Ctrl-C is for borg, which unmounts and exits; we clean up after
+            signals::install()?;
+        }
+        if !options.fuse_options.is_empty() {
+            cmd.arg("-o").arg(options.fuse_options.join(","));
+        }
+        cmd.args([&source, mount_point]);
+        let result = match self.runner.execute(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            Ok(_) => Err("borg mount failed".to_string()),
+            Err(e) => Err(format!("Failed to run borg mount: {}", e)),
+        };
+        self.audit(
+            AuditEntry::new(&self.hostname, "mount", archive.as_deref(), &result)
+                .with_target(mount_point),
+        );
+        if result.is_err() || options.foreground {
+            self.remove_mount_point(mount_point);
+        }
+        result
+    }
+
+    /// Unmount what `mount` mounted at `mount_point`.
+    pub fn umount_repository(&mut self, mount_point: &str) -> Result<(), String> {
+        let mut cmd = self.borg();
+        cmd.args(["umount", mount_point]);
+        let result = match self.runner.execute(cmd) {
+            Ok(status) if status.success() => Ok(()),
+            _ => fuse_unmount(mount_point),
+        };
+        self.audit(
+            AuditEntry::new(&self.hostname, "umount", None, &result).with_target(mount_point),
+        );
+        result?;
+
+        self.remove_mount_point(mount_point);
+        Ok(())
+    }
+
+    fn remove_mount_point(&mut self, mount_point: &str) {
+        match self.state().forget_mount_point(mount_point) {
+            Ok(true) => {
+                if let Err(e) = fs::remove_dir(mount_point) {
+                    self.warn(&format!("Failed to remove {}: {}", mount_point, e));
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.warn(&e),
+        }
+    }
+
+    pub fn generate_example_config(output_path: &str, format: ConfigFormat) -> Result<(), String> {
+        fs::write(output_path, Config::example(format)?)
+            .map_err(|e| format!("Failed to write example config: {}", e))
+    }
+}
+
+fn desktop_summary(
+    outcome: &CycleOutcome,
+    report: &CycleReport,
+) -> (&'static str, String, desktop::Urgency) {
+    let elapsed = summary::duration(report);
+    match outcome {
+        CycleOutcome::Success => (
+            "Backup complete",
+            format!("Finished in {}", elapsed),
+            desktop::Urgency::Low,
+        ),
+        CycleOutcome::SuccessWithWarnings => {
+            let mut body = format!(
+                "Finished in {} with {} warning(s)",
+                elapsed,
+                report.warnings.len()
+            );
+            if let Some(first) = report.warnings.first() {
+                body.push_str(": ");
+                body.push_str(first);
+            }
+            (
+                "Backup finished with warnings",
+                body,
+                desktop::Urgency::Normal,
+            )
+        }
+        CycleOutcome::Failed(error) => ("Backup failed", error.clone(), desktop::Urgency::Critical),
+    }
+}
+
+fn fuse_unmount(mount_point: &str) -> Result<(), String> {
+    let tools: &[&[&str]] = if cfg!(target_os = "linux") {
+        &[&["fusermount", "-u"], &["fusermount3", "-u"], &["umount"]]
+    } else {
+        &[&["umount"]]
+    };
+    unmount_with(tools, mount_point)
+}
+
+fn unmount_with(tools: &[&[&str]], mount_point: &str) -> Result<(), String> {
+    let mut failures = Vec::new();
+    for tool in tools {
+        match Command::new(tool[0])
+            .args(&tool[1..])
+            .arg(mount_point)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => failures.push(format!("{} {}", tool[0], status)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                failures.push(format!("{} is not installed", tool[0]))
+            }
+            Err(e) => failures.push(format!("{}: {}", tool[0], e)),
+        }
+    }
+    Err(format!(
+        "Failed to unmount {}: {}",
+        mount_point,
+        failures.join(", ")
+    ))
+}
+
+fn get_hostname(configured: Option<&str>) -> Result<String, String> {
+    let hostname = match configured.map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => {
+            let full = gethostname::gethostname().to_string_lossy().into_owned();
+            full.split('.')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        }
+    };
+    if hostname.is_empty() {
+        return Err("Failed to get hostname; set hostname in the config".to_string());
+    }
+    Ok(hostname)
+}
+
+fn timestamped(message: &str) -> String {
+    format!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message)
+}
+
+/// Match an archive name against a glob with `*`, `?` and `[...]`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name index it is tried against
+    let mut backtrack = None;
+
+    while n < name.len() {
+        // A `[` without a closing `]` is an ordinary character
+        let class = match pattern.get(p) {
+            Some('[') => char_class(&pattern[p..], name[n]),
+            _ => None,
+        };
+        match (pattern.get(p), class) {
+            (Some('*'), _) => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            (_, Some((true, len))) => {
+                p += len;
+                n += 1;
+            }
+            (Some(&c), None) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn char_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut hit = false;
+    let start = i;
+    loop {
+        match pattern.get(i)? {
+            ']' if i > start => return Some((hit != negated, i + 1)),
+            &low if pattern.get(i + 1) == Some(&'-')
+                && pattern.get(i + 2).is_some_and(|&h| h != ']') =>
+            {
+                hit |= (low..=pattern[i + 2]).contains(&c);
+                i += 3;
+            }
+            &single => {
+                hit |= single == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+pub(crate) fn on_path(program: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| {
+            fs::metadata(dir.join(program))
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+    })
+}
+
+/// The patterns in an `--exclude-from` file.
+pub fn read_pattern_file(path: &str) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read exclusion file {}: {}", path, e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Parse an age in borg's `--keep-within` style, e.g. `7d`.
+pub fn parse_age(age: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("Invalid age {} (expected e.g. 12H, 90d, 4w, 6m, 1y)", age);
+    let unit = age.chars().last().ok_or_else(invalid)?;
+    let count: i64 = age[..age.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    if count <= 0 {
+        return Err(format!("Invalid age {}: must be more than 0", age));
+    }
+    let hours = match unit {
+        'H' => 1,
+        'd' => 24,
+        'w' => 24 * 7,
+        'm' => 24 * 31,
+        'y' => 24 * 365,
+        _ => return Err(invalid()),
+    };
+    count
+        .checked_mul(hours)
+        .and_then(chrono::Duration::try_hours)
+        .ok_or_else(|| format!("Invalid age {}: too long", age))
+}
+
+/// Parse a timeout such as `90s`, `30min`, `1h` or an age.
+pub fn parse_timeout(timeout: &str) -> Result<std::time::Duration, String> {
+    let invalid = || {
+        format!(
+            "Invalid timeout {} (expected e.g. 90s, 30min, 1h, 1d)",
+            timeout
+        )
+    };
+    let seconds = |count: &str, unit: u64| {
+        count
+            .parse::<u64>()
+            .ok()
+            .and_then(|count| count.checked_mul(unit))
+            .map(std::time::Duration::from_secs)
+            .ok_or_else(invalid)
+    };
+    if let Some(count) = timeout.strip_suffix("min") {
+        seconds(count, 60)
+    } else if let Some(count) = timeout.strip_suffix('s') {
+        seconds(count, 1)
+    } else if let Some(count) = timeout.strip_suffix('h') {
+        seconds(count, 3600)
+    } else {
+        parse_age(timeout)
+            .ok()
+            .and_then(|age| age.to_std().ok())
+            .ok_or_else(invalid)
+    }
+}
+
+/// Parse a point in time: an age, a local date (and time) or RFC 3339.
+pub fn parse_point_in_time(when: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+
+    if let Ok(age) = parse_age(when) {
+        return Ok(now - age);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(when) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let naive = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(when, format).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(when, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(23, 59, 59))
+    })
+    .ok_or_else(|| {
+        format!(
+            "Invalid time {} (expected e.g. 2024-03-01, '2024-03-01 14:30' or 3d)",
+            when
+        )
+    })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("{} does not exist in local time", when))
+}
+
+fn free_space(path: &str) -> Result<u64, String> {
+    let c_path = std::ffi::CString::new(path).map_err(|_| format!("Invalid path {}", path))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "Failed to read the free space of {}: {}",
+            path,
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn restrict_permissions(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict permissions of {}: {}", path, e))
+}
+
+fn write_secret(path: &str, secret: &str) -> Result<(), String> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let tmp = format!("{}.tmp", path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+    io::Write::write_all(&mut file, format!("{}\n", secret).as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to replace {}: {}", path, e))
+}
+
+/// Whether `label` is one of the words of an archive comment.
+pub fn has_tag(comment: &str, label: &str) -> bool {
+    comment.split_whitespace().any(|word| word == label)
+}
+
+fn retag(comment: &str, label: &str, remove: bool) -> String {
+    if has_tag(comment, label) != remove {
+        comment.to_string()
+    } else if remove {
+        let words: Vec<&str> = comment
+            .split_whitespace()
+            .filter(|word| *word != label)
+            .collect();
+        words.join(" ")
+    } else if comment.trim().is_empty() {
+        label.to_string()
+    } else {
+        format!("{} {}", comment.trim_end(), label)
+    }
+}
+
+/// The `borg export-tar --tar-filter` for a tarball name.
+pub fn tar_filter(output: &str) -> Result<Option<&'static str>, String> {
+    const FILTERS: [(&str, Option<&str>); 11] = [
+        (".tar", None),
+        (".tar.gz", Some("gzip")),
+        (".tgz", Some("gzip")),
+        (".tar.bz2", Some("bzip2")),
+        (".tbz2", Some("bzip2")),
+        (".tar.xz", Some("xz")),
+        (".txz", Some("xz")),
+        (".tar.zst", Some("zstd")),
+        (".tar.zstd", Some("zstd")),
+        (".tar.lz4", Some("lz4")),
+        (".tar.lzma", Some("lzma")),
+    ];
+    FILTERS
+        .iter()
+        .find(|(extension, _)| output.ends_with(extension))
+        .map(|(_, filter)| *filter)
+        .ok_or_else(|| {
+            format!(
+                "Unknown tarball extension for {} (use .tar, .tar.gz, .tar.bz2, .tar.xz, .tar.zst or .tar.lz4)",
+                output
+            )
+        })
+}
+
+fn verify_extracted(target: &Path, files: &[FileEntry]) -> Result<(), String> {
+    for file in files {
+        let extracted = target.join(&file.path);
+        let metadata = extracted
+            .symlink_metadata()
+            .map_err(|_| format!("{} was not extracted", extracted.display()))?;
+        if metadata.is_file() && metadata.len() != file.size {
+            return Err(format!(
+                "{} is {} bytes but {} in the archive",
+                extracted.display(),
+                metadata.len(),
+                file.size
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rotation disks whose last backup is older than `max_age`, or missing.
+pub fn stale_disks(
+    disks: &[RotationDisk],
+    records: &[DiskRecord],
+    now: DateTime<Utc>,
+    max_age: chrono::Duration,
+) -> Vec<String> {
+    disks
+        .iter()
+        .filter(|disk| {
+            records
+                .iter()
+                .find(|r| r.name == disk.name)
+                .is_none_or(|r| now - r.last_backup > max_age)
+        })
+        .map(|disk| disk.name.clone())
+        .collect()
+}
+
+/// Each host with archives outside the trash, and whether it is stale.
+pub fn host_statuses(
+    archives: &[ArchiveEntry],
+    now: DateTime<Utc>,
+    max_age: chrono::Duration,
+) -> Vec<HostStatus> {
+    let mut hosts: Vec<HostStatus> = Vec::new();
+    for archive in archives
+        .iter()
+        .filter(|a| !a.name.starts_with(TRASH_PREFIX))
+    {
+        let hostname = archive.hostname.split('.').next().unwrap_or_default();
+        match hosts.iter_mut().find(|h| h.hostname == hostname) {
+            Some(host) => {
+                host.archives += 1;
+                host.last_backup = host.last_backup.max(archive.time);
+            }
+            None => hosts.push(HostStatus {
+                hostname: hostname.to_string(),
+                archives: 1,
+                last_backup: archive.time,
+                stale: false,
+            }),
+        }
+    }
+    for host in &mut hosts {
+        host.stale = now - host.last_backup > max_age;
+    }
+    hosts.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    hosts
+}
+
+/// Name of `archive` once moved to the trash at `now`.
+pub fn trash_name(archive: &str, now: DateTime<Utc>) -> String {
+    format!(
+        "{}{}-{}",
+        TRASH_PREFIX,
+        now.format(TRASH_TIMESTAMP),
+        archive
+    )
+}
+
+/// Split a trashed archive name into when it was trashed and its original name.
+pub fn parse_trash_name(name: &str) -> Option<(DateTime<Utc>, &str)> {
+    let rest = name.strip_prefix(TRASH_PREFIX)?;
+    let (timestamp, original) = rest.split_at_checked(18)?;
+    let original = original.strip_prefix('-')?;
+    let trashed_at = chrono::NaiveDateTime::parse_from_str(timestamp, TRASH_TIMESTAMP).ok()?;
+    Some((trashed_at.and_utc(), original))
+}
+
+/// Human-readable size using binary units, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// zstd level for `compression: auto`.
+pub fn auto_compression(cpus: usize, remote: bool) -> String {
+    let level = match cpus {
+        0..=2 => 1,
+        3..=7 => 3,
+        _ => 6,
+    };
+    let level = if remote { level + 3 } else { level };
+    format!("zstd,{}", level)
+}
+
+/// Append a `.N` suffix to `base` until it doesn't collide with `existing`.
+pub fn unique_archive_name(base: &str, existing: &[String]) -> String {
+    let taken = |name: &str| existing.iter().any(|e| e == name);
+    if !taken(base) {
+        return base.to_string();
+    }
+
+    let mut seq = 1;
+    loop {
+        let candidate = format!("{}.{}", base, seq);
+        if !taken(&candidate) {
+            return candidate;
+        }
+        seq += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_load_default_config() {
+        let config = Config::load_or_default(None);
+        assert!(config.is_ok());
+
+        let config = config.unwrap();
+        assert_eq!(config.repository.path, "/tmp/borg");
+        assert_eq!(config.compression, "lz4");
+        assert!(!config.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_config_jobs() {
+        let config = Config::load_or_default(None).unwrap();
+        assert!(config.jobs.iter().any(|j| j.name == "system-config"));
+        assert!(config.jobs.iter().any(|j| j.name == "user-homes"));
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let config = Config::load_or_default(None).unwrap();
+        assert_eq!(config.retention.within, "24H");
+        assert_eq!(config.retention.hourly, 24);
+        assert_eq!(config.retention.daily, 7);
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let overrides = [
+            overrides::Override::from_env("BTM_REPOSITORY__PATH", "/srv/borg").unwrap(),
+            overrides::Override::parse("retention.daily=3").unwrap(),
+            overrides::Override::parse("repository.path=/srv/other").unwrap(),
+        ];
+        let config = Config::load_with_overrides(None, &overrides).unwrap();
+        assert_eq!(config.repository.path, "/srv/other");
+        assert_eq!(config.retention.daily, 3);
+
+        let bad = [overrides::Override::parse("retention.daily=many").unwrap()];
+        assert!(Config::load_with_overrides(None, &bad).is_err());
+    }
+
+    #[test]
+    fn test_toml_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg-config.toml");
+        fs::write(&path, Config::example(ConfigFormat::Toml).unwrap()).unwrap();
+        let path = path.display().to_string();
+
+        let config = Config::load(&path).unwrap();
+        let default = Config::load_or_default(None).unwrap();
+        assert_eq!(config.repository.path, default.repository.path);
+        assert_eq!(config.jobs.len(), default.jobs.len());
+        assert_eq!(config.exclusions, default.exclusions);
+        assert!(Config::diff_against_default(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retention_lint() {
+        let mut config = Config::load_or_default(None).unwrap();
+        assert!(config.lint().is_ok());
+
+        config.retention.within = "24 hours".to_string();
+        assert!(config.lint().unwrap_err().contains("retention.within"));
+
+        // borg refuses --keep-within=0H, and it would keep nothing
+        config.retention = serde_yaml::from_str(
+            "within: 0H\nhourly: 0\ndaily: 0\nweekly: 0\nmonthly: 0\nyearly: 0",
+        )
+        .unwrap();
+        assert!(config.lint().unwrap_err().contains("more than 0"));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let passphrase = dir.path().join("passphrase");
+        fs::write(&passphrase, "secret").unwrap();
+        fs::set_permissions(&passphrase, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut config = Config::load_or_default(None).unwrap();
+        config.jobs.truncate(1);
+        config.jobs[0].source = dir.path().display().to_string();
+        config.security.passphrase_file = passphrase.display().to_string();
+        config.repository.path = dir.path().join("repo").display().to_string();
+        let results = validate::validate(&config);
+        assert!(
+            results.iter().all(|r| r.status == CheckStatus::Pass),
+            "{:?}",
+            results
+        );
+
+        config.policy.on_missing_source = policy::OnMissingSource::Fail;
+        config.jobs[0].source = dir.path().join("missing").display().to_string();
+        config.compression = "gzip".to_string();
+        config.maintenance.check_day = 9;
+        config.exclusions.push("re:(unclosed".to_string());
+        config.version = 0;
+        fs::set_permissions(&passphrase, fs::Permissions::from_mode(0o644)).unwrap();
+        let failed: Vec<String> = validate::validate(&config)
+            .into_iter()
+            .filter(|r| r.status != CheckStatus::Pass)
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                "version",
+                "job system-config",
+                "passphrase",
+                "compression",
+                "check_day",
+                "exclusions"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg-config.yaml");
+        let current = Config::load_or_default(None).unwrap();
+        assert_eq!(current.version, migrate::CURRENT);
+
+        // A file from before versioning loads with its old option names
+        let old = DEFAULT_CONFIG
+            .replace("version: 1\n", "")
+            .replace("  # key_backup: ", "  key_backup_path: ");
+        fs::write(&path, &old).unwrap();
+        let config = Config::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.version, 0);
+        assert_eq!(
+            config.security.key_backup.as_deref(),
+            Some("/root/borg-key-backup.txt")
+        );
+
+        fs::write(&path, old.replace("borg:\n", "version: 7\nborg:\n")).unwrap();
+        let err = Config::load(path.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("newer than this borg-timemachine"), "{}", err);
+    }
+
+    #[test]
+    fn test_retention_simulate() {
+        let now = DateTime::parse_from_rfc3339("2026-06-15T12:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let retention: Retention = serde_yaml::from_str(
+            "within: 24H\nhourly: 0\ndaily: 7\nweekly: 0\nmonthly: 0\nyearly: 0",
+        )
+        .unwrap();
+
+        let hour = chrono::Duration::hours(1);
+        let kept = retention::simulate(&retention, chrono::Duration::days(30), hour, now).unwrap();
+        assert_eq!(
+            kept.iter().filter(|(_, rule)| *rule == "within").count(),
+            24
+        );
+        assert_eq!(kept.iter().filter(|(_, rule)| *rule == "daily").count(), 7);
+        assert_eq!(kept.len(), 31);
+        assert!(kept.windows(2).all(|pair| pair[0].0 > pair[1].0));
+
+        // Two days of history can't fill seven daily slots: borg keeps the
+        // oldest archive instead.
+        let kept = retention::simulate(&retention, chrono::Duration::days(2), hour, now).unwrap();
+        assert_eq!(kept.len(), 24 + 2);
+        assert_eq!(kept.last().unwrap().0, now - chrono::Duration::hours(47));
+
+        assert!(retention::simulate(&retention, hour, chrono::Duration::zero(), now).is_err());
+    }
+
+    #[test]
+    fn test_local_repository_url() {
+        let config = Config::load_or_default(None).unwrap();
+        assert_eq!(config.repository.url(), "/tmp/borg");
+        assert!(config.repository.ssh_command().is_none());
+    }
+
+    #[test]
+    fn test_remote_repository_url() {
+        let repo: Repository = serde_yaml::from_str(
+            "path: /srv/borg\nencryption: repokey\nremote:\n  user: backup\n  host: nas\n",
+        )
+        .unwrap();
+        assert_eq!(repo.url(), "backup@nas:/srv/borg");
+
+        let repo: Repository = serde_yaml::from_str(
+            "path: borg\nencryption: repokey\nremote:\n  user: backup\n  host: nas\n  port: 2222\n  ssh_key: /root/.ssh/backup_key\n  ssh_options:\n    - StrictHostKeyChecking=yes\n",
+        )
+        .unwrap();
+        assert_eq!(repo.url(), "ssh://backup@nas:2222/./borg");
+        assert_eq!(
+            repo.ssh_command().unwrap(),
+            "'ssh' '-i' '/root/.ssh/backup_key' '-o' 'StrictHostKeyChecking=yes'"
+        );
+
+        // borg splits BORG_RSH like a shell would
+        let repo: Repository = serde_yaml::from_str(
+            "path: borg\nencryption: repokey\nremote:\n  user: backup\n  host: nas\n  ssh_key: \"/root/my keys/it's\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            repo.ssh_command().unwrap(),
+            "'ssh' '-i' '/root/my keys/it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn test_hooks_default() {
+        let config = Config::load_or_default(None).unwrap();
+        assert!(config.hooks.pre_cycle.is_none());
+        assert!(config.hooks.fail_on_error);
+        assert!(config.jobs.iter().all(|j| j.pre_command.is_none()));
+    }
+
+    #[test]
+    fn test_archive_timestamp_formats() {
+        let now = DateTime::parse_from_rfc3339("2024-03-31T01:15:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(ArchiveTimestamp::Utc.format(now), "2024-03-31-011500Z");
+
+        let local = ArchiveTimestamp::Local.format(now);
+        let offset = now.with_timezone(&Local).format("%z").to_string();
+        assert!(local.ends_with(&offset));
+    }
+
+    #[test]
+    fn test_archive_timestamp_default_local() {
+        let config = Config::load_or_default(None).unwrap();
+        assert_eq!(config.archive_timestamp, ArchiveTimestamp::Local);
+    }
+
+    #[test]
+    fn test_forward_lines_splits_progress() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let input = b"12 files\r34 files\rdone\nlast".to_vec();
+        runner::forward_lines(Box::new(io::Cursor::new(input)), tx);
+        let lines: Vec<_> = rx.iter().collect();
+        assert_eq!(
+            lines,
+            vec![
+                (true, "12 files".to_string()),
+                (true, "34 files".to_string()),
+                (false, "done".to_string()),
+                (false, "last".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_logging_level_config() {
+        let config = Config::load_or_default(None).unwrap();
+        assert_eq!(
+            logging::parse_level(&config.logging.level).unwrap(),
+            tracing_subscriber::filter::LevelFilter::INFO
+        );
+        assert_eq!(config.logging.format, logging::LogFormat::Text);
+        assert!(logging::parse_level("loud").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(logging::parse_size("512").unwrap(), 512);
+        assert_eq!(logging::parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(logging::parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(logging::parse_size("2T").unwrap(), 2 << 40);
+        assert!(logging::parse_size("ten").is_err());
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("borg.log");
+        let log = log.to_str().unwrap();
+
+        fs::write(log, "small").unwrap();
+        assert!(!logging::rotate(log, 100, 2, false).unwrap());
+
+        for round in 1..=3 {
+            fs::write(log, format!("round {}", round)).unwrap();
+            assert!(logging::rotate(log, 1, 2, false).unwrap());
+        }
+        assert!(!Path::new(log).exists());
+        assert_eq!(fs::read_to_string(format!("{}.1", log)).unwrap(), "round 3");
+        assert_eq!(fs::read_to_string(format!("{}.2", log)).unwrap(), "round 2");
+        assert!(!Path::new(&format!("{}.3", log)).exists());
+
+        fs::write(log, "round 4").unwrap();
+        assert!(logging::rotate(log, 1, 2, true).unwrap());
+        assert!(Path::new(&format!("{}.1.gz", log)).exists());
+        assert_eq!(fs::read_to_string(format!("{}.2", log)).unwrap(), "round 3");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_state_store_records_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/state.json");
+        let store = StateStore::new(path.to_str().unwrap());
+        assert!(store.history().unwrap().is_empty());
+
+        let mut report = CycleReport::new(Utc::now());
+        report.success = true;
+        report.archive = Some("host-1".to_string());
+        report.warnings.push("skipped a file".to_string());
+        store
+            .record(HistoryEntry::from_report(&report, vec!["home".to_string()]))
+            .unwrap();
+
+        let history = store.history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].archive.as_deref(), Some("host-1"));
+        assert_eq!(history[0].jobs, vec!["home".to_string()]);
+        assert_eq!(history[0].warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_state_store_marks_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let store = StateStore::new(path.to_str().unwrap());
+
+        store.mark_verified("host-1", "manual").unwrap();
+        store.mark_verified("host-2", "manual").unwrap();
+        store.mark_verified("host-1", "extract-dry-run").unwrap();
+
+        let verified = store.verified_archives().unwrap();
+        assert_eq!(verified.len(), 2);
+        let host1 = verified.iter().find(|v| v.archive == "host-1").unwrap();
+        assert_eq!(host1.method, "extract-dry-run");
+    }
+
+    #[test]
+    fn test_auto_compression() {
+        assert_eq!(auto_compression(1, false), "zstd,1");
+        assert_eq!(auto_compression(4, false), "zstd,3");
+        assert_eq!(auto_compression(16, false), "zstd,6");
+        assert_eq!(auto_compression(4, true), "zstd,6");
+    }
+
+    #[test]
+    fn test_unique_archive_name() {
+        let existing = vec![
+            "host-2024-01-01-120000Z".to_string(),
+            "host-2024-01-01-120000Z.1".to_string(),
+        ];
+        assert_eq!(
+            unique_archive_name("host-2024-01-01-130000Z", &existing),
+            "host-2024-01-01-130000Z"
+        );
+        assert_eq!(
+            unique_archive_name("host-2024-01-01-120000Z", &existing),
+            "host-2024-01-01-120000Z.2"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_borg_version_is_refused() {
         let config = Config::load_or_default(None).unwrap();
-        assert_eq!(config.retention.within, "24H");
-        assert_eq!(config.retention.hourly, 24);
-        assert_eq!(config.retention.daily, 7);
+        let runner = runner::mock::MockRunner::default().respond("--version", 0, "borg 2.0.0b8");
+        let err = BorgBackup::with_runner(config, Box::new(runner))
+            .err()
+            .unwrap();
+        assert!(err.contains("not supported"));
+    }
+
+    #[test]
+    fn test_old_borg_skips_compact() {
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("--version", 0, "borg 1.1.18"));
+        backup.compact_repository().unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deletions_are_audited() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default().respond(
+            "list",
+            0,
+            "host-2024-03-01-120000Z",
+        ));
+        backup.config.retention.trash_days = 0;
+        backup.config.maintenance.auto_compact = false;
+        backup
+            .delete_archives(&["host-2024-03-01-120000Z".to_string()])
+            .unwrap();
+
+        let log = fs::read_to_string(&backup.config.logging.audit_log).unwrap();
+        let entry: AuditEntry = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+        assert_eq!(entry.action, "delete");
+        assert_eq!(entry.archive.as_deref(), Some("host-2024-03-01-120000Z"));
+        assert!(entry.success);
+        assert_eq!(backup.state().audit_log().unwrap(), vec![entry]);
+    }
+
+    #[test]
+    fn test_wrong_device_is_refused() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.repository.expect_uuid =
+            Some("00000000-0000-0000-0000-000000000000".to_string());
+        let err = backup.init_repository(None).unwrap_err();
+        assert!(err.contains("not the expected disk"), "{}", err);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("host-*", "host-2024-03-01-120000Z"));
+        assert!(glob_match("host-2024-0?-*Z", "host-2024-03-01-120000Z"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match(
+            "host-2024-03-01-120000Z",
+            "host-2024-03-01-120000Z"
+        ));
+        assert!(!glob_match("host-*", "web-2024"));
+        assert!(!glob_match("host-?", "host-12"));
+        assert!(glob_match("host-[0-9]*", "host-2024-03-01"));
+        assert!(!glob_match("host-[0-9]*", "host-etc-2024-03-01"));
+        assert!(glob_match("host-[!a-z]", "host-1"));
+        assert!(glob_match("host-[x", "host-[x"));
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90d").unwrap(), chrono::Duration::days(90));
+        assert_eq!(parse_age("12H").unwrap(), chrono::Duration::hours(12));
+        assert_eq!(parse_age("2w").unwrap(), chrono::Duration::days(14));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("").is_err());
+        assert!(parse_age("0d").unwrap_err().contains("more than 0"));
+        assert!(parse_age("-3w").unwrap_err().contains("more than 0"));
+        assert!(parse_age("9223372036854775807y")
+            .unwrap_err()
+            .contains("too long"));
+        assert!(parse_age("3000000000000000H").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        use std::time::Duration;
+        assert_eq!(parse_timeout("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_timeout("30min").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_timeout("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_timeout("2H").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_timeout("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_timeout("soon").is_err());
+        assert!(parse_timeout("-1h").is_err());
+        assert!(parse_timeout("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_wait_for_running_cycle() {
+        use std::time::Duration;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock").display().to_string();
+        assert!(lock::wait_until_free(
+            &path,
+            Some(Duration::ZERO),
+            Duration::ZERO
+        ));
+
+        let (guard, _) = lock::LockGuard::acquire(&path).unwrap();
+        assert_eq!(
+            lock::running_owner(&path).map(|owner| owner.pid),
+            Some(std::process::id())
+        );
+        assert!(!lock::wait_until_free(
+            &path,
+            Some(Duration::from_millis(20)),
+            Duration::from_millis(5)
+        ));
+
+        drop(guard);
+        assert!(lock::wait_until_free(
+            &path,
+            Some(Duration::ZERO),
+            Duration::ZERO
+        ));
+    }
+
+    #[test]
+    fn test_select_archives() {
+        let listing = r#"{"archives": [
+            {"archive": "host-2000-01-01-000000Z", "time": "2000-01-01T00:00:00.000000"},
+            {"archive": "host-2999-01-01-000000Z", "time": "2999-01-01T00:00:00.000000"},
+            {"archive": "trash-2000-01-02-000000Z-host-1999-12-01-000000Z", "time": "1999-12-01T00:00:00.000000"},
+            {"archive": "other-2000-01-01-000000Z", "time": "2000-01-01T00:00:00.000000"}
+        ]}"#;
+        let (backup, _) =
+            mock_backup(runner::mock::MockRunner::default().respond("list --json", 0, listing));
+
+        assert_eq!(
+            backup.select_archives(Some("*"), Some("30d")).unwrap(),
+            vec!["host-2000-01-01-000000Z", "other-2000-01-01-000000Z"]
+        );
+        assert_eq!(
+            backup.select_archives(Some("host-*"), None).unwrap(),
+            vec!["host-2000-01-01-000000Z", "host-2999-01-01-000000Z"]
+        );
+        assert!(backup.select_archives(Some("missing"), None).is_err());
+    }
+
+    #[test]
+    fn test_stale_rotation_disks() {
+        let disks: Vec<RotationDisk> = serde_yaml::from_str(
+            "- {name: a, uuid: 1111, path: /mnt/a}\n- {name: b, uuid: 2222, path: /mnt/b}\n- {name: c, uuid: 3333, path: /mnt/c}\n",
+        )
+        .unwrap();
+        let now = Utc::now();
+        let records = vec![
+            DiskRecord {
+                name: "a".to_string(),
+                last_backup: now - chrono::Duration::days(2),
+            },
+            DiskRecord {
+                name: "b".to_string(),
+                last_backup: now - chrono::Duration::days(20),
+            },
+        ];
+        assert_eq!(
+            stale_disks(&disks, &records, now, chrono::Duration::days(14)),
+            vec!["b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_missing_rotation_disk_is_refused() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.repository.rotation = serde_yaml::from_str(
+            "- {name: offsite-a, uuid: 00000000-0000-0000-0000-000000000000, path: /mnt/a}\n",
+        )
+        .unwrap();
+        let err = backup.init_repository(None).unwrap_err();
+        assert_eq!(err, "None of the rotation disks (offsite-a) is attached");
+        assert!(calls.lock().unwrap().is_empty());
+
+        // One lock whichever disk is attached, for `wait` too
+        let mut attached = backup.config.clone();
+        attached.repository.path = "/mnt/a".to_string();
+        assert_eq!(attached.lock_file(), backup.config.lock_file());
+        assert!(backup.config.lint().is_ok());
+        backup.config.repository.rotation_max_age = "2 weeks".to_string();
+        assert!(backup
+            .config
+            .lint()
+            .unwrap_err()
+            .starts_with("repository.rotation_max_age"));
+    }
+
+    #[test]
+    fn test_find_files() {
+        let archives = r#"{"archives": [
+            {"archive": "host-a", "time": "2024-03-01T00:00:00.000000"},
+            {"archive": "other-a", "time": "2024-03-01T00:00:00.000000"}
+        ]}"#;
+        let files = r#"{"type": "d", "path": "etc", "size": 0, "mtime": "2024-02-01T00:00:00.000000"}
+{"type": "-", "path": "etc/hosts", "size": 120, "mtime": "2024-02-01T00:00:00.000000"}
+{"type": "-", "path": "etc/hostname", "size": 5, "mtime": "2024-02-01T00:00:00.000000"}
+"#;
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, archives)
+                .respond("list --json-lines", 0, files),
+        );
+        backup.hostname = "host".to_string();
+
+        let found = backup.find_files("/etc/host*").unwrap();
+        let paths: Vec<_> = found
+            .iter()
+            .map(|(archive, file)| (archive.as_str(), file.path.as_str(), file.size))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![("host-a", "etc/hosts", 120), ("host-a", "etc/hostname", 5)]
+        );
+
+        backup.find_files("/etc/hosts").unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls.last().unwrap(),
+            &vec!["list", "--json-lines", "/tmp/borg::host-a", "etc/hosts"]
+        );
+    }
+
+    #[test]
+    fn test_restore_file_as_of() {
+        let archives = r#"{"archives": [
+            {"archive": "host-1", "time": "2024-03-01T00:00:00.000000"},
+            {"archive": "host-2", "time": "2024-03-05T00:00:00.000000"},
+            {"archive": "other-3", "time": "2024-03-04T00:00:00.000000"}
+        ]}"#;
+        let files = r#"{"type": "d", "path": "etc", "size": 0, "mtime": "2024-02-01T00:00:00.000000"}
+{"type": "-", "path": "etc/hosts", "size": 120, "mtime": "2024-02-01T00:00:00.000000"}
+"#;
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, archives)
+                .respond("list --json-lines", 0, files),
+        );
+        backup.hostname = "host".to_string();
+        let dir = tempfile::tempdir().unwrap();
+
+        let as_of = parse_point_in_time("2024-03-04", Utc::now()).unwrap();
+        assert_eq!(backup.archive_as_of(as_of).unwrap().name, "host-1");
+        assert!(backup
+            .archive_as_of(parse_point_in_time("2024-02-01", Utc::now()).unwrap())
+            .is_err());
+
+        // The mock extracts nothing, so verification must catch it
+        let target = dir.path().join("restore");
+        let err = backup
+            .restore_file("/etc/hosts", as_of, Some(target.to_str().unwrap()))
+            .unwrap_err();
+        assert!(err.contains("was not extracted"));
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["extract", "/tmp/borg::host-1", "etc/hosts"]
+        );
+        let audit = backup.state().audit_log().unwrap();
+        assert_eq!(audit[0].action, "restore");
+        assert_eq!(audit[0].paths, vec!["etc/hosts"]);
+        assert!(!audit[0].success);
+
+        // Existing files are never overwritten
+        fs::create_dir_all(target.join("etc")).unwrap();
+        fs::write(target.join("etc/hosts"), "live").unwrap();
+        let err = backup
+            .restore_file("/etc/hosts", as_of, Some(target.to_str().unwrap()))
+            .unwrap_err();
+        assert!(err.contains("already exists"));
+
+        let err = backup.restore_file("/etc", as_of, None).unwrap_err();
+        assert!(err.contains("is a directory"));
+        assert!(backup.restore_file("/etc/shadow", as_of, None).is_err());
+    }
+
+    #[test]
+    fn test_latest_alias() {
+        let archives = r#"{"archives": [
+            {"archive": "host-2", "time": "2024-03-05T00:00:00.000000"}
+        ]}"#;
+        let info = r#"{"repository": {"location": "/tmp/borg"}, "archives": [
+            {"name": "host-2", "start": "2024-03-05T00:00:00.000000", "stats": {"original_size": 1, "compressed_size": 1, "deduplicated_size": 1, "nfiles": 1}}
+        ]}"#;
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, archives)
+                .respond("info --json", 0, info),
+        );
+        backup.hostname = "host".to_string();
+
+        assert_eq!(backup.resolve_archive("host-1").unwrap(), "host-1");
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(backup.resolve_archive(LATEST).unwrap(), "host-2");
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            vec![
+                "list",
+                "--json",
+                "--last",
+                "1",
+                "--glob-archives=host-*",
+                "/tmp/borg"
+            ]
+        );
+
+        backup.repository_info(Some("latest")).unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["info", "--json", "/tmp/borg::host-2"]
+        );
+    }
+
+    #[test]
+    fn test_latest_without_archives() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default().respond(
+            "list --json",
+            0,
+            r#"{"archives": []}"#,
+        ));
+        backup.hostname = "host".to_string();
+        assert_eq!(
+            backup.latest_archive().unwrap_err(),
+            "No archives of host yet"
+        );
+    }
+
+    #[test]
+    fn test_parse_point_in_time() {
+        let now = Utc::now();
+        assert_eq!(
+            parse_point_in_time("3d", now).unwrap(),
+            now - chrono::Duration::days(3)
+        );
+        assert_eq!(
+            parse_point_in_time("2024-03-01T12:00:00Z", now).unwrap(),
+            "2024-03-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        let day = parse_point_in_time("2024-03-01", now).unwrap();
+        let minute = parse_point_in_time("2024-03-01 14:30", now).unwrap();
+        assert!(minute < day);
+        assert!(parse_point_in_time("last tuesday", now).is_err());
+    }
+
+    #[test]
+    fn test_also_include_filesystems() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.jobs[0].source = "/".to_string();
+        backup.config.jobs[0].also_include_filesystems = vec![
+            "/proc".to_string(),
+            "/etc".to_string(),
+            "/nonexistent".to_string(),
+        ];
+        backup.create_backup().unwrap();
+
+        let create = calls.lock().unwrap()[1].clone();
+        let sources = &create[create.len() - 2..];
+        assert_eq!(sources, &["/", "/proc"]);
+        assert!(backup.report.warnings[0].contains("/nonexistent"));
+
+        // Without --one-file-system borg crosses into /proc by itself
+        calls.lock().unwrap().clear();
+        backup.config.options.one_file_system = false;
+        backup.create_backup().unwrap();
+        let create = calls.lock().unwrap()[1].clone();
+        assert_eq!(create.last().unwrap(), "/");
+    }
+
+    #[test]
+    fn test_skipped_files_make_a_warning_outcome() {
+        let (mut backup, _calls) = mock_backup(runner::mock::MockRunner::default().respond(
+            "create",
+            1,
+            "/etc/shadow: open: [Errno 13] Permission denied: 'shadow'\n\
+             /etc/mtab: file changed while we backed it up\n",
+        ));
+        backup.create_backup().unwrap();
+
+        assert_eq!(
+            backup.report.skipped,
+            [
+                "/etc/shadow: open: [Errno 13] Permission denied: 'shadow'",
+                "/etc/mtab: file changed while we backed it up",
+            ]
+        );
+        assert_eq!(
+            backup.report.warnings.last().unwrap(),
+            "Backup created with warnings, 2 file(s) skipped"
+        );
+        assert!(backup
+            .cycle_log
+            .join("\n")
+            .contains("Skipped /etc/mtab: file changed while we backed it up"));
+
+        backup.report.success = true;
+        assert_eq!(backup.report.outcome(), CycleOutcome::SuccessWithWarnings);
+        assert_eq!(backup.report.outcome().exit_code(), 1);
+        backup.report.warnings.clear();
+        assert_eq!(backup.report.outcome().exit_code(), 0);
+        backup.report.success = false;
+        backup.report.error = Some("borg create failed".to_string());
+        assert_eq!(
+            backup.report.outcome(),
+            CycleOutcome::Failed("borg create failed".to_string())
+        );
+        assert_eq!(backup.report.outcome().exit_code(), 2);
+    }
+
+    #[test]
+    fn test_tar_filter() {
+        assert_eq!(tar_filter("etc.tar").unwrap(), None);
+        assert_eq!(tar_filter("etc.tar.gz").unwrap(), Some("gzip"));
+        assert_eq!(tar_filter("/tmp/etc.tgz").unwrap(), Some("gzip"));
+        assert_eq!(tar_filter("etc.tar.zst").unwrap(), Some("zstd"));
+        assert!(tar_filter("etc.zip").is_err());
+    }
+
+    #[test]
+    fn test_export_tar_arguments() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("etc.tar.xz").display().to_string();
+
+        backup
+            .export_tar("host-1", &output, &["/etc/nginx/".to_string()])
+            .unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            vec![
+                "export-tar",
+                "--tar-filter=xz",
+                "/tmp/borg::host-1",
+                output.as_str(),
+                "etc/nginx"
+            ]
+        );
+        let audit = backup.state().audit_log().unwrap();
+        assert_eq!(audit[0].action, "export");
+        assert_eq!(audit[0].target.as_deref(), Some(output.as_str()));
+
+        fs::write(&output, "").unwrap();
+        assert!(backup.export_tar("host-1", &output, &[]).is_err());
+    }
+
+    #[test]
+    fn test_import_tar() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = dir.path().join("old-full.tar.gz").display().to_string();
+        fs::write(&tarball, "").unwrap();
+
+        let time = Local
+            .with_ymd_and_hms(2024, 3, 1, 2, 30, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        let archive = backup.import_tar(&tarball, Some(time)).unwrap();
+        assert!(archive.starts_with(&backup.hostname));
+        let calls = calls.lock().unwrap();
+        let import = calls.iter().find(|c| c[0] == "import-tar").unwrap();
+        assert!(import.contains(&"--tar-filter=gzip -d".to_string()));
+        assert!(import.contains(&"--timestamp=2024-03-01T02:30:00".to_string()));
+        assert_eq!(import[import.len() - 2], format!("/tmp/borg::{}", archive));
+        assert_eq!(import[import.len() - 1], tarball);
+        drop(calls);
+        let audit = backup.state().audit_log().unwrap();
+        assert_eq!(audit[0].action, "import");
+        assert_eq!(audit[0].target.as_deref(), Some(tarball.as_str()));
+
+        assert!(backup.import_tar("backup.zip", None).is_err());
+        backup.version = version::BorgVersion::new(1, 1, 18);
+        assert!(backup.import_tar(&tarball, None).is_err());
+    }
+
+    #[test]
+    fn test_missing_source_policy() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.jobs[0].source = "/nonexistent/source".to_string();
+        backup.create_backup().unwrap();
+        assert!(backup.report.warnings[0].contains("/nonexistent/source"));
+        assert!(calls.lock().unwrap().iter().all(|c| c[0] != "create"));
+
+        backup.config.policy.on_missing_source = OnMissingSource::Fail;
+        assert!(backup.create_backup().is_err());
+    }
+
+    #[test]
+    fn test_warning_policy_fails_stage() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.policy.on_warning = OnWarning::Fail;
+        let err = backup
+            .timed_stage("create", |b| {
+                b.warn("something was skipped");
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(err.contains("something was skipped"));
+        assert!(backup.timed_stage("prune", |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_trash_names_round_trip() {
+        let now = chrono::NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_hms_opt(14, 30, 0)
+            .unwrap()
+            .and_utc();
+        let trashed = trash_name("host-2024-03-01-120000Z", now);
+        assert_eq!(trashed, "trash-2024-03-05-143000Z-host-2024-03-01-120000Z");
+        assert_eq!(
+            parse_trash_name(&trashed),
+            Some((now, "host-2024-03-01-120000Z"))
+        );
+        assert_eq!(parse_trash_name("host-2024-03-01-120000Z"), None);
+        assert_eq!(parse_trash_name("trash-bin"), None);
+    }
+
+    #[test]
+    fn test_delete_moves_archive_to_trash() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default().respond(
+            "list",
+            0,
+            "host-2024-03-01-120000Z",
+        ));
+        backup
+            .delete_archives(&["host-2024-03-01-120000Z".to_string()])
+            .unwrap();
+
+        let calls = calls.lock().unwrap();
+        let rename = calls.last().unwrap();
+        assert_eq!(
+            rename[..2],
+            ["rename", "/tmp/borg::host-2024-03-01-120000Z"]
+        );
+        assert_eq!(
+            parse_trash_name(&rename[2]).map(|(_, original)| original),
+            Some("host-2024-03-01-120000Z")
+        );
+    }
+
+    #[test]
+    fn test_recreate_dry_runs_first() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default().respond(
+            "recreate --dry-run",
+            0,
+            "x /etc/cache.tmp\n- /etc/hosts\n",
+        ));
+        backup.config.compression = "zstd,3".to_string();
+        let archives = ["host-2024-03-01-120000Z".to_string()];
+
+        // A refused confirmation leaves the archives alone
+        let err = backup
+            .recreate_archives(&archives, || Err("Aborted".to_string()))
+            .unwrap_err();
+        assert_eq!(err, "Aborted");
+        let dry_run = calls.lock().unwrap().clone();
+        assert_eq!(dry_run.len(), 1);
+        assert_eq!(
+            dry_run[0][..5],
+            [
+                "recreate",
+                "--dry-run",
+                "--list",
+                "--compression=zstd,3",
+                "--recompress=if-different"
+            ]
+        );
+        assert!(dry_run[0].join(" ").contains("--exclude *.tmp"));
+        assert_eq!(
+            dry_run[0].last().unwrap(),
+            "/tmp/borg::host-2024-03-01-120000Z"
+        );
+
+        calls.lock().unwrap().clear();
+        backup.recreate_archives(&archives, || Ok(())).unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0][1], "--dry-run");
+        assert_eq!(
+            calls[1][..3],
+            ["recreate", "--progress", "--compression=zstd,3"]
+        );
+        assert!(backup
+            .cycle_log
+            .join("\n")
+            .contains("Recreating archive 1/1: host-2024-03-01-120000Z"));
+    }
+
+    #[test]
+    fn test_prune_purges_expired_trash() {
+        let listing = "trash-2000-01-01-000000Z-host-1999-12-01-000000Z\n\
+                       trash-2999-01-01-000000Z-host-2998-12-01-000000Z\n\
+                       host-2024-03-01-120000Z";
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("list", 0, listing));
+        backup.prune_backups().unwrap();
+
+        let calls = calls.lock().unwrap();
+        let deleted: Vec<_> = calls.iter().filter(|c| c[0] == "delete").collect();
+        assert_eq!(
+            deleted,
+            vec![&vec![
+                "delete".to_string(),
+                "/tmp/borg::trash-2000-01-01-000000Z-host-1999-12-01-000000Z".to_string()
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_notification_channels() {
+        let notifications: Notifications = serde_yaml::from_str(
+            "enabled: true\nemail: admin@example.com\nchannels:\n  - type: ntfy\n    topic: backups\n  - type: slack\n    url: https://hooks.slack.com/x\n",
+        )
+        .unwrap();
+        let names: Vec<_> = notifications
+            .all_channels()
+            .iter()
+            .map(|c| c.name())
+            .collect();
+        assert_eq!(names, vec!["email", "ntfy", "slack"]);
+        assert!(!notifications.on_success);
+    }
+
+    #[test]
+    fn test_smtp_config_defaults() {
+        let notifications: Notifications = serde_yaml::from_str(
+            "enabled: true\nemail: admin@example.com\nsmtp:\n  host: smtp.example.com\n  from: backups@example.com\n",
+        )
+        .unwrap();
+        let smtp = notifications.smtp.unwrap();
+        assert_eq!(smtp.tls, notify::SmtpTls::Starttls);
+        assert_eq!(smtp.port(), 587);
+    }
+
+    #[test]
+    fn test_desktop_notification_defaults() {
+        let config = Config::load_or_default(None).unwrap();
+        assert!(config.notifications.desktop.is_none());
+
+        let notifications: Notifications =
+            serde_yaml::from_str("enabled: true\ndesktop:\n  user: alice\n").unwrap();
+        assert!(notifications.all_channels().is_empty());
+        let desktop = notifications.desktop.unwrap();
+        assert_eq!(desktop.user.as_deref(), Some("alice"));
+        assert!(desktop.on_start && desktop.on_warning && desktop.on_failure);
+        assert!(!desktop.on_success);
+
+        // A pop-up that can't be shown is no reason to exit 1
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.notifications.enabled = true;
+        backup.config.notifications.desktop =
+            Some(serde_yaml::from_str("user: no-such-user-borg-timemachine").unwrap());
+        backup.notify_desktop_started();
+        assert!(backup.report.warnings.is_empty());
+        assert!(backup.log_tail(1).contains("Desktop notification failed"));
+    }
+
+    #[test]
+    fn test_healthcheck_config() {
+        let config = Config::load_or_default(None).unwrap();
+        assert!(config.healthcheck.is_none());
+
+        let healthcheck: Healthcheck =
+            serde_yaml::from_str("url: https://hc-ping.com/abc/").unwrap();
+        assert_eq!(healthcheck.log_lines, 50);
+    }
+
+    #[test]
+    fn test_webhook_template_escapes_values() {
+        let notification = Notification {
+            subject: "Backup Failure on host".to_string(),
+            message: "borg said \"no\"\nline two".to_string(),
+            hostname: "host".to_string(),
+            success: false,
+            html: None,
+        };
+        assert_eq!(
+            notification.render(r#"{"text": "{{message}}", "status": "{{status}}"}"#),
+            r#"{"text": "borg said \"no\"\nline two", "status": "failure"}"#
+        );
+    }
+
+    #[test]
+    fn test_archive_entries_from_list_json() {
+        let json = r#"{"archives": [{"archive": "host-1", "name": "host-1", "id": "ab", "start": "2024-03-01T12:00:00.000000", "time": "2024-03-01T12:00:00.000000"}], "encryption": {"mode": "repokey"}}"#;
+        let entries = ArchiveEntry::from_list_json(json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "host-1");
+        assert_eq!(
+            entries[0].time.with_timezone(&Local).naive_local(),
+            chrono::NaiveDate::from_ymd_opt(2024, 3, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        assert!(report::is_checkpoint("host-2024-03-01.checkpoint"));
+        assert!(report::is_checkpoint("host-2024-03-01.checkpoint.2"));
+        assert!(!report::is_checkpoint("host-2024-03-01"));
+        assert!(!report::is_checkpoint("host.checkpoint.x"));
+        assert!(!report::is_checkpoint(".checkpoint"));
+
+        let json = r#"{"archives": [{"archive": "host-1.checkpoint", "time": "2024-03-01T12:00:00.000000"}, {"archive": "host-1", "time": "2024-03-01T13:00:00.000000"}]}"#;
+        let entries = ArchiveEntry::from_list_json(json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "host-1");
+
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default().respond(
+            "list --short --consider-checkpoints",
+            0,
+            "host-1.checkpoint\nhost-1.checkpoint.1\n",
+        ));
+        let checkpoints = backup.find_checkpoints();
+        assert_eq!(checkpoints, ["host-1.checkpoint", "host-1.checkpoint.1"]);
+        assert!(calls.lock().unwrap()[0]
+            .iter()
+            .any(|arg| arg.starts_with("--glob-archives=") && arg.ends_with(".checkpoint*")));
+
+        // By default prune takes care of them
+        calls.lock().unwrap().clear();
+        backup.clean_up_checkpoints(&checkpoints);
+        assert!(calls.lock().unwrap().is_empty());
+
+        backup.config.policy.on_checkpoints = OnCheckpoints::Delete;
+        backup.clean_up_checkpoints(&checkpoints);
+        let deleted: Vec<String> = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c[0] == "delete")
+            .map(|c| c.last().unwrap().clone())
+            .collect();
+        assert_eq!(
+            deleted,
+            [
+                "/tmp/borg::host-1.checkpoint",
+                "/tmp/borg::host-1.checkpoint.1"
+            ]
+        );
+
+        // A failed look is no reason to skip the backup
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default().respond(
+            "list --short --consider-checkpoints",
+            2,
+            "",
+        ));
+        assert!(backup.find_checkpoints().is_empty());
+        assert_eq!(
+            backup.report.warnings,
+            ["Failed to look for checkpoints: borg list failed"]
+        );
+    }
+
+    #[test]
+    fn test_shared_repository() {
+        let mut config = Config::load_or_default(None).unwrap();
+        config.repository.shared = true;
+        config.lint().unwrap();
+        config.archive_name_template = "backup-{timestamp}".to_string();
+        assert!(config.lint().unwrap_err().contains("{hostname}"));
+
+        // web-2's archives match web's globs; their names tell them apart.
+        // borg recorded the container's ID rather than the configured web.
+        let list = r#"{"archives": [
+            {"archive": "web-2024-03-01-120000Z", "time": "2024-03-01T12:00:00.000000", "hostname": "3f9a2c1b"},
+            {"archive": "web-2-2024-03-01-120000Z", "time": "2024-03-01T12:00:00.000000", "hostname": "web-2"},
+            {"archive": "trash-2024-03-01-120000Z-web-2-2024-02-01-120000Z", "time": "2024-02-01T12:00:00.000000", "hostname": "web-2"}
+        ]}"#;
+        let stats = r#""stats": {"original_size": 1, "compressed_size": 1, "deduplicated_size": 1, "nfiles": 1}"#;
+        let info = format!(
+            r#"{{"archives": [{{"name": "web-2024-03-01-120000Z", "start": "2024-03-01T12:00:00.000000", "hostname": "3f9a2c1b", {stats}}}, {{"name": "web-2-2024-03-01-120000Z", "start": "2024-03-01T12:00:00.000000", "hostname": "web-2", {stats}}}]}}"#
+        );
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, list)
+                .respond("list --json --format={hostname}", 0, list)
+                .respond(
+                    "list --short",
+                    0,
+                    "web-2024-03-01-120000Z\nweb-2-2024-03-01-120000Z\ntrash-2024-03-01-120000Z-web-2-2024-02-01-120000Z\n",
+                )
+                .respond("info --json", 0, &info),
+        );
+        backup.hostname = "web".to_string();
+        backup.config.repository.shared = true;
+
+        let err = backup
+            .delete_archives(&["web-2-2024-03-01-120000Z".to_string()])
+            .unwrap_err();
+        assert!(err.contains("not one of this host's"), "{}", err);
+        let err = backup
+            .recreate_archives(&["web-2-2024-03-01-120000Z".to_string()], || Ok(()))
+            .unwrap_err();
+        assert!(err.contains("Refusing to recreate"), "{}", err);
+        let err = backup.prune_backups().unwrap_err();
+        assert!(err.contains("Refusing to prune"), "{}", err);
+        assert!(!calls.lock().unwrap().iter().any(|c| c[0] == "prune"));
+
+        // Emptying the trash leaves web-2's alone
+        calls.lock().unwrap().clear();
+        assert_eq!(backup.purge_trash(None).unwrap(), 0);
+        backup
+            .delete_archives(&["web-2024-03-01-120000Z".to_string()])
+            .unwrap();
+        assert!(calls.lock().unwrap().iter().any(|c| c[0] == "rename"));
+
+        let hosts = backup.host_status().unwrap();
+        let counts: Vec<(&str, usize)> = hosts
+            .iter()
+            .map(|h| (h.hostname.as_str(), h.archives))
+            .collect();
+        assert_eq!(counts, [("3f9a2c1b", 1), ("web-2", 1)]);
+        assert!(hosts.iter().all(|h| h.stale));
+
+        let names = |options: &ListOptions| -> Vec<String> {
+            backup
+                .archive_details(options)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.name)
+                .collect()
+        };
+        assert_eq!(names(&ListOptions::default()), ["web-2024-03-01-120000Z"]);
+        let all_hosts = ListOptions {
+            all_hosts: true,
+            ..ListOptions::default()
+        };
+        assert_eq!(
+            names(&all_hosts),
+            ["web-2024-03-01-120000Z", "web-2-2024-03-01-120000Z"]
+        );
+
+        // Status itself stays off the repository
+        calls.lock().unwrap().clear();
+        assert!(backup.status().unwrap().hosts.is_empty());
+        assert!(calls.lock().unwrap().is_empty());
+
+        // Not shared: anything goes
+        backup.config.repository.shared = false;
+        assert_eq!(backup.purge_trash(None).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_archive_stats_from_info_json() {
+        let json = r#"{"archives": [{"name": "host-1", "stats": {"compressed_size": 50, "deduplicated_size": 10, "nfiles": 3, "original_size": 100}}]}"#;
+        assert_eq!(
+            ArchiveStats::from_info_json(json).unwrap(),
+            ArchiveStats {
+                original_size: 100,
+                compressed_size: 50,
+                deduplicated_size: 10,
+                nfiles: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_metrics_render() {
+        let started = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut report = CycleReport::new(started);
+        report.success = true;
+        report.stats = Some(ArchiveStats {
+            original_size: 100,
+            compressed_size: 50,
+            deduplicated_size: 10,
+            nfiles: 3,
+        });
+        report.stages.push(StageReport {
+            name: "prune".to_string(),
+            started,
+            duration: std::time::Duration::from_millis(1500),
+            outcome: StageOutcome::Warning,
+        });
+
+        let text = metrics::render(&report, "host");
+        assert!(text
+            .contains("borg_timemachine_last_run_timestamp_seconds{host=\"host\"} 1704067200\n"));
+        assert!(text.contains("borg_timemachine_last_run_success{host=\"host\"} 1\n"));
+        assert!(text.contains("borg_timemachine_archive_files{host=\"host\"} 3\n"));
+        assert!(text.contains(
+            "borg_timemachine_stage_duration_seconds{host=\"host\",stage=\"prune\"} 1.500\n"
+        ));
+        assert!(
+            text.contains("borg_timemachine_stage_success{host=\"host\",stage=\"prune\"} 0.5\n")
+        );
+    }
+
+    #[test]
+    fn test_metrics_render_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path().join("state.json").to_str().unwrap());
+
+        let mut report = CycleReport::new(Utc::now());
+        report.success = true;
+        report.stages.push(StageReport {
+            name: "create".to_string(),
+            started: report.started,
+            duration: std::time::Duration::from_secs(2),
+            outcome: StageOutcome::Success,
+        });
+        store
+            .record(HistoryEntry::from_report(&report, Vec::new()))
+            .unwrap();
+        report.success = false;
+        store
+            .record(HistoryEntry::from_report(&report, Vec::new()))
+            .unwrap();
+
+        let text = metrics::render_state(&store, "host").unwrap();
+        assert!(text.contains("borg_timemachine_last_run_success{host=\"host\"} 0\n"));
+        assert!(text.contains("borg_timemachine_recorded_cycles{host=\"host\"} 2\n"));
+        assert!(text.contains("borg_timemachine_recorded_failures{host=\"host\"} 1\n"));
+        assert!(text.contains("stage=\"create\"} 2.000\n"));
+
+        let stage = &store.history().unwrap()[0].stages[0];
+        assert_eq!(stage.outcome, Some(StageOutcome::Success));
+        assert_eq!(
+            stage.finished.unwrap() - stage.started.unwrap(),
+            chrono::Duration::seconds(2)
+        );
+    }
+
+    #[test]
+    fn test_legacy_stage_records() {
+        let entry: HistoryEntry = serde_json::from_str(
+            r#"{"started": "2024-01-01T00:00:00Z", "duration_secs": 5.0, "success": true,
+                "stages": [["create", 4.5]]}"#,
+        )
+        .unwrap();
+        assert_eq!(entry.stages[0].name, "create");
+        assert_eq!(entry.stages[0].duration_secs, 4.5);
+        assert_eq!(entry.stages[0].outcome, None);
+        assert_eq!(entry.to_report().stages[0].started, entry.started);
+    }
+
+    #[test]
+    fn test_metrics_write_textfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg.prom");
+        let path = path.to_str().unwrap();
+        metrics::write_textfile(path, "x 1\n").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "x 1\n");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn test_repository_overlap() {
+        use preflight::{repository_overlap, Overlap};
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        let other = dir.path().join("other");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&other).unwrap();
+
+        // Repository does not exist yet; its parent is inside the source
+        assert_eq!(
+            repository_overlap(&source.join("borg/repo"), &source),
+            Some(Overlap::Inside)
+        );
+        assert_eq!(
+            repository_overlap(&other.join("repo"), &source),
+            Some(Overlap::SameDevice)
+        );
+    }
+
+    #[test]
+    fn test_job_paths_from() {
+        let job: BackupJob = serde_yaml::from_str(
+            "name: media\nsource: /srv/media\ndestination: media\npaths_from:\n  command: media-manager export\npaths_delimiter: nul\n",
+        )
+        .unwrap();
+        assert!(
+            matches!(job.paths_from, Some(PathsFrom::Command { ref command }) if command == "media-manager export")
+        );
+        assert_eq!(job.paths_delimiter, PathsDelimiter::Nul);
+
+        let config = Config::load_or_default(None).unwrap();
+        assert!(config.jobs.iter().all(|j| j.paths_from.is_none()));
+    }
+
+    #[test]
+    fn test_paths_from_nul_delimiter() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("paths");
+        fs::write(&list, "/srv/media/a\0/srv/media/b\0").unwrap();
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.jobs[0].paths_from = Some(PathsFrom::File {
+            file: list.display().to_string(),
+        });
+        backup.config.jobs[0].paths_delimiter = PathsDelimiter::Nul;
+        backup.create_paths_from_archives(Utc::now()).unwrap();
+
+        let create = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| c[0] == "create")
+            .cloned()
+            .unwrap();
+        let delimiter = create
+            .iter()
+            .position(|a| a == "--paths-delimiter")
+            .unwrap();
+        assert_eq!(create[delimiter + 1], "\\0");
+
+        // The arguments must survive being handed to a real process
+        let mut cmd = BorgCommand::new();
+        cmd.args(&create);
+        assert!(runner::SystemRunner::new("true")
+            .execute(cmd)
+            .unwrap()
+            .success());
+    }
+
+    #[test]
+    fn test_verify_new_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("paths");
+        fs::write(&list, "/srv/media/a\n").unwrap();
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("check", 1, ""));
+        let mut media = backup.config.jobs[0].clone();
+        media.name = "media".to_string();
+        media.paths_from = Some(PathsFrom::File {
+            file: list.display().to_string(),
+        });
+        backup.config.jobs.push(media);
+        backup.config.maintenance.verify_new_archive = true;
+        backup.create_backup().unwrap();
+        assert_eq!(backup.report.created.len(), 2);
+
+        // Warnings from borg check don't fail the cycle
+        calls.lock().unwrap().clear();
+        backup.verify_new_archive().unwrap();
+        let checks: Vec<Vec<String>> = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c[0] == "check")
+            .cloned()
+            .collect();
+        assert_eq!(checks.len(), 2);
+        for (check, archive) in checks.iter().zip(&backup.report.created) {
+            assert_eq!(check[1..4], ["--archives-only", "--glob-archives", archive]);
+        }
+        assert!(backup
+            .report
+            .warnings
+            .iter()
+            .any(|w| w.contains("reported warnings")));
+
+        let (mut backup, _) =
+            mock_backup(runner::mock::MockRunner::default().respond("check", 2, ""));
+        backup.config.maintenance.verify_new_archive = true;
+        backup.report.created = vec!["host-1".to_string()];
+        let err = backup.verify_new_archive().unwrap_err();
+        assert!(err.contains("host-1"), "{}", err);
+    }
+
+    #[test]
+    fn test_lock_is_exclusive_and_reusable() {
+        let dir = tempfile::tempdir().unwrap();
+        // The lock directory is created when missing
+        let path = dir.path().join("run/lock/borg.lock");
+        let path = path.to_str().unwrap();
+
+        let (guard, previous) = LockGuard::acquire(path).unwrap();
+        assert!(previous.is_none());
+        let owner = lock::read_owner(path).unwrap();
+        assert_eq!(owner.pid, std::process::id());
+
+        let err = LockGuard::acquire(path).err().unwrap();
+        assert!(err.contains("Another backup is running"));
+
+        drop(guard);
+        assert!(LockGuard::acquire(path).is_ok());
+    }
+
+    #[test]
+    fn test_lock_per_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut first, _) = mock_backup(runner::mock::MockRunner::default());
+        first.config.logging.lock_file = dir.path().join("btm.lock").display().to_string();
+        let (mut second, _) = mock_backup(runner::mock::MockRunner::default());
+        second.config.logging = first.config.logging.clone();
+        second.config.repository.path = "/tmp/other".to_string();
+
+        // Different repositories lock independently
+        let _guard = first.acquire_lock().unwrap();
+        let second_guard = second.acquire_lock().unwrap();
+        assert!(first
+            .config
+            .lock_file()
+            .starts_with(&format!("{}/btm-", dir.path().display())));
+        assert!(first.config.lock_file().ends_with(".lock"));
+
+        // The same one, however it is spelled, does not
+        drop(second_guard);
+        second.config.repository.path = "/tmp/borg/".to_string();
+        assert_eq!(second.config.lock_file(), first.config.lock_file());
+        let err = second.acquire_lock().err().unwrap();
+        assert!(err.contains("Another backup is running"), "{}", err);
+
+        // Reading needs no lock
+        second.archive_names().unwrap();
+        second.status().unwrap();
+    }
+
+    #[test]
+    fn test_hostname() {
+        assert_eq!(get_hostname(Some(" nas ")).unwrap(), "nas");
+        let hostname = get_hostname(Some("")).unwrap();
+        assert!(!hostname.is_empty());
+        assert!(!hostname.contains('.'));
+        assert_eq!(get_hostname(None).unwrap(), hostname);
+    }
+
+    #[test]
+    fn test_stale_lock_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg.lock");
+        let path = path.to_str().unwrap();
+
+        // PIDs this large exceed the kernel's pid_max
+        fs::write(path, "999999999\n2024-01-01 00:00:00\n").unwrap();
+        let (guard, previous) = LockGuard::acquire(path).unwrap();
+        assert_eq!(previous.unwrap().pid, 999999999);
+
+        // A live PID that started after the lock was written was reused
+        drop(guard);
+        fs::write(path, "1\n2000-01-01 00:00:00\n").unwrap();
+        assert!(!lock::read_owner(path).unwrap().is_alive());
+        let (guard, previous) = LockGuard::acquire(path).unwrap();
+        assert_eq!(previous.unwrap().pid, 1);
+        assert!(lock::read_owner(path).unwrap().is_alive());
+
+        // Breaking waits for the lock to be free
+        let err = lock::break_lock(path).unwrap_err();
+        assert!(err.contains("held by a running backup"), "{}", err);
+        drop(guard);
+        lock::break_lock(path).unwrap();
+        assert!(!Path::new(path).exists());
+    }
+
+    fn mock_backup(runner: runner::mock::MockRunner) -> (BorgBackup, runner::mock::Calls) {
+        let mut config = Config::load_or_default(None).unwrap();
+        config.exclusions = vec!["*.tmp".to_string()];
+        config.jobs.truncate(1);
+        // Keep state, audit and lock files out of /var; the directory outlives the test
+        let dir = tempfile::tempdir().unwrap().keep();
+        config.logging.state_file = dir.join("state.json").display().to_string();
+        config.logging.audit_log = dir.join("audit.log").display().to_string();
+        config.logging.lock_file = dir.join("lock").display().to_string();
+        let calls = runner.calls.clone();
+        let backup = BorgBackup::with_runner(config, Box::new(runner)).unwrap();
+        // Leave out the version probe so tests see only their own calls
+        calls.lock().unwrap().clear();
+        (backup, calls)
+    }
+
+    #[test]
+    fn test_job_stats() {
+        let info = r#"{"archives": [{"stats": {"original_size": 1000, "compressed_size": 500, "deduplicated_size": 100, "nfiles": 3}}]}"#;
+        let (mut backup, _) =
+            mock_backup(runner::mock::MockRunner::default().respond("info --json", 0, info));
+        backup.create_backup().unwrap();
+
+        let job_stats = &backup.report.job_stats;
+        assert_eq!(job_stats.len(), 1);
+        assert_eq!(job_stats[0].jobs, vec!["system-config"]);
+        assert!((job_stats[0].stats.savings() - 0.9).abs() < 1e-9);
+        assert_eq!(backup.report.stats.as_ref(), Some(&job_stats[0].stats));
+        // This output has no repository totals
+        assert_eq!(backup.report.repository, None);
+    }
+
+    #[test]
+    fn test_growth_trends() {
+        let info = r#"{"archives": [{"stats": {"original_size": 1000, "compressed_size": 500, "deduplicated_size": 100, "nfiles": 3}}],
+            "cache": {"path": "/root/.cache/borg/x", "stats": {"total_chunks": 10, "total_csize": 500, "total_size": 1000, "total_unique_chunks": 5, "unique_csize": 4000, "unique_size": 8000}}}"#;
+        let (mut backup, _) =
+            mock_backup(runner::mock::MockRunner::default().respond("info --json", 0, info));
+        backup.create_backup().unwrap();
+        assert_eq!(
+            backup.report.repository.as_ref().unwrap().unique_csize,
+            4000
+        );
+
+        let now = Utc::now();
+        assert!(backup.growth_trends(5000, now).is_empty());
+        let state = backup.state();
+        for (days_ago, size) in [(40, 1000), (10, 3000), (3, 4000)] {
+            let mut report = CycleReport::new(now - chrono::Duration::days(days_ago));
+            report.repository = Some(RepositoryStats {
+                unique_csize: size,
+                ..RepositoryStats::default()
+            });
+            state
+                .record(state::HistoryEntry::from_report(&report, Vec::new()))
+                .unwrap();
+        }
+        assert_eq!(
+            backup.growth_trends(6144, now),
+            ["grew 3.1 KiB this week", "grew 5.0 KiB in 30 days"]
+        );
+        assert_eq!(
+            backup.growth_trends(2000, now)[0],
+            "shrank 1000 B this week"
+        );
+
+        // With only recent history the growth since then is shown
+        let dir = tempfile::tempdir().unwrap();
+        backup.config.logging.state_file = dir.path().join("state.json").display().to_string();
+        let mut report = CycleReport::new(now - chrono::Duration::days(2));
+        report.repository = Some(RepositoryStats::default());
+        backup
+            .state()
+            .record(state::HistoryEntry::from_report(&report, Vec::new()))
+            .unwrap();
+        let trends = backup.growth_trends(1024, now);
+        assert_eq!(trends.len(), 1);
+        assert!(trends[0].starts_with("grew 1.0 KiB since "));
+    }
+
+    #[test]
+    fn test_stage_status_sent_to_systemd() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("notify");
+        let systemd = std::os::unix::net::UnixDatagram::bind(&socket).unwrap();
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        backup.sd_notify = Some(SdNotify::new(socket.to_str().unwrap()).unwrap());
+
+        backup.timed_stage("compact", |_| Ok(())).unwrap();
+        let mut buf = [0u8; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=Backup cycle: compact");
+    }
+
+    #[test]
+    fn test_savings_by_job() {
+        let entry = |job: &str, original, deduplicated| report::JobStats {
+            jobs: vec![job.to_string()],
+            archive: format!("host-{}", job),
+            stats: ArchiveStats {
+                original_size: original,
+                compressed_size: original,
+                deduplicated_size: deduplicated,
+                nfiles: 1,
+            },
+        };
+        let stats = [
+            entry("media", 1000, 900),
+            entry("etc", 100, 10),
+            entry("etc", 100, 0),
+        ];
+        let totals = report::savings_by_job(&stats);
+        assert_eq!(totals[0].0, "etc");
+        assert_eq!(totals[0].1.original_size, 200);
+        assert!((totals[0].1.savings() - 0.95).abs() < 1e-9);
+        assert_eq!(totals[1].0, "media");
+        assert_eq!(ArchiveStats::default().savings(), 0.0);
+    }
+
+    #[test]
+    fn test_create_arguments() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.create_backup().unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0], vec!["list", "--short", "/tmp/borg"]);
+        let create = &calls[1];
+        let archive = create[create.len() - 2].clone();
+        assert!(archive.starts_with(&format!("/tmp/borg::{}-", backup.hostname)));
+        assert_eq!(
+            create,
+            &vec![
+                "create",
+                "--stats",
+                "--progress",
+                "--one-file-system",
+                "--exclude-caches",
+                "--compression=lz4",
+                "--exclude",
+                "*.tmp",
+                &archive,
+                "/etc",
+            ]
+        );
+        assert_eq!(calls[2][..2], ["info", "--json"]);
+    }
+
+    #[test]
+    fn test_create_exclusion_files_and_markers() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.exclusions_from = vec!["/etc/borg/exclude.txt".to_string()];
+        backup.config.patterns_from = vec!["/etc/borg/patterns.txt".to_string()];
+        backup.config.options.exclude_if_present = vec![".nobackup".to_string()];
+        backup.config.options.keep_exclude_tags = true;
+        backup.create_backup().unwrap();
+
+        let calls = calls.lock().unwrap();
+        let create = calls[1].join(" ");
+        assert!(
+            create.contains("--exclude-caches --exclude-if-present .nobackup --keep-exclude-tags"),
+            "{}",
+            create
+        );
+        assert!(
+            create.contains(
+                "--exclude *.tmp --exclude-from /etc/borg/exclude.txt \
+                 --patterns-from /etc/borg/patterns.txt"
+            ),
+            "{}",
+            create
+        );
+    }
+
+    #[test]
+    fn test_verify_sample_needs_restored_files() {
+        let files = r#"{"type": "d", "path": "etc", "size": 0, "mtime": "2024-02-01T00:00:00.000000"}
+{"type": "-", "path": "etc/hosts", "size": 120, "mtime": "2024-02-01T00:00:00.000000"}"#;
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("list --json-lines", 0, files));
+
+        // The mock extracts nothing
+        let error = backup.verify_sample("host-1", 5).unwrap_err();
+        assert!(error.contains("/etc/hosts was not restored"), "{}", error);
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["extract", "/tmp/borg::host-1", "etc/hosts"]
+        );
+        assert!(backup.state().verified_archives().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_jobs() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        let snapshot: snapshot::Snapshot =
+            serde_yaml::from_str("type: lvm\nvolume: vg0/root").unwrap();
+        backup.config.jobs[0].snapshot = Some(snapshot.clone());
+        assert!(backup.config.lint().is_ok());
+
+        // A dry run reads the live source instead
+        backup.set_dry_run(true);
+        backup.create_backup().unwrap();
+        let calls = calls.lock().unwrap();
+        let create = calls.iter().find(|args| args[0] == "create").unwrap();
+        assert!(create.contains(&"/etc".to_string()));
+
+        // Another job on the volume mounts its own snapshot elsewhere
+        let mut other = backup.config.jobs[0].clone();
+        other.name = "other".to_string();
+        other.source = "/etc/ssh".to_string();
+        backup.config.jobs.push(other.clone());
+        assert!(backup
+            .config
+            .lint()
+            .unwrap_err()
+            .contains("overlapping job"));
+        other.source = "/srv".to_string();
+        backup.config.jobs[1] = other.clone();
+        assert!(backup.config.lint().is_ok());
+        backup.config.jobs.pop();
+
+        other.snapshot = Some(snapshot::Snapshot {
+            mountpoint: "/mnt/snap".to_string(),
+            ..snapshot
+        });
+        backup.config.jobs.push(other.clone());
+        assert!(backup
+            .config
+            .lint()
+            .unwrap_err()
+            .contains("share one mountpoint"));
+
+        other.paths_from = Some(PathsFrom::File {
+            file: "/tmp/list".to_string(),
+        });
+        backup.config.jobs[1] = other;
+        assert!(backup.config.lint().unwrap_err().contains("paths_from"));
+    }
+
+    #[test]
+    fn test_create_options() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.options = serde_yaml::from_str(
+            "one_file_system: false\nexclude_caches: false\nshow_progress: false\n\
+             show_stats: false\ncheckpoint_interval: 600\nnoatime: false\n\
+             numeric_ids: true\nfiles_cache: mtime,size\nremote_ratelimit: 5000\n\
+             nice: 10\nionice: best-effort:7\n\
+             extra_args:\n  create: [--sparse]\n  prune: [--keep-last=3]\n",
+        )
+        .unwrap();
+        assert!(backup.config.options.lint().is_ok());
+        assert_eq!(
+            backup.create_command(false).get_wrapper(),
+            ["nice", "-n", "10", "ionice", "-c", "2", "-n", "7"]
+        );
+
+        backup.create_backup().unwrap();
+        backup.prune_backups().unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls[1][..8],
+            [
+                "create",
+                "--checkpoint-interval=600",
+                "--atime",
+                "--numeric-ids",
+                "--files-cache=mtime,size",
+                "--upload-ratelimit=5000",
+                "--compression=lz4",
+                "--exclude",
+            ]
+        );
+        assert!(calls[1].contains(&"--sparse".to_string()));
+        let prune = calls.iter().find(|c| c[0] == "prune").unwrap();
+        assert_eq!(prune[prune.len() - 2], "--keep-last=3");
+
+        backup.version = version::BorgVersion::new(1, 1, 18);
+        backup.config.options.noatime = Some(true);
+        let create = backup.create_command(false).get_args();
+        assert!(create.contains(&"--noatime".to_string()));
+        assert!(create.contains(&"--numeric-owner".to_string()));
+        assert!(create.contains(&"--remote-ratelimit=5000".to_string()));
+
+        backup.config.options.ionice = Some("idle:3".to_string());
+        assert!(backup.config.options.lint().is_err());
+        backup.config.options.ionice = None;
+        backup.config.options.files_cache = Some("ctime,bogus".to_string());
+        assert!(backup.config.options.lint().unwrap_err().contains("bogus"));
+    }
+
+    #[test]
+    fn test_low_priority() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        assert!(backup.create_command(false).get_wrapper().is_empty());
+
+        backup.config.options.low_priority = true;
+        let wrapper = backup.create_command(false).get_wrapper();
+        assert_eq!(wrapper[..3], ["nice", "-n", "19"]);
+        if on_path("ionice") {
+            assert_eq!(wrapper[3..], ["ionice", "-c", "3"]);
+        }
+
+        backup.config.options.nice = Some(5);
+        backup.config.options.ionice = Some("best-effort".to_string());
+        assert_eq!(
+            backup.create_command(false).get_wrapper(),
+            ["nice", "-n", "5", "ionice", "-c", "2"]
+        );
+    }
+
+    #[test]
+    fn test_borg_user() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        backup.run_as = Some(privileges::Account {
+            name: "borg".to_string(),
+            uid: 990,
+            gid: 990,
+            home: "/var/lib/borg".to_string(),
+        });
+        let list = backup.borg();
+        assert_eq!(
+            list.get_wrapper(),
+            [
+                "setpriv",
+                "--reuid=990",
+                "--regid=990",
+                "--init-groups",
+                "--"
+            ]
+        );
+        assert_eq!(list.get_env("HOME").as_deref(), Some("/var/lib/borg"));
+
+        // Only creates for jobs with sudo: true may read everything
+        let caps = "--ambient-caps=+dac_read_search".to_string();
+        assert!(!backup.create_command(false).get_wrapper().contains(&caps));
+        assert!(backup.create_command(true).get_wrapper().contains(&caps));
+
+        // Restores write files back as root, with the borg user's keys
+        let extract = backup.borg_as_root();
+        assert!(extract.get_wrapper().is_empty());
+        assert_eq!(extract.get_env("HOME"), None);
+        if std::env::var_os("BORG_KEYS_DIR").is_none() {
+            assert_eq!(
+                extract.get_env("BORG_KEYS_DIR").as_deref(),
+                Some("/var/lib/borg/.config/borg/keys")
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_pattern_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exclude.txt");
+        fs::write(
+            &path,
+            "# caches
+/home/*/.cache
+
+  **/node_modules  
+",
+        )
+        .unwrap();
+        assert_eq!(
+            read_pattern_file(path.to_str().unwrap()).unwrap(),
+            vec!["/home/*/.cache", "**/node_modules"]
+        );
+        assert!(read_pattern_file("/nonexistent/exclude.txt").is_err());
+    }
+
+    #[test]
+    fn test_create_dry_run_arguments() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.set_dry_run(true);
+        backup.create_backup().unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2, "no borg info after a dry run");
+        assert_eq!(
+            calls[1][..4],
+            ["create", "--dry-run", "--list", "--progress"]
+        );
+    }
+
+    #[test]
+    fn test_prune_and_restore_dry_run_arguments() {
+        let listing = r#"{"archives": [
+            {"archive": "host-1", "time": "2024-03-01T00:00:00.000000", "comment": "keep"},
+            {"archive": "host-2", "time": "2024-03-02T00:00:00.000000"}
+        ]}"#;
+        let files = r#"{"type": "-", "path": "etc/hosts", "size": 120, "mtime": "2024-02-01T00:00:00.000000"}"#;
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, listing)
+                .respond("list --json-lines", 0, files)
+                .respond(
+                    "prune",
+                    0,
+                    "Would prune:     host-1     Fri, 2024-03-01 00:00:00\nWould prune:     host-2     Sat, 2024-03-02 00:00:00",
+                ),
+        );
+        backup.hostname = "host".to_string();
+        backup.set_dry_run(true);
+
+        // A tagged archive makes us delete by hand, which a dry run skips too
+        backup.prune_backups().unwrap();
+        {
+            let calls = calls.lock().unwrap();
+            let prune = calls.iter().find(|c| c[0] == "prune").unwrap();
+            assert_eq!(prune[..3], ["prune", "--list", "--dry-run"]);
+            assert!(calls.iter().all(|c| c[0] != "delete"));
+        }
+        assert_eq!(backup.report.pruned, ["host-2"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("restore");
+        backup
+            .restore_file("/etc/hosts", Utc::now(), Some(target.to_str().unwrap()))
+            .unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["extract", "--dry-run", "/tmp/borg::host-2", "etc/hosts"]
+        );
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_dry_run_reaches_hooks_and_notifications() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("dry_run");
+        let hook = format!("echo $DRY_RUN >> {}", seen.display());
+        backup.run_hook("pre_cycle", &hook, &[]).unwrap();
+        backup.set_dry_run(true);
+        backup.run_hook("pre_cycle", &hook, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&seen).unwrap(), "0\n1\n");
+
+        let notification = Notification {
+            subject: "Backup Failure on host".to_string(),
+            message: "Borg backup failed".to_string(),
+            hostname: "host".to_string(),
+            success: false,
+            html: None,
+        };
+        let sent = backup.as_sent(notification.clone());
+        assert_eq!(sent.subject, "[DRY RUN] Backup Failure on host");
+        assert!(sent.message.ends_with("the repository was not modified."));
+
+        backup.set_dry_run(false);
+        assert_eq!(
+            backup.as_sent(notification.clone()).subject,
+            notification.subject
+        );
+    }
+
+    #[test]
+    fn test_create_avoids_existing_archive_name() {
+        // Take this second and the next so the test can't straddle a tick
+        let hostname = get_hostname(None).unwrap();
+        let now = Utc::now();
+        let taken: Vec<String> = [now, now + chrono::Duration::seconds(1)]
+            .iter()
+            .map(|t| format!("{}-{}", hostname, ArchiveTimestamp::Local.format(*t)))
+            .collect();
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("list", 0, &taken.join("\n")));
+        backup.create_backup().unwrap();
+
+        let calls = calls.lock().unwrap();
+        let create = &calls[1];
+        let archive = &create[create.len() - 2];
+        assert!(taken
+            .iter()
+            .any(|t| *archive == format!("/tmp/borg::{}.1", t)));
+    }
+
+    #[test]
+    fn test_prune_arguments() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.prune_backups().unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            *calls,
+            vec![
+                vec![
+                    "list".to_string(),
+                    "--json".to_string(),
+                    "--format={comment}".to_string(),
+                    "--glob-archives=vm-[0-9]*".to_string(),
+                    "/tmp/borg".to_string(),
+                ],
+                vec![
+                    "prune".to_string(),
+                    "--list".to_string(),
+                    format!("--glob-archives={}-[0-9]*", backup.hostname),
+                    "--keep-within=24H".to_string(),
+                    "--keep-hourly=24".to_string(),
+                    "--keep-daily=7".to_string(),
+                    "--keep-weekly=4".to_string(),
+                    "--keep-monthly=6".to_string(),
+                    "--keep-yearly=2".to_string(),
+                    "/tmp/borg".to_string(),
+                ],
+                vec![
+                    "list".to_string(),
+                    "--short".to_string(),
+                    "/tmp/borg".to_string(),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_archive_name_template() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.hostname = "web".to_string();
+        backup.user = "ops".to_string();
+        backup.config.archive_name_template = "{user}@{hostname}_{job}_{date}".to_string();
+        backup.config.archive_timestamp = ArchiveTimestamp::Utc;
+
+        backup.create_backup().unwrap();
+        let archive = backup.report.archive.clone().unwrap();
+        assert_eq!(
+            archive,
+            format!("ops@web_{}", Utc::now().format("%Y-%m-%d"))
+        );
+
+        calls.lock().unwrap().clear();
+        backup.prune_backups().unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[1][2],
+            "--glob-archives=ops@web_[0-9]*"
+        );
+        assert!(glob_match(&backup.name_template().any_glob(), &archive));
+
+        backup.config.archive_name_template = "{hostname}".to_string();
+        assert!(backup.config.lint().is_err());
+    }
+
+    #[test]
+    fn test_prune_failure_is_reported() {
+        let (mut backup, _) =
+            mock_backup(runner::mock::MockRunner::default().respond("prune", 2, ""));
+        assert_eq!(
+            backup.prune_backups().unwrap_err(),
+            "borg prune failed with exit code 2"
+        );
+    }
+
+    #[test]
+    fn test_replicate_under_borg_lock() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        assert!(backup.replicate_after_cycle().is_ok());
+        assert!(calls.lock().unwrap().is_empty());
+
+        backup.config.replication =
+            Some(serde_yaml::from_str("target: nas:/srv/borg\nafter_cycle: false").unwrap());
+        backup.replicate_after_cycle().unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        backup.replicate_repository().unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec![
+                "with-lock",
+                "/tmp/borg",
+                "rsync",
+                "--archive",
+                "--partial",
+                "--delete",
+                "/tmp/borg/",
+                "nas:/srv/borg/"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_until_attempts_run_out() {
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("create", 2, "")
+                .respond("prune", 2, ""),
+        );
+        backup.config.retry.max_attempts = 3;
+        backup.config.retry.backoff = "0s".to_string();
+
+        assert_eq!(
+            backup.create_backup().unwrap_err(),
+            "borg create failed with exit code 2 after 3 attempts"
+        );
+        let creates = |calls: &runner::mock::Calls| {
+            calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|args| args[0] == "create")
+                .count()
+        };
+        assert_eq!(creates(&calls), 3);
+        let log = backup.cycle_log.join("\n");
+        assert!(log.contains("borg create attempt 1/3 failed with exit code 2; retrying in 0s"));
+        assert!(log.contains("borg create attempt 2/3 failed"));
+        assert!(!log.contains("attempt 3/3"));
+
+        // Other exit codes fail straight away
+        backup.config.retry.retry_on_exit_codes = vec![130];
+        calls.lock().unwrap().clear();
+        assert_eq!(
+            backup.prune_backups().unwrap_err(),
+            "borg prune failed with exit code 2"
+        );
+        // The listing for tagged archives, then a single prune
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_keep_tag_protects_archives_from_prune() {
+        let listing = r#"{"archives": [
+            {"archive": "host-1", "time": "2024-03-01T00:00:00.000000", "comment": "pre-upgrade keep"},
+            {"archive": "host-2", "time": "2024-03-02T00:00:00.000000", "comment": "keeper"}
+        ]}"#;
+        let pruned = "Would prune:     host-1     Fri, 2024-03-01 00:00:00\nWould prune:     host-2     Sat, 2024-03-02 00:00:00";
+        let (mut backup, calls) = mock_backup(
+            runner::mock::MockRunner::default()
+                .respond("list --json", 0, listing)
+                .respond("prune", 0, pruned),
+        );
+
+        backup.prune_backups().unwrap();
+        let calls = calls.lock().unwrap();
+        let prune = calls.iter().find(|c| c[0] == "prune").unwrap();
+        assert!(prune.contains(&"--dry-run".to_string()));
+        let deleted: Vec<&Vec<String>> = calls.iter().filter(|c| c[0] == "delete").collect();
+        assert_eq!(
+            deleted,
+            [&vec!["delete".to_string(), "/tmp/borg::host-2".to_string()]]
+        );
+        assert_eq!(backup.report.pruned, ["host-2"]);
+        assert!(backup
+            .cycle_log
+            .iter()
+            .any(|line| line.contains("Keeping host-1: tagged keep")));
+    }
+
+    #[test]
+    fn test_tag_archive() {
+        let info = r#"{"archives": [{"name": "host-1", "start": "2024-03-01T12:00:00.000000", "comment": "pre-upgrade", "stats": {"original_size": 1, "compressed_size": 1, "deduplicated_size": 1, "nfiles": 1}}]}"#;
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("info --json", 0, info));
+
+        backup.tag_archive("host-1", "keep", false).unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &[
+                "recreate",
+                "--comment=pre-upgrade keep",
+                "/tmp/borg::host-1"
+            ]
+        );
+        assert_eq!(backup.state().audit_log().unwrap()[0].action, "tag");
+
+        // Nothing to take off
+        calls.lock().unwrap().clear();
+        backup.tag_archive("host-1", "keep", true).unwrap();
+        assert!(calls.lock().unwrap().iter().all(|c| c[0] != "recreate"));
+        assert!(backup.tag_archive("host-1", "two words", false).is_err());
+
+        assert!(has_tag("pre-upgrade keep", "keep"));
+        assert!(!has_tag("keeper", "keep"));
+        assert_eq!(retag("a keep b", "keep", true), "a b");
+        assert_eq!(retag("", "keep", false), "keep");
+    }
+
+    #[test]
+    fn test_append_only_repository() {
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("info", 2, ""));
+        backup.config.repository.append_only = true;
+        backup.init_repository(None).unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap()[..3],
+            ["init", "--encryption=repokey-blake2", "--append-only"]
+        );
+
+        // Nothing is removed from this host, and the cycle carries on
+        calls.lock().unwrap().clear();
+        assert!(backup
+            .delete_archives(&["host-2024-03-01".to_string()])
+            .unwrap_err()
+            .contains("admin prune"));
+        assert!(backup.prune_backups().is_err());
+        assert!(backup.compact_repository().is_err());
+        backup.scheduled_prune().unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        backup.set_admin(true);
+        backup.prune_backups().unwrap();
+        assert_eq!(calls.lock().unwrap()[1][0], "prune");
+
+        // From the trusted machine: the client's archives, with the
+        // repository's own flag lifted meanwhile
+        let (mut admin, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("config", 0, "1\n"));
+        admin.config.repository.append_only = true;
+        assert!(admin.admin_prune(None).unwrap_err().contains("--hostname"));
+        admin.admin_prune(Some("laptop")).unwrap();
+        let calls = calls.lock().unwrap();
+        let config: Vec<&[String]> = calls
+            .iter()
+            .filter(|c| c[0] == "config")
+            .map(|c| &c[2..])
+            .collect();
+        assert_eq!(
+            config,
+            [
+                &["append_only"][..],
+                &["append_only", "0"],
+                &["append_only", "1"]
+            ]
+        );
+        let prune = calls.iter().position(|c| c[0] == "prune").unwrap();
+        assert!(calls[prune].contains(&"--glob-archives=laptop-[0-9]*".to_string()));
+        assert!(prune < calls.iter().rposition(|c| c[0] == "config").unwrap());
+
+        assert!(backup.authorized_keys_line("ssh-ed25519 AAAA").is_err());
+        backup.config.repository.remote = Some(Remote {
+            user: None,
+            host: "nas".to_string(),
+            port: None,
+            ssh_key: None,
+            ssh_options: Vec::new(),
+        });
+        assert_eq!(
+            backup
+                .authorized_keys_line("ssh-ed25519 AAAA laptop\n")
+                .unwrap(),
+            "command=\"borg serve --append-only --restrict-to-repository /tmp/borg\",restrict ssh-ed25519 AAAA laptop"
+        );
+    }
+
+    #[test]
+    fn test_first_run_initializes_repository() {
+        let missing = "Repository /tmp/borg does not exist.";
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("info", 2, missing));
+
+        // Not interactive: asking means failing with the way forward
+        let error = backup.ensure_repository().unwrap_err();
+        assert!(error.contains("borg-timemachine init"), "{}", error);
+
+        backup.config.policy.on_missing_repository = OnMissingRepository::Init;
+        backup.ensure_repository().unwrap();
+        assert!(calls.lock().unwrap().iter().any(|c| c[0] == "init"));
+
+        // Interactive: the question goes to the reporter, and so do the
+        // messages init has for the user
+        struct Recording(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+        impl Reporter for Recording {
+            fn message(&self, text: &str) {
+                self.0.lock().unwrap().push(text.to_string());
+            }
+            fn confirm(&self, question: &str) -> Result<bool, String> {
+                self.0.lock().unwrap().push(question.to_string());
+                Ok(true)
+            }
+        }
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        calls.lock().unwrap().clear();
+        backup.config.policy.on_missing_repository = OnMissingRepository::Ask;
+        backup.set_interactive(true);
+        backup.set_reporter(Recording(seen.clone()));
+        backup.ensure_repository().unwrap();
+        assert!(calls.lock().unwrap().iter().any(|c| c[0] == "init"));
+        {
+            let seen = seen.lock().unwrap();
+            assert!(seen[0].contains("Initialize it now?"), "{:?}", seen);
+            assert!(seen[1].starts_with("Initializing"), "{:?}", seen);
+        }
+        let outcome = backup.init_repository(None).unwrap();
+        assert_eq!(outcome.location, "/tmp/borg");
+        assert_eq!(outcome.key_exported_to, None);
+
+        let (backup, _) = mock_backup(runner::mock::MockRunner::default().respond(
+            "info",
+            2,
+            "Failed to create/acquire the lock",
+        ));
+        assert!(backup.repository_exists().is_err());
+    }
+
+    #[test]
+    fn test_borg_lock_held_by_another_process() {
+        let repo = tempfile::tempdir().unwrap();
+        fs::write(
+            repo.path().join("lock.roster"),
+            r#"{"exclusive": [["nas@123456", 4321, 0]], "shared": []}"#,
+        )
+        .unwrap();
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default().respond(
+            "prune",
+            2,
+            "Failed to create/acquire the lock /srv/borg/lock.exclusive (timeout).",
+        ));
+        backup.config.repository.path = repo.path().display().to_string();
+
+        let holders = backup.borg_lock_holders();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].to_string(), "PID 4321 on nas");
+
+        let error = backup.prune_backups().unwrap_err();
+        assert!(error.contains("locked by PID 4321 on nas"), "{}", error);
+        assert!(error.contains("600s"), "{}", error);
+    }
+
+    #[test]
+    fn test_check_and_compact_arguments() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.maintenance.check_day = Local::now().weekday().num_days_from_monday() + 1;
+        backup.config.maintenance.compact_threshold = Some(20);
+        backup.check_repository().unwrap();
+        backup.scheduled_compact().unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                vec!["check", "/tmp/borg"],
+                vec!["compact", "--threshold=20", "/tmp/borg"]
+            ]
+        );
+
+        // Both already ran today
+        calls.lock().unwrap().clear();
+        backup.check_repository().unwrap();
+        backup.scheduled_compact().unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        // Deleting archives compacts regardless
+        backup.compact_repository().unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_archive_details_filters_and_sorts() {
+        let host = get_hostname(None).unwrap();
+        let archive = |name: &str, day: u32, duration: u32, size: u32| {
+            format!(
+                r#"{{"name": "{}", "start": "2024-03-0{}T12:00:00.000000", "duration": {}, "stats": {{"original_size": {}, "compressed_size": 0, "deduplicated_size": 0, "nfiles": 1}}}}"#,
+                name, day, duration, size
+            )
+        };
+        let info = format!(
+            r#"{{"archives": [{}, {}, {}, {}]}}"#,
+            archive(&format!("{}-20240301", host), 1, 30, 300),
+            archive(&format!("{}-20240302", host), 2, 10, 100),
+            archive("otherhost-20240303", 3, 20, 200),
+            archive("trash-20240304T000000-x", 4, 1, 1),
+        );
+        let (backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("info --json", 0, &info));
+        let names = |options: ListOptions| -> Vec<String> {
+            backup
+                .archive_details(&options)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.name.split('-').next_back().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            names(ListOptions::default()),
+            ["20240301", "20240302", "20240303"]
+        );
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            vec!["info", "--json", "--glob-archives=*", "/tmp/borg"]
+        );
+        let job = ListOptions {
+            job: Some("system-config".to_string()),
+            ..ListOptions::default()
+        };
+        assert_eq!(names(job), ["20240301", "20240302"]);
+
+        let since = Local.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            names(ListOptions {
+                since: Some(since.with_timezone(&Utc)),
+                sort: ListSort::Size,
+                ..ListOptions::default()
+            }),
+            ["20240302", "20240303"]
+        );
+        assert_eq!(
+            names(ListOptions {
+                last: Some(1),
+                ..ListOptions::default()
+            }),
+            ["20240303"]
+        );
+        assert_eq!(
+            names(ListOptions {
+                sort: ListSort::Duration,
+                reverse: true,
+                ..ListOptions::default()
+            }),
+            ["20240301", "20240303", "20240302"]
+        );
+        assert!(backup
+            .archive_details(&ListOptions {
+                job: Some("nope".to_string()),
+                ..ListOptions::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_quota_prunes_harder_then_warns() {
+        let info = r#"{"repository": {"location": "/tmp/borg"},
+            "cache": {"stats": {"total_chunks": 1, "total_csize": 1, "total_size": 1, "total_unique_chunks": 1, "unique_csize": 4096, "unique_size": 4096}}}"#;
+        let (mut backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("info --json", 0, info));
+        let dir = tempfile::tempdir().unwrap();
+        backup.config.repository.path = dir.path().display().to_string();
+        backup.config.notifications.enabled = false;
+
+        // Within the limits nothing happens
+        backup.config.maintenance.max_repo_size = Some("1M".to_string());
+        backup.config.maintenance.min_free_space = Some("1K".to_string());
+        backup.check_quota().unwrap();
+        assert!(backup.report.warnings.is_empty());
+
+        backup.config.maintenance.max_repo_size = Some("1K".to_string());
+        backup.config.maintenance.quota_retention = Some(
+            serde_yaml::from_str(
+                "{within: 1d, hourly: 0, daily: 7, weekly: 4, monthly: 0, yearly: 0}",
+            )
+            .unwrap(),
+        );
+        assert!(backup.config.maintenance.lint().is_ok());
+        calls.lock().unwrap().clear();
+        backup.check_quota().unwrap();
+        let calls = calls.lock().unwrap();
+        let prune = calls.iter().find(|c| c[0] == "prune").unwrap();
+        assert!(prune.contains(&"--keep-daily=7".to_string()));
+        assert!(calls.iter().any(|c| c[0] == "compact"));
+        // Still too big afterwards
+        assert_eq!(
+            backup.report.warnings,
+            ["repository stores 4.0 KiB, more than maintenance.max_repo_size 1.0 KiB"]
+        );
+
+        let free = backup
+            .config
+            .maintenance
+            .quota_problems(0, Some(512), &display::Output::default())
+            .unwrap();
+        assert!(free[0].starts_with("only 512 B free"));
+        // Free space of remote repositories is unknown
+        assert!(backup
+            .config
+            .maintenance
+            .quota_problems(0, None, &display::Output::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_mount_creates_and_umount_removes_mount_point() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        let dir = tempfile::tempdir().unwrap();
+        let mount_point = dir.path().join("mnt/borg").display().to_string();
+
+        backup
+            .mount_repository(
+                &mount_point,
+                &MountOptions {
+                    archive: Some("host-1".to_string()),
+                    fuse_options: vec!["versions".to_string(), "uid=1000".to_string()],
+                    ..MountOptions::default()
+                },
+            )
+            .unwrap();
+        assert!(Path::new(&mount_point).is_dir());
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec![
+                "mount",
+                "-o",
+                "versions,uid=1000",
+                "/tmp/borg::host-1",
+                mount_point.as_str()
+            ]
+        );
+
+        backup.umount_repository(&mount_point).unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["umount", mount_point.as_str()]
+        );
+        assert!(!Path::new(&mount_point).exists());
+
+        // A directory that was there before is left alone
+        fs::create_dir_all(&mount_point).unwrap();
+        backup
+            .mount_repository(&mount_point, &MountOptions::default())
+            .unwrap();
+        backup.umount_repository(&mount_point).unwrap();
+        assert!(Path::new(&mount_point).is_dir());
+    }
+
+    #[test]
+    fn test_unmount_falls_through_to_next_tool() {
+        assert!(unmount_with(&[&["false"], &["true"]], "/mnt/borg").is_ok());
+        assert!(unmount_with(&[&["no-such-fusermount"], &["true"]], "/mnt/borg").is_ok());
+
+        let err = unmount_with(&[&["false"], &["no-such-umount"]], "/mnt/borg").unwrap_err();
+        assert!(err.contains("false exit status: 1"), "{}", err);
+        assert!(err.contains("no-such-umount is not installed"), "{}", err);
+    }
+
+    #[test]
+    fn test_desktop_summary_for_each_outcome() {
+        let mut report = CycleReport::new(Utc::now());
+        report.duration = std::time::Duration::from_secs(90);
+        report.success = true;
+
+        let (title, body, urgency) = desktop_summary(&CycleOutcome::Success, &report);
+        assert_eq!(title, "Backup complete");
+        assert_eq!(body, "Finished in 1m 30s");
+        assert_eq!(urgency, desktop::Urgency::Low);
+
+        // Outcomes can come from elsewhere than the report's own warnings
+        let (_, body, urgency) = desktop_summary(&CycleOutcome::SuccessWithWarnings, &report);
+        assert_eq!(body, "Finished in 1m 30s with 0 warning(s)");
+        assert_eq!(urgency, desktop::Urgency::Normal);
+
+        report.warnings = vec!["file changed".to_string(), "other".to_string()];
+        let (title, body, _) = desktop_summary(&report.outcome(), &report);
+        assert_eq!(title, "Backup finished with warnings");
+        assert_eq!(body, "Finished in 1m 30s with 2 warning(s): file changed");
+
+        let failed = CycleOutcome::Failed("borg create failed".to_string());
+        let (title, body, urgency) = desktop_summary(&failed, &report);
+        assert_eq!(title, "Backup failed");
+        assert_eq!(body, "borg create failed");
+        assert_eq!(urgency, desktop::Urgency::Critical);
+    }
+
+    #[test]
+    fn test_signal_is_forwarded_and_interrupts_the_cycle() {
+        // Being interrupted is process-wide, so run in a process of our own
+        if std::env::var_os("BORG_TIMEMACHINE_SIGNAL_TEST").is_none() {
+            let output = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "tests::test_signal_is_forwarded_and_interrupts_the_cycle",
+                ])
+                .env("BORG_TIMEMACHINE_SIGNAL_TEST", "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{}", stdout);
+            assert!(stdout.contains("1 passed"), "{}", stdout);
+            return;
+        }
+
+        signals::install().unwrap();
+        assert!(signals::installed());
+
+        // sh stands in for borg; once it prints, it is waiting to be killed
+        let mut cmd = BorgCommand::new();
+        cmd.args(["-c", "echo started; exec sleep 30"]);
+        let started = Instant::now();
+        let status = runner::SystemRunner::new("sh")
+            .stream(cmd, &mut |_, _| {
+                // SAFETY: kill(2) has no memory-safety preconditions
+                unsafe {
+                    libc::kill(libc::getpid(), libc::SIGTERM);
+                }
+            })
+            .unwrap();
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert!(started.elapsed() < std::time::Duration::from_secs(30));
+        assert_eq!(signals::interrupted(), Some(libc::SIGTERM));
+
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.notifications.enabled = false;
+        backup.config.options.inhibit_sleep = false;
+        match backup.run_backup_cycle() {
+            CycleOutcome::Failed(e) => assert!(e.contains("Interrupted by SIGTERM"), "{}", e),
+            outcome => panic!("{:?}", outcome),
+        }
+        assert!(calls.lock().unwrap().iter().all(|c| c[0] != "create"));
+        assert!(backup
+            .report
+            .warnings
+            .iter()
+            .any(|w| w.contains("interrupted by SIGTERM")));
+    }
+
+    #[test]
+    fn test_check_options() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+
+        backup
+            .check_now(&CheckOptions {
+                archives_only: true,
+                verify_data: true,
+                last: Some(3),
+                ..CheckOptions::default()
+            })
+            .unwrap();
+        backup
+            .check_now(&CheckOptions {
+                repair: true,
+                ..CheckOptions::default()
+            })
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                vec![
+                    "check",
+                    "--archives-only",
+                    "--verify-data",
+                    "--last=3",
+                    "/tmp/borg"
+                ],
+                vec!["check", "--repair", "/tmp/borg"],
+            ]
+        );
+        // The repair covered the whole repository, so it counts as a check
+        assert!(backup.state().last_check().unwrap().is_some());
+        let maintenance: Maintenance =
+            serde_yaml::from_str("check_day: 7\nauto_compact: true\ncheck_mode: deep").unwrap();
+        assert_eq!(maintenance.check_mode, CheckMode::Deep);
+    }
+
+    #[test]
+    fn test_dry_run_skips_compact() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        backup.set_dry_run(true);
+        backup.compact_repository().unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remote_repository_sets_borg_rsh() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        backup.config.repository = serde_yaml::from_str(
+            "path: /srv/borg\nencryption: repokey\nremote:\n  user: backup\n  host: nas\n  ssh_key: /root/.ssh/id_backup\n",
+        )
+        .unwrap();
+        let cmd = backup.borg();
+        assert!(cmd
+            .get_env("BORG_RSH")
+            .unwrap()
+            .contains("/root/.ssh/id_backup"));
+    }
+
+    #[test]
+    fn test_key_commands() {
+        let (mut backup, calls) = mock_backup(runner::mock::MockRunner::default());
+        let dir = tempfile::tempdir().unwrap();
+        let passphrase_file = dir.path().join("passphrase");
+        backup.config.security.passphrase_file = passphrase_file.display().to_string();
+
+        // The mock writes nothing, so stand in for borg's export
+        let key = dir.path().join("key.txt");
+        fs::write(&key, "BORG_KEY").unwrap();
+        backup
+            .export_key(Some(key.to_str().unwrap()), true)
+            .unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            vec![
+                "key",
+                "export",
+                "--paper",
+                "/tmp/borg",
+                key.to_str().unwrap()
+            ]
+        );
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        backup.change_passphrase(Some("new secret")).unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[1],
+            vec!["key", "change-passphrase", "/tmp/borg"]
+        );
+        assert_eq!(
+            fs::read_to_string(&passphrase_file).unwrap(),
+            "new secret\n"
+        );
+
+        let actions: Vec<_> = backup
+            .state()
+            .audit_log()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect();
+        assert_eq!(actions, vec!["key-export", "key-change-passphrase"]);
+    }
+
+    #[test]
+    fn test_key_backup_problem() {
+        let key = "BORG_KEY 0123abcd\nhqlhbGdvcml0aG2mc2hhMjU2\n";
+        let (backup, calls) =
+            mock_backup(runner::mock::MockRunner::default().respond("key export", 0, key));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.txt");
+        let path = path.to_str().unwrap();
+
+        assert!(backup
+            .key_backup_problem(path)
+            .unwrap()
+            .unwrap()
+            .contains("unreadable"));
+
+        fs::write(path, key).unwrap();
+        assert_eq!(backup.key_backup_problem(path).unwrap(), None);
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &vec!["key", "export", "/tmp/borg"]
+        );
+
+        fs::write(path, "BORG_KEY 0123abcd\nold\n").unwrap();
+        assert!(backup
+            .key_backup_problem(path)
+            .unwrap()
+            .unwrap()
+            .contains("does not match"));
+    }
+
+    #[test]
+    fn test_passphrase_reaches_borg() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        assert_eq!(backup.borg().get_env("BORG_PASSPHRASE"), None);
+
+        backup.config.security.passphrase = Some(PassphraseSource::Command {
+            command: "pass show borg".to_string(),
+        });
+        backup.load_passphrase().unwrap();
+        let cmd = backup.borg();
+        assert_eq!(
+            cmd.get_env("BORG_PASSCOMMAND").as_deref(),
+            Some("pass show borg")
+        );
+        assert_eq!(cmd.get_env("BORG_PASSPHRASE"), None);
+    }
+
+    #[test]
+    fn test_lock_settings() {
+        let (mut backup, _) = mock_backup(runner::mock::MockRunner::default());
+        let cmd = backup.borg();
+        assert_eq!(cmd.get_common_args(), vec!["--lock-wait=600"]);
+        assert_eq!(cmd.get_env("BORG_HOSTNAME_IS_UNIQUE"), None);
+
+        backup.config.borg =
+            serde_yaml::from_str("lock_wait: 30\nhostname_is_unique: true").unwrap();
+        let cmd = backup.borg();
+        assert_eq!(cmd.get_common_args(), vec!["--lock-wait=30"]);
+        assert_eq!(
+            cmd.get_env("BORG_HOSTNAME_IS_UNIQUE").as_deref(),
+            Some("yes")
+        );
     }
 
     #[test]
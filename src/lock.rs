@@ -0,0 +1,290 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Owner recorded inside a lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub started: String,
+}
+
+impl LockOwner {
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        let pid = lines.next()?.trim().parse().ok()?;
+        let started = lines.next().unwrap_or("").trim().to_string();
+        Some(Self { pid, started })
+    }
+
+    /// Whether the process that wrote the lock still runs: the PID exists
+    /// and, where /proc tells, started before the lock was written, so a
+    /// PID reused since doesn't count.
+    pub fn is_alive(&self) -> bool {
+        if !Path::new(&format!("/proc/{}", self.pid)).exists() {
+            return false;
+        }
+        let written = NaiveDateTime::parse_from_str(&self.started, "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .and_then(|time| Local.from_local_datetime(&time).earliest());
+        match (written, process_started(self.pid)) {
+            // Both are only good to the second
+            (Some(written), Some(started)) => started <= written + chrono::Duration::seconds(2),
+            _ => true,
+        }
+    }
+}
+
+/// When process `pid` started, from /proc.
+fn process_started(pid: u32) -> Option<DateTime<Local>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the command name, which may hold spaces and parens;
+    // the start time in clock ticks after boot is the 22nd field
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: i64 = fields.get(19)?.parse().ok()?;
+    let boot: i64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    // SAFETY: sysconf has no preconditions
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return None;
+    }
+    DateTime::from_timestamp(boot + ticks / hz, 0).map(|time| time.with_timezone(&Local))
+}
+
+/// Exclusive lock held for the lifetime of a backup cycle. The kernel drops
+/// the `flock` if the process dies, so a crash can never leave it held; the
+/// PID and start time written inside are for humans and older versions.
+pub struct LockGuard {
+    file: File,
+}
+
+impl LockGuard {
+    /// Take the lock at `path`. Returns the guard and, if the file was left
+    /// behind by a process that no longer exists, its previous owner.
+    pub fn acquire(path: &str) -> Result<(Self, Option<LockOwner>), String> {
+        // /run/lock and /var/lock don't exist in every container or on macOS
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Failed to open lock file {}: {}", path, e))?;
+
+        let mut contents = String::new();
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let _ = file.read_to_string(&mut contents);
+                return Err(match LockOwner::parse(&contents) {
+                    Some(owner) => format!(
+                        "Another backup is running (PID {}, started {}). Lock file: {}",
+                        owner.pid, owner.started, path
+                    ),
+                    None => format!("Another backup is running. Lock file: {}", path),
+                });
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("Failed to lock {}: {}", path, e));
+            }
+        }
+
+        let _ = file.read_to_string(&mut contents);
+        let previous = LockOwner::parse(&contents);
+        if let Some(owner) = &previous {
+            // Written by a version that did not use flock and still running
+            if owner.is_alive() && owner.pid != std::process::id() {
+                return Err(format!(
+                    "Lock file {} is held by running process {} (started {})",
+                    path, owner.pid, owner.started
+                ));
+            }
+        }
+
+        let started: DateTime<Local> = Local::now();
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| {
+                writeln!(
+                    file,
+                    "{}\n{}",
+                    std::process::id(),
+                    started.format("%Y-%m-%d %H:%M:%S")
+                )
+            })
+            .map_err(|e| format!("Failed to write lock file {}: {}", path, e))?;
+
+        Ok((Self { file }, previous))
+    }
+
+    /// Take the lock at `path`, waiting for whoever holds it.
+    pub fn wait(path: &str) -> Result<Self, String> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Failed to open lock file {}: {}", path, e))?;
+        file.lock()
+            .map_err(|e| format!("Failed to lock {}: {}", path, e))?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        // Leave the file in place: deleting it would let a waiting process
+        // lock the old inode while another creates a new one.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// The lock file for the repository at `location`: `lock_file` with a hash
+/// of the location added to the file name, so cycles on different
+/// repositories, from one configuration or several, never wait for each
+/// other while two on the same repository still do.
+pub fn repository_lock_file(lock_file: &str, location: &str) -> String {
+    let location = match location.trim_end_matches('/') {
+        "" => location,
+        trimmed => trimmed,
+    };
+    // FNV-1a: unlike DefaultHasher, stable across Rust releases, so an
+    // upgraded binary finds the lock an older one is holding
+    let hash = location.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    crate::targets::file_for(lock_file, &format!("{:016x}", hash))
+}
+
+/// Owner recorded in the lock file, if any.
+pub fn read_owner(path: &str) -> Option<LockOwner> {
+    LockOwner::parse(&fs::read_to_string(path).ok()?)
+}
+
+/// Start of the message borg prints when it can't lock the repository,
+/// whether it gave up waiting or the lock could not be created at all.
+pub const BORG_LOCK_FAILED: &str = "Failed to create/acquire the lock";
+
+/// A process holding borg's own lock on a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BorgLockHolder {
+    pub host: String,
+    pub pid: u32,
+    pub exclusive: bool,
+    /// Command line, when the holder runs on this machine
+    pub command: Option<String>,
+}
+
+impl fmt::Display for BorgLockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PID {} on {}", self.pid, self.host)?;
+        if let Some(command) = &self.command {
+            write!(f, " (`{}`)", command)?;
+        }
+        if !self.exclusive {
+            write!(f, ", shared")?;
+        }
+        Ok(())
+    }
+}
+
+/// Processes listed in the `lock.roster` of the local repository at
+/// `repo`. Borg identifies hosts as `hostname@node`; holders on `hostname`
+/// are looked up in /proc to show what they are running.
+pub fn borg_lock_holders(repo: &Path, hostname: &str) -> Vec<BorgLockHolder> {
+    let Ok(contents) = fs::read_to_string(repo.join("lock.roster")) else {
+        return Vec::new();
+    };
+    let Ok(roster) = serde_json::from_str::<serde_json::Value>(&contents) else {
+        return Vec::new();
+    };
+
+    let mut holders = Vec::new();
+    for (kind, exclusive) in [("exclusive", true), ("shared", false)] {
+        let Some(entries) = roster.get(kind).and_then(|e| e.as_array()) else {
+            continue;
+        };
+        for entry in entries {
+            let host_id = entry.get(0).and_then(|h| h.as_str()).unwrap_or("");
+            let Some(pid) = entry.get(1).and_then(|p| p.as_u64()) else {
+                continue;
+            };
+            let host = host_id.split('@').next().unwrap_or(host_id).to_string();
+            let command = (host == hostname)
+                .then(|| fs::read(format!("/proc/{}/cmdline", pid)).ok())
+                .flatten()
+                .map(|cmdline| String::from_utf8_lossy(&cmdline).replace('\0', " "))
+                .map(|cmdline| cmdline.trim().to_string())
+                .filter(|cmdline| !cmdline.is_empty());
+            holders.push(BorgLockHolder {
+                host,
+                pid: pid as u32,
+                exclusive,
+                command,
+            });
+        }
+    }
+    holders
+}
+
+/// Owner of the lock at `path` if a backup cycle is running. Only reads the
+/// file: taking the lock to test it could make a starting cycle fail.
+pub fn running_owner(path: &str) -> Option<LockOwner> {
+    read_owner(path).filter(|owner| owner.is_alive())
+}
+
+/// Block until no backup cycle holds the lock at `path`, checking every
+/// `interval`. Returns false if `timeout` passed first.
+pub fn wait_until_free(path: &str, timeout: Option<Duration>, interval: Duration) -> bool {
+    let started = Instant::now();
+    while running_owner(path).is_some() {
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            return false;
+        }
+        std::thread::sleep(interval);
+    }
+    true
+}
+
+/// Remove a lock file left behind, refusing while a running cycle holds
+/// it.
+pub fn break_lock(path: &str) -> Result<(), String> {
+    let file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Failed to open lock file {}: {}", path, e)),
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let owner = read_owner(path)
+                .map(|owner| format!(" (PID {}, started {})", owner.pid, owner.started))
+                .unwrap_or_default();
+            return Err(format!(
+                "Lock file {} is held by a running backup{}; stop it instead of breaking the lock",
+                path, owner
+            ));
+        }
+        Err(TryLockError::Error(e)) => return Err(format!("Failed to lock {}: {}", path, e)),
+    }
+    // Still holding it, so nothing takes the lock on the file going away
+    fs::remove_file(path).map_err(|e| format!("Failed to remove lock file {}: {}", path, e))
+}
@@ -0,0 +1,195 @@
+use chrono::Local;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+
+/// Format of lines written to the log file.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki/ELK ingestion
+    Json,
+}
+
+/// Log file shared by all threads. Events are dropped until it is opened,
+/// so only commands that call `open_file` write to it.
+static LOG_FILE: Mutex<Option<fs::File>> = Mutex::new(None);
+
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", Local::now().format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+struct FileWriter;
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match LOG_FILE.lock() {
+            Ok(mut guard) => match guard.as_mut() {
+                Some(file) => file.write(buf),
+                None => Ok(buf.len()),
+            },
+            Err(_) => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG_FILE.lock() {
+            Ok(mut guard) => match guard.as_mut() {
+                Some(file) => file.flush(),
+                None => Ok(()),
+            },
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Parse a level name such as `info` or `debug`.
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .parse()
+        .map_err(|_| format!("Invalid log level: {}", level))
+}
+
+/// Install the global subscriber: human-readable console output plus the
+/// (not yet opened) log file in the configured format. Console output goes
+/// to stderr with `to_stderr`, for commands whose stdout is data.
+pub fn init(level: LevelFilter, format: LogFormat, to_stderr: bool) {
+    let (ansi, writer) = if to_stderr {
+        (io::stderr().is_terminal(), BoxMakeWriter::new(io::stderr))
+    } else {
+        (io::stdout().is_terminal(), BoxMakeWriter::new(io::stdout))
+    };
+    let console = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_timer(LocalTime)
+        .with_target(false)
+        .with_writer(writer);
+
+    let text = (format == LogFormat::Text).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_timer(LocalTime)
+            .with_target(false)
+            .with_writer(|| FileWriter)
+    });
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(|| FileWriter)
+    });
+
+    let _ = tracing_subscriber::registry()
+        .with(level)
+        .with(console)
+        .with(text)
+        .with(json)
+        .try_init();
+}
+
+/// Start appending log events to `path`.
+pub fn open_file(path: &str) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open log file: {}", e))?;
+
+    if let Ok(mut guard) = LOG_FILE.lock() {
+        *guard = Some(file);
+    }
+    Ok(())
+}
+
+/// Parse a size such as `10M`, `512K`, `1G`, `2T` or a plain byte count.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1024),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        Some('T') | Some('t') => (&size[..size.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("Invalid size: {}", size))
+}
+
+/// Rotate `path` once it reaches `max_size` bytes: `log` becomes `log.1`,
+/// `log.1` becomes `log.2` and so on, keeping at most `keep` rotated files.
+/// Rotated files are gzipped to `log.N.gz` when `compress` is set.
+/// Returns whether a rotation happened.
+pub fn rotate(path: &str, max_size: u64, keep: usize, compress: bool) -> Result<bool, String> {
+    let size = match fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(_) => return Ok(false),
+    };
+    if size < max_size {
+        return Ok(false);
+    }
+
+    let rotated = |n: usize, gz: bool| {
+        if gz {
+            format!("{}.{}.gz", path, n)
+        } else {
+            format!("{}.{}", path, n)
+        }
+    };
+
+    for gz in [false, true] {
+        let _ = fs::remove_file(rotated(keep, gz));
+    }
+    for n in (1..keep).rev() {
+        for gz in [false, true] {
+            let from = rotated(n, gz);
+            if Path::new(&from).exists() {
+                fs::rename(&from, rotated(n + 1, gz))
+                    .map_err(|e| format!("Failed to rotate {}: {}", from, e))?;
+            }
+        }
+    }
+
+    if keep == 0 {
+        fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+        return Ok(true);
+    }
+
+    let first = rotated(1, false);
+    fs::rename(path, &first).map_err(|e| format!("Failed to rotate {}: {}", path, e))?;
+    if compress {
+        gzip(&first, &rotated(1, true))?;
+    }
+    Ok(true)
+}
+
+fn gzip(from: &str, to: &str) -> Result<(), String> {
+    let contents = fs::read(from).map_err(|e| format!("Failed to read {}: {}", from, e))?;
+    let file = fs::File::create(to).map_err(|e| format!("Failed to create {}: {}", to, e))?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder
+        .write_all(&contents)
+        .and_then(|_| encoder.finish().map(|_| ()))
+        .map_err(|e| format!("Failed to compress {}: {}", from, e))?;
+    fs::remove_file(from).map_err(|e| format!("Failed to remove {}: {}", from, e))
+}
@@ -1,6 +1,37 @@
-use borg_timemachine::{BorgBackup, Config};
-use clap::{Parser, Subcommand};
+use borg_timemachine::config_diff::ConfigDifference;
+use borg_timemachine::config_format::ConfigFormat;
+use borg_timemachine::display::{relative_time, Output, Units};
+use borg_timemachine::logging;
+use borg_timemachine::migrate;
+use borg_timemachine::overrides::{self, Override};
+use borg_timemachine::passphrase::PassphraseSource;
+use borg_timemachine::preflight::CheckStatus;
+use borg_timemachine::report::{
+    savings_by_job, ArchiveDetails, CycleOutcome, InitOutcome, RepositoryReport, StageOutcome,
+    Status,
+};
+use borg_timemachine::reporter::Reporter;
+use borg_timemachine::sd_notify::SdNotify;
+use borg_timemachine::signals;
+use borg_timemachine::state::{HistoryEntry, StateStore};
+use borg_timemachine::systemd;
+use borg_timemachine::targets;
+use borg_timemachine::validate;
+use borg_timemachine::wizard;
+use borg_timemachine::{
+    lock, parse_age, parse_timeout, retention, BorgBackup, CheckOptions, Config, ListOptions,
+    ListSort, MountOptions, LATEST,
+};
+use chrono::{Local, NaiveDate, TimeZone, Utc};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use clap_complete::env::CompleteEnv;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
 use std::process;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
 
 #[derive(Parser)]
 #[command(name = "borg-timemachine")]
@@ -10,6 +41,42 @@ struct Cli {
     #[arg(short, long, value_name = "FILE")]
     config: Option<String>,
 
+    /// Show what would be done without modifying the repository
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Remove stale locks (ours and borg's) before running the command
+    #[arg(long, global = true)]
+    break_lock: bool,
+
+    /// Log debug output, including borg progress
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Size units for human output (overrides output.units)
+    #[arg(long, global = true, value_enum)]
+    units: Option<Units>,
+
+    /// strftime format for times (overrides output.date_format)
+    #[arg(long, global = true, value_name = "FORMAT")]
+    date_format: Option<String>,
+
+    /// Show times as "3 hours ago" (overrides output.relative_times)
+    #[arg(long, global = true)]
+    relative_times: bool,
+
+    /// Override a config value, e.g. --set repository.path=/srv/borg
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    /// Work on the repository of one of the configured targets
+    #[arg(long = "repo", global = true, value_name = "TARGET")]
+    repo: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -17,115 +84,1943 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new Borg repository
-    Init,
+    Init {
+        /// Export the new repository's key to this file
+        #[arg(long, value_name = "FILE")]
+        export_key: Option<String>,
+
+        /// Ask for the settings and write the config file first
+        #[arg(long, short)]
+        interactive: bool,
+    },
+
+    /// Manage the repository key
+    Key {
+        #[command(subcommand)]
+        action: KeyCommand,
+    },
 
     /// Run a backup cycle (create, prune, compact)
-    Backup,
+    Backup {
+        /// Comment to store with the new archives, e.g. "pre-upgrade"
+        #[arg(long, value_name = "TEXT")]
+        comment: Option<String>,
+    },
+
+    /// Tasks for the trusted machine of an append-only repository
+    Admin {
+        #[command(subcommand)]
+        action: AdminCommand,
+    },
+
+    /// Prune archives according to the retention policy
+    Prune,
+
+    /// Copy the repository to the `replication` target
+    Replicate,
+
+    /// List the archives in the repository with their statistics
+    List {
+        /// Show deleted archives still in the trash instead
+        #[arg(long)]
+        trash: bool,
+
+        /// Only archives holding this job
+        #[arg(long, value_name = "JOB")]
+        job: Option<String>,
+
+        /// Only archives created at or after this time, e.g. 2024-03-01 or 7d
+        #[arg(long, value_name = "TIME")]
+        since: Option<String>,
+
+        /// Only archives created at or before this time
+        #[arg(long, value_name = "TIME")]
+        until: Option<String>,
+
+        /// Only the newest N archives left after the other filters
+        #[arg(long, value_name = "N")]
+        last: Option<usize>,
+
+        /// Order of the archives
+        #[arg(long, value_enum, default_value_t)]
+        sort: ListSort,
+
+        /// Reverse the order
+        #[arg(long, short)]
+        reverse: bool,
+
+        /// Every host's archives of a shared repository, grouped by host
+        #[arg(long)]
+        all_hosts: bool,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Delete archives (moved to the trash while retention.trash_days > 0)
+    Delete {
+        /// Archive name or glob; defaults to all of this host's archives
+        #[arg(
+            value_name = "ARCHIVE|GLOB",
+            required_unless_present = "older_than",
+            add = ArgValueCompleter::new(complete_archives)
+        )]
+        archive: Option<String>,
 
-    /// List all archives in the repository
-    List,
+        /// Only archives older than this, e.g. 90d, 12w, 6m
+        #[arg(long, value_name = "AGE")]
+        older_than: Option<String>,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Rewrite archives with the current compression and exclusions
+    Recreate {
+        /// Archive name or glob; defaults to all of this host's archives
+        #[arg(value_name = "ARCHIVE|GLOB", add = ArgValueCompleter::new(complete_archives))]
+        archive: Option<String>,
+
+        /// Only archives older than this, e.g. 90d, 12w, 6m
+        #[arg(long, value_name = "AGE")]
+        older_than: Option<String>,
+
+        /// Don't ask for confirmation after the dry run
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Add a label to an archive's comment, e.g. keep
+    Tag {
+        /// Archive name, or `latest`
+        #[arg(value_name = "ARCHIVE", add = ArgValueCompleter::new(complete_archives))]
+        archive: String,
+
+        /// A single word, e.g. keep
+        #[arg(value_name = "LABEL")]
+        label: String,
+
+        /// Take the label off instead
+        #[arg(long)]
+        remove: bool,
+    },
+
+    /// Restore an archive from the trash
+    Undelete {
+        /// Archive name, original or as shown by `list --trash`
+        #[arg(value_name = "ARCHIVE")]
+        archive: String,
+    },
+
+    /// Delete all archives in the trash now
+    EmptyTrash,
+
+    /// Find which archives contain a file, e.g. /etc/hosts or '/home/*/notes.txt'
+    Find {
+        /// Path or glob, as it was on disk
+        #[arg(value_name = "PATH")]
+        pattern: String,
+    },
+
+    /// Restore a file or directory as it was at a point in time
+    RestoreFile {
+        /// Path as it was on disk, e.g. /etc/hosts
+        #[arg(value_name = "PATH")]
+        path: String,
+
+        /// Use the newest archive at or before this time, e.g. 2024-03-01 or 3d
+        #[arg(long, value_name = "TIME")]
+        as_of: Option<String>,
+
+        /// Directory to restore into; the full path is recreated below it
+        #[arg(long, value_name = "DIR", required_unless_present = "stdout")]
+        target: Option<String>,
+
+        /// Write a single file to stdout instead
+        #[arg(long, conflicts_with = "target")]
+        stdout: bool,
+    },
+
+    /// Browse archives in a terminal UI and restore marked files
+    Browse {
+        /// Directory to restore into; full paths are recreated below it
+        #[arg(long, value_name = "DIR", default_value = "restored")]
+        target: String,
+    },
+
+    /// Export an archive as a tarball, compressed by its extension
+    Export {
+        /// Archive name, or `latest`
+        #[arg(value_name = "ARCHIVE", add = ArgValueCompleter::new(complete_archives))]
+        archive: String,
+
+        /// Tarball to write, e.g. snapshot.tar.gz
+        #[arg(value_name = "OUTPUT")]
+        output: String,
+
+        /// Only these paths, e.g. /etc/nginx
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    /// Import a tarball as an archive
+    Import {
+        /// Tarball to read, decompressed by its extension
+        #[arg(value_name = "TARBALL")]
+        tarball: String,
+
+        /// Archive time, e.g. 2024-03-01 [default: the tarball's modification time]
+        #[arg(long, value_name = "WHEN")]
+        timestamp: Option<String>,
+    },
 
     /// Mount the repository for browsing
     Mount {
         /// Mount point directory
         #[arg(value_name = "MOUNT_POINT")]
         mount_point: String,
+
+        /// Mount only this archive (a name or `latest`)
+        #[arg(
+            long,
+            value_name = "ARCHIVE",
+            add = ArgValueCompleter::new(complete_archives)
+        )]
+        archive: Option<String>,
+
+        /// Stay in the foreground until unmounted or interrupted
+        #[arg(long, short)]
+        foreground: bool,
+
+        /// Mount options, e.g. `-o versions` or `-o uid=1000,allow_other`
+        #[arg(short = 'o', value_name = "OPTIONS", value_delimiter = ',')]
+        options: Vec<String>,
+    },
+
+    /// Unmount what `mount` mounted and remove the mount point it created
+    Umount {
+        /// Mount point directory
+        #[arg(value_name = "MOUNT_POINT")]
+        mount_point: String,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Write the manual page to stdout, or one page per command to a directory
+    #[command(hide = true)]
+    Mangen {
+        #[arg(long, value_name = "DIR")]
+        out_dir: Option<String>,
     },
 
     /// Generate an example configuration file
     GenerateConfig {
-        /// Output path for the example config
-        #[arg(value_name = "OUTPUT", default_value = "borg-config.yaml")]
-        output: String,
+        /// Output path for the example config [default: borg-config.yaml]
+        #[arg(value_name = "OUTPUT")]
+        output: Option<String>,
+
+        /// File format; defaults to the one OUTPUT's extension names
+        #[arg(long, value_enum)]
+        format: Option<ConfigFormat>,
     },
 
-    /// Check repository integrity
-    Check,
+    /// Try out the retention policy
+    Retention {
+        #[command(subcommand)]
+        action: RetentionCommand,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+
+    /// Upgrade the config file to the current schema
+    MigrateConfig,
+
+    /// Restore-test an archive and record it as verified
+    Verify {
+        /// Archive name, or `latest`
+        #[arg(value_name = "ARCHIVE", add = ArgValueCompleter::new(complete_archives))]
+        archive: String,
+
+        /// Only record that the archive was restore-tested by hand
+        #[arg(long, conflicts_with = "sample")]
+        manual: bool,
+
+        /// Restore this many random files and compare them with the live ones
+        #[arg(long, value_name = "FILES")]
+        sample: Option<usize>,
+    },
+
+    /// Serve Prometheus metrics from the state file over HTTP
+    Exporter {
+        /// Address to listen on
+        #[arg(long, value_name = "ADDR", default_value = ":9861")]
+        listen: String,
+    },
+
+    /// Remove a stale lock file and run borg break-lock on the repository
+    BreakLock,
 
-    /// Show repository info
-    Info,
+    /// Check the repository, key and configuration for problems
+    Doctor,
+
+    /// Check the configuration without touching the repository
+    Validate,
+
+    /// Show the last backup and the state of any disk rotation
+    Status {
+        /// Print JSON, including each stage's start, end and outcome
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List directories no job backs up, e.g. a forgotten data disk
+    Coverage {
+        /// Only report directories at least this big, e.g. 500M or 2G
+        #[arg(long, value_name = "SIZE", default_value = "100M")]
+        min_size: String,
+
+        /// Also report directories the OS recreates, such as /usr and /boot
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Print the systemd service and timer for `schedule`, or install them
+    InstallSystemd {
+        /// Write the units and reload systemd instead of printing them
+        #[arg(long)]
+        install: bool,
+
+        /// Directory to install the units into
+        #[arg(long, value_name = "DIR", default_value = "/etc/systemd/system")]
+        unit_dir: String,
+    },
+
+    /// Wait until no backup cycle is running; exits 2 if it failed, 124 on timeout
+    Wait {
+        /// Give up after this long, e.g. 90s, 30min, 1h
+        #[arg(long, value_name = "DURATION")]
+        timeout: Option<String>,
+    },
+
+    /// Show recorded backup cycles
+    History {
+        /// Number of most recent cycles to show
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        last: usize,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+
+        /// Total the space borg saved per job over these cycles instead
+        #[arg(long, conflicts_with = "json")]
+        by_job: bool,
+    },
+
+    /// Show restores, exports, mounts and deletions performed through this tool
+    Audit {
+        /// Number of most recent entries to show
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        last: usize,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Run the repository check and compact when they are due
+    Maintenance {
+        /// Run both now, whether due or not
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check repository integrity now
+    Check {
+        /// Also read and verify every data chunk (slow)
+        #[arg(long)]
+        verify_data: bool,
+
+        /// Fix the problems found, after confirmation
+        #[arg(long)]
+        repair: bool,
+
+        /// Check only the archives' metadata
+        #[arg(long, conflicts_with = "repository_only")]
+        archives_only: bool,
+
+        /// Check only the repository's segments and index
+        #[arg(long, conflicts_with_all = ["verify_data", "last"])]
+        repository_only: bool,
+
+        /// Check only the newest N archives
+        #[arg(long, value_name = "N")]
+        last: Option<u32>,
+
+        /// Don't ask for confirmation before repairing
+        #[arg(long, short)]
+        yes: bool,
+    },
+
+    /// Show repository size, chunks, cache and growth, or one archive's statistics
+    Info {
+        /// Archive name, or `latest`
+        #[arg(value_name = "ARCHIVE", add = ArgValueCompleter::new(complete_archives))]
+        archive: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// Prune and compact an append-only repository from a trusted machine
+    Prune {
+        /// The client whose archives to prune [default: hostname from the config]
+        #[arg(long, value_name = "NAME")]
+        hostname: Option<String>,
+    },
+
+    /// Print the authorized_keys line restricting a client to append-only
+    AuthorizedKey {
+        /// The client's SSH public key [default: repository.remote.ssh_key with .pub]
+        #[arg(long, value_name = "FILE")]
+        public_key: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Export the key, to a file or stdout
+    Export {
+        #[arg(value_name = "FILE")]
+        path: Option<String>,
+    },
+
+    /// Export the key in a format meant for printing and typing back in
+    ExportPaper {
+        #[arg(value_name = "FILE")]
+        path: Option<String>,
+    },
+
+    /// Import a key exported with `key export`
+    Import {
+        #[arg(value_name = "FILE")]
+        path: String,
+    },
+
+    /// Change the passphrase; borg prompts unless a file is given
+    ChangePassphrase {
+        /// Read the new passphrase from this file and save it as the passphrase file
+        #[arg(long, value_name = "FILE")]
+        new_passphrase_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RetentionCommand {
+    /// Show how many archives the policy keeps as backups accumulate
+    Simulate {
+        /// Length of the simulated backup history, e.g. 90d or 2y
+        #[arg(long, value_name = "AGE", default_value = "90d")]
+        history: String,
+
+        /// Time between simulated backups, e.g. 1H (the bundled timer) or 1d
+        #[arg(long, value_name = "AGE", default_value = "1H")]
+        interval: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Show how the config differs from the bundled default
+    Diff,
 }
 
 fn main() {
-    let cli = Cli::parse();
+    // Answers the shell when run as `COMPLETE=<shell> borg-timemachine`
+    CompleteEnv::with_factory(Cli::command).complete();
+    let mut cli = Cli::parse();
+
+    // Neither needs a config file
+    if let Commands::Completions { shell } = cli.command {
+        clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            "borg-timemachine",
+            &mut io::stdout(),
+        );
+        return;
+    }
+    if let Commands::Mangen { out_dir } = &cli.command {
+        let result = match out_dir {
+            Some(dir) => clap_mangen::generate_to(Cli::command(), dir),
+            None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: Failed to write manual page: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
     // Handle generate-config separately since it doesn't need a config file
-    if let Commands::GenerateConfig { output } = cli.command {
-        if let Err(e) = BorgBackup::generate_example_config(&output) {
+    if let Commands::GenerateConfig { output, format } = cli.command {
+        let format = format
+            .or_else(|| {
+                output
+                    .as_deref()
+                    .map(|o| ConfigFormat::from_path(Path::new(o)))
+            })
+            .unwrap_or_default();
+        let output = output.unwrap_or_else(|| format.default_file_name().to_string());
+        if let Err(e) = BorgBackup::generate_example_config(&output, format) {
             eprintln!("Error: {}", e);
             process::exit(1);
         }
+        println!("Example configuration written to: {}", output);
         return;
     }
 
+    if let Commands::Config {
+        action: ConfigCommand::Diff,
+    } = cli.command
+    {
+        config_diff(cli.config.as_deref());
+        return;
+    }
+
+    if let Commands::MigrateConfig = cli.command {
+        if let Err(e) = migrate_config(cli.config.as_deref(), cli.dry_run) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // The wizard writes the config the rest of init then loads
+    if let Commands::Init {
+        interactive: true,
+        export_key,
+    } = &mut cli.command
+    {
+        match init_wizard(cli.config.as_deref()) {
+            Ok((path, key_backup)) => {
+                cli.config = Some(path);
+                export_key.get_or_insert(key_backup);
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // backup keeps 1 for cycles that finished with warnings
+    let failed = if matches!(cli.command, Commands::Backup { .. }) {
+        2
+    } else {
+        1
+    };
+
     // Load configuration
-    let config = match Config::load_or_default(cli.config.as_deref()) {
+    let mut overrides = overrides::from_environment();
+    for setting in &cli.set {
+        match Override::parse(setting) {
+            Ok(setting) => overrides.push(setting),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    // validate reports the load-time checks along with its own
+    let loaded = if matches!(cli.command, Commands::Validate) {
+        Config::read(cli.config.as_deref(), &overrides)
+    } else {
+        Config::load_with_overrides(cli.config.as_deref(), &overrides)
+    };
+    let mut config = match loaded {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Error loading configuration: {}", e);
             eprintln!("\nGenerate an example config with:");
             eprintln!("  borg-timemachine generate-config");
-            process::exit(1);
+            process::exit(failed);
         }
     };
+    if let Some(target) = &cli.repo {
+        if let Err(e) = targets::select(&mut config, target) {
+            eprintln!("Error: {}", e);
+            process::exit(failed);
+        }
+    }
 
-    // Load passphrase from file
-    let passphrase = match std::fs::read_to_string(&config.security.passphrase_file) {
-        Ok(p) => p.trim().to_string(),
-        Err(e) => {
-            eprintln!(
-                "Error reading passphrase file {}: {}",
-                config.security.passphrase_file, e
-            );
-            eprintln!("\nCreate the passphrase file with:");
-            eprintln!(
-                "  echo 'your-strong-passphrase' > {}",
-                config.security.passphrase_file
-            );
-            eprintln!("  chmod 600 {}", config.security.passphrase_file);
-            process::exit(1);
+    let level = if cli.verbose {
+        LevelFilter::DEBUG
+    } else if cli.quiet {
+        LevelFilter::WARN
+    } else {
+        match logging::parse_level(&config.logging.level) {
+            Ok(level) => level,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(failed);
+            }
         }
     };
+    // Keep log lines out of file contents written to stdout
+    let log_to_stderr = matches!(cli.command, Commands::RestoreFile { stdout: true, .. });
+    logging::init(level, config.logging.format, log_to_stderr);
+
+    if let Some(units) = cli.units {
+        config.output.units = units;
+    }
+    if let Some(date_format) = &cli.date_format {
+        config.output.date_format = date_format.clone();
+    }
+    if cli.relative_times {
+        config.output.relative_times = true;
+    }
+    if let Err(e) = config.output.validate() {
+        eprintln!("Error: {}", e);
+        process::exit(failed);
+    }
+
+    // History only reads the local state file
+    if let Commands::History { last, json, by_job } = cli.command {
+        let result = if by_job {
+            show_job_savings(&config.logging.state_file, &config.output, last)
+        } else {
+            show_history(&config.logging.state_file, &config.output, last, json)
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Commands::Retention {
+        action: RetentionCommand::Simulate { history, interval },
+    } = &cli.command
+    {
+        if let Err(e) = simulate_retention(&config, history, interval) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Commands::Wait { timeout } = &cli.command {
+        match wait(&config, timeout.as_deref()) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Commands::Audit { last, json } = cli.command {
+        if let Err(e) = show_audit(&config.logging.state_file, &config.output, last, json) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Commands::Validate = cli.command {
+        let results = validate::validate(&config);
+        for result in &results {
+            println!("{}", result);
+        }
+        let failures = results
+            .iter()
+            .filter(|r| r.status == CheckStatus::Fail)
+            .count();
+        if failures > 0 {
+            eprintln!("\n{} problem(s) found", failures);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Commands::InstallSystemd { install, unit_dir } = &cli.command {
+        if let Err(e) = install_systemd(&config, cli.config.as_deref(), *install, unit_dir) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Coverage only looks at the local filesystems
+    if let Commands::Coverage { min_size, all } = &cli.command {
+        let result = logging::parse_size(min_size).and_then(|min_size| {
+            let backup = BorgBackup::new(config)?;
+            show_coverage(&backup, min_size, *all)
+        });
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Status reads the state file and the attached disks, and the
+    // repository only when it is shared, for the other hosts; failing
+    // to leaves out just those
+    if let Commands::Status { json } = cli.command {
+        let output = config.output.clone();
+        let shared = config.repository.shared;
+        let result = BorgBackup::new(config)
+            .and_then(|mut b| {
+                let mut status = b.status()?;
+                if shared {
+                    match b.load_passphrase().and_then(|_| b.host_status()) {
+                        Ok(hosts) => status.hosts = hosts,
+                        Err(e) => status.hosts_unavailable = Some(e),
+                    }
+                }
+                Ok(status)
+            })
+            .and_then(|status| show_status(&status, &output, json));
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // The exporter only reads the local state file
+    if let Commands::Exporter { listen } = &cli.command {
+        let result = BorgBackup::new(config).and_then(|b| b.run_exporter(listen));
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // With targets, each gets its cycle in a process of its own
+    if matches!(cli.command, Commands::Backup { .. }) && !config.targets.is_empty() {
+        process::exit(backup_targets(&config));
+    }
 
-    // Set passphrase environment variable for borg
-    std::env::set_var("BORG_PASSPHRASE", passphrase);
+    let source = config.security.passphrase_source();
 
     // Create BorgBackup instance
     let mut backup = match BorgBackup::new(config) {
         Ok(b) => b,
         Err(e) => {
             eprintln!("Error: {}", e);
-            process::exit(1);
+            process::exit(failed);
         }
     };
 
+    if let Err(e) = backup.load_passphrase() {
+        eprintln!("Error: {}", e);
+        if let PassphraseSource::File { path } = source {
+            eprintln!("\nCreate the passphrase file with:");
+            eprintln!("  echo 'your-strong-passphrase' > {}", path);
+            eprintln!("  chmod 600 {}", path);
+        }
+        process::exit(failed);
+    }
+
+    backup.set_dry_run(cli.dry_run);
+    backup.set_interactive(io::stdin().is_terminal());
+    backup.set_reporter(Terminal);
+
+    if cli.break_lock && !matches!(cli.command, Commands::BreakLock) {
+        if let Err(e) = backup.break_lock() {
+            eprintln!("Error: {}", e);
+            process::exit(failed);
+        }
+    }
+
     // Execute command
     let result = match cli.command {
-        Commands::Init => backup.init_repository(),
-        Commands::Backup => backup.run_backup_cycle(),
-        Commands::List => backup.list_archives(),
-        Commands::Mount { mount_point } => backup.mount_repository(&mount_point),
-        Commands::Check => backup.check_repository(),
-        Commands::Info => {
-            use std::process::Command;
-            let repo_path = backup.get_repo_path();
-            Command::new("borg")
-                .args(["info", &repo_path])
-                .status()
-                .map_err(|e| format!("Failed to run borg info: {}", e))
-                .and_then(|status| {
-                    if !status.success() {
-                        Err("borg info failed".to_string())
-                    } else {
-                        Ok(())
-                    }
-                })
+        Commands::Init { export_key, .. } => backup
+            .init_repository(export_key.as_deref())
+            .map(|outcome| show_init(&outcome)),
+        Commands::Key { action } => key(&mut backup, action),
+        Commands::Admin { action } => admin(&mut backup, action),
+        Commands::Backup { comment } => {
+            backup.set_comment(comment);
+            let outcome = backup.run_backup_cycle();
+            if let CycleOutcome::Failed(e) = &outcome {
+                eprintln!("Error: {}", e);
+            }
+            drop(backup);
+            process::exit(match signals::interrupted() {
+                Some(signal) => signals::exit_code(signal),
+                None => outcome.exit_code(),
+            });
+        }
+        Commands::Prune => backup.prune_backups(),
+        Commands::Replicate => backup.replicate(),
+        Commands::BreakLock => backup.break_lock(),
+        Commands::Doctor => {
+            let results = backup.doctor();
+            for result in &results {
+                println!("{}", result);
+            }
+            let failures = results
+                .iter()
+                .filter(|r| r.status == CheckStatus::Fail)
+                .count();
+            if failures > 0 {
+                Err(format!("{} check(s) failed", failures))
+            } else {
+                Ok(())
+            }
         }
-        Commands::GenerateConfig { .. } => unreachable!(),
+        Commands::List {
+            trash,
+            job,
+            since,
+            until,
+            last,
+            sort,
+            reverse,
+            all_hosts,
+            json,
+        } => list(
+            &backup,
+            ListOptions {
+                trash,
+                job,
+                last,
+                sort,
+                reverse,
+                all_hosts,
+                ..ListOptions::default()
+            },
+            since.as_deref(),
+            until.as_deref(),
+            json,
+        ),
+        Commands::Delete {
+            archive,
+            older_than,
+            yes,
+        } => delete(&mut backup, archive.as_deref(), older_than.as_deref(), yes),
+        Commands::Recreate {
+            archive,
+            older_than,
+            yes,
+        } => recreate(&mut backup, archive.as_deref(), older_than.as_deref(), yes),
+        Commands::Tag {
+            archive,
+            label,
+            remove,
+        } => backup.tag_archive(&archive, &label, remove),
+        Commands::Undelete { archive } => backup.undelete_archive(&archive),
+        Commands::EmptyTrash => backup.empty_trash(),
+        Commands::Verify {
+            archive,
+            sample: Some(count),
+            ..
+        } => backup.verify_sample_and_notify(&archive, count),
+        Commands::Verify {
+            archive, manual, ..
+        } => backup.verify_restore(&archive, manual),
+        Commands::Find { pattern } => find(&backup, &pattern),
+        Commands::RestoreFile {
+            path,
+            as_of,
+            target,
+            ..
+        } => restore_file(&mut backup, &path, as_of.as_deref(), target.as_deref()),
+        Commands::Browse { target } => backup.browse(&target),
+        Commands::Export {
+            archive,
+            output,
+            paths,
+        } => backup.export_tar(&archive, &output, &paths),
+        Commands::Import { tarball, timestamp } => {
+            import_tar(&mut backup, &tarball, timestamp.as_deref())
+        }
+        Commands::Mount {
+            mount_point,
+            archive,
+            foreground,
+            options,
+        } => backup
+            .mount_repository(
+                &mount_point,
+                &MountOptions {
+                    archive,
+                    foreground,
+                    fuse_options: options,
+                },
+            )
+            .map(|()| {
+                if !foreground {
+                    println!("Mounted successfully!");
+                    println!("Browse backups: ls {}", mount_point);
+                    println!("Unmount with: borg-timemachine umount {}", mount_point);
+                }
+            }),
+        Commands::Umount { mount_point } => backup
+            .umount_repository(&mount_point)
+            .map(|()| println!("Unmounted {}", mount_point)),
+        Commands::Maintenance { force } => backup.run_maintenance(force),
+        Commands::Check {
+            verify_data,
+            repair,
+            archives_only,
+            repository_only,
+            last,
+            yes,
+        } => check(
+            &mut backup,
+            CheckOptions {
+                verify_data,
+                repair,
+                archives_only,
+                repository_only,
+                last,
+            },
+            yes,
+        ),
+        Commands::Info { archive } => backup
+            .repository_info(archive.as_deref())
+            .map(|report| show_info(&report, &backup.config().output)),
+        Commands::GenerateConfig { .. }
+        | Commands::Completions { .. }
+        | Commands::Mangen { .. }
+        | Commands::Config { .. }
+        | Commands::MigrateConfig
+        | Commands::History { .. }
+        | Commands::Retention { .. }
+        | Commands::Wait { .. }
+        | Commands::Coverage { .. }
+        | Commands::Validate
+        | Commands::InstallSystemd { .. }
+        | Commands::Audit { .. }
+        | Commands::Status { .. }
+        | Commands::Exporter { .. } => unreachable!(),
     };
 
     if let Err(e) = result {
         eprintln!("Error: {}", e);
-        process::exit(1);
+        match signals::interrupted() {
+            Some(signal) => process::exit(signals::exit_code(signal)),
+            None => process::exit(1),
+        }
+    }
+}
+
+fn complete_archives(current: &OsStr) -> Vec<CompletionCandidate> {
+    let prefix = current.to_string_lossy();
+    let args: Vec<String> = std::env::args().collect();
+    let names = completion_archive_names(&args).unwrap_or_default();
+    std::iter::once(LATEST.to_string())
+        .chain(names)
+        .filter(|name| name.starts_with(prefix.as_ref()))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+fn completion_archive_names(args: &[String]) -> Result<Vec<String>, String> {
+    let option = |long: &str, short: Option<&str>| {
+        args.iter().enumerate().find_map(|(i, arg)| {
+            if let Some(value) = arg.strip_prefix(long).and_then(|a| a.strip_prefix('=')) {
+                return Some(value.to_string());
+            }
+            if arg == long || Some(arg.as_str()) == short {
+                return args.get(i + 1).cloned();
+            }
+            None
+        })
+    };
+    let mut config = Config::load_or_default(option("--config", Some("-c")).as_deref())?;
+    if let Some(target) = option("--repo", None) {
+        targets::select(&mut config, &target)?;
+    }
+    let mut backup = BorgBackup::new(config)?;
+    backup.load_passphrase()?;
+    backup.archive_names()
+}
+
+fn backup_targets(config: &Config) -> i32 {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error: Failed to find our own executable: {}", e);
+            return 2;
+        }
+    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let names = targets::names(config);
+
+    // Only this process may talk to systemd (NotifyAccess=main)
+    let notify = SdNotify::from_env();
+    if let Some(notify) = &notify {
+        let _ = notify.send(&format!(
+            "READY=1\nSTATUS=Backing up to {}",
+            names.join(", ")
+        ));
+    }
+    // Pinged as the targets' cycles write output
+    let watchdog = notify.as_ref().and_then(SdNotify::watchdog);
+
+    let results = targets::run_parallel(
+        &names,
+        config.options.max_parallel,
+        |name| {
+            let mut cmd = process::Command::new(&exe);
+            cmd.args(&args)
+                .args(["--repo", name])
+                .env_remove("NOTIFY_SOCKET");
+            cmd
+        },
+        |name, stream, line| {
+            if let Some(watchdog) = &watchdog {
+                watchdog.ping();
+            }
+            match stream {
+                targets::Stream::Stdout => println!("[{}] {}", name, line),
+                targets::Stream::Stderr => eprintln!("[{}] {}", name, line),
+            }
+        },
+    );
+
+    for (name, code) in &results {
+        let outcome = match code {
+            0 => "succeeded",
+            1 => "succeeded with warnings",
+            _ => "failed",
+        };
+        println!("Target {}: {} (exit code {})", name, outcome, code);
+    }
+    let code = results.iter().map(|(_, code)| *code).max().unwrap_or(0);
+    if let Some(notify) = &notify {
+        let _ = notify.send("STOPPING=1\nSTATUS=Backup cycles complete");
+    }
+    code
+}
+
+fn delete(
+    backup: &mut BorgBackup,
+    pattern: Option<&str>,
+    older_than: Option<&str>,
+    yes: bool,
+) -> Result<(), String> {
+    let archives = backup.select_archives(pattern, older_than)?;
+    if archives.is_empty() {
+        println!("No archives match");
+        return Ok(());
+    }
+
+    println!("Archives to delete:");
+    for archive in &archives {
+        println!("  {}", archive);
+    }
+
+    if !yes && !backup.is_dry_run() {
+        confirm("delete", &format!("Delete {} archive(s)?", archives.len()))?;
+    }
+
+    backup.delete_archives(&archives)
+}
+
+fn recreate(
+    backup: &mut BorgBackup,
+    pattern: Option<&str>,
+    older_than: Option<&str>,
+    yes: bool,
+) -> Result<(), String> {
+    let archives = backup.select_archives(pattern, older_than)?;
+    if archives.is_empty() {
+        println!("No archives match");
+        return Ok(());
+    }
+
+    backup.recreate_archives(&archives, || {
+        if yes {
+            return Ok(());
+        }
+        confirm(
+            "recreate",
+            &format!(
+                "Rewrite {} archive(s)? Excluded files are gone from them for good",
+                archives.len()
+            ),
+        )
+    })
+}
+
+fn check(backup: &mut BorgBackup, options: CheckOptions, yes: bool) -> Result<(), String> {
+    if options.repair && !yes && !backup.is_dry_run() {
+        eprintln!(
+            "borg check --repair rewrites the repository to make it consistent and \
+             may delete data it cannot recover. Back up the repository directory first \
+             if you can."
+        );
+        confirm("repair", "Repair the repository?")?;
+    }
+    backup.check_now(&options)
+}
+
+fn confirm(action: &str, question: &str) -> Result<(), String> {
+    if !io::stdin().is_terminal() {
+        return Err(format!(
+            "Refusing to {} without --yes when not run interactively",
+            action
+        ));
+    }
+    if !ask(question)? {
+        return Err("Aborted".to_string());
+    }
+    Ok(())
+}
+
+fn ask(question: &str) -> Result<bool, String> {
+    print!("{} [y/N] ", question);
+    io::stdout()
+        .flush()
+        .map_err(|e| format!("Failed to write prompt: {}", e))?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Failed to read answer: {}", e))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+struct Terminal;
+
+impl Reporter for Terminal {
+    fn message(&self, text: &str) {
+        println!("{}", text);
+    }
+
+    fn confirm(&self, question: &str) -> Result<bool, String> {
+        ask(question)
+    }
+}
+
+fn find(backup: &BorgBackup, pattern: &str) -> Result<(), String> {
+    let found = backup.find_files(pattern)?;
+    let (archive, _) = match found.last() {
+        Some(last) => last,
+        None => {
+            println!("{} is not in any archive", pattern);
+            return Ok(());
+        }
+    };
+
+    let output = &backup.config().output;
+    println!("{:<20} {:>10}  {:<32} PATH", "MODIFIED", "SIZE", "ARCHIVE");
+    for (archive, file) in &found {
+        println!(
+            "{:<20} {:>10}  {:<32} /{}",
+            output.time(file.mtime),
+            output.bytes(file.size),
+            archive,
+            file.path
+        );
+    }
+    println!("\nMost recent archive with a match: {}", archive);
+    Ok(())
+}
+
+fn admin(backup: &mut BorgBackup, action: AdminCommand) -> Result<(), String> {
+    match action {
+        AdminCommand::Prune { hostname } => backup.admin_prune(hostname.as_deref()),
+        AdminCommand::AuthorizedKey { public_key } => {
+            let path = public_key
+                .or_else(|| {
+                    let remote = backup.config().repository.remote.as_ref()?;
+                    remote.ssh_key.as_ref().map(|key| format!("{}.pub", key))
+                })
+                .ok_or("Give the client's public key with --public-key")?;
+            let key = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            println!("{}", backup.authorized_keys_line(&key)?);
+            Ok(())
+        }
+    }
+}
+
+fn key(backup: &mut BorgBackup, action: KeyCommand) -> Result<(), String> {
+    match action {
+        KeyCommand::Export { path } => export_key(backup, path, false),
+        KeyCommand::ExportPaper { path } => export_key(backup, path, true),
+        KeyCommand::Import { path } => {
+            backup.import_key(&path)?;
+            if !backup.is_dry_run() {
+                println!("Key imported from {}", path);
+            }
+            Ok(())
+        }
+        KeyCommand::ChangePassphrase {
+            new_passphrase_file,
+        } => {
+            let passphrase = new_passphrase_file
+                .map(|file| {
+                    std::fs::read_to_string(&file)
+                        .map(|p| p.trim().to_string())
+                        .map_err(|e| format!("Failed to read {}: {}", file, e))
+                })
+                .transpose()?;
+            if passphrase.as_deref() == Some("") {
+                return Err("The new passphrase is empty".to_string());
+            }
+            let updated = backup.change_passphrase(passphrase.as_deref())?;
+            if backup.is_dry_run() {
+                return Ok(());
+            }
+            println!("Passphrase changed");
+            match updated {
+                Some(path) => println!("Updated {}", path),
+                None => println!(
+                    "Now update the passphrase {} or scheduled backups will fail",
+                    backup.config().security.passphrase_source().describe()
+                ),
+            }
+            Ok(())
+        }
+    }
+}
+
+fn export_key(backup: &mut BorgBackup, path: Option<String>, paper: bool) -> Result<(), String> {
+    backup.export_key(path.as_deref(), paper)?;
+    if let Some(path) = path {
+        println!("Key exported to {}", path);
+    }
+    Ok(())
+}
+
+fn list(
+    backup: &BorgBackup,
+    mut options: ListOptions,
+    since: Option<&str>,
+    until: Option<&str>,
+    json: bool,
+) -> Result<(), String> {
+    let now = Utc::now();
+    if let Some(since) = since {
+        // A bare date means from the start of that day
+        let start_of_day = NaiveDate::parse_from_str(since, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .and_then(|time| Local.from_local_datetime(&time).earliest())
+            .map(|time| time.with_timezone(&Utc));
+        options.since = Some(match start_of_day {
+            Some(time) => time,
+            None => borg_timemachine::parse_point_in_time(since, now)?,
+        });
+    }
+    if let Some(until) = until {
+        options.until = Some(borg_timemachine::parse_point_in_time(until, now)?);
+    }
+    let archives = backup.archive_details(&options)?;
+    if json {
+        let json = serde_json::to_string_pretty(&archives)
+            .map_err(|e| format!("Failed to serialize archives: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+    if !options.all_hosts {
+        show_archives(&archives, &backup.config().output);
+        return Ok(());
+    }
+    let mut hostnames: Vec<&str> = archives.iter().map(|a| a.hostname.as_str()).collect();
+    hostnames.sort();
+    hostnames.dedup();
+    for (index, hostname) in hostnames.iter().enumerate() {
+        let of_host: Vec<ArchiveDetails> = archives
+            .iter()
+            .filter(|a| a.hostname == *hostname)
+            .cloned()
+            .collect();
+        if index > 0 {
+            println!();
+        }
+        println!("{} ({} archives)", hostname, of_host.len());
+        show_archives(&of_host, &backup.config().output);
+    }
+    Ok(())
+}
+
+fn show_archives(archives: &[ArchiveDetails], output: &Output) {
+    if archives.is_empty() {
+        return;
+    }
+    let width = archives.iter().map(|a| a.name.len()).max().unwrap_or(0);
+    println!(
+        "{:<width$}  {:<20} {:>9} {:>10} {:>10} {:>9}",
+        "ARCHIVE",
+        "TIME",
+        "DURATION",
+        "ORIGINAL",
+        "DEDUP",
+        "FILES",
+        width = width
+    );
+    for archive in archives {
+        let marker = if archive.verified { "  [verified]" } else { "" };
+        let comment = if archive.comment.is_empty() {
+            String::new()
+        } else {
+            format!("  {}", archive.comment)
+        };
+        println!(
+            "{:<width$}  {:<20} {:>8.0}s {:>10} {:>10} {:>9}{}{}",
+            archive.name,
+            output.time(archive.time),
+            archive.duration_secs,
+            output.bytes(archive.stats.original_size),
+            output.bytes(archive.stats.deduplicated_size),
+            archive.stats.nfiles,
+            marker,
+            comment,
+            width = width
+        );
+    }
+}
+
+fn show_init(outcome: &InitOutcome) {
+    println!("Repository initialized successfully!");
+    if let Some(path) = &outcome.key_exported_to {
+        println!("Key exported to {}", path);
+        println!("Keep a copy of {} somewhere other than this machine.", path);
+        return;
+    }
+    println!("\nIMPORTANT: Export and backup your encryption key:");
+    println!(
+        "  borg key export {} ~/borg-key-backup.txt",
+        outcome.location
+    );
+    println!(
+        "  borg key export --paper {} borg-key-qr.html",
+        outcome.location
+    );
+}
+
+fn show_info(report: &RepositoryReport, output: &Output) {
+    let info = &report.info;
+    let stats = &info.stats;
+    if let Some(archive) = &info.archive {
+        println!("Archive:            {}", archive.name);
+        println!("Time:               {}", output.time(archive.time));
+        println!("Duration:           {:.0}s", archive.duration_secs);
+        println!("Files:              {}", archive.stats.nfiles);
+        println!(
+            "Original size:      {}",
+            output.bytes(archive.stats.original_size)
+        );
+        println!(
+            "Compressed size:    {}",
+            output.bytes(archive.stats.compressed_size)
+        );
+        println!(
+            "Deduplicated size:  {} ({:.0}% saved)",
+            output.bytes(archive.stats.deduplicated_size),
+            archive.stats.savings() * 100.0
+        );
+        println!();
+    }
+
+    println!("Repository:         {}", info.location);
+    println!("Repository ID:      {}", info.id);
+    println!("Encryption:         {}", info.encryption);
+    if let Some(modified) = info.last_modified {
+        println!("Last modified:      {}", output.time(modified));
+    }
+    println!(
+        "All archives:       {} original, {} compressed",
+        output.bytes(stats.total_size),
+        output.bytes(stats.total_csize)
+    );
+    println!("Deduplicated size:  {}", output.bytes(stats.unique_csize));
+    println!(
+        "Chunks:             {} unique, {} in total",
+        stats.total_unique_chunks, stats.total_chunks
+    );
+    println!("Cache:              {}", info.cache_path);
+    if !report.growth.is_empty() {
+        println!("Growth:             {}", report.growth.join(", "));
+    }
+}
+
+fn show_status(status: &Status, output: &Output, json: bool) -> Result<(), String> {
+    if json {
+        let json = serde_json::to_string_pretty(status)
+            .map_err(|e| format!("Failed to serialize status: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    match &status.last_cycle {
+        Some(entry) => {
+            println!(
+                "Last backup: {} ({}){}",
+                output.time(entry.started),
+                if entry.success { "ok" } else { "failed" },
+                entry
+                    .archive
+                    .as_ref()
+                    .map(|a| format!(", archive {}", a))
+                    .unwrap_or_default()
+            );
+            for stage in &entry.stages {
+                let outcome = match stage.outcome {
+                    Some(StageOutcome::Success) => "ok",
+                    Some(StageOutcome::Warning) => "warning",
+                    Some(StageOutcome::Failed) => "failed",
+                    None => "-",
+                };
+                println!(
+                    "  {:<10} {:>8.1}s  {}",
+                    stage.name, stage.duration_secs, outcome
+                );
+            }
+        }
+        None => println!("Last backup: never"),
+    }
+
+    if let Some(e) = &status.hosts_unavailable {
+        println!("\nHosts of the shared repository: unavailable ({})", e);
+    }
+    if !status.hosts.is_empty() {
+        println!(
+            "\nHosts of the shared repository (max age {}):",
+            status.host_max_age
+        );
+        for host in &status.hosts {
+            println!(
+                "  {:<24} {:>5} archives  last backup {}",
+                host.hostname,
+                host.archives,
+                output.time(host.last_backup)
+            );
+        }
+        for host in status.hosts.iter().filter(|host| host.stale) {
+            println!(
+                "WARNING: host {} has not backed up within {}",
+                host.hostname, status.host_max_age
+            );
+        }
+    }
+
+    if status.disks.is_empty() {
+        return Ok(());
+    }
+
+    println!("\nRotation disks (max age {}):", status.rotation_max_age);
+    for disk in &status.disks {
+        let last = disk
+            .last_backup
+            .map(|time| output.time(time))
+            .unwrap_or_else(|| "never".to_string());
+        let attached = if disk.attached { "attached" } else { "-" };
+        println!(
+            "  {:<12} {:<36} {:<8} last backup {}",
+            disk.name, disk.uuid, attached, last
+        );
+    }
+    for disk in status.disks.iter().filter(|disk| disk.stale) {
+        println!(
+            "WARNING: disk {} has not had a backup within {}",
+            disk.name, status.rotation_max_age
+        );
+    }
+    Ok(())
+}
+
+fn restore_file(
+    backup: &mut BorgBackup,
+    path: &str,
+    as_of: Option<&str>,
+    target: Option<&str>,
+) -> Result<(), String> {
+    let now = Utc::now();
+    let as_of = match as_of {
+        Some(when) => borg_timemachine::parse_point_in_time(when, now)?,
+        None => now,
+    };
+    backup.restore_file(path, as_of, target)
+}
+
+fn import_tar(
+    backup: &mut BorgBackup,
+    tarball: &str,
+    timestamp: Option<&str>,
+) -> Result<(), String> {
+    let time = timestamp
+        .map(|when| borg_timemachine::parse_point_in_time(when, Utc::now()))
+        .transpose()?;
+    backup.import_tar(tarball, time).map(|_| ())
+}
+
+fn init_wizard(config: Option<&str>) -> Result<(String, String), String> {
+    let mut prompt = wizard::Prompt::new(io::stdin().lock(), io::stdout());
+    let default_path = config.unwrap_or("/etc/borg/borg-config.yaml");
+    let path = prompt.ask("Config file to write", Some(default_path))?;
+    if Path::new(&path).exists() {
+        return Err(format!(
+            "{} already exists; remove it or choose another path",
+            path
+        ));
+    }
+    let answers = prompt.run()?;
+
+    if let Some(dir) = Path::new(&path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&path, answers.to_config()?)
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    println!("\nConfiguration written to: {}", path);
+
+    let passphrase = Path::new(&answers.passphrase_file);
+    if passphrase.exists() {
+        println!("Using the existing passphrase in {}", passphrase.display());
+    } else {
+        wizard::write_passphrase_file(passphrase)?;
+        println!("Random passphrase written to: {}", passphrase.display());
+        println!("Without it the backups cannot be read; keep a copy somewhere safe.");
+    }
+    println!();
+    Ok((path, answers.key_backup))
+}
+
+fn migrate_config(path: Option<&str>, dry_run: bool) -> Result<(), String> {
+    let path = path.ok_or("Give the config file to migrate with --config")?;
+    let Some(migrated) = migrate::migrate_file(path, !dry_run)? else {
+        println!("{} is at config version {}", path, migrate::CURRENT);
+        return Ok(());
+    };
+
+    for line in &migrated.diff {
+        println!("{}", line);
+    }
+    for change in &migrated.changes {
+        println!("  {}", change);
+    }
+    if dry_run {
+        println!(
+            "Would migrate {} from config version {} to {}",
+            path,
+            migrated.from,
+            migrate::CURRENT
+        );
+        if migrated.drops_comments {
+            println!("Its comments would be lost, so it has to be migrated by hand");
+        }
+        return Ok(());
+    }
+    println!(
+        "Migrated {} from config version {} to {}; the original is in {}.bak",
+        path,
+        migrated.from,
+        migrate::CURRENT,
+        path
+    );
+    Ok(())
+}
+
+fn config_diff(path: Option<&str>) {
+    let path = match path {
+        Some(p) => p,
+        None => {
+            println!("No --config given; the bundled default is in use.");
+            return;
+        }
+    };
+
+    let differences = match Config::diff_against_default(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if differences.is_empty() {
+        println!("{} matches the bundled default.", path);
+        return;
+    }
+
+    let (mut customized, mut not_set, mut extra) = (Vec::new(), Vec::new(), Vec::new());
+    for d in &differences {
+        match d {
+            ConfigDifference::Customized { .. } => customized.push(d),
+            ConfigDifference::NotSet { .. } => not_set.push(d),
+            ConfigDifference::Extra { .. } => extra.push(d),
+        }
+    }
+
+    for (title, entries) in [
+        ("Customized values:", customized),
+        ("Available options not set (defaults apply):", not_set),
+        ("Not in the bundled default:", extra),
+    ] {
+        if entries.is_empty() {
+            continue;
+        }
+        println!("{}", title);
+        for entry in entries {
+            println!("{}", entry);
+        }
+        println!();
+    }
+}
+
+fn install_systemd(
+    config: &Config,
+    config_path: Option<&str>,
+    install: bool,
+    unit_dir: &str,
+) -> Result<(), String> {
+    let config_path = config_path.ok_or("The units need a config file; pass it with --config")?;
+    let config_path = Path::new(config_path)
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve {}: {}", config_path, e))?;
+    let binary = std::env::current_exe()
+        .map_err(|e| format!("Failed to find the borg-timemachine binary: {}", e))?;
+    let units = [
+        (
+            systemd::SERVICE_NAME,
+            config.schedule.service_unit(&binary, &config_path)?,
+        ),
+        (systemd::TIMER_NAME, config.schedule.timer_unit()?),
+    ];
+
+    if !install {
+        for (i, (name, unit)) in units.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("# {}", name);
+            print!("{}", unit);
+        }
+        return Ok(());
+    }
+
+    for (name, unit) in &units {
+        let path = Path::new(unit_dir).join(name);
+        std::fs::write(&path, unit)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+    }
+    let status = process::Command::new("systemctl")
+        .arg("daemon-reload")
+        .status()
+        .map_err(|e| format!("Failed to run systemctl: {}", e))?;
+    if !status.success() {
+        return Err("systemctl daemon-reload failed".to_string());
+    }
+    println!("\nEnable the schedule with:");
+    println!("  systemctl enable --now {}", systemd::TIMER_NAME);
+    Ok(())
+}
+
+fn show_coverage(backup: &BorgBackup, min_size: u64, all: bool) -> Result<(), String> {
+    let output = &backup.config().output;
+    let gaps = backup.coverage_gaps(min_size, all)?;
+    if gaps.is_empty() {
+        println!(
+            "Every directory of {} or more is backed up or excluded",
+            output.bytes(min_size)
+        );
+        return Ok(());
+    }
+
+    println!("Not backed up ({} or more each):", output.bytes(min_size));
+    for gap in &gaps {
+        println!("  {:>10}  {}", output.bytes(gap.size), gap.path.display());
+    }
+    println!("\nAdd a job for these, or exclude them to mark them as left out on purpose.");
+    Ok(())
+}
+
+const EXIT_CYCLE_FAILED: i32 = 2;
+const EXIT_TIMEOUT: i32 = 124;
+
+fn wait(config: &Config, timeout: Option<&str>) -> Result<i32, String> {
+    let timeout = timeout.map(parse_timeout).transpose()?;
+    let lock_file = &config.lock_file();
+
+    if let Some(owner) = lock::running_owner(lock_file) {
+        println!(
+            "Waiting for the backup cycle (PID {}, started {}) to finish...",
+            owner.pid, owner.started
+        );
+        if !lock::wait_until_free(lock_file, timeout, Duration::from_secs(1)) {
+            println!("Timed out; the backup cycle is still running");
+            return Ok(EXIT_TIMEOUT);
+        }
+    }
+
+    let history = StateStore::new(&config.logging.state_file).history()?;
+    Ok(match history.last() {
+        None => {
+            println!("No backup cycles recorded");
+            0
+        }
+        Some(entry) if entry.success => {
+            println!("Last backup cycle succeeded");
+            0
+        }
+        Some(entry) => {
+            println!(
+                "Last backup cycle failed: {}",
+                entry.error.as_deref().unwrap_or("unknown error")
+            );
+            EXIT_CYCLE_FAILED
+        }
+    })
+}
+
+fn show_history(state_file: &str, output: &Output, last: usize, json: bool) -> Result<(), String> {
+    let history = StateStore::new(state_file).history()?;
+    let start = history.len().saturating_sub(last);
+    let entries: &[HistoryEntry] = &history[start..];
+
+    if json {
+        let json = serde_json::to_string_pretty(entries)
+            .map_err(|e| format!("Failed to serialize history: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No backup cycles recorded in {}", state_file);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:>9} {:<8} {:>10} {:>10} {:>9}  ARCHIVE",
+        "STARTED", "DURATION", "RESULT", "ORIGINAL", "DEDUP", "FILES"
+    );
+    for entry in entries {
+        let result = match (entry.success, entry.warnings.is_empty()) {
+            (false, _) => "failed",
+            (true, false) => "warning",
+            (true, true) => "ok",
+        };
+        let (original, dedup, files) = match &entry.stats {
+            Some(s) => (
+                output.bytes(s.original_size),
+                output.bytes(s.deduplicated_size),
+                s.nfiles.to_string(),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        println!(
+            "{:<20} {:>8.0}s {:<8} {:>10} {:>10} {:>9}  {}",
+            output.time(entry.started),
+            entry.duration_secs,
+            result,
+            original,
+            dedup,
+            files,
+            entry.archive.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+fn simulate_retention(config: &Config, history: &str, interval: &str) -> Result<(), String> {
+    let history_age = parse_age(history)?;
+    let interval_age = parse_age(interval)?;
+    let now = Utc::now();
+    let retention = &config.retention;
+    let kept = retention::simulate(retention, history_age, interval_age, now)?;
+    let total = (history_age.num_seconds() / interval_age.num_seconds().max(1)).max(1);
+
+    println!(
+        "Backing up every {} for {} keeps {} of {} archives:",
+        interval,
+        history,
+        kept.len(),
+        total
+    );
+    for rule in ["within", "hourly", "daily", "weekly", "monthly", "yearly"] {
+        let count = kept.iter().filter(|(_, r)| *r == rule).count();
+        if count > 0 {
+            println!("  {:<8} {:>5}", rule, count);
+        }
+    }
+    if let Some((oldest, _)) = kept.last() {
+        println!("Oldest kept archive: {}", relative_time(*oldest, now));
+    }
+
+    println!("\nArchives kept as the history grows:");
+    for checkpoint in ["1d", "7d", "30d", "90d", "6m", "1y", "2y", "5y"] {
+        let age = parse_age(checkpoint)?;
+        if age >= history_age {
+            break;
+        }
+        let kept = retention::simulate(retention, age, interval_age, now)?;
+        println!("  after {:<4} {:>5}", checkpoint, kept.len());
+    }
+    println!("  after {:<4} {:>5}", history, kept.len());
+    Ok(())
+}
+
+fn show_job_savings(state_file: &str, output: &Output, last: usize) -> Result<(), String> {
+    let history = StateStore::new(state_file).history()?;
+    let entries = &history[history.len().saturating_sub(last)..];
+    let totals = savings_by_job(entries.iter().flat_map(|entry| &entry.job_stats));
+    if totals.is_empty() {
+        println!("No per-job statistics recorded in {}", state_file);
+        return Ok(());
+    }
+
+    println!(
+        "{:>10} {:>10} {:>10} {:>6}  JOBS",
+        "ORIGINAL", "COMPRESSED", "NEW", "SAVED"
+    );
+    for (jobs, stats) in totals {
+        println!(
+            "{:>10} {:>10} {:>10} {:>5.0}%  {}",
+            output.bytes(stats.original_size),
+            output.bytes(stats.compressed_size),
+            output.bytes(stats.deduplicated_size),
+            stats.savings() * 100.0,
+            jobs
+        );
+    }
+    Ok(())
+}
+
+fn show_audit(state_file: &str, output: &Output, last: usize, json: bool) -> Result<(), String> {
+    let log = StateStore::new(state_file).audit_log()?;
+    let entries = &log[log.len().saturating_sub(last)..];
+
+    if json {
+        let json = serde_json::to_string_pretty(entries)
+            .map_err(|e| format!("Failed to serialize audit log: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No audited operations recorded in {}", state_file);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<24} {:<9} {:<7} ARCHIVE -> TARGET",
+        "WHEN", "USER", "ACTION", "RESULT"
+    );
+    for entry in entries {
+        println!(
+            "{:<20} {:<24} {:<9} {:<7} {} -> {}",
+            output.time(entry.at),
+            entry.user,
+            entry.action,
+            if entry.success { "ok" } else { "failed" },
+            entry.archive.as_deref().unwrap_or("(repository)"),
+            entry.target.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_completion_archive_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let borg = dir.path().join("borg");
+        fs::write(
+            &borg,
+            "#!/bin/sh\ncase \" $* \" in\n  *' --version '*) echo 'borg 1.2.8' ;;\n  *' list '*) printf 'host-1\\nhost-2\\nhost-2.checkpoint\\n' ;;\nesac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&borg, fs::Permissions::from_mode(0o755)).unwrap();
+        let passphrase = dir.path().join("passphrase");
+        fs::write(&passphrase, "secret").unwrap();
+        let config = dir.path().join("config.yaml");
+        fs::write(
+            &config,
+            include_str!("../borg-config.yaml")
+                .replace("binary: borg", &format!("binary: {}", borg.display()))
+                .replace(
+                    "passphrase_file: /root/.borg-passphrase",
+                    &format!("passphrase_file: {}", passphrase.display()),
+                ),
+        )
+        .unwrap();
+
+        // The config comes from the command line being completed
+        let command_line = |config: &str| -> Vec<String> {
+            [
+                "borg-timemachine",
+                "--config",
+                config,
+                "restore",
+                "/etc/hosts",
+                "--as-of",
+            ]
+            .map(String::from)
+            .to_vec()
+        };
+        assert_eq!(
+            completion_archive_names(&command_line(config.to_str().unwrap())).unwrap(),
+            ["host-1", "host-2"]
+        );
+        let inline = [
+            "borg-timemachine".to_string(),
+            format!("--config={}", config.display()),
+        ];
+        assert_eq!(completion_archive_names(&inline).unwrap().len(), 2);
+        assert!(completion_archive_names(&command_line(
+            dir.path().join("missing.yaml").to_str().unwrap()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_manual_pages() {
+        let mut page = Vec::new();
+        clap_mangen::Man::new(Cli::command())
+            .render(&mut page)
+            .unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(
+            page.starts_with(".ie \\n(.g .ds Aq \\(aq"),
+            "{}",
+            &page[..80]
+        );
+        assert!(page.contains(".TH borg-timemachine 1"));
+        assert!(page.contains("\\-\\-dry\\-run"));
+
+        let dir = tempfile::tempdir().unwrap();
+        clap_mangen::generate_to(Cli::command(), dir.path()).unwrap();
+        for page in [
+            "borg-timemachine.1",
+            "borg-timemachine-backup.1",
+            "borg-timemachine-key-export.1",
+        ] {
+            assert!(dir.path().join(page).is_file(), "{}", page);
+        }
     }
 }
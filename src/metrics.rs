@@ -0,0 +1,249 @@
+use crate::report::{CycleReport, StageOutcome};
+use crate::state::StateStore;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Prometheus textfile collector output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Metrics {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Usually inside node_exporter's `--collector.textfile.directory`
+    #[serde(default = "default_textfile")]
+    pub textfile: String,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            textfile: default_textfile(),
+        }
+    }
+}
+
+fn default_textfile() -> String {
+    "/var/lib/node_exporter/textfile_collector/borg_timemachine.prom".to_string()
+}
+
+/// Render a cycle report in the Prometheus text exposition format.
+pub fn render(report: &CycleReport, hostname: &str) -> String {
+    let labels = format!("host=\"{}\"", hostname.replace('"', "\\\""));
+    let mut out = String::new();
+
+    let mut gauge = |name: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP borg_timemachine_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE borg_timemachine_{} gauge", name);
+        let _ = writeln!(out, "borg_timemachine_{}{{{}}} {}", name, labels, value);
+    };
+
+    gauge(
+        "last_run_timestamp_seconds",
+        "Start time of the last backup cycle.",
+        report.started.timestamp().to_string(),
+    );
+    gauge(
+        "last_run_duration_seconds",
+        "Duration of the last backup cycle.",
+        format!("{:.3}", report.duration.as_secs_f64()),
+    );
+    gauge(
+        "last_run_success",
+        "Whether the last backup cycle succeeded (1) or failed (0).",
+        (report.success as u8).to_string(),
+    );
+
+    if let Some(stats) = &report.stats {
+        gauge(
+            "archive_original_size_bytes",
+            "Original size of the last archive.",
+            stats.original_size.to_string(),
+        );
+        gauge(
+            "archive_compressed_size_bytes",
+            "Compressed size of the last archive.",
+            stats.compressed_size.to_string(),
+        );
+        gauge(
+            "archive_deduplicated_size_bytes",
+            "Deduplicated size added by the last archive.",
+            stats.deduplicated_size.to_string(),
+        );
+        gauge(
+            "archive_files",
+            "Number of files in the last archive.",
+            stats.nfiles.to_string(),
+        );
+    }
+
+    if !report.stages.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP borg_timemachine_stage_duration_seconds Duration of each stage of the last cycle."
+        );
+        let _ = writeln!(out, "# TYPE borg_timemachine_stage_duration_seconds gauge");
+        for stage in &report.stages {
+            let _ = writeln!(
+                out,
+                "borg_timemachine_stage_duration_seconds{{{},stage=\"{}\"}} {:.3}",
+                labels,
+                stage.name,
+                stage.duration.as_secs_f64()
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP borg_timemachine_stage_success Whether each stage of the last cycle succeeded (1), warned (0.5) or failed (0)."
+        );
+        let _ = writeln!(out, "# TYPE borg_timemachine_stage_success gauge");
+        for stage in &report.stages {
+            let value = match stage.outcome {
+                StageOutcome::Success => "1",
+                StageOutcome::Warning => "0.5",
+                StageOutcome::Failed => "0",
+            };
+            let _ = writeln!(
+                out,
+                "borg_timemachine_stage_success{{{},stage=\"{}\"}} {}",
+                labels, stage.name, value
+            );
+        }
+    }
+
+    out
+}
+
+/// Write the textfile atomically so the collector never reads a partial file.
+pub fn write_textfile(path: &str, contents: &str) -> Result<(), String> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents).map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to move metrics into {}: {}", path, e))
+}
+
+/// Metrics derived from the whole state file: the last cycle plus
+/// history-wide gauges.
+pub fn render_state(store: &StateStore, hostname: &str) -> Result<String, String> {
+    let history = store.history()?;
+    let mut out = match history.last() {
+        Some(entry) => render(&entry.to_report(), hostname),
+        None => String::new(),
+    };
+
+    let labels = format!("host=\"{}\"", hostname.replace('"', "\\\""));
+    let mut gauge = |name: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP borg_timemachine_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE borg_timemachine_{} gauge", name);
+        let _ = writeln!(out, "borg_timemachine_{}{{{}}} {}", name, labels, value);
+    };
+
+    if let Some(entry) = history.iter().rev().find(|e| e.success) {
+        gauge(
+            "last_success_timestamp_seconds",
+            "Start time of the last successful backup cycle.",
+            entry.started.timestamp().to_string(),
+        );
+    }
+    gauge(
+        "recorded_cycles",
+        "Backup cycles in the state file.",
+        history.len().to_string(),
+    );
+    gauge(
+        "recorded_failures",
+        "Failed backup cycles in the state file.",
+        history.iter().filter(|e| !e.success).count().to_string(),
+    );
+    gauge(
+        "verified_archives",
+        "Archives with a successful restore test.",
+        store.verified_archives()?.len().to_string(),
+    );
+
+    Ok(out)
+}
+
+/// Grafana dashboard showing the exporter's metrics.
+pub fn dashboard() -> serde_json::Value {
+    let panel = |id: u32, title: &str, expr: &str, unit: &str, x: u32, y: u32| {
+        serde_json::json!({
+            "id": id,
+            "type": "timeseries",
+            "title": title,
+            "datasource": {"type": "prometheus", "uid": "${datasource}"},
+            "gridPos": {"h": 8, "w": 12, "x": x, "y": y},
+            "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+            "targets": [{"expr": expr, "legendFormat": "{{host}}", "refId": "A"}]
+        })
+    };
+
+    serde_json::json!({
+        "title": "Borg Time Machine",
+        "uid": "borg-timemachine",
+        "schemaVersion": 39,
+        "time": {"from": "now-7d", "to": "now"},
+        "templating": {"list": [{
+            "name": "datasource",
+            "type": "datasource",
+            "query": "prometheus"
+        }]},
+        "panels": [
+            panel(1, "Time since last success", "time() - borg_timemachine_last_success_timestamp_seconds", "s", 0, 0),
+            panel(2, "Last run succeeded", "borg_timemachine_last_run_success", "bool", 12, 0),
+            panel(3, "Cycle duration", "borg_timemachine_last_run_duration_seconds", "s", 0, 8),
+            panel(4, "Deduplicated size of last archive", "borg_timemachine_archive_deduplicated_size_bytes", "bytes", 12, 8),
+        ]
+    })
+}
+
+/// Serve `/metrics` (and `/dashboard.json`) over HTTP until the process is
+/// stopped. Every scrape re-reads the state file.
+pub fn serve(listen: &str, store: &StateStore, hostname: &str) -> Result<(), String> {
+    let address = if listen.starts_with(':') {
+        format!("0.0.0.0{}", listen)
+    } else {
+        listen.to_string()
+    };
+    let listener = TcpListener::bind(&address)
+        .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+    tracing::info!("Serving metrics on http://{}/metrics", address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle(stream, store, hostname) {
+                    tracing::warn!("metrics request failed: {}", e);
+                }
+            }
+            Err(e) => tracing::warn!("metrics connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, store: &StateStore, hostname: &str) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        "/metrics" => match render_state(store, hostname) {
+            Ok(body) => ("200 OK", "text/plain; version=0.0.4", body),
+            Err(e) => ("500 Internal Server Error", "text/plain", e),
+        },
+        "/dashboard.json" => ("200 OK", "application/json", dashboard().to_string()),
+        _ => ("404 Not Found", "text/plain", "See /metrics\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
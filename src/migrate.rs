@@ -0,0 +1,341 @@
+use crate::config_format::ConfigFormat;
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+
+/// Schema version of the config files this release writes. Files from
+/// before `version:` existed are version 0.
+pub const CURRENT: u32 = 1;
+
+pub const VERSION_KEY: &str = "version";
+
+/// Each entry upgrades a config from its index to the next version,
+/// returning what it changed.
+const MIGRATIONS: [fn(&mut Mapping) -> Vec<String>; CURRENT as usize] = [to_v1];
+
+/// Version 1 settled on the names of options that had been renamed.
+fn to_v1(root: &mut Mapping) -> Vec<String> {
+    let mut changes = Vec::new();
+    rename(
+        root,
+        "security",
+        "key_backup_path",
+        "key_backup",
+        &mut changes,
+    );
+    rename(
+        root,
+        "options",
+        "remote_ratelimit",
+        "upload_ratelimit",
+        &mut changes,
+    );
+    changes
+}
+
+/// Move `section.from` to `section.to`; a value already at `to` wins.
+fn rename(root: &mut Mapping, section: &str, from: &str, to: &str, changes: &mut Vec<String>) {
+    let Some(Value::Mapping(section_map)) = root.get_mut(section) else {
+        return;
+    };
+    let Some(value) = section_map.remove(from) else {
+        return;
+    };
+    if section_map.contains_key(to) {
+        changes.push(format!(
+            "{}.{} removed; {}.{} is set",
+            section, from, section, to
+        ));
+    } else {
+        section_map.insert(Value::from(to), value);
+        changes.push(format!("{}.{} is now {}.{}", section, from, section, to));
+    }
+}
+
+/// Schema version of `doc`, refusing versions newer than this release
+/// understands rather than misreading them.
+pub fn version(doc: &Value) -> Result<u32, String> {
+    let version = match doc.get(VERSION_KEY) {
+        None => 0,
+        Some(value) => value
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("Config version must be a number, not {:?}", value))?,
+    };
+    if version > CURRENT {
+        return Err(format!(
+            "Config version {} is newer than this borg-timemachine understands ({}); upgrade borg-timemachine",
+            version, CURRENT
+        ));
+    }
+    Ok(version)
+}
+
+/// Bring `doc` to the current schema, leaving its `version` as it is so
+/// `validate` can still tell the file is old. Returns what changed.
+pub fn upgrade(doc: &mut Value) -> Result<Vec<String>, String> {
+    let from = version(doc)? as usize;
+    let Value::Mapping(root) = doc else {
+        return Ok(Vec::new());
+    };
+    Ok(MIGRATIONS[from..]
+        .iter()
+        .flat_map(|migration| migration(root))
+        .collect())
+}
+
+/// A config file brought to the current schema by `migrate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migrated {
+    pub from: u32,
+    pub changes: Vec<String>,
+    /// Line diff between the file and `contents`
+    pub diff: Vec<String>,
+    pub contents: String,
+    /// `contents` was rendered from the parsed file, dropping the comments
+    /// it has
+    pub drops_comments: bool,
+}
+
+/// Upgrade the config file `contents`, or `None` if it is current. When
+/// only the version changes, the `version` line is edited in place;
+/// otherwise the file is rendered anew.
+pub fn migrate(contents: &str, format: ConfigFormat) -> Result<Option<Migrated>, String> {
+    let mut doc = format
+        .parse(contents)
+        .map_err(|e| format!("Failed to parse config file: {}", e))?;
+    let from = version(&doc)?;
+    if from == CURRENT {
+        return Ok(None);
+    }
+    let changes = upgrade(&mut doc)?;
+    let (new, drops_comments) = if changes.is_empty() {
+        (stamp_version(contents, format), false)
+    } else {
+        // The version goes first, where a reader looks for it
+        if let Value::Mapping(root) = &mut doc {
+            root.remove(VERSION_KEY);
+            let mut stamped = Mapping::new();
+            stamped.insert(Value::from(VERSION_KEY), Value::from(CURRENT));
+            stamped.extend(std::mem::take(root));
+            *root = stamped;
+        }
+        (format.render(&doc)?, has_comments(contents))
+    };
+    Ok(Some(Migrated {
+        from,
+        changes,
+        diff: line_diff(contents, &new),
+        contents: new,
+        drops_comments,
+    }))
+}
+
+/// `contents` with its top-level version set to `CURRENT`, replacing the
+/// line that has one or adding one at the top.
+fn stamp_version(contents: &str, format: ConfigFormat) -> String {
+    let line = match format {
+        ConfigFormat::Yaml => format!("{}: {}", VERSION_KEY, CURRENT),
+        ConfigFormat::Toml => format!("{} = {}", VERSION_KEY, CURRENT),
+    };
+    let is_version = |text: &str| {
+        text.strip_prefix(VERSION_KEY).is_some_and(|rest| {
+            let rest = rest.trim_start();
+            match format {
+                ConfigFormat::Yaml => rest.starts_with(':'),
+                ConfigFormat::Toml => rest.starts_with('='),
+            }
+        })
+    };
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    // TOML's top-level keys end at the first table
+    let top_level = match format {
+        ConfigFormat::Yaml => lines.len(),
+        ConfigFormat::Toml => lines
+            .iter()
+            .position(|l| l.trim_start().starts_with('['))
+            .unwrap_or(lines.len()),
+    };
+    match lines[..top_level].iter().position(|l| is_version(l)) {
+        Some(index) => lines[index] = line,
+        None => lines.insert(0, line),
+    }
+    let mut stamped = lines.join("\n");
+    stamped.push('\n');
+    stamped
+}
+
+/// Whether `contents` has any `#` comments, YAML and TOML alike. A `#`
+/// inside a quoted value counts too.
+fn has_comments(contents: &str) -> bool {
+    contents
+        .lines()
+        .any(|line| line.trim_start().starts_with('#') || line.contains(" #"))
+}
+
+/// Migrate the config file at `path` in place, keeping the original as
+/// `<path>.bak`. With `write` false only report what would change. A file
+/// whose comments the migration would drop is left alone.
+pub fn migrate_file(path: &str, write: bool) -> Result<Option<Migrated>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+    let Some(migrated) = migrate(&contents, ConfigFormat::from_path(Path::new(path)))? else {
+        return Ok(None);
+    };
+    if !write {
+        return Ok(Some(migrated));
+    }
+    if migrated.drops_comments {
+        return Err(format!(
+            "Not rewriting {}: its comments would be lost. Make these changes by hand \
+             and set {} to {}:\n  {}",
+            path,
+            VERSION_KEY,
+            CURRENT,
+            migrated.changes.join("\n  ")
+        ));
+    }
+
+    let backup = format!("{}.bak", path);
+    // Copied, so the backup keeps the permissions of a file that may hold
+    // SMTP credentials
+    fs::copy(path, &backup).map_err(|e| format!("Failed to write {}: {}", backup, e))?;
+    let tmp = format!("{}.tmp", path);
+    fs::copy(path, &tmp)
+        .and_then(|_| fs::write(&tmp, &migrated.contents))
+        .map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to replace {}: {}", path, e))?;
+    Ok(Some(migrated))
+}
+
+/// Lines removed from `old` (`-`) and added in `new` (`+`), in order,
+/// leaving out those both share.
+pub fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j]: length of the longest common subsequence of old[i..]
+    // and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            diff.push(format!("+ {}", new[j]));
+            j += 1;
+        } else {
+            diff.push(format!("- {}", old[i]));
+            i += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let old = "security:\n  key_backup_path: /root/key.txt\noptions:\n  remote_ratelimit: 500\n  upload_ratelimit: 100\n";
+        let migrated = migrate(old, ConfigFormat::Yaml).unwrap().unwrap();
+        assert_eq!(migrated.from, 0);
+        assert_eq!(
+            migrated.changes,
+            [
+                "security.key_backup_path is now security.key_backup",
+                "options.remote_ratelimit removed; options.upload_ratelimit is set"
+            ]
+        );
+        assert!(migrated.contents.starts_with("version: 1\n"));
+        assert!(migrated.contents.contains("key_backup: /root/key.txt"));
+        assert!(migrated.diff.contains(&"+ version: 1".to_string()));
+        assert!(migrated
+            .diff
+            .contains(&"-   remote_ratelimit: 500".to_string()));
+
+        // Current files are left alone, newer ones refused
+        assert_eq!(
+            migrate(&migrated.contents, ConfigFormat::Yaml).unwrap(),
+            None
+        );
+        assert!(migrate("version: 99\n", ConfigFormat::Yaml)
+            .unwrap_err()
+            .contains("newer"));
+    }
+
+    #[test]
+    fn test_migrate_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg-config.toml");
+        let path = path.to_str().unwrap();
+        fs::write(path, "[security]\nkey_backup_path = \"/root/key.txt\"\n").unwrap();
+
+        assert!(migrate_file(path, false).unwrap().is_some());
+        assert!(!Path::new(&format!("{}.bak", path)).exists());
+
+        migrate_file(path, true).unwrap().unwrap();
+        let contents = fs::read_to_string(path).unwrap();
+        assert!(contents.starts_with("version = 1\n"), "{}", contents);
+        assert!(contents.contains("key_backup = \"/root/key.txt\""));
+        assert!(fs::read_to_string(format!("{}.bak", path))
+            .unwrap()
+            .contains("key_backup_path"));
+        assert_eq!(migrate_file(path, true).unwrap(), None);
+    }
+
+    #[test]
+    fn test_comments_are_kept() {
+        // Only the version changes: its line is edited in place
+        let commented =
+            "# Office NAS\nversion: 0  # old\nretention:\n  daily: 3 # a week is plenty\n";
+        let migrated = migrate(commented, ConfigFormat::Yaml).unwrap().unwrap();
+        assert!(!migrated.drops_comments);
+        assert_eq!(
+            migrated.contents,
+            "# Office NAS\nversion: 1\nretention:\n  daily: 3 # a week is plenty\n"
+        );
+        assert_eq!(migrated.diff, ["+ version: 1", "- version: 0  # old"]);
+
+        let toml = "# NAS\n[retention]\ndaily = 3\n";
+        let migrated = migrate(toml, ConfigFormat::Toml).unwrap().unwrap();
+        assert_eq!(
+            migrated.contents,
+            "version = 1\n# NAS\n[retention]\ndaily = 3\n"
+        );
+
+        // A rename needs rendering, which would drop them: shown, not written
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("borg-config.yaml");
+        let path = path.to_str().unwrap();
+        let old = "# keys live on the USB stick\nsecurity:\n  key_backup_path: /media/key.txt\n";
+        fs::write(path, old).unwrap();
+        let migrated = migrate_file(path, false).unwrap().unwrap();
+        assert!(migrated.drops_comments);
+        assert!(migrated
+            .diff
+            .contains(&"- # keys live on the USB stick".to_string()));
+        let error = migrate_file(path, true).unwrap_err();
+        assert!(error.contains("comments would be lost"), "{}", error);
+        assert!(error.contains("security.key_backup_path is now security.key_backup"));
+        assert_eq!(fs::read_to_string(path).unwrap(), old);
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(line_diff("a\nb\nc\n", "a\nc\nd\n"), ["- b", "+ d"]);
+        assert!(line_diff("a\n", "a\n").is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// A single notification destination.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Channel {
+    /// Send through SMTP when `notifications.smtp` is configured, otherwise
+    /// through the local `mail` command
+    Email { to: String },
+    /// POST a JSON body to an arbitrary URL. The body is a template where
+    /// `{{subject}}`, `{{message}}`, `{{hostname}}` and `{{status}}` are
+    /// replaced with JSON-escaped values.
+    Webhook {
+        url: String,
+        #[serde(default = "default_webhook_body")]
+        body: String,
+    },
+    /// Publish to an ntfy topic
+    Ntfy {
+        topic: String,
+        #[serde(default = "default_ntfy_server")]
+        server: String,
+    },
+    /// Slack incoming webhook
+    Slack { url: String },
+    /// Discord webhook
+    Discord { url: String },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection with STARTTLS (port 587)
+    #[default]
+    Starttls,
+    /// Implicit TLS (port 465)
+    Tls,
+    /// Unencrypted, only sensible for a local relay (port 25)
+    None,
+}
+
+/// SMTP server used for email channels instead of the `mail` command.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Smtp {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// File with the username on the first line and the password on the second
+    #[serde(default)]
+    pub credentials_file: Option<String>,
+    pub from: String,
+}
+
+impl Smtp {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        })
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, String> {
+        let path = match &self.credentials_file {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read SMTP credentials file {}: {}", path, e))?;
+        let mut lines = contents.lines();
+        match (lines.next(), lines.next()) {
+            (Some(user), Some(password)) => Ok(Some(Credentials::new(
+                user.trim().to_string(),
+                password.trim().to_string(),
+            ))),
+            _ => Err(format!(
+                "SMTP credentials file {} must contain a username and a password line",
+                path
+            )),
+        }
+    }
+
+    fn send(&self, to: &str, notification: &Notification) -> Result<(), String> {
+        let from: Mailbox = self
+            .from
+            .parse()
+            .map_err(|e| format!("Invalid SMTP from address {}: {}", self.from, e))?;
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| format!("Invalid email address {}: {}", to, e))?;
+
+        let builder = Message::builder()
+            .from(from)
+            .to(to)
+            .subject(notification.subject.clone());
+        let email = match &notification.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(
+                notification.message.clone(),
+                html.clone(),
+            )),
+            None => builder.body(notification.message.clone()),
+        }
+        .map_err(|e| format!("Failed to build email: {}", e))?;
+
+        let builder = match self.tls {
+            SmtpTls::Starttls => SmtpTransport::starttls_relay(&self.host),
+            SmtpTls::Tls => SmtpTransport::relay(&self.host),
+            SmtpTls::None => Ok(SmtpTransport::builder_dangerous(&self.host)),
+        }
+        .map_err(|e| format!("Failed to configure SMTP transport: {}", e))?
+        .port(self.port());
+
+        let builder = match self.credentials()? {
+            Some(credentials) => builder.credentials(credentials),
+            None => builder,
+        };
+
+        builder
+            .build()
+            .send(&email)
+            .map_err(|e| format!("SMTP delivery to {} failed: {}", self.host, e))?;
+        Ok(())
+    }
+}
+
+/// Dead-man-switch ping in the style of healthchecks.io.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Healthcheck {
+    /// Base ping URL; `/start` and `/fail` are appended for those events
+    pub url: String,
+    /// Number of trailing log lines sent with the final ping
+    #[serde(default = "default_log_lines")]
+    pub log_lines: usize,
+}
+
+fn default_log_lines() -> usize {
+    50
+}
+
+impl Healthcheck {
+    pub fn ping_start(&self) -> Result<(), String> {
+        curl(&["-X", "POST", &self.endpoint("/start")])
+    }
+
+    /// Report the end of a cycle, with the log tail as the request body.
+    pub fn ping_finish(&self, success: bool, log_tail: &str) -> Result<(), String> {
+        let url = if success {
+            self.endpoint("")
+        } else {
+            self.endpoint("/fail")
+        };
+        curl_with_input(&["--data-binary", "@-", &url], log_tail)
+    }
+
+    fn endpoint(&self, suffix: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), suffix)
+    }
+}
+
+fn default_webhook_body() -> String {
+    r#"{"subject": "{{subject}}", "message": "{{message}}", "hostname": "{{hostname}}", "status": "{{status}}"}"#
+        .to_string()
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+/// Message delivered to every configured channel.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub subject: String,
+    pub message: String,
+    pub hostname: String,
+    pub success: bool,
+    /// HTML version of `message`, sent along with it by SMTP email
+    pub html: Option<String>,
+}
+
+impl Notification {
+    fn status(&self) -> &'static str {
+        if self.success {
+            "success"
+        } else {
+            "failure"
+        }
+    }
+
+    /// Fill in a webhook body template.
+    pub fn render(&self, template: &str) -> String {
+        // Strip the surrounding quotes so placeholders can sit inside strings
+        let escape = |s: &str| {
+            let quoted = serde_json::Value::String(s.to_string()).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        };
+
+        template
+            .replace("{{subject}}", &escape(&self.subject))
+            .replace("{{message}}", &escape(&self.message))
+            .replace("{{hostname}}", &escape(&self.hostname))
+            .replace("{{status}}", self.status())
+    }
+
+    fn text(&self) -> String {
+        format!("{}\n{}", self.subject, self.message)
+    }
+}
+
+impl Channel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Email { .. } => "email",
+            Channel::Webhook { .. } => "webhook",
+            Channel::Ntfy { .. } => "ntfy",
+            Channel::Slack { .. } => "slack",
+            Channel::Discord { .. } => "discord",
+        }
+    }
+
+    pub fn send(&self, notification: &Notification, smtp: Option<&Smtp>) -> Result<(), String> {
+        match self {
+            Channel::Email { to } => match smtp {
+                Some(smtp) => smtp.send(to, notification),
+                None => send_mail(to, notification),
+            },
+            Channel::Webhook { url, body } => post_json(url, &notification.render(body)),
+            Channel::Ntfy { topic, server } => {
+                let url = format!("{}/{}", server.trim_end_matches('/'), topic);
+                let priority = if notification.success {
+                    "default"
+                } else {
+                    "high"
+                };
+                curl(&[
+                    "-H",
+                    &format!("Title: {}", notification.subject),
+                    "-H",
+                    &format!("Priority: {}", priority),
+                    "--data-binary",
+                    &notification.message,
+                    &url,
+                ])
+            }
+            Channel::Slack { url } => {
+                let body = serde_json::json!({ "text": notification.text() });
+                post_json(url, &body.to_string())
+            }
+            Channel::Discord { url } => {
+                let body = serde_json::json!({ "content": notification.text() });
+                post_json(url, &body.to_string())
+            }
+        }
+    }
+}
+
+fn send_mail(to: &str, notification: &Notification) -> Result<(), String> {
+    let mut child = Command::new("mail")
+        .args(["-s", &notification.subject, to])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            format!(
+                "Failed to run mail: {} (install a mail client or configure notifications.smtp)",
+                e
+            )
+        })?;
+
+    // Dropping stdin at the end of this block sends EOF to mail
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(notification.message.as_bytes())
+            .map_err(|e| format!("Failed to write mail body: {}", e))?;
+    }
+
+    let status = child
+        .wait()
+        .map_err(|e| format!("Failed to run mail: {}", e))?;
+    if !status.success() {
+        return Err(format!("mail exited with status {}", status));
+    }
+    Ok(())
+}
+
+fn post_json(url: &str, body: &str) -> Result<(), String> {
+    curl(&[
+        "-H",
+        "Content-Type: application/json",
+        "--data-binary",
+        body,
+        url,
+    ])
+}
+
+fn curl(args: &[&str]) -> Result<(), String> {
+    curl_with_input(args, "")
+}
+
+/// Run curl with `input` on stdin, for `--data-binary @-`.
+fn curl_with_input(args: &[&str], input: &str) -> Result<(), String> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("Failed to write curl input: {}", e))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
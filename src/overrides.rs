@@ -0,0 +1,327 @@
+use serde_yaml::{Mapping, Value};
+
+/// Environment variables starting with this override config keys, with
+/// `__` between levels: `BTM_REPOSITORY__PATH` sets `repository.path`.
+pub const ENV_PREFIX: &str = "BTM_";
+
+/// A config value set from outside the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// Dotted path, e.g. `repository.path`; numbers index lists
+    pub key: String,
+    /// YAML scalar or flow value, e.g. `/srv/borg`, `30` or `[a, b]`
+    pub value: String,
+    /// Where the override came from, for error messages
+    pub origin: String,
+}
+
+impl Override {
+    /// Parse a `--set key=value` argument.
+    pub fn parse(setting: &str) -> Result<Self, String> {
+        match setting.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                key: key.trim().to_string(),
+                value: value.to_string(),
+                origin: format!("--set {}", key.trim()),
+            }),
+            _ => Err(format!("Expected --set key=value, got '{}'", setting)),
+        }
+    }
+
+    /// The override an environment variable stands for, if it has the
+    /// prefix and the `BTM_SECTION__KEY` shape: upper case, at least two
+    /// levels. Others, such as a `BTM_DEBUG` of some other tool, are left
+    /// alone.
+    pub fn from_env(name: &str, value: &str) -> Option<Self> {
+        let path = name.strip_prefix(ENV_PREFIX)?;
+        let segments: Vec<&str> = path.split("__").collect();
+        let shaped = segments.len() >= 2
+            && segments.iter().all(|segment| {
+                !segment.is_empty()
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            });
+        if !shaped {
+            return None;
+        }
+        Some(Self {
+            key: path.to_lowercase().replace("__", "."),
+            value: value.to_string(),
+            origin: name.to_string(),
+        })
+    }
+}
+
+/// Overrides from the process environment, in a stable order.
+pub fn from_environment() -> Vec<Override> {
+    let mut overrides: Vec<Override> = std::env::vars()
+        .filter_map(|(name, value)| Override::from_env(&name, &value))
+        .collect();
+    overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+    overrides
+}
+
+/// Every key `config` shows, set or commented out, as mappings nested
+/// like the config; a list holds one entry with the keys of all of its
+/// entries. `apply` checks keys against it, so optional settings the
+/// bundled config only documents can be set too.
+pub fn known_keys(config: &str) -> Value {
+    let mut known = Value::Mapping(Mapping::new());
+    // Indentation and path of the keys and list entries enclosing a line
+    let mut stack: Vec<(usize, Vec<String>)> = Vec::new();
+    for line in config.lines() {
+        // `  #   user: backup` is `    user: backup`, commented out
+        let mut line = line.to_string();
+        while let Some(rest) = line.trim_start().strip_prefix("# ") {
+            line = format!("{}{}", &line[..line.len() - line.trim_start().len()], rest);
+        }
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut indent = line.len() - line.trim_start().len();
+        let mut content = line.trim();
+        let item = content.starts_with("- ");
+        if item {
+            content = content[2..].trim_start();
+        }
+        let Some((key, _)) = content.split_once(':') else {
+            continue;
+        };
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            continue;
+        }
+
+        while stack.last().is_some_and(|(level, _)| *level >= indent) {
+            stack.pop();
+        }
+        let mut path = stack
+            .last()
+            .map(|(_, path)| path.clone())
+            .unwrap_or_default();
+        if item {
+            path.push("*".to_string());
+            stack.push((indent, path.clone()));
+            indent += 2;
+        }
+        path.push(key.to_string());
+        insert(&mut known, &path);
+        stack.push((indent, path));
+    }
+    known
+}
+
+/// Add `path` to `known`, `*` standing for a list entry.
+fn insert(known: &mut Value, path: &[String]) {
+    let mut node = known;
+    for segment in path {
+        node = if segment == "*" {
+            if !node.is_sequence() {
+                *node = Value::Sequence(vec![Value::Null]);
+            }
+            let Value::Sequence(items) = node else {
+                unreachable!("just made a list");
+            };
+            &mut items[0]
+        } else {
+            if !node.is_mapping() {
+                *node = Value::Mapping(Mapping::new());
+            }
+            child(node, segment)
+        };
+    }
+}
+
+/// Set `setting.key` in `doc`, creating missing mappings on the way. Every
+/// key must exist in `doc` or in `known` (see `known_keys`), so a misspelt
+/// key is an error rather than silently ignored. Below a key `known` has
+/// no keys for, such as a free-form mapping, anything goes.
+pub fn apply(doc: &mut Value, known: &Value, setting: &Override) -> Result<(), String> {
+    let segments: Vec<&str> = setting.key.split('.').collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(format!("{}: invalid key '{}'", setting.origin, setting.key));
+    }
+
+    let value = if setting.value.is_empty() {
+        Value::String(String::new())
+    } else {
+        serde_yaml::from_str(&setting.value)
+            .map_err(|e| format!("{}: invalid value: {}", setting.origin, e))?
+    };
+
+    let mut node = doc;
+    let mut known = Some(known);
+    for (depth, segment) in segments.iter().enumerate() {
+        if let (Some(Value::Mapping(keys)), false) = (known, node.is_sequence()) {
+            let key = Value::String(segment.to_string());
+            if !keys.contains_key(&key) && !node.as_mapping().is_some_and(|m| m.contains_key(&key))
+            {
+                return Err(match depth {
+                    0 => format!("{}: unknown config section '{}'", setting.origin, segment),
+                    _ => format!(
+                        "{}: unknown config key '{}'",
+                        setting.origin,
+                        segments[..=depth].join(".")
+                    ),
+                });
+            }
+        }
+        known = match known {
+            Some(Value::Sequence(items)) => items.first(),
+            Some(known) => known.get(*segment),
+            None => None,
+        };
+        node = match node {
+            Value::Sequence(items) => {
+                let index: usize = segment.parse().map_err(|_| {
+                    format!(
+                        "{}: '{}' is a list, expected an index",
+                        setting.origin, segment
+                    )
+                })?;
+                let len = items.len();
+                items.get_mut(index).ok_or_else(|| {
+                    format!(
+                        "{}: index {} is out of range (the list has {} entries)",
+                        setting.origin, index, len
+                    )
+                })?
+            }
+            Value::Null => {
+                *node = Value::Mapping(Mapping::new());
+                child(node, segment)
+            }
+            Value::Mapping(_) => child(node, segment),
+            _ => {
+                return Err(format!(
+                    "{}: cannot set '{}' inside a plain value",
+                    setting.origin, segment
+                ))
+            }
+        };
+    }
+    *node = value;
+    Ok(())
+}
+
+/// Entry `key` of a mapping, inserted as null if missing.
+fn child<'a>(node: &'a mut Value, key: &str) -> &'a mut Value {
+    let Value::Mapping(mapping) = node else {
+        unreachable!("child of a non-mapping");
+    };
+    mapping
+        .entry(Value::String(key.to_string()))
+        .or_insert(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Value {
+        serde_yaml::from_str(
+            "repository:\n  path: /mnt/backup\njobs:\n  - name: home\n    source: /home\nhealthcheck:\n",
+        )
+        .unwrap()
+    }
+
+    fn known() -> Value {
+        known_keys(
+            r#"
+repository:
+  path: /mnt/backup
+  # Removable disk:
+  # expect_label: BACKUP1
+jobs:
+  - name: etc
+    source: /etc
+  # - name: media
+  #   paths_from:
+  #     command: list
+  #     # file: /srv/list
+healthcheck:
+  # url: https://hc-ping.com/uuid
+"#,
+        )
+    }
+
+    #[test]
+    fn test_known_keys() {
+        let known = known();
+        assert!(known["repository"].get("expect_label").is_some());
+        assert!(known["repository"].get("Removable disk").is_none());
+        assert!(known["jobs"][0].get("source").is_some());
+        assert!(known["jobs"][0]["paths_from"].get("file").is_some());
+        assert!(known["healthcheck"].get("url").is_some());
+
+        let bundled = known_keys(include_str!("../borg-config.yaml"));
+        assert!(bundled["repository"]["remote"].get("ssh_key").is_some());
+        assert!(bundled["notifications"]["channels"][0].get("to").is_some());
+    }
+
+    #[test]
+    fn test_env_names() {
+        let o = Override::from_env("BTM_REPOSITORY__PATH", "/srv/borg").unwrap();
+        assert_eq!(o.key, "repository.path");
+        let o = Override::from_env("BTM_MAINTENANCE__CHECK_DAY", "3").unwrap();
+        assert_eq!(o.key, "maintenance.check_day");
+        assert!(Override::from_env("HOME", "/root").is_none());
+        assert!(Override::from_env("BTM_", "x").is_none());
+        // Not shaped like ours, e.g. another tool's
+        assert!(Override::from_env("BTM_DEBUG", "1").is_none());
+        assert!(Override::from_env("BTM_repository__path", "/x").is_none());
+        assert!(Override::from_env("BTM_REPOSITORY__", "/x").is_none());
+        assert!(Override::from_env("BTM_JOBS__0__SOURCE", "/x").is_some());
+    }
+
+    #[test]
+    fn test_parse_set() {
+        let o = Override::parse("notifications.email=ops@example.com").unwrap();
+        assert_eq!(o.key, "notifications.email");
+        assert_eq!(o.value, "ops@example.com");
+        assert!(Override::parse("novalue").is_err());
+        assert!(Override::parse("=x").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut doc = doc();
+        let known = known();
+        let set = |s: &str| Override::parse(s).unwrap();
+
+        apply(&mut doc, &known, &set("repository.path=/srv/borg")).unwrap();
+        apply(&mut doc, &known, &set("jobs.0.source=/srv/home")).unwrap();
+        apply(&mut doc, &known, &set("healthcheck.url=https://hc/1")).unwrap();
+        apply(&mut doc, &known, &set("repository.expect_label=")).unwrap();
+        assert_eq!(doc["repository"]["path"], Value::from("/srv/borg"));
+        assert_eq!(doc["jobs"][0]["source"], Value::from("/srv/home"));
+        assert_eq!(doc["healthcheck"]["url"], Value::from("https://hc/1"));
+        assert_eq!(doc["repository"]["expect_label"], Value::from(""));
+
+        // Misspelt or misplaced keys are refused at any level
+        let error = apply(&mut doc, &known, &set("repository.lock_wait=30")).unwrap_err();
+        assert!(error.contains("unknown config key 'repository.lock_wait'"));
+        let error = apply(&mut doc, &known, &set("jobs.0.sorce=/x")).unwrap_err();
+        assert!(error.contains("unknown config key 'jobs.0.sorce'"));
+        apply(&mut doc, &known, &set("jobs.0.paths_from.file=/srv/list")).unwrap();
+        assert_eq!(
+            doc["jobs"][0]["paths_from"]["file"],
+            Value::from("/srv/list")
+        );
+
+        assert!(apply(&mut doc, &known, &set("jobs.3.source=/x")).is_err());
+        assert!(apply(&mut doc, &known, &set("jobs.name=x")).is_err());
+        assert!(apply(&mut doc, &known, &set("repository.path.x=1")).is_err());
+        let error = apply(&mut doc, &known, &set("repostory.path=/x")).unwrap_err();
+        assert!(error.contains("unknown config section 'repostory'"));
+
+        let known = known_keys("borg:\n  lock_wait: 600");
+        apply(&mut doc, &known, &set("borg.lock_wait=5")).unwrap();
+        assert_eq!(doc["borg"]["lock_wait"], Value::from(5));
+        assert!(apply(&mut doc, &known, &set("borg.lockwait=5")).is_err());
+    }
+}
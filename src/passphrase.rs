@@ -0,0 +1,206 @@
+use serde::Deserialize;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Where borg gets the repository passphrase. Without one,
+/// `security.passphrase_file` is read.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum PassphraseSource {
+    /// File readable only by root holding the passphrase
+    File { path: String },
+    /// Shell command printing the passphrase, run by borg itself
+    /// (`BORG_PASSCOMMAND`), e.g. `pass show borg`
+    Command { command: String },
+    /// Environment variable of this process, e.g. set by a systemd
+    /// `LoadCredential=` wrapper
+    Env { variable: String },
+    /// Secret Service on Linux (`secret-tool`) or the macOS Keychain
+    Keyring { service: String, account: String },
+}
+
+/// Environment variable and value that hand the passphrase to borg. Only
+/// borg invocations get it; the value is overwritten when dropped.
+#[derive(Clone, PartialEq)]
+pub struct BorgPassphrase {
+    pub variable: &'static str,
+    pub value: String,
+}
+
+impl std::fmt::Debug for BorgPassphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = if self.variable == "BORG_PASSPHRASE" {
+            "<redacted>"
+        } else {
+            &self.value
+        };
+        f.debug_struct("BorgPassphrase")
+            .field("variable", &self.variable)
+            .field("value", &value)
+            .finish()
+    }
+}
+
+impl Drop for BorgPassphrase {
+    fn drop(&mut self) {
+        zeroize(&mut self.value);
+    }
+}
+
+impl PassphraseSource {
+    /// Read the passphrase, or for command-based sources, the command borg
+    /// should run to get it.
+    pub fn resolve(&self) -> Result<BorgPassphrase, String> {
+        match self {
+            Self::File { path } => {
+                let mut contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read passphrase file {}: {}", path, e))?;
+                let passphrase = contents.trim().to_string();
+                zeroize(&mut contents);
+                if passphrase.is_empty() {
+                    return Err(format!("Passphrase file {} is empty", path));
+                }
+                Ok(passphrase_value(passphrase))
+            }
+            Self::Command { command } => Ok(BorgPassphrase {
+                variable: "BORG_PASSCOMMAND",
+                value: command.clone(),
+            }),
+            Self::Env { variable } => match std::env::var(variable) {
+                Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase_value(passphrase)),
+                _ => Err(format!("Environment variable {} is not set", variable)),
+            },
+            Self::Keyring { service, account } => Ok(BorgPassphrase {
+                variable: "BORG_PASSCOMMAND",
+                value: keyring_command(service, account),
+            }),
+        }
+    }
+
+    /// Keep the passphrase, and the variables borg would read it from, out
+    /// of a command that isn't borg, such as a hook.
+    pub fn scrub(&self, command: &mut Command) {
+        command.env_remove("BORG_PASSPHRASE");
+        command.env_remove("BORG_PASSCOMMAND");
+        if let Self::Env { variable } = self {
+            command.env_remove(variable);
+        }
+    }
+
+    /// Short description for messages, without the secret.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::File { path } => format!("file {}", path),
+            Self::Command { command } => format!("command `{}`", command),
+            Self::Env { variable } => format!("environment variable {}", variable),
+            Self::Keyring { service, account } => {
+                format!("keyring entry {} / {}", service, account)
+            }
+        }
+    }
+}
+
+fn passphrase_value(passphrase: String) -> BorgPassphrase {
+    BorgPassphrase {
+        variable: "BORG_PASSPHRASE",
+        value: passphrase,
+    }
+}
+
+/// Overwrite a secret before its memory is freed.
+pub fn zeroize(secret: &mut String) {
+    // SAFETY: zero bytes are valid UTF-8
+    for byte in unsafe { secret.as_bytes_mut() } {
+        // Volatile so the stores aren't dropped as dead before the free
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    secret.clear();
+}
+
+/// Command that prints a keyring secret, for `BORG_PASSCOMMAND`.
+fn keyring_command(service: &str, account: &str) -> String {
+    if cfg!(target_os = "macos") {
+        format!(
+            "security find-generic-password -s {} -a {} -w",
+            shell_quote(service),
+            shell_quote(account)
+        )
+    } else {
+        format!(
+            "secret-tool lookup service {} account {}",
+            shell_quote(service),
+            shell_quote(account)
+        )
+    }
+}
+
+/// Quote `value` as one word for `sh`.
+pub(crate) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources_from_config() {
+        let source: PassphraseSource =
+            serde_yaml::from_str("source: command\ncommand: pass show borg").unwrap();
+        assert_eq!(
+            source.resolve().unwrap(),
+            BorgPassphrase {
+                variable: "BORG_PASSCOMMAND",
+                value: "pass show borg".to_string(),
+            }
+        );
+
+        let source: PassphraseSource =
+            serde_yaml::from_str("source: keyring\nservice: borg\naccount: it's me").unwrap();
+        let command = source.resolve().unwrap().value.clone();
+        assert!(command.ends_with("'it'\\''s me'") || command.contains("'it'\\''s me' -w"));
+    }
+
+    #[test]
+    fn test_file_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passphrase");
+        fs::write(&path, "secret\n").unwrap();
+        let source = PassphraseSource::File {
+            path: path.display().to_string(),
+        };
+        assert_eq!(source.resolve().unwrap().value, "secret");
+        assert_eq!(source.resolve().unwrap().variable, "BORG_PASSPHRASE");
+
+        fs::write(&path, "\n").unwrap();
+        assert!(source.resolve().is_err());
+        let debug = format!("{:?}", passphrase_value("secret".to_string()));
+        assert!(!debug.contains("secret"));
+        let missing = PassphraseSource::Env {
+            variable: "BTM_TEST_UNSET_PASSPHRASE".to_string(),
+        };
+        assert!(missing.resolve().is_err());
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut secret = "hunter2".to_string();
+        zeroize(&mut secret);
+        assert!(secret.is_empty());
+        assert_eq!(secret.capacity(), 7);
+    }
+
+    #[test]
+    fn test_scrub() {
+        let source = PassphraseSource::Env {
+            variable: "CREDS".to_string(),
+        };
+        let mut command = Command::new("true");
+        command.env("CREDS", "secret");
+        source.scrub(&mut command);
+        let removed: Vec<_> = command.get_envs().filter(|(_, v)| v.is_none()).collect();
+        assert_eq!(removed.len(), 3);
+    }
+}
@@ -0,0 +1,187 @@
+use chrono::{DateTime, Datelike, Duration, Local, Utc};
+use serde::Deserialize;
+
+/// What a cycle does once something has logged a warning.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnWarning {
+    /// Finish the cycle and report success with warnings
+    #[default]
+    Continue,
+    /// Fail the cycle at the end of the stage that warned
+    Fail,
+}
+
+/// What to do with an enabled job whose source does not exist.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingSource {
+    /// Leave the job out of the archive with a warning
+    #[default]
+    Skip,
+    Fail,
+}
+
+/// What to do when the scheduled `borg check` has not run for too long,
+/// e.g. because the machine was off on every check day.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnCheckOverdue {
+    #[default]
+    Warn,
+    Fail,
+}
+
+/// What `backup` does when the repository has not been created yet.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingRepository {
+    /// Offer to initialize it when run from a terminal, fail otherwise
+    #[default]
+    Ask,
+    /// Initialize it, export the key to `security.key_backup` and carry on
+    Init,
+    Fail,
+}
+
+/// What a cycle does with the checkpoint archives of an interrupted one,
+/// once it has written a complete archive.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnCheckpoints {
+    /// Leave them to `borg prune`, which removes those a complete archive
+    /// has superseded
+    #[default]
+    Prune,
+    /// Delete them straight away
+    Delete,
+}
+
+/// Decisions the cycle makes about non-fatal problems. Defaults suit a
+/// laptop; a server would usually set everything to `fail`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Policies {
+    #[serde(default)]
+    pub on_warning: OnWarning,
+    #[serde(default)]
+    pub on_missing_source: OnMissingSource,
+    #[serde(default)]
+    pub on_check_overdue: OnCheckOverdue,
+    #[serde(default)]
+    pub on_missing_repository: OnMissingRepository,
+    #[serde(default)]
+    pub on_checkpoints: OnCheckpoints,
+}
+
+/// A weekly check counts as overdue after this many days without one.
+pub const CHECK_OVERDUE_DAYS: i64 = 8;
+
+/// Whether the repository check is overdue at `now`. Before the first
+/// check, the first recorded cycle stands in for it, so a new install is not
+/// flagged straight away.
+pub fn check_overdue(
+    last_check: Option<DateTime<Utc>>,
+    first_cycle: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> bool {
+    last_check
+        .or(first_cycle)
+        .is_some_and(|since| now - since > Duration::days(CHECK_OVERDUE_DAYS))
+}
+
+/// Whether the scheduled check runs at `now`: once on `check_day` (1=Mon,
+/// 7=Sun, 0=never), or on any day once a week has passed without one, so a
+/// machine that is off on check days still gets checked.
+pub fn check_due(
+    check_day: u32,
+    last_check: Option<DateTime<Utc>>,
+    first_cycle: Option<DateTime<Utc>>,
+    now: DateTime<Local>,
+) -> bool {
+    if check_day == 0 {
+        return false;
+    }
+    if last_check.is_some_and(|last| last.with_timezone(&Local).date_naive() == now.date_naive()) {
+        return false;
+    }
+    now.weekday().number_from_monday() == check_day
+        || last_check
+            .or(first_cycle)
+            .is_some_and(|since| now.with_timezone(&Utc) - since >= Duration::days(7))
+}
+
+/// Whether `borg compact` runs at `now`, at most once per `interval`.
+pub fn compact_due(
+    interval: std::time::Duration,
+    last_compact: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> bool {
+    last_compact.is_none_or(|last| {
+        (now - last)
+            .to_std()
+            .is_ok_and(|elapsed| elapsed >= interval)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_check_overdue() {
+        let now = Utc::now();
+        let days = Duration::days;
+        assert!(!check_overdue(None, None, now));
+        assert!(!check_overdue(None, Some(now - days(3)), now));
+        assert!(check_overdue(None, Some(now - days(30)), now));
+        assert!(!check_overdue(
+            Some(now - days(7)),
+            Some(now - days(30)),
+            now
+        ));
+        assert!(check_overdue(Some(now - days(9)), None, now));
+    }
+
+    #[test]
+    fn test_check_due() {
+        let days = Duration::days;
+        // A Sunday
+        let sunday = Local.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap();
+        let utc = |t: DateTime<Local>| t.with_timezone(&Utc);
+        assert!(!check_due(0, None, None, sunday));
+        assert!(check_due(7, None, None, sunday));
+        assert!(check_due(7, Some(utc(sunday - days(7))), None, sunday));
+        // Once per day, however many cycles run
+        assert!(!check_due(
+            7,
+            Some(utc(sunday - Duration::hours(1))),
+            None,
+            sunday
+        ));
+        // Missed check days are made up for
+        let monday = sunday + days(1);
+        assert!(check_due(7, Some(utc(sunday - days(7))), None, monday));
+        assert!(!check_due(7, Some(utc(sunday)), None, monday));
+        assert!(!check_due(7, None, Some(utc(sunday)), monday));
+        assert!(check_due(7, None, Some(utc(sunday - days(7))), monday));
+    }
+
+    #[test]
+    fn test_compact_due() {
+        let now = Utc::now();
+        let day = std::time::Duration::from_secs(86400);
+        assert!(compact_due(day, None, now));
+        assert!(!compact_due(day, Some(now - Duration::hours(2)), now));
+        assert!(compact_due(day, Some(now - Duration::hours(25)), now));
+        assert!(compact_due(std::time::Duration::ZERO, Some(now), now));
+    }
+
+    #[test]
+    fn test_policy_defaults() {
+        let policies: Policies = serde_yaml::from_str("on_warning: fail").unwrap();
+        assert_eq!(policies.on_warning, OnWarning::Fail);
+        assert_eq!(policies.on_missing_source, OnMissingSource::Skip);
+        assert_eq!(policies.on_check_overdue, OnCheckOverdue::Warn);
+    }
+}
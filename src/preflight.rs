@@ -0,0 +1,301 @@
+use serde::Deserialize;
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// What to do when a preflight check finds a problem.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Ignore,
+    #[default]
+    Warn,
+    Fail,
+}
+
+/// Outcome of a single `doctor` check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    pub fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+/// Classify the result of a trivial `borg list` run with the configured
+/// passphrase: whether the key decrypts, and whether borg complained about
+/// missing TAM authentication.
+pub fn key_checks(success: bool, stderr: &str) -> Vec<CheckResult> {
+    let lower = stderr.to_lowercase();
+    let mut results = Vec::new();
+
+    let decrypt = if success {
+        CheckResult::new(
+            "key",
+            CheckStatus::Pass,
+            "passphrase decrypts the repository key",
+        )
+    } else if lower.contains("passphrase") && lower.contains("incorrect") {
+        CheckResult::new(
+            "key",
+            CheckStatus::Fail,
+            "passphrase is incorrect for this repository",
+        )
+    } else {
+        CheckResult::new(
+            "key",
+            CheckStatus::Fail,
+            format!("borg list failed: {}", stderr.trim()),
+        )
+    };
+    results.push(decrypt);
+
+    if lower.contains("tam") {
+        results.push(CheckResult::new(
+            "tam",
+            CheckStatus::Warn,
+            "borg reported TAM authentication problems; see `borg upgrade --tam` and `borg upgrade --archives-tam`",
+        ));
+    } else if success {
+        results.push(CheckResult::new(
+            "tam",
+            CheckStatus::Pass,
+            "no TAM warnings reported",
+        ));
+    }
+
+    results
+}
+
+/// How a local repository relates to a backup source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    /// The repository lives inside the source tree
+    Inside,
+    /// The repository is on the same device as the source
+    SameDevice,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overlap::Inside => write!(f, "is inside"),
+            Overlap::SameDevice => write!(f, "is on the same device as"),
+        }
+    }
+}
+
+/// Compare a repository path with a backup source. The repository may not
+/// exist yet, in which case its closest existing ancestor is used.
+pub fn repository_overlap(repo: &Path, source: &Path) -> Option<Overlap> {
+    let repo = existing_ancestor(repo)?;
+    let source = source.canonicalize().ok()?;
+
+    if repo.starts_with(&source) {
+        return Some(Overlap::Inside);
+    }
+
+    let repo_dev = repo.metadata().ok()?.dev();
+    let source_dev = source.metadata().ok()?.dev();
+    if repo_dev == source_dev {
+        return Some(Overlap::SameDevice);
+    }
+    None
+}
+
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors().find_map(|p| p.canonicalize().ok())
+}
+
+/// Whether `path` is `source` itself or lies below it. Both sides are
+/// resolved where possible so symlinked sources still match.
+pub fn is_under(path: &Path, source: &Path) -> bool {
+    let path = existing_ancestor(path)
+        .map(|resolved| {
+            // Re-attach the components that do not exist yet
+            let missing = path
+                .ancestors()
+                .find(|p| p.exists())
+                .and_then(|existing| path.strip_prefix(existing).ok())
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            resolved.join(missing)
+        })
+        .unwrap_or_else(|| path.to_path_buf());
+    let source = source
+        .canonicalize()
+        .unwrap_or_else(|_| source.to_path_buf());
+    path.starts_with(source)
+}
+
+/// The filesystem a path lives on, with the identifiers udev knows it by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountedDevice {
+    pub mount_point: PathBuf,
+    pub device: String,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+/// Find the mounted filesystem holding `path`. The path need not exist
+/// yet; its closest existing ancestor is used.
+pub fn mounted_device(path: &Path) -> Option<MountedDevice> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let resolved = existing.canonicalize().ok()?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    let (mount_point, device) = mount_containing(&mounts, &resolved)?;
+
+    Some(MountedDevice {
+        uuid: disk_id("/dev/disk/by-uuid", &device),
+        label: disk_id("/dev/disk/by-label", &device),
+        mount_point,
+        device,
+    })
+}
+
+/// Mount point and device of the innermost mount containing `path`.
+fn mount_containing(mounts: &str, path: &Path) -> Option<(PathBuf, String)> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mount_point = PathBuf::from(fields.next()?.replace("\\040", " "));
+            path.starts_with(&mount_point)
+                .then(|| (mount_point, device.to_string()))
+        })
+        // Later mounts shadow earlier ones on the same mount point
+        .fold(
+            None,
+            |best: Option<(PathBuf, String)>, candidate| match best {
+                Some(b) if b.0.components().count() > candidate.0.components().count() => Some(b),
+                _ => Some(candidate),
+            },
+        )
+}
+
+/// Name of the udev symlink in `dir` that points at `device`, e.g. the
+/// filesystem UUID under `/dev/disk/by-uuid`.
+fn disk_id(dir: &str, device: &str) -> Option<String> {
+    let device = Path::new(device).canonicalize().ok()?;
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .find(|entry| entry.path().canonicalize().ok().as_ref() == Some(&device))
+        // udev escapes spaces in labels as \x20
+        .map(|entry| entry.file_name().to_string_lossy().replace("\\x20", " "))
+}
+
+/// Mount points of active `borg mount` FUSE filesystems.
+pub fn borg_mount_points() -> Vec<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    parse_borg_mounts(&mounts)
+}
+
+fn parse_borg_mounts(mounts: &str) -> Vec<PathBuf> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fstype = fields.next()?;
+            if device == "borgfs" || fstype == "fuse.borgfs" {
+                // /proc/mounts escapes spaces as \040
+                Some(PathBuf::from(mount_point.replace("\\040", " ")))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_borg_mounts() {
+        let mounts = "/dev/sda1 / ext4 rw 0 0\nborgfs /mnt/borg\\040browse fuse rw 0 0\n";
+        assert_eq!(
+            parse_borg_mounts(mounts),
+            vec![PathBuf::from("/mnt/borg browse")]
+        );
+    }
+
+    #[test]
+    fn test_mount_containing() {
+        let mounts = "/dev/sda2 / ext4 rw 0 0\n\
+                      /dev/sdb1 /mnt/backup\\040disk vfat rw 0 0\n\
+                      /dev/sdc1 /mnt/backup\\040disk ext4 rw 0 0\n";
+        assert_eq!(
+            mount_containing(mounts, Path::new("/mnt/backup disk/borg")),
+            Some((PathBuf::from("/mnt/backup disk"), "/dev/sdc1".to_string()))
+        );
+        assert_eq!(
+            mount_containing(mounts, Path::new("/mnt/backup2")),
+            Some((PathBuf::from("/"), "/dev/sda2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_key_checks() {
+        let ok = key_checks(true, "");
+        assert_eq!(ok[0].status, CheckStatus::Pass);
+        assert_eq!(ok[1].status, CheckStatus::Pass);
+
+        let wrong = key_checks(
+            false,
+            "passphrase supplied in BORG_PASSPHRASE, by BORG_PASSCOMMAND or via BORG_PASSPHRASE_FD is incorrect.",
+        );
+        assert_eq!(wrong[0].status, CheckStatus::Fail);
+        assert!(wrong[0].detail.contains("incorrect"));
+
+        let tam = key_checks(true, "Archive TAM not found\n");
+        assert_eq!(tam[1].status, CheckStatus::Warn);
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under(
+            Path::new("/nonexistent-root/var/log/x.log"),
+            Path::new("/nonexistent-root/var")
+        ));
+        assert!(!is_under(
+            Path::new("/nonexistent-root/variable"),
+            Path::new("/nonexistent-root/var")
+        ));
+    }
+}
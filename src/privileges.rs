@@ -0,0 +1,330 @@
+use crate::{on_path, BackupJob, Config};
+use serde::Deserialize;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Who borg runs as for system-wide backups.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Run {
+    /// Account that owns the repository, borg's cache and the passphrase.
+    /// Started as root, we run borg as this user; jobs with `sudo: true`
+    /// keep the right to read every file (CAP_DAC_READ_SEARCH) and nothing
+    /// else, so the repository never ends up with files owned by root.
+    #[serde(default)]
+    pub borg_user: Option<String>,
+}
+
+/// A local account, from the password database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+impl Account {
+    pub fn lookup(name: &str) -> Result<Self, String> {
+        let c_name = CString::new(name).map_err(|_| format!("Invalid user name {}", name))?;
+        // SAFETY: passwd is plain data that getpwnam_r fills in
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut found = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call; the strings in pwd
+        // point into buf, which outlives them
+        let rc = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if rc != 0 || found.is_null() {
+            return Err(format!("No user named {}", name));
+        }
+        // SAFETY: pw_dir is a NUL-terminated string inside buf
+        let home = unsafe { CStr::from_ptr(pwd.pw_dir) }
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self {
+            name: name.to_string(),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            home,
+        })
+    }
+
+    /// The primary and supplementary groups.
+    fn groups(&self) -> Vec<u32> {
+        let Ok(name) = CString::new(self.name.as_str()) else {
+            return vec![self.gid];
+        };
+        let mut groups = vec![0 as libc::gid_t; 256];
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: groups has room for count entries
+        let rc =
+            unsafe { libc::getgrouplist(name.as_ptr(), self.gid, groups.as_mut_ptr(), &mut count) };
+        if rc < 0 {
+            return vec![self.gid];
+        }
+        groups.truncate(count as usize);
+        groups
+    }
+}
+
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The account borg runs as instead of root, if any.
+pub fn run_as(config: &Config) -> Result<Option<Account>, String> {
+    match &config.run.borg_user {
+        Some(name) if is_root() => Account::lookup(name).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// `setpriv` arguments that run borg as `account`; with `read_all` it can
+/// still read every file, as root could.
+pub fn setpriv_args(account: &Account, read_all: bool) -> Vec<String> {
+    let mut args = vec![
+        format!("--reuid={}", account.uid),
+        format!("--regid={}", account.gid),
+        "--init-groups".to_string(),
+    ];
+    if read_all {
+        args.push("--inh-caps=+dac_read_search".to_string());
+        args.push("--ambient-caps=+dac_read_search".to_string());
+    }
+    args.push("--".to_string());
+    args
+}
+
+/// Find what would make borg fail partway through a backup for lack of
+/// privileges: a `borg_user` we can't switch to, a repository it doesn't
+/// own, or a job source it can't read.
+pub fn check(config: &Config) -> Result<(), String> {
+    let root = is_root();
+    let account = match &config.run.borg_user {
+        Some(name) => Some(Account::lookup(name).map_err(|e| format!("run.borg_user: {}", e))?),
+        None => None,
+    };
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    if let Some(account) = &account {
+        if !root && account.uid != uid {
+            return Err(format!(
+                "run.borg_user is {}, but we run as uid {}; start as root or as {}",
+                account.name, uid, account.name
+            ));
+        }
+        if root && !on_path("setpriv") {
+            return Err("run.borg_user needs setpriv (from util-linux) on the PATH".to_string());
+        }
+        if root && config.repository.remote.is_none() {
+            check_repository_owner(&config.repository.path, account)?;
+        }
+    }
+
+    let binary = find_binary(&config.borg.binary);
+    let interpreter = binary.as_deref().and_then(script_interpreter);
+    let read_all = root || (interpreter.is_none() && binary.as_deref().is_some_and(can_read_all));
+    for job in config.jobs.iter().filter(|job| job.enabled) {
+        if job.sudo {
+            if read_all {
+                continue;
+            }
+            let start_as_root = "start as root (with run.borg_user to keep the repository owned by the backup user)";
+            return Err(match &interpreter {
+                // setcap on the script would pass getcap and still fail
+                Some(interpreter) => format!(
+                    "job {} has sudo: true, but borg can't read every file as uid {}: {} is a script run by {}, and file capabilities don't apply to scripts; {}",
+                    job.name, uid, config.borg.binary, interpreter, start_as_root
+                ),
+                None => format!(
+                    "job {} has sudo: true, but borg can't read every file as uid {}; {} or run `setcap cap_dac_read_search+ep` on {}",
+                    job.name, uid, start_as_root, config.borg.binary
+                ),
+            });
+        }
+        let unprivileged = match &account {
+            Some(account) if root => Some(account),
+            _ => None,
+        };
+        if unprivileged.is_some() || !read_all {
+            check_source(job, unprivileged)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_repository_owner(path: &str, account: &Account) -> Result<(), String> {
+    let Ok(meta) = fs::metadata(path) else {
+        // Not created yet; init will create it as the borg user
+        return Ok(());
+    };
+    if meta.uid() != account.uid {
+        return Err(format!(
+            "repository {} is owned by uid {}, not run.borg_user {}; run chown -R {} {}",
+            path,
+            meta.uid(),
+            account.name,
+            account.name,
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Whether borg can read `job.source`: as `account` judged by the mode
+/// bits, or as ourselves by trying.
+fn check_source(job: &BackupJob, account: Option<&Account>) -> Result<(), String> {
+    if job.paths_from.is_some() {
+        return Ok(());
+    }
+    let path = Path::new(&job.source);
+    let Ok(meta) = fs::metadata(path) else {
+        // on_missing_source decides about those
+        return Ok(());
+    };
+    let readable = match account {
+        Some(account) => readable_by(
+            meta.mode(),
+            meta.is_dir(),
+            (meta.uid(), meta.gid()),
+            account.uid,
+            &account.groups(),
+        ),
+        None => {
+            let result = if meta.is_dir() {
+                fs::read_dir(path).map(|_| ())
+            } else {
+                fs::File::open(path).map(|_| ())
+            };
+            !matches!(result, Err(e) if e.kind() == ErrorKind::PermissionDenied)
+        }
+    };
+    if readable {
+        return Ok(());
+    }
+    let user = account.map_or_else(|| "this user".to_string(), |a| a.name.clone());
+    Err(format!(
+        "job {}: {} can't read {}; set `sudo: true` on the job",
+        job.name, user, job.source
+    ))
+}
+
+/// Whether a file with `mode` and `(owner, group)` is readable (and, for a
+/// directory, searchable) by `uid` in `groups`.
+fn readable_by(
+    mode: u32,
+    is_dir: bool,
+    (owner, group): (u32, u32),
+    uid: u32,
+    groups: &[u32],
+) -> bool {
+    let shift = if owner == uid {
+        6
+    } else if groups.contains(&group) {
+        3
+    } else {
+        0
+    };
+    let needed = if is_dir { 0o5 } else { 0o4 };
+    (mode >> shift) & needed == needed
+}
+
+/// `binary` as run: the path given, or where it is on the PATH.
+fn find_binary(binary: &str) -> Option<PathBuf> {
+    if binary.contains('/') {
+        return Some(binary.into());
+    }
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(binary))
+            .find(|path| path.is_file())
+    })
+}
+
+/// The interpreter on the `#!` line of `path`, if it is a script, as
+/// borg installed with pip is.
+fn script_interpreter(path: &Path) -> Option<String> {
+    let mut head = [0u8; 256];
+    let read = fs::File::open(path)
+        .and_then(|mut file| file.read(&mut head))
+        .ok()?;
+    let line = head[..read].strip_prefix(b"#!")?;
+    let line = line.split(|&b| b == b'\n').next().unwrap_or_default();
+    let interpreter = String::from_utf8_lossy(line).trim().to_string();
+    Some(if interpreter.is_empty() {
+        "a shell".to_string()
+    } else {
+        interpreter
+    })
+}
+
+/// Whether the executable at `path` has the CAP_DAC_READ_SEARCH file
+/// capability.
+fn can_read_all(path: &Path) -> bool {
+    Command::new("getcap")
+        .arg(path)
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("cap_dac_read_search"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readable_by() {
+        // drwx------ root:root
+        assert!(!readable_by(0o40700, true, (0, 0), 1000, &[1000]));
+        assert!(readable_by(0o40700, true, (1000, 0), 1000, &[1000]));
+        // drwxr-x--- root:backup
+        assert!(readable_by(0o40750, true, (0, 34), 1000, &[1000, 34]));
+        // -rw-r--r-- needs no search bit
+        assert!(readable_by(0o100644, false, (0, 0), 1000, &[1000]));
+        // drw-r--r-- can't be searched
+        assert!(!readable_by(0o40644, true, (0, 0), 1000, &[1000]));
+    }
+
+    #[test]
+    fn test_script_interpreter() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("borg");
+        fs::write(&script, "#!/usr/bin/python3 -s\nimport borg\n").unwrap();
+        assert_eq!(
+            script_interpreter(&script).as_deref(),
+            Some("/usr/bin/python3 -s")
+        );
+        let binary = dir.path().join("borg.bin");
+        fs::write(&binary, b"\x7fELF\x02\x01").unwrap();
+        assert_eq!(script_interpreter(&binary), None);
+    }
+
+    #[test]
+    fn test_setpriv_args() {
+        let account = Account::lookup("root").unwrap();
+        assert_eq!(account.uid, 0);
+        assert_eq!(
+            setpriv_args(&account, true),
+            [
+                "--reuid=0",
+                "--regid=0",
+                "--init-groups",
+                "--inh-caps=+dac_read_search",
+                "--ambient-caps=+dac_read_search",
+                "--"
+            ]
+        );
+        assert!(Account::lookup("no-such-user-here").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use serde::Deserialize;
+
+/// Program copying the repository to the replica.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationTool {
+    /// A local path or `[user@]host:path` over SSH
+    #[default]
+    Rsync,
+    /// Any rclone remote, e.g. `b2:bucket/borg`
+    Rclone,
+}
+
+impl ReplicationTool {
+    pub fn program(self) -> &'static str {
+        match self {
+            ReplicationTool::Rsync => "rsync",
+            ReplicationTool::Rclone => "rclone",
+        }
+    }
+}
+
+/// A second copy of the repository, synced from the primary after the
+/// cycle or with `replicate`. The copy runs under `borg with-lock`, so it
+/// never sees a repository borg is halfway through writing.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Replication {
+    /// Where the copy goes, in the tool's syntax
+    pub target: String,
+    #[serde(default)]
+    pub tool: ReplicationTool,
+    /// Replicate at the end of every backup cycle; otherwise only
+    /// `replicate` does
+    #[serde(default = "crate::default_true")]
+    pub after_cycle: bool,
+    /// Remove files from the replica that are gone from the primary, so
+    /// pruned archives disappear there too
+    #[serde(default = "crate::default_true")]
+    pub delete: bool,
+    /// Bandwidth limit in the tool's syntax, e.g. `5M`
+    #[serde(default)]
+    pub bwlimit: Option<String>,
+    /// SSH key for rsync targets on another host
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    /// Further arguments for the tool
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl Replication {
+    pub fn lint(&self, repository: &str) -> Result<(), String> {
+        if self.target.trim().is_empty() {
+            return Err("replication.target is empty".to_string());
+        }
+        if self.target.trim_end_matches('/') == repository.trim_end_matches('/') {
+            return Err(format!(
+                "replication.target {} is the repository itself",
+                self.target
+            ));
+        }
+        if self.ssh_key.is_some() && self.tool != ReplicationTool::Rsync {
+            return Err("replication.ssh_key only applies to rsync".to_string());
+        }
+        Ok(())
+    }
+
+    /// The tool's arguments copying the repository at `source`.
+    pub fn args(&self, source: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        match self.tool {
+            ReplicationTool::Rsync => {
+                // Segments are only ever added or removed whole, so
+                // --partial lets an interrupted run pick up where it was
+                args.extend(["--archive", "--partial"].map(String::from));
+                if self.delete {
+                    args.push("--delete".to_string());
+                }
+                if let Some(limit) = &self.bwlimit {
+                    args.push(format!("--bwlimit={}", limit));
+                }
+                if let Some(key) = &self.ssh_key {
+                    args.push("--rsh".to_string());
+                    args.push(format!("ssh -i {}", key));
+                }
+                args.extend(self.extra_args.iter().cloned());
+                // Trailing slashes copy the contents, not the directory
+                args.push(format!("{}/", source.trim_end_matches('/')));
+                args.push(format!("{}/", self.target.trim_end_matches('/')));
+            }
+            ReplicationTool::Rclone => {
+                args.push(if self.delete { "sync" } else { "copy" }.to_string());
+                if let Some(limit) = &self.bwlimit {
+                    args.push(format!("--bwlimit={}", limit));
+                }
+                args.extend(self.extra_args.iter().cloned());
+                args.push(source.to_string());
+                args.push(self.target.clone());
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let rsync: Replication = serde_yaml::from_str(
+            "target: backup@nas:/srv/borg\nbwlimit: 5M\nssh_key: /root/.ssh/id",
+        )
+        .unwrap();
+        assert!(rsync.lint("/mnt/borg").is_ok());
+        assert_eq!(
+            rsync.args("/mnt/borg"),
+            [
+                "--archive",
+                "--partial",
+                "--delete",
+                "--bwlimit=5M",
+                "--rsh",
+                "ssh -i /root/.ssh/id",
+                "/mnt/borg/",
+                "backup@nas:/srv/borg/"
+            ]
+        );
+
+        let rclone: Replication =
+            serde_yaml::from_str("target: b2:bucket/borg\ntool: rclone\ndelete: false").unwrap();
+        assert_eq!(
+            rclone.args("/mnt/borg/"),
+            ["copy", "/mnt/borg/", "b2:bucket/borg"]
+        );
+
+        let itself = Replication {
+            target: "/mnt/borg/".to_string(),
+            ..rsync
+        };
+        assert!(itself.lint("/mnt/borg").is_err());
+    }
+}
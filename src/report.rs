@@ -0,0 +1,546 @@
+use crate::state::HistoryEntry;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Size and file counts of a single archive, as reported by `borg info --json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ArchiveStats {
+    pub original_size: u64,
+    pub compressed_size: u64,
+    pub deduplicated_size: u64,
+    pub nfiles: u64,
+}
+
+impl ArchiveStats {
+    /// Fraction of the original size that did not have to be stored, e.g.
+    /// `0.95` when 5% of the data was new after compression and dedup.
+    pub fn savings(&self) -> f64 {
+        if self.original_size == 0 {
+            return 0.0;
+        }
+        1.0 - self.deduplicated_size as f64 / self.original_size as f64
+    }
+}
+
+/// Statistics of one archive written by a cycle, with the jobs it holds.
+/// `paths_from` jobs get an archive each; the other jobs share one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobStats {
+    pub jobs: Vec<String>,
+    pub archive: String,
+    pub stats: ArchiveStats,
+}
+
+/// Sum the statistics of many archives per job (or group of jobs sharing
+/// an archive), the job that benefits most from dedup and compression
+/// first.
+pub fn savings_by_job<'a>(
+    stats: impl IntoIterator<Item = &'a JobStats>,
+) -> Vec<(String, ArchiveStats)> {
+    let mut totals: Vec<(String, ArchiveStats)> = Vec::new();
+    for entry in stats {
+        let label = entry.jobs.join(", ");
+        let index = match totals.iter().position(|(name, _)| *name == label) {
+            Some(index) => index,
+            None => {
+                totals.push((label, ArchiveStats::default()));
+                totals.len() - 1
+            }
+        };
+        let total = &mut totals[index].1;
+        total.original_size += entry.stats.original_size;
+        total.compressed_size += entry.stats.compressed_size;
+        total.deduplicated_size += entry.stats.deduplicated_size;
+        total.nfiles += entry.stats.nfiles;
+    }
+    totals.sort_by(|a, b| b.1.savings().total_cmp(&a.1.savings()));
+    totals
+}
+
+#[derive(Deserialize)]
+struct InfoOutput {
+    archives: Vec<InfoArchive>,
+}
+
+#[derive(Deserialize)]
+struct InfoArchive {
+    stats: ArchiveStats,
+}
+
+impl ArchiveStats {
+    /// Parse the output of `borg info --json <repo>::<archive>`.
+    pub fn from_info_json(json: &str) -> Result<Self, String> {
+        let info: InfoOutput = serde_json::from_str(json)
+            .map_err(|e| format!("Failed to parse borg info output: {}", e))?;
+        info.archives
+            .into_iter()
+            .next()
+            .map(|a| a.stats)
+            .ok_or_else(|| "borg info returned no archives".to_string())
+    }
+}
+
+/// An archive with its statistics, from `borg info --json` over many
+/// archives.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ArchiveDetails {
+    pub name: String,
+    pub time: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub stats: ArchiveStats,
+    /// Given with `backup --comment` or `tag`
+    pub comment: String,
+    /// Machine borg created the archive on
+    pub hostname: String,
+    /// Passed a restore test; filled in from the state file
+    pub verified: bool,
+}
+
+#[derive(Deserialize)]
+struct InfoDetailsOutput {
+    archives: Vec<InfoArchiveDetails>,
+}
+
+#[derive(Deserialize)]
+struct InfoArchiveDetails {
+    name: String,
+    start: String,
+    #[serde(default)]
+    duration: f64,
+    stats: ArchiveStats,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    hostname: String,
+}
+
+impl ArchiveDetails {
+    /// Parse the output of `borg info --json --glob-archives=<glob> <repo>`,
+    /// leaving out checkpoints.
+    pub fn from_info_json(json: &str) -> Result<Vec<Self>, String> {
+        let info: InfoDetailsOutput = serde_json::from_str(json)
+            .map_err(|e| format!("Failed to parse borg info output: {}", e))?;
+        info.archives
+            .into_iter()
+            .filter(|a| !is_checkpoint(&a.name))
+            .map(InfoArchiveDetails::into_details)
+            .collect()
+    }
+}
+
+impl InfoArchiveDetails {
+    fn into_details(self) -> Result<ArchiveDetails, String> {
+        Ok(ArchiveDetails {
+            time: parse_borg_time(&self.start)?,
+            name: self.name,
+            duration_secs: self.duration,
+            stats: self.stats,
+            comment: self.comment,
+            hostname: self.hostname,
+            verified: false,
+        })
+    }
+}
+
+/// Totals over the whole repository, from the `cache` section of `borg
+/// info --json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RepositoryStats {
+    /// Size of all archives added up
+    pub total_size: u64,
+    pub total_csize: u64,
+    /// What the repository stores after compression and dedup
+    pub unique_csize: u64,
+    pub unique_size: u64,
+    pub total_chunks: u64,
+    pub total_unique_chunks: u64,
+}
+
+/// The repository as described by `borg info --json`, with the archive
+/// when one was asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepositoryInfo {
+    pub location: String,
+    pub id: String,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub encryption: String,
+    /// Local chunk cache
+    pub cache_path: String,
+    pub stats: RepositoryStats,
+    pub archive: Option<ArchiveDetails>,
+}
+
+/// `RepositoryInfo` with how the repository grew over the last week and
+/// month, e.g. `grew 2.3 GiB this week`; no growth for a single archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepositoryReport {
+    pub info: RepositoryInfo,
+    pub growth: Vec<String>,
+}
+
+/// A repository `init` created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitOutcome {
+    pub location: String,
+    /// Where the key was exported, if it was
+    pub key_exported_to: Option<String>,
+}
+
+/// The last backup cycle and the rotation disks, for `status`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub last_cycle: Option<HistoryEntry>,
+    pub disks: Vec<DiskStatus>,
+    /// `repository.rotation_max_age`, for telling when a disk is stale
+    #[serde(skip)]
+    pub rotation_max_age: String,
+    /// Every host with archives in a shared repository
+    pub hosts: Vec<HostStatus>,
+    /// Why `hosts` couldn't be read, e.g. the repository being locked or
+    /// unreachable
+    pub hosts_unavailable: Option<String>,
+    /// `repository.host_max_age`, for telling when a host is stale
+    #[serde(skip)]
+    pub host_max_age: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiskStatus {
+    pub name: String,
+    pub uuid: String,
+    pub attached: bool,
+    pub last_backup: Option<DateTime<Utc>>,
+    /// No backup within `rotation_max_age`
+    pub stale: bool,
+}
+
+/// A host backing up to a shared repository, from the archives it wrote.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HostStatus {
+    pub hostname: String,
+    pub archives: usize,
+    pub last_backup: DateTime<Utc>,
+    /// No archive within `host_max_age`
+    pub stale: bool,
+}
+
+#[derive(Deserialize)]
+struct RepositoryInfoOutput {
+    repository: RepositorySection,
+    #[serde(default)]
+    encryption: Option<EncryptionSection>,
+    #[serde(default)]
+    cache: Option<CacheSection>,
+    #[serde(default)]
+    archives: Vec<InfoArchiveDetails>,
+}
+
+#[derive(Deserialize)]
+struct RepositorySection {
+    location: String,
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    last_modified: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EncryptionSection {
+    mode: String,
+}
+
+#[derive(Deserialize)]
+struct CacheSection {
+    #[serde(default)]
+    path: String,
+    stats: RepositoryStats,
+}
+
+#[derive(Deserialize)]
+struct CacheOnlyOutput {
+    #[serde(default)]
+    cache: Option<CacheSection>,
+}
+
+impl RepositoryStats {
+    /// The repository totals in any `borg info --json` output, which
+    /// includes them for archives too.
+    pub fn from_info_json(json: &str) -> Result<Option<Self>, String> {
+        let info: CacheOnlyOutput = serde_json::from_str(json)
+            .map_err(|e| format!("Failed to parse borg info output: {}", e))?;
+        Ok(info.cache.map(|cache| cache.stats))
+    }
+}
+
+impl RepositoryInfo {
+    /// Parse the output of `borg info --json <repo>` or
+    /// `<repo>::<archive>`.
+    pub fn from_info_json(json: &str) -> Result<Self, String> {
+        let info: RepositoryInfoOutput = serde_json::from_str(json)
+            .map_err(|e| format!("Failed to parse borg info output: {}", e))?;
+        let cache = info.cache.unwrap_or(CacheSection {
+            path: String::new(),
+            stats: RepositoryStats::default(),
+        });
+        let archive = info
+            .archives
+            .into_iter()
+            .next()
+            .map(InfoArchiveDetails::into_details)
+            .transpose()?;
+        Ok(Self {
+            location: info.repository.location,
+            id: info.repository.id,
+            last_modified: info
+                .repository
+                .last_modified
+                .as_deref()
+                .map(parse_borg_time)
+                .transpose()?,
+            encryption: info.encryption.map(|e| e.mode).unwrap_or_default(),
+            cache_path: cache.path,
+            stats: cache.stats,
+            archive,
+        })
+    }
+}
+
+/// How much the repository grew from the newest `samples` entry at least
+/// `period` before `now` to `current`, with the time of that entry. When
+/// every sample is more recent, the growth since the oldest is given.
+/// `samples` are `(time, unique_csize)`, oldest first.
+pub fn growth(
+    samples: &[(DateTime<Utc>, u64)],
+    current: u64,
+    now: DateTime<Utc>,
+    period: chrono::Duration,
+) -> Option<(DateTime<Utc>, i64)> {
+    let (since, size) = samples
+        .iter()
+        .rev()
+        .find(|(time, _)| *time <= now - period)
+        .or(samples.first())?;
+    Some((*since, current as i64 - *size as i64))
+}
+
+/// Whether `name` is a checkpoint borg wrote during a `create` that was
+/// interrupted: `<archive>.checkpoint`, or `<archive>.checkpoint.<n>` for
+/// later ones of the same run. Borg before 1.2 lists them with the rest.
+pub fn is_checkpoint(name: &str) -> bool {
+    match name.rsplit_once(".checkpoint") {
+        Some((archive, suffix)) => {
+            !archive.is_empty()
+                && (suffix.is_empty()
+                    || suffix
+                        .strip_prefix('.')
+                        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())))
+        }
+        None => false,
+    }
+}
+
+/// An archive as listed by `borg list --json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub time: DateTime<Utc>,
+    /// Only filled in when listed with `--format={comment}`
+    pub comment: String,
+    /// Only filled in when listed with `--format={hostname}`
+    pub hostname: String,
+}
+
+#[derive(Deserialize)]
+struct ListOutput {
+    archives: Vec<ListArchive>,
+}
+
+#[derive(Deserialize)]
+struct ListArchive {
+    archive: String,
+    /// Local time without an offset, e.g. `2024-03-01T12:00:00.000000`
+    time: String,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    hostname: String,
+}
+
+impl ArchiveEntry {
+    /// Parse the output of `borg list --json <repo>`, leaving out
+    /// checkpoints.
+    pub fn from_list_json(json: &str) -> Result<Vec<Self>, String> {
+        let list: ListOutput = serde_json::from_str(json)
+            .map_err(|e| format!("Failed to parse borg list output: {}", e))?;
+        list.archives
+            .into_iter()
+            .filter(|a| !is_checkpoint(&a.archive))
+            .map(|a| {
+                Ok(Self {
+                    time: parse_borg_time(&a.time)?,
+                    name: a.archive,
+                    comment: a.comment,
+                    hostname: a.hostname,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A file inside an archive, from `borg list --json-lines`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub path: String,
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ListLine {
+    #[serde(rename = "type")]
+    kind: String,
+    path: String,
+    #[serde(default)]
+    size: u64,
+    mtime: String,
+}
+
+impl FileEntry {
+    /// Parse the regular files and symlinks out of `borg list --json-lines
+    /// <repo>::<archive>`; directories are skipped.
+    pub fn from_json_lines(lines: &str) -> Result<Vec<Self>, String> {
+        lines
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str::<ListLine>(line)
+                    .map_err(|e| format!("Failed to parse borg list output: {}", e))
+            })
+            .filter(|entry| !matches!(entry, Ok(e) if e.kind == "d"))
+            .map(|entry| {
+                let entry = entry?;
+                Ok(Self {
+                    mtime: parse_borg_time(&entry.mtime)?,
+                    path: entry.path,
+                    size: entry.size,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Borg prints local times without an offset, e.g. `2024-03-01T12:00:00.000000`.
+fn parse_borg_time(time: &str) -> Result<DateTime<Utc>, String> {
+    let naive = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+        .map_err(|e| format!("Invalid time {}: {}", time, e))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or_else(|| format!("Invalid local time {}", time))
+}
+
+/// How a stage of the cycle ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StageOutcome {
+    Success,
+    /// Finished, but logged warnings
+    Warning,
+    Failed,
+}
+
+/// One pipeline stage that ran, e.g. `create` or `prune`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageReport {
+    pub name: String,
+    pub started: DateTime<Utc>,
+    pub duration: Duration,
+    pub outcome: StageOutcome,
+}
+
+/// What happened during one backup cycle.
+#[derive(Debug, Clone)]
+pub struct CycleReport {
+    pub started: DateTime<Utc>,
+    pub duration: Duration,
+    pub success: bool,
+    pub error: Option<String>,
+    pub archive: Option<String>,
+    /// Every archive written, including those of `paths_from` jobs
+    pub created: Vec<String>,
+    /// Compression actually passed to borg, after resolving `auto`
+    pub compression: Option<String>,
+    pub stats: Option<ArchiveStats>,
+    /// Repository totals after the last archive was written
+    pub repository: Option<RepositoryStats>,
+    /// Statistics of every archive written, by job
+    pub job_stats: Vec<JobStats>,
+    /// Archives removed by prune
+    pub pruned: Vec<String>,
+    /// Files borg could not read, or that changed while it read them, as
+    /// `path: reason`
+    pub skipped: Vec<String>,
+    /// Each pipeline stage that ran, in order
+    pub stages: Vec<StageReport>,
+    pub warnings: Vec<String>,
+}
+
+impl CycleReport {
+    pub fn new(started: DateTime<Utc>) -> Self {
+        Self {
+            started,
+            duration: Duration::ZERO,
+            success: false,
+            error: None,
+            archive: None,
+            created: Vec::new(),
+            compression: None,
+            stats: None,
+            repository: None,
+            job_stats: Vec::new(),
+            pruned: Vec::new(),
+            skipped: Vec::new(),
+            stages: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// How the cycle ended, once `success` and `error` are set.
+    pub fn outcome(&self) -> CycleOutcome {
+        if !self.success {
+            let error = self
+                .error
+                .clone()
+                .unwrap_or_else(|| "Backup failed".to_string());
+            CycleOutcome::Failed(error)
+        } else if self.warnings.is_empty() {
+            CycleOutcome::Success
+        } else {
+            CycleOutcome::SuccessWithWarnings
+        }
+    }
+}
+
+/// How a whole backup cycle ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CycleOutcome {
+    Success,
+    /// Every stage ran, but something was logged as a warning, e.g. files
+    /// borg skipped
+    SuccessWithWarnings,
+    Failed(String),
+}
+
+impl CycleOutcome {
+    /// Process exit code for `backup`, so cron and monitoring can tell a
+    /// clean run (0) from one with warnings (1) and a failure (2).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::SuccessWithWarnings => 1,
+            Self::Failed(_) => 2,
+        }
+    }
+}
@@ -0,0 +1,21 @@
+/// Where `BorgBackup` sends what the user should see while it works, e.g.
+/// which archive it is mounting or what a recreate would drop, and how it
+/// asks questions. Results are returned instead, and logging goes through
+/// `tracing`.
+pub trait Reporter {
+    fn message(&self, text: &str);
+
+    /// A yes/no question; anything but yes is no.
+    fn confirm(&self, question: &str) -> Result<bool, String>;
+}
+
+/// Says nothing and answers no; the default when embedding the crate.
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn message(&self, _text: &str) {}
+
+    fn confirm(&self, _question: &str) -> Result<bool, String> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::report::FileEntry;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Up to `count` of `items`, picked at random.
+pub fn sample<T: Clone>(items: &[T], count: usize) -> Vec<T> {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+        ^ u64::from(std::process::id()) << 32;
+    sample_with_seed(items, count, seed)
+}
+
+/// `sample` with a fixed seed, for tests.
+fn sample_with_seed<T: Clone>(items: &[T], count: usize, seed: u64) -> Vec<T> {
+    // xorshift64 is plenty for picking files; it only must not be zero
+    let mut state = seed | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    // Partial Fisher-Yates shuffle over the indices
+    let mut indices: Vec<usize> = (0..items.len()).collect();
+    let count = count.min(items.len());
+    for i in 0..count {
+        let j = i + (next() % (items.len() - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices[..count].iter().map(|&i| items[i].clone()).collect()
+}
+
+/// How restored files compared with the archive listing and the live
+/// filesystem.
+#[derive(Debug, Default, PartialEq)]
+pub struct Comparison {
+    pub checked: usize,
+    /// Byte-for-byte equal to a live file unchanged since the backup
+    pub identical: usize,
+    /// The live file was modified since, so only the size was checked
+    pub changed: usize,
+    /// No longer on the live filesystem
+    pub gone: usize,
+    pub failures: Vec<String>,
+}
+
+impl Comparison {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} file(s) restored: {} identical to the live file, {} changed since the backup, {} no longer present, {} failed",
+            self.checked,
+            self.identical,
+            self.changed,
+            self.gone,
+            self.failures.len()
+        )
+    }
+}
+
+/// Compare `files` restored below `restored` with their archived sizes
+/// and, where the live file below `live` still has the archived size and
+/// modification time, with its contents.
+pub fn compare(restored: &Path, live: &Path, files: &[FileEntry]) -> Comparison {
+    let mut comparison = Comparison::default();
+    for file in files {
+        comparison.checked += 1;
+        let restored_path = restored.join(&file.path);
+        let Ok(metadata) = restored_path.symlink_metadata() else {
+            comparison
+                .failures
+                .push(format!("/{} was not restored", file.path));
+            continue;
+        };
+        if metadata.is_file() && metadata.len() != file.size {
+            comparison.failures.push(format!(
+                "/{} was restored with {} bytes but has {} in the archive",
+                file.path,
+                metadata.len(),
+                file.size
+            ));
+            continue;
+        }
+
+        let live_path = live.join(&file.path);
+        let Ok(live_metadata) = live_path.symlink_metadata() else {
+            comparison.gone += 1;
+            continue;
+        };
+        let unchanged = live_metadata.len() == file.size
+            && live_metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .is_some_and(|t| t.as_secs() as i64 == file.mtime.timestamp());
+        if !unchanged {
+            comparison.changed += 1;
+            continue;
+        }
+        match same_contents(&restored_path, &live_path) {
+            Ok(true) => comparison.identical += 1,
+            Ok(false) => comparison.failures.push(format!(
+                "/{} differs from the live file, which has not changed since the backup",
+                file.path
+            )),
+            // E.g. a socket or a file we may not read; the size matched
+            Err(_) => comparison.changed += 1,
+        }
+    }
+    comparison
+}
+
+/// Whether two files, or two symlinks, have the same contents.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    if a.symlink_metadata()?.is_symlink() {
+        return Ok(fs::read_link(a)? == fs::read_link(b)?);
+    }
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_sample() {
+        let items: Vec<u32> = (0..100).collect();
+        let picked = sample_with_seed(&items, 10, 42);
+        assert_eq!(picked.len(), 10);
+        let mut unique = picked.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 10);
+        assert_ne!(picked, sample_with_seed(&items, 10, 7));
+        assert_eq!(sample(&items[..3], 10).len(), 3);
+    }
+
+    #[test]
+    fn test_compare() {
+        let restored = tempfile::tempdir().unwrap();
+        let live = tempfile::tempdir().unwrap();
+        let write = |root: &Path, path: &str, contents: &str| {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), contents).unwrap();
+        };
+        let backed_up = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        write(restored.path(), "etc/same", "hello");
+        write(live.path(), "etc/same", "hello");
+        write(restored.path(), "etc/edited", "hello");
+        write(live.path(), "etc/edited", "HELLO");
+        write(restored.path(), "etc/gone", "hello");
+        write(restored.path(), "etc/short", "hell");
+        for path in ["etc/same", "etc/edited"] {
+            File::options()
+                .write(true)
+                .open(live.path().join(path))
+                .unwrap()
+                .set_modified(backed_up.into())
+                .unwrap();
+        }
+        let entry = |path: &str, size: u64| FileEntry {
+            path: path.to_string(),
+            size,
+            mtime: backed_up,
+        };
+        let files = [
+            entry("etc/same", 5),
+            // Same size and time, different contents: the restore is wrong
+            entry("etc/edited", 5),
+            entry("etc/gone", 5),
+            entry("etc/short", 5),
+            entry("etc/missing", 5),
+        ];
+
+        let comparison = compare(restored.path(), live.path(), &files);
+        assert_eq!(comparison.checked, 5);
+        assert_eq!(comparison.identical, 1);
+        assert_eq!(comparison.gone, 1);
+        assert_eq!(comparison.failures.len(), 3);
+        assert!(comparison.failures[0].contains("/etc/edited differs"));
+        assert!(comparison.failures[1].contains("4 bytes"));
+        assert!(comparison.failures[2].contains("not restored"));
+
+        // A live file modified after the backup only gets a size check
+        let older = FileEntry {
+            mtime: DateTime::from_timestamp(0, 0).unwrap(),
+            ..entry("etc/edited", 5)
+        };
+        let comparison = compare(restored.path(), live.path(), &[older]);
+        assert_eq!(comparison.changed, 1);
+        assert!(comparison.failures.is_empty());
+    }
+}
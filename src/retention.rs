@@ -0,0 +1,105 @@
+use crate::{parse_age, Retention};
+use chrono::{DateTime, Duration, Local, Utc};
+use std::collections::HashSet;
+
+/// `borg prune` rules in the order borg applies them, with the strftime
+/// pattern (in local time) that names each rule's period.
+const RULES: [(&str, &str); 5] = [
+    ("hourly", "%Y-%m-%d %H"),
+    ("daily", "%Y-%m-%d"),
+    ("weekly", "%G-%V"),
+    ("monthly", "%Y-%m"),
+    ("yearly", "%Y"),
+];
+
+/// Reject retention settings borg would refuse. `within` is always more
+/// than 0, so prune never deletes everything.
+pub fn lint(retention: &Retention) -> Result<(), String> {
+    parse_age(&retention.within).map_err(|e| format!("retention.within: {}", e))?;
+    Ok(())
+}
+
+fn count(retention: &Retention, rule: &str) -> u32 {
+    match rule {
+        "hourly" => retention.hourly,
+        "daily" => retention.daily,
+        "weekly" => retention.weekly,
+        "monthly" => retention.monthly,
+        "yearly" => retention.yearly,
+        _ => 0,
+    }
+}
+
+/// Which of `archives` (creation times) `borg prune` would keep at `now`,
+/// each with the rule that kept it, newest first. Follows borg: archives
+/// kept by `within` or an earlier rule don't count towards later rules,
+/// and a rule that runs out of periods keeps the oldest archive.
+pub fn prune(
+    archives: &[DateTime<Utc>],
+    retention: &Retention,
+    now: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, &'static str)>, String> {
+    let within = parse_age(&retention.within)?;
+    let mut sorted = archives.to_vec();
+    sorted.sort_by_key(|&time| std::cmp::Reverse(time));
+
+    let mut kept: Vec<(DateTime<Utc>, &'static str)> = sorted
+        .iter()
+        .filter(|&&time| time > now - within)
+        .map(|&time| (time, "within"))
+        .collect();
+    let mut kept_times: HashSet<DateTime<Utc>> = kept.iter().map(|(time, _)| *time).collect();
+
+    for (rule, pattern) in RULES {
+        let wanted = count(retention, rule) as usize;
+        if wanted == 0 {
+            continue;
+        }
+        let mut last_period = None;
+        let mut added = 0;
+        for &time in &sorted {
+            let period = time.with_timezone(&Local).format(pattern).to_string();
+            if last_period.as_ref() == Some(&period) {
+                continue;
+            }
+            last_period = Some(period);
+            if kept_times.insert(time) {
+                kept.push((time, rule));
+                added += 1;
+                if added == wanted {
+                    break;
+                }
+            }
+        }
+        if added < wanted {
+            if let Some(&oldest) = sorted.last() {
+                if kept_times.insert(oldest) {
+                    kept.push((oldest, rule));
+                }
+            }
+        }
+    }
+
+    kept.sort_by_key(|&(time, _)| std::cmp::Reverse(time));
+    Ok(kept)
+}
+
+/// Archives kept after backing up every `interval` for `history` up to
+/// `now`, newest first.
+pub fn simulate(
+    retention: &Retention,
+    history: Duration,
+    interval: Duration,
+    now: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, &'static str)>, String> {
+    if interval <= Duration::zero() {
+        return Err("The backup interval must be positive".to_string());
+    }
+    let mut archives = Vec::new();
+    let mut time = now;
+    while time > now - history {
+        archives.push(time);
+        time -= interval;
+    }
+    prune(&archives, retention, now)
+}
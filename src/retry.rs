@@ -0,0 +1,102 @@
+use crate::parse_timeout;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Retrying `borg create` and `borg prune` after failures that tend to go
+/// away by themselves, such as an SSH connection dropped by flaky Wi-Fi.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Retry {
+    /// Attempts in total; 1 never retries
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling for each one after it
+    #[serde(default = "default_backoff")]
+    pub backoff: String,
+    /// Longest wait between attempts
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: String,
+    /// borg exit codes worth another attempt
+    #[serde(default = "default_retry_on_exit_codes")]
+    pub retry_on_exit_codes: Vec<i32>,
+}
+
+fn default_max_attempts() -> u32 {
+    1
+}
+
+fn default_backoff() -> String {
+    "30s".to_string()
+}
+
+fn default_max_backoff() -> String {
+    "10min".to_string()
+}
+
+fn default_retry_on_exit_codes() -> Vec<i32> {
+    // borg's generic error, which covers lost connections
+    vec![2]
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+            retry_on_exit_codes: default_retry_on_exit_codes(),
+        }
+    }
+}
+
+impl Retry {
+    pub fn lint(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("retry.max_attempts must be at least 1".to_string());
+        }
+        parse_timeout(&self.backoff).map_err(|e| format!("retry.backoff: {}", e))?;
+        parse_timeout(&self.max_backoff).map_err(|e| format!("retry.max_backoff: {}", e))?;
+        Ok(())
+    }
+
+    /// Whether a command exiting with `exit_code` on attempt `attempt`
+    /// (counting from 1) is tried again.
+    pub fn should_retry(&self, attempt: u32, exit_code: i32) -> bool {
+        attempt < self.max_attempts && self.retry_on_exit_codes.contains(&exit_code)
+    }
+
+    /// Wait after failed attempt `attempt`: `backoff`, doubled per attempt
+    /// and capped at `max_backoff`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = parse_timeout(&self.backoff).unwrap_or_default();
+        let max = parse_timeout(&self.max_backoff).unwrap_or(Duration::MAX);
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        backoff.saturating_mul(factor).min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let retry: Retry =
+            serde_yaml::from_str("max_attempts: 4\nbackoff: 30s\nmax_backoff: 1min").unwrap();
+        assert!(retry.lint().is_ok());
+        assert_eq!(retry.delay(1), Duration::from_secs(30));
+        assert_eq!(retry.delay(2), Duration::from_secs(60));
+        assert_eq!(retry.delay(3), Duration::from_secs(60));
+
+        assert!(retry.should_retry(1, 2));
+        assert!(retry.should_retry(3, 2));
+        assert!(!retry.should_retry(4, 2));
+        assert!(!retry.should_retry(1, 1));
+
+        assert!(!Retry::default().should_retry(1, 2));
+        let never = Retry {
+            max_attempts: 0,
+            ..Retry::default()
+        };
+        assert!(never.lint().is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use crate::signals;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+
+/// A borg invocation: arguments (without the binary), extra environment,
+/// an optional stdin and working directory. Built like
+/// `std::process::Command`.
+#[derive(Default)]
+pub struct BorgCommand {
+    /// Program and arguments borg is run through, such as `nice -n 19`
+    wrapper: Vec<OsString>,
+    /// Common options such as `--lock-wait`, placed before the subcommand
+    common: Vec<OsString>,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    stdin: Option<Stdio>,
+    current_dir: Option<PathBuf>,
+}
+
+impl BorgCommand {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Add a common option, which borg accepts before any subcommand.
+    pub fn common_arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.common.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Run borg through `program` with `args`, e.g. `ionice -c 3`. Wrappers
+    /// nest in the order added.
+    pub fn wrap<I, S>(&mut self, program: &str, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.wrapper.push(program.into());
+        self.wrapper
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn get_wrapper(&self) -> Vec<String> {
+        self.wrapper
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.env
+            .push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn stdin(&mut self, stdin: impl Into<Stdio>) -> &mut Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    /// Directory to run in; `borg extract` writes relative to it.
+    pub fn current_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    pub fn get_common_args(&self) -> Vec<String> {
+        self.common
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Subcommand and its arguments, without the common options.
+    pub fn get_args(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    pub fn get_env(&self, key: &str) -> Option<String> {
+        self.env
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.to_string_lossy().into_owned())
+    }
+}
+
+/// Runs borg. `BorgBackup` only talks to borg through this trait so the
+/// argument lists it builds can be tested without a borg install.
+pub trait BorgRunner {
+    /// Run with stdout and stderr inherited from this process.
+    fn execute(&self, cmd: BorgCommand) -> io::Result<ExitStatus>;
+
+    /// Run and collect stdout and stderr.
+    fn capture(&self, cmd: BorgCommand) -> io::Result<Output>;
+
+    /// Run, calling `line(is_progress, text)` for each line of stdout and
+    /// stderr as it arrives.
+    fn stream(
+        &self,
+        cmd: BorgCommand,
+        line: &mut dyn FnMut(bool, String),
+    ) -> io::Result<ExitStatus>;
+}
+
+/// Runs the real borg binary.
+pub struct SystemRunner {
+    binary: String,
+}
+
+impl SystemRunner {
+    pub fn new(binary: &str) -> Self {
+        Self {
+            binary: binary.to_string(),
+        }
+    }
+
+    fn command(&self, cmd: BorgCommand) -> Command {
+        let mut command = match cmd.wrapper.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args).arg(&self.binary);
+                command
+            }
+            None => Command::new(&self.binary),
+        };
+        command.args(cmd.common).args(cmd.args).envs(cmd.env);
+        if let Some(stdin) = cmd.stdin {
+            command.stdin(stdin);
+        }
+        if let Some(dir) = cmd.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+}
+
+impl Default for SystemRunner {
+    fn default() -> Self {
+        Self::new("borg")
+    }
+}
+
+impl BorgRunner for SystemRunner {
+    fn execute(&self, cmd: BorgCommand) -> io::Result<ExitStatus> {
+        self.command(cmd).status()
+    }
+
+    fn capture(&self, cmd: BorgCommand) -> io::Result<Output> {
+        self.command(cmd).output()
+    }
+
+    fn stream(
+        &self,
+        cmd: BorgCommand,
+        line: &mut dyn FnMut(bool, String),
+    ) -> io::Result<ExitStatus> {
+        let mut command = self.command(cmd);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        // Own process group: a terminal Ctrl-C reaches only us, and we
+        // forward it once (see signals::install). Without the handlers
+        // borg stays in ours and gets Ctrl-C from the terminal itself.
+        if signals::installed() {
+            command.process_group(0);
+        }
+        let mut child = command.spawn()?;
+        signals::set_child(Some(child.id()));
+
+        let (tx, rx) = mpsc::channel();
+        let readers: Vec<_> = [
+            child
+                .stdout
+                .take()
+                .map(|s| Box::new(s) as Box<dyn Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|s| Box::new(s) as Box<dyn Read + Send>),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|stream| {
+            let tx = tx.clone();
+            thread::spawn(move || forward_lines(stream, tx))
+        })
+        .collect();
+        drop(tx);
+
+        for (progress, text) in rx {
+            line(progress, text);
+        }
+
+        for reader in readers {
+            let _ = reader.join();
+        }
+        let status = child.wait();
+        signals::set_child(None);
+        status
+    }
+}
+
+/// Read a subprocess stream, sending `(is_progress, line)` for every
+/// non-empty segment terminated by `\n` or `\r`.
+pub(crate) fn forward_lines(mut stream: Box<dyn Read + Send>, tx: mpsc::Sender<(bool, String)>) {
+    let mut buf = [0u8; 4096];
+    let mut pending = Vec::new();
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        for &byte in &buf[..n] {
+            if byte == b'\n' || byte == b'\r' {
+                let line = String::from_utf8_lossy(&pending).trim_end().to_string();
+                pending.clear();
+                if !line.is_empty() && tx.send((byte == b'\r', line)).is_err() {
+                    return;
+                }
+            } else {
+                pending.push(byte);
+            }
+        }
+    }
+    let line = String::from_utf8_lossy(&pending).trim_end().to_string();
+    if !line.is_empty() {
+        let _ = tx.send((false, line));
+    }
+}
+
+/// Records every invocation instead of running borg. Canned stdout can be
+/// set per argument prefix such as `list` or `list --json-lines`, the
+/// longest match winning; everything else succeeds with no output, and
+/// the repository lists no archives.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::collections::HashMap;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::{Arc, Mutex};
+
+    pub type Calls = Arc<Mutex<Vec<Vec<String>>>>;
+
+    pub struct MockRunner {
+        pub calls: Calls,
+        responses: HashMap<String, (i32, String)>,
+    }
+
+    impl Default for MockRunner {
+        fn default() -> Self {
+            Self {
+                calls: Calls::default(),
+                responses: HashMap::new(),
+            }
+            .respond("--version", 0, "borg 1.2.8")
+            .respond("list --json", 0, r#"{"archives": []}"#)
+        }
+    }
+
+    impl MockRunner {
+        pub fn respond(mut self, args: &str, exit_code: i32, stdout: &str) -> Self {
+            self.responses
+                .insert(args.to_string(), (exit_code, stdout.to_string()));
+            self
+        }
+
+        fn record(&self, cmd: &BorgCommand) -> (ExitStatus, String) {
+            let args = cmd.get_args();
+            let (code, stdout) = self
+                .responses
+                .iter()
+                .filter(|(prefix, _)| {
+                    let prefix: Vec<&str> = prefix.split(' ').collect();
+                    args.len() >= prefix.len() && args.iter().zip(&prefix).all(|(a, p)| a == p)
+                })
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, response)| response.clone())
+                .unwrap_or_default();
+            self.calls.lock().unwrap().push(args);
+            (ExitStatus::from_raw(code << 8), stdout)
+        }
+    }
+
+    impl BorgRunner for MockRunner {
+        fn execute(&self, cmd: BorgCommand) -> io::Result<ExitStatus> {
+            Ok(self.record(&cmd).0)
+        }
+
+        fn capture(&self, cmd: BorgCommand) -> io::Result<Output> {
+            let (status, stdout) = self.record(&cmd);
+            Ok(Output {
+                status,
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn stream(
+            &self,
+            cmd: BorgCommand,
+            line: &mut dyn FnMut(bool, String),
+        ) -> io::Result<ExitStatus> {
+            let (status, stdout) = self.record(&cmd);
+            for text in stdout.lines() {
+                line(false, text.to_string());
+            }
+            Ok(status)
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The `sd_notify` protocol: state changes sent as datagrams to the socket
+/// systemd names in `NOTIFY_SOCKET`, for `Type=notify` units.
+#[derive(Clone)]
+pub struct SdNotify {
+    address: SocketAddr,
+}
+
+impl SdNotify {
+    /// The notification socket of the unit we run in, if any.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        Self::new(&path).ok()
+    }
+
+    /// A path, or an abstract socket name starting with `@`.
+    pub fn new(path: &str) -> io::Result<Self> {
+        let address = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets need Linux",
+                ))
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self { address })
+    }
+
+    /// Send newline-separated `KEY=value` assignments, e.g. `READY=1`.
+    pub fn send(&self, state: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &self.address)?;
+        Ok(())
+    }
+
+    /// How often systemd expects `WATCHDOG=1`, when the unit has
+    /// `WatchdogSec=` set and the watchdog is meant for this process.
+    pub fn watchdog_interval() -> Option<Duration> {
+        if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok() != Some(std::process::id()) {
+                return None;
+            }
+        }
+        let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        (usec > 0).then(|| Duration::from_micros(usec))
+    }
+
+    /// The watchdog to ping as work gets done, when systemd expects one.
+    pub fn watchdog(&self) -> Option<Watchdog> {
+        Self::watchdog_interval().map(|interval| Watchdog::new(self.clone(), interval))
+    }
+}
+
+/// Sends `WATCHDOG=1` when told the cycle made progress, so systemd
+/// restarts or kills us when the cycle hangs, not only when the process
+/// freezes.
+pub struct Watchdog {
+    notify: SdNotify,
+    /// Least time between two pings
+    every: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Watchdog {
+    pub fn new(notify: SdNotify, interval: Duration) -> Self {
+        Self {
+            notify,
+            every: interval / 4,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// The cycle got on: ping, unless we did so very recently.
+    pub fn ping(&self) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if last.is_some_and(|last| last.elapsed() < self.every) {
+            return;
+        }
+        *last = Some(Instant::now());
+        if let Err(e) = self.notify.send("WATCHDOG=1") {
+            tracing::debug!("sd_notify failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let notify = SdNotify::new(path.to_str().unwrap()).unwrap();
+        notify.send("READY=1\nSTATUS=Starting").unwrap();
+        let mut buf = [0u8; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Starting");
+
+        // Pinged on progress, at most every quarter interval
+        let watchdog = Watchdog::new(notify, Duration::from_millis(400));
+        watchdog.ping();
+        watchdog.ping();
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        systemd.set_nonblocking(true).unwrap();
+        assert!(systemd.recv(&mut buf).is_err());
+        std::thread::sleep(Duration::from_millis(110));
+        watchdog.ping();
+        systemd.set_nonblocking(false).unwrap();
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+    }
+}
@@ -0,0 +1,71 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::thread;
+
+/// Signal that interrupted the run, or 0.
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+/// PID of the borg process currently running, or 0.
+static CHILD: AtomicU32 = AtomicU32::new(0);
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Catch SIGINT and SIGTERM for the rest of the process. The first signal is
+/// forwarded to the running borg process so it can write a checkpoint and
+/// exit, and the run is marked interrupted; a second signal exits at once.
+pub fn install() -> Result<(), String> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])
+        .map_err(|e| format!("Failed to install signal handlers: {}", e))?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if INTERRUPTED.swap(signal, Ordering::SeqCst) != 0 {
+                std::process::exit(exit_code(signal));
+            }
+            let child = CHILD.load(Ordering::SeqCst);
+            if child != 0 {
+                // SAFETY: kill(2) has no memory-safety preconditions
+                unsafe {
+                    libc::kill(child as libc::pid_t, signal);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Whether `install` ran, so signals reach borg through us.
+pub fn installed() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Record the borg process that should receive forwarded signals.
+pub fn set_child(pid: Option<u32>) {
+    CHILD.store(pid.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// The signal that interrupted this run, if any.
+pub fn interrupted() -> Option<i32> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Shell-style exit status for a run killed by `signal`.
+pub fn exit_code(signal: i32) -> i32 {
+    128 + signal
+}
+
+pub fn name(signal: i32) -> &'static str {
+    match signal {
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "signal",
+    }
+}
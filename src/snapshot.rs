@@ -0,0 +1,363 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Start of the names given to our snapshots, so a leftover from a
+/// crashed run is recognised and replaced.
+const SNAPSHOT_NAME: &str = "borg-timemachine";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotType {
+    Lvm,
+    Btrfs,
+    Zfs,
+}
+
+impl SnapshotType {
+    /// The program managing snapshots of this type.
+    pub fn program(self) -> &'static str {
+        match self {
+            SnapshotType::Lvm => "lvcreate",
+            SnapshotType::Btrfs => "btrfs",
+            SnapshotType::Zfs => "zfs",
+        }
+    }
+}
+
+/// A read-only snapshot a job is backed up from instead of the live
+/// filesystem, so databases and VM images are captured at one instant.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    #[serde(rename = "type")]
+    pub kind: SnapshotType,
+    /// `vg/lv` for LVM, the subvolume path for btrfs, the dataset for ZFS
+    pub volume: String,
+    /// Where the volume is mounted on the live system, when the job's
+    /// source is a directory inside it rather than its root; default: the
+    /// source
+    #[serde(default)]
+    pub volume_root: Option<String>,
+    /// Directory standing in for `/` while the snapshot is backed up: it is
+    /// mounted at `<mountpoint>/<volume_root>` and borg reads the source
+    /// from there by its relative path, so archives hold the same paths as
+    /// without a snapshot. For btrfs it must be on the subvolume's
+    /// filesystem.
+    #[serde(default = "default_mountpoint")]
+    pub mountpoint: String,
+    /// Space for changes made while an LVM snapshot exists
+    #[serde(default = "default_size")]
+    pub size: String,
+    /// Target the snapshot is taken for, added to its name
+    #[serde(skip)]
+    pub target: Option<String>,
+}
+
+fn default_mountpoint() -> String {
+    "/run/borg-timemachine/snapshot".to_string()
+}
+
+fn default_size() -> String {
+    "1G".to_string()
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+impl Snapshot {
+    pub fn lint(&self, job: &str, source: &str) -> Result<(), String> {
+        let invalid = |problem: &str| Err(format!("job {}: snapshot {}", job, problem));
+        // The job name goes into the snapshot's name
+        if !job
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return invalid("needs a job name of only letters, digits, -, _ and .");
+        }
+        if let Some(root) = &self.volume_root {
+            if !Path::new(root).is_absolute() {
+                return invalid("volume_root must be an absolute path");
+            }
+            if !Path::new(source).starts_with(root) {
+                return invalid(&format!("volume_root {} does not contain {}", root, source));
+            }
+        }
+        match self.kind {
+            _ if self.volume.trim().is_empty() => invalid("needs a volume"),
+            SnapshotType::Lvm if self.volume.split('/').count() != 2 => {
+                invalid("volume must be vg/lv for LVM")
+            }
+            SnapshotType::Zfs if self.volume.contains('@') => {
+                invalid("volume must be a dataset, not a snapshot")
+            }
+            _ if !Path::new(&self.mountpoint).is_absolute() => {
+                invalid("mountpoint must be an absolute path")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Where the snapshot for `source` is mounted: the volume's root, so
+    /// `source` is found below it by the same path as on the live system.
+    pub fn path(&self, source: &str) -> PathBuf {
+        let root = self.volume_root.as_deref().unwrap_or(source);
+        Path::new(&self.mountpoint).join(root.trim_start_matches('/'))
+    }
+
+    /// The snapshot of `job` as its tools name it:
+    /// `vg/lv-borg-timemachine-<job>` for LVM,
+    /// `dataset@borg-timemachine-<job>` for ZFS and the path for btrfs,
+    /// with `-<target>` added for other targets than the top level.
+    fn name(&self, job: &str, path: &Path) -> String {
+        let snapshot = match &self.target {
+            Some(target) => format!("{}-{}-{}", SNAPSHOT_NAME, job, target),
+            None => format!("{}-{}", SNAPSHOT_NAME, job),
+        };
+        match self.kind {
+            SnapshotType::Lvm => format!("{}-{}", self.volume, snapshot),
+            SnapshotType::Btrfs => path.display().to_string(),
+            SnapshotType::Zfs => format!("{}@{}", self.volume, snapshot),
+        }
+    }
+
+    fn create_commands(&self, job: &str, path: &Path) -> Vec<Vec<String>> {
+        let name = self.name(job, path);
+        let path = path.display().to_string();
+        match self.kind {
+            SnapshotType::Lvm => {
+                let lv = name.rsplit('/').next().unwrap_or_default();
+                vec![
+                    command(&[
+                        "lvcreate",
+                        "--snapshot",
+                        "--size",
+                        &self.size,
+                        "--name",
+                        lv,
+                        &self.volume,
+                    ]),
+                    command(&["mount", "-o", "ro", &format!("/dev/{}", name), &path]),
+                ]
+            }
+            SnapshotType::Btrfs => vec![command(&[
+                "btrfs",
+                "subvolume",
+                "snapshot",
+                "-r",
+                &self.volume,
+                &path,
+            ])],
+            SnapshotType::Zfs => vec![
+                command(&["zfs", "snapshot", &name]),
+                command(&["mount", "-t", "zfs", &name, &path]),
+            ],
+        }
+    }
+
+    /// Undo `create_commands`, last step first.
+    fn destroy_commands(&self, job: &str, path: &Path) -> Vec<Vec<String>> {
+        let name = self.name(job, path);
+        let path = path.display().to_string();
+        match self.kind {
+            SnapshotType::Lvm => vec![
+                command(&["umount", &path]),
+                command(&["lvremove", "--yes", &name]),
+            ],
+            SnapshotType::Btrfs => vec![command(&["btrfs", "subvolume", "delete", &path])],
+            SnapshotType::Zfs => vec![
+                command(&["umount", &path]),
+                command(&["zfs", "destroy", &name]),
+            ],
+        }
+    }
+
+    /// Snapshot the volume for `job` and mount it for `source`. The
+    /// snapshot is destroyed when the returned guard is released or
+    /// dropped.
+    pub fn take(&self, job: &str, source: &str) -> Result<Taken, String> {
+        let path = self.path(source);
+        // Left behind by a run that was killed before cleaning up
+        for args in self.destroy_commands(job, &path) {
+            let _ = run(&args);
+        }
+
+        // btrfs creates the snapshot directory itself
+        let dir = match self.kind {
+            SnapshotType::Btrfs => path.parent().unwrap_or(&path),
+            _ => &path,
+        };
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+        let mut taken = Taken {
+            snapshot: self.clone(),
+            job: job.to_string(),
+            path,
+            done: 0,
+        };
+        for args in self.create_commands(job, &taken.path) {
+            run(&args)?;
+            taken.done += 1;
+        }
+        Ok(taken)
+    }
+}
+
+/// Run a snapshot tool, returning its error output on failure.
+fn run(args: &[String]) -> Result<(), String> {
+    tracing::debug!("Running {}", args.join(" "));
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", args[0], e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+/// A snapshot that exists and is mounted.
+pub struct Taken {
+    snapshot: Snapshot,
+    job: String,
+    path: PathBuf,
+    /// Steps of `create_commands` that succeeded
+    done: usize,
+}
+
+impl Taken {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unmount and destroy the snapshot.
+    pub fn release(mut self) -> Result<(), String> {
+        self.destroy()
+    }
+
+    fn destroy(&mut self) -> Result<(), String> {
+        let commands = self.snapshot.destroy_commands(&self.job, &self.path);
+        // Only undo the steps that were done
+        let skip = commands.len() - self.done.min(commands.len());
+        self.done = 0;
+        let mut result = Ok(());
+        for args in commands.iter().skip(skip) {
+            if let Err(e) = run(args) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        if let Err(e) = self.destroy() {
+            tracing::warn!("Failed to remove snapshot: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let lvm: Snapshot = serde_yaml::from_str("type: lvm\nvolume: vg0/data").unwrap();
+        assert!(lvm.lint("db", "/var/lib/postgresql").is_ok());
+        let path = lvm.path("/var/lib/postgresql");
+        assert_eq!(
+            path,
+            Path::new("/run/borg-timemachine/snapshot/var/lib/postgresql")
+        );
+        assert_eq!(
+            lvm.create_commands("db", &path),
+            [
+                command(&[
+                    "lvcreate",
+                    "--snapshot",
+                    "--size",
+                    "1G",
+                    "--name",
+                    "data-borg-timemachine-db",
+                    "vg0/data"
+                ]),
+                command(&[
+                    "mount",
+                    "-o",
+                    "ro",
+                    "/dev/vg0/data-borg-timemachine-db",
+                    "/run/borg-timemachine/snapshot/var/lib/postgresql"
+                ]),
+            ]
+        );
+        assert_eq!(
+            lvm.destroy_commands("db", &path)[1],
+            command(&["lvremove", "--yes", "vg0/data-borg-timemachine-db"])
+        );
+
+        let zfs: Snapshot =
+            serde_yaml::from_str("type: zfs\nvolume: tank/vms\nmountpoint: /mnt/snap").unwrap();
+        let path = zfs.path("/srv/vms");
+        assert_eq!(
+            zfs.create_commands("vms", &path),
+            [
+                command(&["zfs", "snapshot", "tank/vms@borg-timemachine-vms"]),
+                command(&[
+                    "mount",
+                    "-t",
+                    "zfs",
+                    "tank/vms@borg-timemachine-vms",
+                    "/mnt/snap/srv/vms"
+                ]),
+            ]
+        );
+
+        let btrfs: Snapshot =
+            serde_yaml::from_str("type: btrfs\nvolume: /home\nmountpoint: /home/.snap").unwrap();
+        assert_eq!(
+            btrfs.destroy_commands("home", &btrfs.path("/home")),
+            [command(&[
+                "btrfs",
+                "subvolume",
+                "delete",
+                "/home/.snap/home"
+            ])]
+        );
+
+        let cloud = Snapshot {
+            target: Some("cloud".to_string()),
+            ..zfs
+        };
+        assert_eq!(
+            cloud.create_commands("vms", &path)[0],
+            command(&["zfs", "snapshot", "tank/vms@borg-timemachine-vms-cloud"])
+        );
+
+        // A source inside the volume is found below the volume's root
+        let inside: Snapshot =
+            serde_yaml::from_str("type: zfs\nvolume: tank/pg\nvolume_root: /var/lib/postgresql")
+                .unwrap();
+        assert!(inside.lint("db", "/var/lib/postgresql/16/main").is_ok());
+        assert_eq!(
+            inside.path("/var/lib/postgresql/16/main"),
+            Path::new("/run/borg-timemachine/snapshot/var/lib/postgresql")
+        );
+        assert!(inside
+            .lint("db", "/srv/pg")
+            .unwrap_err()
+            .contains("does not contain"));
+        assert!(inside.lint("db/main", "/var/lib/postgresql").is_err());
+
+        let bad: Snapshot = serde_yaml::from_str("type: lvm\nvolume: data").unwrap();
+        assert!(bad.lint("db", "/srv/db").unwrap_err().contains("vg/lv"));
+    }
+}
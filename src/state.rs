@@ -0,0 +1,290 @@
+use crate::audit::AuditEntry;
+use crate::report::{
+    ArchiveStats, CycleReport, JobStats, RepositoryStats, StageOutcome, StageReport,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Oldest entries are dropped once the history grows past this many cycles.
+const MAX_ENTRIES: usize = 1000;
+
+/// One recorded backup cycle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub started: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub success: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub archive: Option<String>,
+    /// Names of the jobs included in the archive
+    #[serde(default)]
+    pub jobs: Vec<String>,
+    #[serde(default)]
+    pub compression: Option<String>,
+    #[serde(default)]
+    pub stats: Option<ArchiveStats>,
+    /// Repository totals after the cycle's last archive, for growth trends
+    #[serde(default)]
+    pub repository: Option<RepositoryStats>,
+    /// Statistics of each archive written, by job
+    #[serde(default)]
+    pub job_stats: Vec<JobStats>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// Stages in the order they ran
+    #[serde(default)]
+    pub stages: Vec<StageRecord>,
+}
+
+/// Timing and outcome of one stage of a recorded cycle. Older state files
+/// stored `[name, duration_secs]` pairs, which still load with the rest
+/// left empty.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StageRecord {
+    pub name: String,
+    pub duration_secs: f64,
+    #[serde(default)]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub finished: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub outcome: Option<StageOutcome>,
+}
+
+impl StageRecord {
+    fn from_report(stage: &StageReport) -> Self {
+        let finished = chrono::Duration::from_std(stage.duration)
+            .ok()
+            .map(|duration| stage.started + duration);
+        Self {
+            name: stage.name.clone(),
+            duration_secs: stage.duration.as_secs_f64(),
+            started: Some(stage.started),
+            finished,
+            outcome: Some(stage.outcome),
+        }
+    }
+}
+
+impl HistoryEntry {
+    pub fn from_report(report: &CycleReport, jobs: Vec<String>) -> Self {
+        Self {
+            started: report.started,
+            duration_secs: report.duration.as_secs_f64(),
+            success: report.success,
+            error: report.error.clone(),
+            archive: report.archive.clone(),
+            jobs,
+            compression: report.compression.clone(),
+            stats: report.stats.clone(),
+            repository: report.repository.clone(),
+            job_stats: report.job_stats.clone(),
+            warnings: report.warnings.clone(),
+            stages: report.stages.iter().map(StageRecord::from_report).collect(),
+        }
+    }
+
+    /// Rebuild the cycle report this entry was recorded from.
+    pub fn to_report(&self) -> CycleReport {
+        let mut report = CycleReport::new(self.started);
+        report.duration = Duration::from_secs_f64(self.duration_secs.max(0.0));
+        report.success = self.success;
+        report.error = self.error.clone();
+        report.archive = self.archive.clone();
+        report.compression = self.compression.clone();
+        report.stats = self.stats.clone();
+        report.repository = self.repository.clone();
+        report.job_stats = self.job_stats.clone();
+        report.warnings = self.warnings.clone();
+        report.stages = self
+            .stages
+            .iter()
+            .map(|stage| StageReport {
+                name: stage.name.clone(),
+                started: stage.started.unwrap_or(self.started),
+                duration: Duration::from_secs_f64(stage.duration_secs.max(0.0)),
+                outcome: stage.outcome.unwrap_or(StageOutcome::Success),
+            })
+            .collect();
+        report
+    }
+}
+
+/// An archive that has been proven restorable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VerifiedArchive {
+    pub archive: String,
+    pub verified_at: DateTime<Utc>,
+    /// How it was verified, e.g. `extract-dry-run` or `manual`
+    pub method: String,
+}
+
+/// Last successful backup to one disk of a rotation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskRecord {
+    pub name: String,
+    pub last_backup: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StateFile {
+    #[serde(default)]
+    history: Vec<HistoryEntry>,
+    #[serde(default)]
+    verified: Vec<VerifiedArchive>,
+    /// Never trimmed, unlike the cycle history
+    #[serde(default)]
+    audit: Vec<AuditEntry>,
+    #[serde(default)]
+    disks: Vec<DiskRecord>,
+    /// Last time a full `borg check` passed
+    #[serde(default)]
+    last_check: Option<DateTime<Utc>>,
+    /// Last time `borg compact` finished
+    #[serde(default)]
+    last_compact: Option<DateTime<Utc>>,
+    /// Mount points `mount` created, removed again by `umount`
+    #[serde(default)]
+    created_mount_points: Vec<String>,
+}
+
+/// JSON file recording the history of backup cycles.
+pub struct StateStore {
+    path: String,
+}
+
+impl StateStore {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+
+    fn read(&self) -> Result<StateFile, String> {
+        if !Path::new(&self.path).exists() {
+            return Ok(StateFile::default());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read state file {}: {}", self.path, e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse state file {}: {}", self.path, e))
+    }
+
+    fn write(&self, state: &StateFile) -> Result<(), String> {
+        if let Some(parent) = Path::new(&self.path).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+
+        let contents = serde_json::to_string_pretty(state)
+            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, contents).map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| format!("Failed to update state file {}: {}", self.path, e))
+    }
+
+    /// All recorded cycles, oldest first.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, String> {
+        Ok(self.read()?.history)
+    }
+
+    /// Archives with a successful restore test, most recent test per archive.
+    pub fn verified_archives(&self) -> Result<Vec<VerifiedArchive>, String> {
+        Ok(self.read()?.verified)
+    }
+
+    /// Record a successful restore test, replacing any earlier one.
+    pub fn mark_verified(&self, archive: &str, method: &str) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.verified.retain(|v| v.archive != archive);
+        state.verified.push(VerifiedArchive {
+            archive: archive.to_string(),
+            verified_at: Utc::now(),
+            method: method.to_string(),
+        });
+        self.write(&state)
+    }
+
+    pub fn last_check(&self) -> Result<Option<DateTime<Utc>>, String> {
+        Ok(self.read()?.last_check)
+    }
+
+    pub fn record_check(&self, at: DateTime<Utc>) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.last_check = Some(at);
+        self.write(&state)
+    }
+
+    pub fn last_compact(&self) -> Result<Option<DateTime<Utc>>, String> {
+        Ok(self.read()?.last_compact)
+    }
+
+    pub fn record_compact(&self, at: DateTime<Utc>) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.last_compact = Some(at);
+        self.write(&state)
+    }
+
+    pub fn record_created_mount_point(&self, path: &str) -> Result<(), String> {
+        let mut state = self.read()?;
+        if !state.created_mount_points.iter().any(|p| p == path) {
+            state.created_mount_points.push(path.to_string());
+        }
+        self.write(&state)
+    }
+
+    /// Forget `path`, returning whether `mount` had created it.
+    pub fn forget_mount_point(&self, path: &str) -> Result<bool, String> {
+        let mut state = self.read()?;
+        let count = state.created_mount_points.len();
+        state.created_mount_points.retain(|p| p != path);
+        if state.created_mount_points.len() == count {
+            return Ok(false);
+        }
+        self.write(&state)?;
+        Ok(true)
+    }
+
+    /// Last successful backup of each rotation disk that has had one.
+    pub fn disk_backups(&self) -> Result<Vec<DiskRecord>, String> {
+        Ok(self.read()?.disks)
+    }
+
+    pub fn record_disk_backup(&self, name: &str, at: DateTime<Utc>) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.disks.retain(|d| d.name != name);
+        state.disks.push(DiskRecord {
+            name: name.to_string(),
+            last_backup: at,
+        });
+        self.write(&state)
+    }
+
+    /// Audited operations, oldest first.
+    pub fn audit_log(&self) -> Result<Vec<AuditEntry>, String> {
+        Ok(self.read()?.audit)
+    }
+
+    pub fn record_audit(&self, entry: AuditEntry) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.audit.push(entry);
+        self.write(&state)
+    }
+
+    pub fn record(&self, entry: HistoryEntry) -> Result<(), String> {
+        let mut state = self.read()?;
+        state.history.push(entry);
+        if state.history.len() > MAX_ENTRIES {
+            let excess = state.history.len() - MAX_ENTRIES;
+            state.history.drain(..excess);
+        }
+        self.write(&state)
+    }
+}
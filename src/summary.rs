@@ -0,0 +1,287 @@
+use crate::display::Output;
+use crate::report::{ArchiveStats, CycleReport};
+use serde::Deserialize;
+
+/// Body of the notification sent after a successful cycle when
+/// `notifications.on_success` is set.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SummaryReport {
+    /// Plain text template. `{{hostname}}`, `{{archive}}`, `{{duration}}`,
+    /// `{{original}}`, `{{compressed}}`, `{{deduplicated}}`, `{{savings}}`,
+    /// `{{files}}`, `{{jobs}}`, `{{pruned}}`, `{{skipped}}` and
+    /// `{{warnings}}` are filled in; the last four are lists, one item per
+    /// line.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Also send an HTML version to email channels delivered over SMTP
+    #[serde(default)]
+    pub html: bool,
+}
+
+const DEFAULT_TEMPLATE: &str = "Backup cycle on {{hostname}} finished in {{duration}}.
+
+Archive: {{archive}}
+{{original}} backed up, {{deduplicated}} of it new after compression and dedup ({{savings}} saved), {{files}} files.
+
+Jobs:
+{{jobs}}
+
+Pruned archives:
+{{pruned}}
+
+Skipped files:
+{{skipped}}
+
+Warnings:
+{{warnings}}
+";
+
+/// Totals over every archive the cycle wrote.
+fn totals(report: &CycleReport) -> ArchiveStats {
+    let mut total = ArchiveStats::default();
+    for job in &report.job_stats {
+        total.original_size += job.stats.original_size;
+        total.compressed_size += job.stats.compressed_size;
+        total.deduplicated_size += job.stats.deduplicated_size;
+        total.nfiles += job.stats.nfiles;
+    }
+    total
+}
+
+pub(crate) fn duration(report: &CycleReport) -> String {
+    let secs = report.duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn job_lines(report: &CycleReport, output: &Output) -> Vec<String> {
+    report
+        .job_stats
+        .iter()
+        .map(|job| {
+            format!(
+                "{}: {} original, {} new ({:.0}% saved), {} files",
+                job.jobs.join(", "),
+                output.bytes(job.stats.original_size),
+                output.bytes(job.stats.deduplicated_size),
+                job.stats.savings() * 100.0,
+                job.stats.nfiles
+            )
+        })
+        .collect()
+}
+
+impl SummaryReport {
+    /// The plain text summary of `report`.
+    pub fn text(&self, report: &CycleReport, hostname: &str, output: &Output) -> String {
+        let total = totals(report);
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "- none".to_string()
+            } else {
+                items
+                    .iter()
+                    .map(|item| format!("- {}", item))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        };
+        self.template
+            .as_deref()
+            .unwrap_or(DEFAULT_TEMPLATE)
+            .replace("{{hostname}}", hostname)
+            .replace("{{archive}}", report.archive.as_deref().unwrap_or("-"))
+            .replace("{{duration}}", &duration(report))
+            .replace("{{original}}", &output.bytes(total.original_size))
+            .replace("{{compressed}}", &output.bytes(total.compressed_size))
+            .replace("{{deduplicated}}", &output.bytes(total.deduplicated_size))
+            .replace("{{savings}}", &format!("{:.0}%", total.savings() * 100.0))
+            .replace("{{files}}", &total.nfiles.to_string())
+            .replace("{{jobs}}", &list(&job_lines(report, output)))
+            .replace("{{pruned}}", &list(&report.pruned))
+            .replace("{{skipped}}", &list(&report.skipped))
+            .replace("{{warnings}}", &list(&report.warnings))
+    }
+
+    /// The summary of `report` as an HTML page, when `html` is set.
+    pub fn html(&self, report: &CycleReport, hostname: &str, output: &Output) -> Option<String> {
+        if !self.html {
+            return None;
+        }
+        let total = totals(report);
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                return "<p>None</p>".to_string();
+            }
+            let items: String = items
+                .iter()
+                .map(|item| format!("<li>{}</li>", escape(item)))
+                .collect();
+            format!("<ul>{}</ul>", items)
+        };
+        let jobs: String = report
+            .job_stats
+            .iter()
+            .map(|job| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.0}%</td><td>{}</td></tr>",
+                    escape(&job.jobs.join(", ")),
+                    output.bytes(job.stats.original_size),
+                    output.bytes(job.stats.deduplicated_size),
+                    job.stats.savings() * 100.0,
+                    job.stats.nfiles
+                )
+            })
+            .collect();
+        Some(format!(
+            "<html><body>\
+             <h2>Backup cycle on {} finished in {}</h2>\
+             <p>Archive <b>{}</b>: {} backed up, {} of it new ({:.0}% saved), {} files.</p>\
+             <table border=\"1\" cellpadding=\"4\" cellspacing=\"0\">\
+             <tr><th>Job</th><th>Original</th><th>New</th><th>Saved</th><th>Files</th></tr>{}</table>\
+             <h3>Pruned archives</h3>{}\
+             <h3>Skipped files</h3>{}\
+             <h3>Warnings</h3>{}\
+             </body></html>",
+            escape(hostname),
+            duration(report),
+            escape(report.archive.as_deref().unwrap_or("-")),
+            output.bytes(total.original_size),
+            output.bytes(total.deduplicated_size),
+            total.savings() * 100.0,
+            total.nfiles,
+            jobs,
+            list(&report.pruned),
+            list(&report.skipped),
+            list(&report.warnings)
+        ))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Names of the archives in `borg prune --list` output lines, e.g.
+/// `Pruning archive (1/2): host-2024-03-01T12:00:00  Fri, ...`; dry runs
+/// print `Would prune:` instead.
+pub fn pruned_archives(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let start = line
+                .find("Pruning archive")
+                .or_else(|| line.find("Would prune"))?;
+            let rest = &line[start..];
+            let (_, rest) = rest.split_once(':')?;
+            rest.split_whitespace().next().map(str::to_string)
+        })
+        .collect()
+}
+
+/// Files borg warned about while creating an archive, as `path: reason`.
+/// It prints `<path>: [Errno 13] Permission denied: ...`, `<path>: file
+/// changed while we backed it up` and, with `--list`, `E <path>`.
+pub fn skipped_files(lines: &[String]) -> Vec<String> {
+    let mut skipped: Vec<String> = Vec::new();
+    for line in lines {
+        let line = match line.strip_prefix('[').and_then(|l| l.split_once("] ")) {
+            Some((_, rest)) => rest,
+            None => line,
+        };
+        let entry = if let Some(path) = line.strip_prefix("E /") {
+            format!("/{}: error", path)
+        } else if let Some((path, reason)) = line.split_once(": ") {
+            if !path.starts_with('/')
+                || !(reason.contains("[Errno") || reason.contains("changed while we backed it up"))
+            {
+                continue;
+            }
+            format!("{}: {}", path, reason)
+        } else {
+            continue;
+        };
+        if !skipped.contains(&entry) {
+            skipped.push(entry);
+        }
+    }
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::JobStats;
+    use chrono::Utc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_summary() {
+        let mut report = CycleReport::new(Utc::now());
+        report.duration = Duration::from_secs(125);
+        report.archive = Some("host-2024-03-01".to_string());
+        report.job_stats.push(JobStats {
+            jobs: vec!["system-config".to_string()],
+            archive: "host-2024-03-01".to_string(),
+            stats: ArchiveStats {
+                original_size: 4096,
+                compressed_size: 2048,
+                deduplicated_size: 1024,
+                nfiles: 7,
+            },
+        });
+        report.pruned = pruned_archives(&[
+            "[2024-03-01 12:00:00] Keeping archive (rule: daily #1):    host-2024-02-29  Thu"
+                .to_string(),
+            "[2024-03-01 12:00:00] Pruning archive (1/1):               host-2024-01-01  Mon"
+                .to_string(),
+            "Would prune:                     host-2023-12-31  Sun".to_string(),
+        ]);
+        assert_eq!(report.pruned, ["host-2024-01-01", "host-2023-12-31"]);
+        report.warnings.push("source <missing>".to_string());
+        report.skipped = skipped_files(&[
+            "[2024-03-01 12:00:00] /etc/shadow: open: [Errno 13] Permission denied: 'shadow'"
+                .to_string(),
+            "[2024-03-01 12:00:00] /var/log/syslog: file changed while we backed it up".to_string(),
+            "[2024-03-01 12:00:00] Creating archive at \"/tmp/borg::host\"".to_string(),
+            "E /root/.cache".to_string(),
+        ]);
+        assert_eq!(
+            report.skipped,
+            [
+                "/etc/shadow: open: [Errno 13] Permission denied: 'shadow'",
+                "/var/log/syslog: file changed while we backed it up",
+                "/root/.cache: error",
+            ]
+        );
+
+        let summary = SummaryReport::default();
+        let text = summary.text(&report, "host", &Output::default());
+        assert!(text.starts_with("Backup cycle on host finished in 2m 5s."));
+        assert!(text.contains("4.0 KiB backed up, 1.0 KiB of it new"));
+        assert!(text.contains("(75% saved), 7 files"));
+        assert!(text.contains("- system-config: 4.0 KiB original"));
+        assert!(text.contains("Pruned archives:\n- host-2024-01-01\n- host-2023-12-31"));
+        assert!(text.contains("Skipped files:\n- /etc/shadow: open:"));
+        assert_eq!(summary.html(&report, "host", &Output::default()), None);
+
+        let custom = SummaryReport {
+            template: Some("{{hostname}}: {{deduplicated}} new\n{{pruned}}".to_string()),
+            html: true,
+        };
+        report.pruned.clear();
+        assert_eq!(
+            custom.text(&report, "host", &Output::default()),
+            "host: 1.0 KiB new\n- none"
+        );
+        let html = custom.html(&report, "host", &Output::default()).unwrap();
+        assert!(html.contains("<li>source &lt;missing&gt;</li>"));
+        assert!(html.contains("<td>system-config</td>"));
+    }
+}
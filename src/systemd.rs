@@ -0,0 +1,208 @@
+use crate::parse_timeout;
+use serde::Deserialize;
+use std::path::Path;
+
+pub const SERVICE_NAME: &str = "borg-timemachine.service";
+pub const TIMER_NAME: &str = "borg-timemachine.timer";
+
+/// When the systemd timer from `install-systemd` runs a backup cycle.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// systemd `OnCalendar=` expression, e.g. `hourly` or `*-*-* 02:00`
+    #[serde(default = "default_on_calendar")]
+    pub on_calendar: String,
+    /// Random delay before each run, spreading load across hosts
+    #[serde(default = "default_randomized_delay")]
+    pub randomized_delay: String,
+    /// Run at boot when a scheduled run was missed while powered off
+    #[serde(default = "crate::default_true")]
+    pub persistent: bool,
+    /// Longest a cycle may run before systemd stops it
+    #[serde(default = "default_timeout")]
+    pub timeout: String,
+    /// systemd kills the cycle if it makes no progress (borg output, log
+    /// lines) for this long; `0` turns the watchdog off
+    #[serde(default = "default_watchdog")]
+    pub watchdog: String,
+}
+
+fn default_on_calendar() -> String {
+    "hourly".to_string()
+}
+
+fn default_randomized_delay() -> String {
+    "10min".to_string()
+}
+
+fn default_timeout() -> String {
+    "4h".to_string()
+}
+
+fn default_watchdog() -> String {
+    "5min".to_string()
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            on_calendar: default_on_calendar(),
+            randomized_delay: default_randomized_delay(),
+            persistent: true,
+            timeout: default_timeout(),
+            watchdog: default_watchdog(),
+        }
+    }
+}
+
+/// `0` for no limit, otherwise a duration `parse_timeout` accepts.
+fn seconds(setting: &str, value: &str) -> Result<u64, String> {
+    if value == "0" {
+        return Ok(0);
+    }
+    parse_timeout(value)
+        .map(|duration| duration.as_secs())
+        .map_err(|e| format!("schedule.{}: {}", setting, e))
+}
+
+/// `path` as one word of a unit's command line: quoted when it has
+/// spaces or quotes, with `%` and `$` kept from expanding.
+fn quote(path: &Path) -> String {
+    let path = path.display().to_string();
+    let escaped = path.replace('%', "%%").replace('$', "$$");
+    if !path
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Schedule {
+    /// The service running `backup` with `config`, as a `Type=notify` unit
+    /// so `systemctl status` shows the stage and the watchdog works.
+    pub fn service_unit(&self, binary: &Path, config: &Path) -> Result<String, String> {
+        let timeout = seconds("timeout", &self.timeout)?;
+        let watchdog = seconds("watchdog", &self.watchdog)?;
+        let mut unit = format!(
+            "\
+[Unit]
+Description=Borg Time Machine Backup
+Documentation=https://borgbackup.readthedocs.io/
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={} --config {} backup
+
+# Run as root to access all files
+User=root
+Group=root
+
+# Logging
+StandardOutput=journal
+StandardError=journal
+SyslogIdentifier=borg-timemachine
+
+# Resource limits - run with lower priority to not impact system
+CPUSchedulingPolicy=idle
+IOSchedulingClass=idle
+Nice=19
+
+# Time to get ready (config, lock, passphrase), then for the whole cycle
+TimeoutStartSec=5min
+",
+            quote(binary),
+            quote(config)
+        );
+        if timeout > 0 {
+            unit.push_str(&format!("RuntimeMaxSec={}\n", timeout));
+        }
+        if watchdog > 0 {
+            unit.push_str(&format!("WatchdogSec={}\n", watchdog));
+        }
+        Ok(unit)
+    }
+
+    /// The timer starting the service on `on_calendar`.
+    pub fn timer_unit(&self) -> Result<String, String> {
+        if self.on_calendar.trim().is_empty() {
+            return Err("schedule.on_calendar is empty".to_string());
+        }
+        let delay = seconds("randomized_delay", &self.randomized_delay)?;
+        Ok(format!(
+            "\
+[Unit]
+Description=Borg Time Machine Backup Timer
+Documentation=https://borgbackup.readthedocs.io/
+Requires={}
+
+[Timer]
+OnCalendar={}
+
+# Run on boot if missed (e.g., system was off)
+Persistent={}
+
+# Randomize start time to avoid load spikes
+RandomizedDelaySec={}
+
+AccuracySec=1min
+
+[Install]
+WantedBy=timers.target
+",
+            SERVICE_NAME, self.on_calendar, self.persistent, delay
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let schedule: Schedule =
+            serde_yaml::from_str("on_calendar: '*-*-* 02:00'\nwatchdog: '0'\ntimeout: 2h").unwrap();
+        let service = schedule
+            .service_unit(
+                Path::new("/usr/local/bin/borg-timemachine"),
+                Path::new("/etc/borg/borg-config.yaml"),
+            )
+            .unwrap();
+        assert!(service.contains(
+            "ExecStart=/usr/local/bin/borg-timemachine --config /etc/borg/borg-config.yaml backup\n"
+        ));
+        assert!(service.contains("Type=notify\n"));
+        assert!(service.contains("RuntimeMaxSec=7200\n"));
+        assert!(!service.contains("WatchdogSec"));
+
+        let timer = schedule.timer_unit().unwrap();
+        assert!(timer.contains("OnCalendar=*-*-* 02:00\n"));
+        assert!(timer.contains("RandomizedDelaySec=600\n"));
+        assert!(timer.contains("Persistent=true\n"));
+
+        let spaced = schedule
+            .service_unit(
+                Path::new("/opt/borg tools/borg-timemachine"),
+                Path::new("/etc/borg/50%\"off\".yaml"),
+            )
+            .unwrap();
+        assert!(spaced.contains(
+            "ExecStart=\"/opt/borg tools/borg-timemachine\" --config \"/etc/borg/50%%\\\"off\\\".yaml\" backup\n"
+        ));
+
+        let default = Schedule::default().service_unit(Path::new("b"), Path::new("c"));
+        assert!(default.unwrap().contains("WatchdogSec=300\n"));
+        let broken = Schedule {
+            timeout: "forever".to_string(),
+            ..Schedule::default()
+        };
+        assert!(broken
+            .service_unit(Path::new("b"), Path::new("c"))
+            .unwrap_err()
+            .starts_with("schedule.timeout"));
+    }
+}
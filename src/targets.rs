@@ -0,0 +1,325 @@
+use crate::{Config, Repository};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+/// Name `--repo` takes for the top-level `repository`.
+pub const PRIMARY: &str = "default";
+
+/// Another repository backed up alongside `repository`, e.g. a cloud copy
+/// next to the NAS. `backup` runs the cycles of all targets at once, each
+/// in its own process with its own lock, state and log file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    pub repository: Repository,
+    /// Jobs backed up to this target; all of them when empty
+    #[serde(default)]
+    pub jobs: Vec<String>,
+}
+
+pub fn lint(config: &Config) -> Result<(), String> {
+    for (index, target) in config.targets.iter().enumerate() {
+        let name = &target.name;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "target '{}': names may only use letters, digits, - and _",
+                name
+            ));
+        }
+        if name == PRIMARY {
+            return Err(format!(
+                "target {}: the name is taken by the top-level repository",
+                PRIMARY
+            ));
+        }
+        if config.targets[..index].iter().any(|t| t.name == *name) {
+            return Err(format!("target {} is defined twice", name));
+        }
+        if target.repository.path == config.repository.path && target.repository.remote.is_none() {
+            return Err(format!("target {}: same repository as the top level", name));
+        }
+        for job in &target.jobs {
+            if !config.jobs.iter().any(|j| j.name == *job) {
+                return Err(format!("target {}: unknown job {}", name, job));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every target `backup` runs, the top-level repository first.
+pub fn names(config: &Config) -> Vec<String> {
+    std::iter::once(PRIMARY.to_string())
+        .chain(config.targets.iter().map(|t| t.name.clone()))
+        .collect()
+}
+
+/// Point `config` at target `name`: its repository and jobs, and state,
+/// log and metrics files and snapshots of its own; the lock follows the
+/// repository. Replication stays with the top-level repository.
+pub fn select(config: &mut Config, name: &str) -> Result<(), String> {
+    // Other targets may be running the same hooks right now
+    if !config.targets.is_empty() {
+        config.hooks.lock_file = Some(file_for(&config.logging.lock_file, "hooks"));
+    }
+    if name == PRIMARY {
+        config.targets.clear();
+        return Ok(());
+    }
+    let target = config
+        .targets
+        .iter()
+        .find(|t| t.name == name)
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "Unknown target {}; configured: {}",
+                name,
+                names(config).join(", ")
+            )
+        })?;
+    config.repository = target.repository;
+    if !target.jobs.is_empty() {
+        config.jobs.retain(|job| target.jobs.contains(&job.name));
+    }
+    // Snapshots of their own, which the other targets' cycles don't
+    // destroy as leftovers
+    for snapshot in config
+        .jobs
+        .iter_mut()
+        .filter_map(|job| job.snapshot.as_mut())
+    {
+        snapshot.mountpoint = file_for(&snapshot.mountpoint, name);
+        snapshot.target = Some(name.to_string());
+    }
+    let logging = &mut config.logging;
+    logging.log_file = file_for(&logging.log_file, name);
+    logging.state_file = file_for(&logging.state_file, name);
+    config.metrics.textfile = file_for(&config.metrics.textfile, name);
+    config.replication = None;
+    config.targets.clear();
+    Ok(())
+}
+
+/// `path` with `-<target>` added to the file name, before the extension:
+/// `/run/lock/borg-timemachine.lock` becomes
+/// `/run/lock/borg-timemachine-cloud.lock`.
+pub fn file_for(path: &str, target: &str) -> String {
+    let file = Path::new(path);
+    let (Some(stem), Some(name)) = (file.file_stem(), file.file_name()) else {
+        return format!("{}-{}", path, target);
+    };
+    let renamed = match file.extension() {
+        Some(ext) => format!(
+            "{}-{}.{}",
+            stem.to_string_lossy(),
+            target,
+            ext.to_string_lossy()
+        ),
+        None => format!("{}-{}", name.to_string_lossy(), target),
+    };
+    file.with_file_name(renamed).display().to_string()
+}
+
+/// Which stream of a target's process a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    /// Also errors starting or waiting for the process
+    Stderr,
+}
+
+/// Run `command(name)` for every name, at most `max_parallel` at a time,
+/// passing each line the processes write to `output` with the name.
+/// Returns every name with its exit code; processes that can't be started
+/// or die from a signal count as failed (2).
+pub fn run_parallel(
+    names: &[String],
+    max_parallel: usize,
+    command: impl Fn(&str) -> Command + Sync,
+    output: impl Fn(&str, Stream, &str) + Sync,
+) -> Vec<(String, i32)> {
+    let queue = Mutex::new(names.iter().cloned().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..max_parallel.clamp(1, names.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some(name) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+                let code = run_forwarding(&name, command(&name), &output);
+                results.lock().unwrap().push((name, code));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(name, _)| names.iter().position(|n| n == name));
+    results
+}
+
+fn run_forwarding(
+    name: &str,
+    mut cmd: Command,
+    output: &(impl Fn(&str, Stream, &str) + Sync),
+) -> i32 {
+    let mut child = match cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            output(name, Stream::Stderr, &format!("Failed to start: {}", e));
+            return 2;
+        }
+    };
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    thread::scope(|scope| {
+        if let Some(stdout) = stdout {
+            scope.spawn(|| forward(stdout, |line| output(name, Stream::Stdout, line)));
+        }
+        if let Some(stderr) = stderr {
+            scope.spawn(|| forward(stderr, |line| output(name, Stream::Stderr, line)));
+        }
+    });
+    match child.wait() {
+        Ok(status) => status.code().unwrap_or(2),
+        Err(e) => {
+            output(name, Stream::Stderr, &e.to_string());
+            2
+        }
+    }
+}
+
+fn forward(stream: impl Read, print: impl Fn(&str)) {
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => print(&line),
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_for() {
+        assert_eq!(
+            file_for("/run/lock/borg-timemachine.lock", "cloud"),
+            "/run/lock/borg-timemachine-cloud.lock"
+        );
+        assert_eq!(
+            file_for("/var/lib/btm/state", "nas"),
+            "/var/lib/btm/state-nas"
+        );
+        assert_eq!(file_for("backup.log", "nas"), "backup-nas.log");
+    }
+
+    #[test]
+    fn test_select() {
+        let mut config = Config::load_or_default(None).unwrap();
+        let first_job = config.jobs[0].name.clone();
+        config.targets.push(Target {
+            name: "cloud".to_string(),
+            repository: Repository {
+                path: "/mnt/cloud/borg".to_string(),
+                ..config.repository.clone()
+            },
+            jobs: vec![first_job.clone()],
+        });
+        config.jobs[0].snapshot =
+            Some(serde_yaml::from_str("type: zfs\nvolume: tank/data").unwrap());
+        lint(&config).unwrap();
+        assert_eq!(names(&config), ["default", "cloud"]);
+
+        let mut primary = config.clone();
+        select(&mut primary, PRIMARY).unwrap();
+        assert_eq!(primary.repository.path, config.repository.path);
+        assert!(primary.targets.is_empty());
+
+        select(&mut config, "cloud").unwrap();
+        assert_eq!(config.repository.path, "/mnt/cloud/borg");
+        assert_eq!(config.jobs.len(), 1);
+        assert_eq!(config.jobs[0].name, first_job);
+        assert_ne!(config.lock_file(), primary.lock_file());
+        // Both run hooks under one lock, and snapshot apart
+        assert!(primary.hooks.lock_file.is_some());
+        assert_eq!(config.hooks.lock_file, primary.hooks.lock_file);
+        let (ours, theirs) = (
+            config.jobs[0].snapshot.as_ref().unwrap(),
+            primary.jobs[0].snapshot.as_ref().unwrap(),
+        );
+        assert_eq!(ours.mountpoint, "/run/borg-timemachine/snapshot-cloud");
+        assert_ne!(ours.mountpoint, theirs.mountpoint);
+        assert_eq!(ours.target.as_deref(), Some("cloud"));
+        assert!(select(&mut config, "nas").is_err());
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut config = Config::load_or_default(None).unwrap();
+        let target = Target {
+            name: "default".to_string(),
+            repository: Repository {
+                path: "/mnt/cloud/borg".to_string(),
+                ..config.repository.clone()
+            },
+            jobs: Vec::new(),
+        };
+        config.targets.push(target.clone());
+        assert!(lint(&config).unwrap_err().contains("taken"));
+        config.targets[0].name = "off site".to_string();
+        assert!(lint(&config).is_err());
+        config.targets[0].name = "cloud".to_string();
+        config.targets[0].jobs.push("nope".to_string());
+        assert!(lint(&config).unwrap_err().contains("unknown job nope"));
+        config.targets[0].jobs.clear();
+        config.targets.push(Target {
+            name: "cloud".to_string(),
+            ..target
+        });
+        assert!(lint(&config).unwrap_err().contains("twice"));
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let lines = Mutex::new(Vec::new());
+        let results = run_parallel(
+            &names,
+            2,
+            |name| {
+                let mut cmd = Command::new("sh");
+                let code = if name == "b" { 1 } else { 0 };
+                cmd.args(["-c", &format!("echo {}; exit {}", name, code)]);
+                cmd
+            },
+            |name, stream, line| {
+                lines
+                    .lock()
+                    .unwrap()
+                    .push((name.to_string(), stream, line.to_string()))
+            },
+        );
+        assert_eq!(
+            results,
+            [
+                ("a".to_string(), 0),
+                ("b".to_string(), 1),
+                ("c".to_string(), 0)
+            ]
+        );
+        let mut lines = lines.into_inner().unwrap();
+        lines.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], ("b".to_string(), Stream::Stdout, "b".to_string()));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::migrate;
+use crate::passphrase::PassphraseSource;
+use crate::policy::OnMissingSource;
+use crate::preflight::{CheckResult, CheckStatus};
+use crate::replication::Replication;
+use crate::{read_pattern_file, Config};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Every problem with `config` that shows without running borg, so they
+/// can all be fixed at once instead of one failed run at a time.
+pub fn validate(config: &Config) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    results.push(if config.version < migrate::CURRENT {
+        CheckResult::new(
+            "version",
+            CheckStatus::Warn,
+            format!(
+                "config schema version {}, current is {}; upgrade the file with `migrate-config`",
+                config.version,
+                migrate::CURRENT
+            ),
+        )
+    } else {
+        CheckResult::new(
+            "version",
+            CheckStatus::Pass,
+            format!("config schema version {} is current", config.version),
+        )
+    });
+
+    for job in config.jobs.iter().filter(|job| job.enabled) {
+        let name = format!("job {}", job.name);
+        results.push(if Path::new(&job.source).exists() {
+            CheckResult::new(&name, CheckStatus::Pass, format!("{} exists", job.source))
+        } else {
+            let status = match config.policy.on_missing_source {
+                OnMissingSource::Skip => CheckStatus::Warn,
+                OnMissingSource::Fail => CheckStatus::Fail,
+            };
+            CheckResult::new(&name, status, format!("{} does not exist", job.source))
+        });
+        if let Some(snapshot) = &job.snapshot {
+            let program = snapshot.kind.program();
+            if !crate::on_path(program) {
+                results.push(CheckResult::new(
+                    &name,
+                    CheckStatus::Fail,
+                    format!("snapshot needs {}, which is not installed", program),
+                ));
+            }
+        }
+    }
+
+    results.push(passphrase(&config.security.passphrase_source()));
+
+    if config.notifications.desktop.is_some() {
+        let program = if cfg!(target_os = "macos") {
+            "osascript"
+        } else {
+            "notify-send"
+        };
+        results.push(if crate::on_path(program) {
+            CheckResult::new(
+                "desktop notifications",
+                CheckStatus::Pass,
+                format!("{} is installed", program),
+            )
+        } else {
+            CheckResult::new(
+                "desktop notifications",
+                CheckStatus::Warn,
+                format!("{} is not installed; no pop-ups will show", program),
+            )
+        });
+    }
+
+    results.push(match config.lint() {
+        Ok(()) => CheckResult::new(
+            "retention",
+            CheckStatus::Pass,
+            "retention settings are valid",
+        ),
+        Err(e) => CheckResult::new("retention", CheckStatus::Fail, e),
+    });
+
+    results.push(match compression_problem(&config.compression) {
+        None => CheckResult::new(
+            "compression",
+            CheckStatus::Pass,
+            format!("{} is a valid compression spec", config.compression),
+        ),
+        Some(problem) => CheckResult::new("compression", CheckStatus::Fail, problem),
+    });
+
+    let check_day = config.maintenance.check_day;
+    results.push(if check_day <= 7 {
+        CheckResult::new(
+            "check_day",
+            CheckStatus::Pass,
+            check_day_description(check_day),
+        )
+    } else {
+        CheckResult::new(
+            "check_day",
+            CheckStatus::Fail,
+            format!(
+                "{} is not a day; use 1 (Monday) to 7 (Sunday), or 0 to never check",
+                check_day
+            ),
+        )
+    });
+
+    let patterns = config
+        .exclusions
+        .iter()
+        .chain(config.jobs.iter().flat_map(|job| &job.exclude));
+    let problems: Vec<String> = patterns
+        .clone()
+        .filter_map(|pattern| pattern_problem(pattern).map(|p| format!("'{}': {}", pattern, p)))
+        .collect();
+    results.push(if problems.is_empty() {
+        CheckResult::new(
+            "exclusions",
+            CheckStatus::Pass,
+            format!("{} patterns are valid", patterns.count()),
+        )
+    } else {
+        CheckResult::new("exclusions", CheckStatus::Fail, problems.join("; "))
+    });
+
+    for file in &config.exclusions_from {
+        results.push(match read_pattern_file(file) {
+            Err(e) => CheckResult::new("exclusion file", CheckStatus::Fail, e),
+            Ok(patterns) => {
+                let problems: Vec<String> = patterns
+                    .iter()
+                    .filter_map(|pattern| {
+                        pattern_problem(pattern).map(|p| format!("'{}': {}", pattern, p))
+                    })
+                    .collect();
+                if problems.is_empty() {
+                    CheckResult::new(
+                        "exclusion file",
+                        CheckStatus::Pass,
+                        format!("{}: {} patterns are valid", file, patterns.len()),
+                    )
+                } else {
+                    CheckResult::new(
+                        "exclusion file",
+                        CheckStatus::Fail,
+                        format!("{}: {}", file, problems.join("; ")),
+                    )
+                }
+            }
+        });
+    }
+    for file in &config.patterns_from {
+        results.push(match std::fs::File::open(file) {
+            Ok(_) => CheckResult::new(
+                "pattern file",
+                CheckStatus::Pass,
+                format!("{} is readable", file),
+            ),
+            Err(e) => CheckResult::new(
+                "pattern file",
+                CheckStatus::Fail,
+                format!("{}: {}", file, e),
+            ),
+        });
+    }
+
+    if config.options.upload_ratelimit.is_some() && config.repository.remote.is_none() {
+        results.push(CheckResult::new(
+            "upload_ratelimit",
+            CheckStatus::Warn,
+            "only limits uploads to a remote repository; this one is local",
+        ));
+    }
+
+    if let Some(replication) = &config.replication {
+        results.push(replication_check(replication, config));
+    }
+
+    results.push(repository(config));
+    results
+}
+
+fn replication_check(replication: &Replication, config: &Config) -> CheckResult {
+    let program = replication.tool.program();
+    if let Err(e) = replication.lint(&config.repository.path) {
+        CheckResult::new("replication", CheckStatus::Fail, e)
+    } else if config.repository.remote.is_some() {
+        CheckResult::new(
+            "replication",
+            CheckStatus::Fail,
+            "needs a local repository to copy from",
+        )
+    } else if !crate::on_path(program) {
+        CheckResult::new(
+            "replication",
+            CheckStatus::Fail,
+            format!("{} is not installed", program),
+        )
+    } else {
+        CheckResult::new(
+            "replication",
+            CheckStatus::Pass,
+            format!("to {} with {}", replication.target, program),
+        )
+    }
+}
+
+/// Whether the passphrase can be had, without running a command or asking
+/// the keyring.
+pub fn passphrase(source: &PassphraseSource) -> CheckResult {
+    match source {
+        PassphraseSource::File { path } => match std::fs::metadata(path) {
+            Err(e) => CheckResult::new("passphrase", CheckStatus::Fail, format!("{}: {}", path, e)),
+            Ok(meta) if meta.permissions().mode() & 0o077 != 0 => CheckResult::new(
+                "passphrase",
+                CheckStatus::Warn,
+                format!(
+                    "{} is readable by other users (mode {:o}); run chmod 600 {}",
+                    path,
+                    meta.permissions().mode() & 0o777,
+                    path
+                ),
+            ),
+            Ok(_) => CheckResult::new(
+                "passphrase",
+                CheckStatus::Pass,
+                format!("{} exists and is private", path),
+            ),
+        },
+        PassphraseSource::Env { variable } => match std::env::var_os(variable) {
+            Some(_) => CheckResult::new(
+                "passphrase",
+                CheckStatus::Pass,
+                format!("{} is set", variable),
+            ),
+            None => CheckResult::new(
+                "passphrase",
+                CheckStatus::Fail,
+                format!("environment variable {} is not set", variable),
+            ),
+        },
+        PassphraseSource::Command { .. } | PassphraseSource::Keyring { .. } => CheckResult::new(
+            "passphrase",
+            CheckStatus::Pass,
+            format!("borg asks the {} when it runs", source.describe()),
+        ),
+    }
+}
+
+fn check_day_description(day: u32) -> String {
+    const DAYS: [&str; 7] = [
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+        "Sunday",
+    ];
+    match day {
+        0 => "scheduled checks are off".to_string(),
+        day => format!("the repository is checked on {}s", DAYS[day as usize - 1]),
+    }
+}
+
+/// A local repository, or the directory it will be created in, must be
+/// writable by us.
+fn repository(config: &Config) -> CheckResult {
+    if config.repository.remote.is_some() {
+        return CheckResult::new(
+            "repository",
+            CheckStatus::Pass,
+            "remote repository; run `doctor` to check it",
+        );
+    }
+    let path = Path::new(&config.repository.path);
+    let Some(existing) = path.ancestors().find(|p| p.exists()) else {
+        return CheckResult::new(
+            "repository",
+            CheckStatus::Fail,
+            format!("no part of {} exists", path.display()),
+        );
+    };
+    let detail = if existing == path {
+        format!("{} exists", path.display())
+    } else {
+        format!(
+            "{} will be created in {}",
+            path.display(),
+            existing.display()
+        )
+    };
+    if writable(existing) {
+        CheckResult::new(
+            "repository",
+            CheckStatus::Pass,
+            format!("{} and is writable", detail),
+        )
+    } else {
+        CheckResult::new(
+            "repository",
+            CheckStatus::Fail,
+            format!("{}, which is not writable", detail),
+        )
+    }
+}
+
+fn writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// What is wrong with a `--compression` spec, if anything. Accepts our
+/// own `auto` as well as borg's `auto,<spec>` and `obfuscate,<level>,<spec>`.
+pub fn compression_problem(spec: &str) -> Option<String> {
+    let parts: Vec<&str> = spec.split(',').collect();
+    let level = |algorithm: &str, level: &str, max: u32, min: u32| match level.parse::<u32>() {
+        Ok(n) if (min..=max).contains(&n) => None,
+        _ => Some(format!(
+            "{} level must be {} to {}, got {}",
+            algorithm, min, max, level
+        )),
+    };
+    match parts.as_slice() {
+        ["auto"] | ["none"] | ["lz4"] | ["zstd"] | ["zlib"] | ["lzma"] => None,
+        ["zstd", n] => level("zstd", n, 22, 1),
+        ["zlib", n] => level("zlib", n, 9, 0),
+        ["lzma", n] => level("lzma", n, 9, 0),
+        ["auto", rest @ ..] if !rest.is_empty() && rest[0] != "auto" => {
+            compression_problem(&rest.join(","))
+        }
+        ["obfuscate", n, rest @ ..] if !rest.is_empty() => match n.parse::<u32>() {
+            Ok(1..=6) | Ok(110..=123) => compression_problem(&rest.join(",")),
+            _ => Some(format!(
+                "obfuscate level must be 1 to 6 or 110 to 123, got {}",
+                n
+            )),
+        },
+        _ => Some(format!(
+            "unknown compression {} (expected none, lz4, zstd, zlib, lzma or auto, \
+             optionally with a level such as zstd,3)",
+            spec
+        )),
+    }
+}
+
+/// What is wrong with an exclude pattern, if anything. Like borg, two
+/// letters and a colon select the pattern style. Only `re:` patterns can
+/// be malformed: borg turns any `fm:` or `sh:` pattern into a regex,
+/// taking an unclosed `[` literally.
+pub fn pattern_problem(pattern: &str) -> Option<String> {
+    let (style, body) = match pattern.split_at_checked(2) {
+        Some((style, rest))
+            if rest.starts_with(':') && style.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            (style, &rest[1..])
+        }
+        _ => ("fm", pattern),
+    };
+    if !["fm", "sh", "re", "pp", "pf"].contains(&style) {
+        return Some(format!(
+            "unknown pattern style {}: (expected fm, sh, re, pp or pf)",
+            style
+        ));
+    }
+    if body.is_empty() {
+        return Some("empty pattern".to_string());
+    }
+    if style != "re" {
+        return None;
+    }
+    match regex::Regex::new(body) {
+        Ok(_) => None,
+        // Python's re, which borg uses, has look-around and backreferences
+        Err(regex::Error::Syntax(e)) if e.contains("not supported") => None,
+        Err(e) => Some(format!("invalid regular expression: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_problem() {
+        for spec in [
+            "lz4",
+            "auto",
+            "zstd,3",
+            "zlib,0",
+            "auto,zstd,10",
+            "obfuscate,3,lz4",
+        ] {
+            assert_eq!(compression_problem(spec), None, "{}", spec);
+        }
+        for spec in [
+            "zstd,23",
+            "zstd,fast",
+            "gzip",
+            "lz4,1",
+            "auto,auto",
+            "obfuscate,9,lz4",
+            "",
+        ] {
+            assert!(compression_problem(spec).is_some(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_pattern_problem() {
+        for pattern in [
+            "*.tmp",
+            "/home/*/.cache",
+            "sh:**/[Cc]ache",
+            "re:^/x/(a|b)$",
+            "pp:/srv",
+        ] {
+            assert_eq!(pattern_problem(pattern), None, "{}", pattern);
+        }
+        // borg matches the [ literally
+        assert_eq!(pattern_problem("fm:*.[ch"), None);
+        assert_eq!(pattern_problem("re:^/home/(?!alice/)"), None);
+        assert!(pattern_problem("re:(a|b")
+            .unwrap()
+            .starts_with("invalid regular expression"));
+        assert!(pattern_problem("re:a)").is_some());
+        assert!(pattern_problem("re:[z-a]").is_some());
+        assert!(pattern_problem("xx:foo")
+            .unwrap()
+            .contains("unknown pattern style"));
+        assert_eq!(pattern_problem("pp:"), Some("empty pattern".to_string()));
+    }
+}
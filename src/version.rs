@@ -0,0 +1,130 @@
+use std::fmt;
+
+/// Oldest borg release this tool drives; `--glob-archives` appeared in 1.1.
+pub const MINIMUM: BorgVersion = BorgVersion::new(1, 1, 0);
+
+/// Version reported by `borg --version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BorgVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl BorgVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse output such as `borg 1.2.8` or `borg 2.0.0b8`.
+    pub fn parse(output: &str) -> Result<Self, String> {
+        let version = output
+            .split_whitespace()
+            .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+            .ok_or_else(|| format!("Unrecognized borg --version output: {}", output.trim()))?;
+
+        let mut parts = version.splitn(3, '.').map(|part| {
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse::<u32>().unwrap_or(0)
+        });
+        Ok(Self::new(
+            parts.next().unwrap_or(0),
+            parts.next().unwrap_or(0),
+            parts.next().unwrap_or(0),
+        ))
+    }
+
+    /// Refuse versions whose command line we don't speak. Borg 2 moved the
+    /// repository to `-r` and renamed several commands.
+    pub fn check_supported(self) -> Result<(), String> {
+        if self < MINIMUM {
+            return Err(format!(
+                "borg {} is too old; borg-timemachine needs borg {} or newer (set borg.binary to pick another install)",
+                self, MINIMUM
+            ));
+        }
+        if self.major >= 2 {
+            return Err(format!(
+                "borg {} is not supported yet; install borg 1.x and point borg.binary at it",
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    /// `borg compact` exists from 1.2; older versions compact on commit.
+    pub fn has_compact(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+
+    /// `borg create --paths-from-stdin` exists from 1.2.
+    pub fn has_paths_from_stdin(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+
+    /// `borg import-tar` exists from 1.2.
+    pub fn has_import_tar(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+
+    /// From 1.2 `borg list` leaves checkpoints out unless given
+    /// `--consider-checkpoints`.
+    pub fn has_consider_checkpoints(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+
+    /// `--bypass-lock`, for reading a repository another process holds
+    /// locked; present in every 1.2.
+    pub fn has_bypass_lock(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+
+    /// 1.2 renamed `--numeric-owner` to `--numeric-ids` and
+    /// `--remote-ratelimit` to `--upload-ratelimit`, and stopped storing
+    /// atime unless given `--atime`.
+    pub fn has_renamed_create_flags(self) -> bool {
+        self >= BorgVersion::new(1, 2, 0)
+    }
+}
+
+impl fmt::Display for BorgVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            BorgVersion::parse("borg 1.2.8\n").unwrap(),
+            BorgVersion::new(1, 2, 8)
+        );
+        assert_eq!(
+            BorgVersion::parse("borg 2.0.0b8").unwrap(),
+            BorgVersion::new(2, 0, 0)
+        );
+        assert_eq!(
+            BorgVersion::parse("borg.exe 1.1").unwrap(),
+            BorgVersion::new(1, 1, 0)
+        );
+        assert!(BorgVersion::parse("command not found").is_err());
+    }
+
+    #[test]
+    fn test_supported_versions() {
+        assert!(BorgVersion::new(1, 0, 9).check_supported().is_err());
+        assert!(BorgVersion::new(1, 1, 18).check_supported().is_ok());
+        assert!(BorgVersion::new(1, 4, 0).check_supported().is_ok());
+        assert!(BorgVersion::new(2, 0, 0).check_supported().is_err());
+        assert!(!BorgVersion::new(1, 1, 18).has_compact());
+        assert!(BorgVersion::new(1, 2, 0).has_compact());
+    }
+}
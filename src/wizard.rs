@@ -0,0 +1,445 @@
+use crate::config_format::ConfigFormat;
+use crate::validate::compression_problem;
+use crate::Config;
+use serde_yaml::{Mapping, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Encryption modes borg 1.x accepts for `borg init`.
+pub const ENCRYPTION_MODES: [&str; 7] = [
+    "repokey-blake2",
+    "repokey",
+    "keyfile-blake2",
+    "keyfile",
+    "authenticated-blake2",
+    "authenticated",
+    "none",
+];
+
+/// Retention settings to start from, named for what they are like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPreset {
+    /// Hourly for a day, daily for a month, weekly for a year
+    TimeMachine,
+    /// A week of dailies and a month of weeklies
+    Minimal,
+    /// Two days of everything, then years of dailies, weeklies and monthlies
+    Paranoid,
+}
+
+impl RetentionPreset {
+    pub const ALL: [Self; 3] = [Self::TimeMachine, Self::Minimal, Self::Paranoid];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TimeMachine => "time-machine",
+            Self::Minimal => "minimal",
+            Self::Paranoid => "paranoid",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::TimeMachine => "hourly for a day, daily for a month, weekly for a year",
+            Self::Minimal => "daily for a week, weekly for a month",
+            Self::Paranoid => "hourly for two days, daily for 3 months, monthly for 5 years",
+        }
+    }
+
+    /// `within`, `hourly`, `daily`, `weekly`, `monthly` and `yearly`.
+    fn settings(self) -> (&'static str, [u32; 5]) {
+        match self {
+            Self::TimeMachine => ("24H", [24, 30, 52, 0, 0]),
+            Self::Minimal => ("1d", [0, 7, 4, 0, 0]),
+            Self::Paranoid => ("48H", [48, 90, 104, 60, 10]),
+        }
+    }
+}
+
+/// Everything `init --interactive` asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answers {
+    /// Path on this machine, or on `host` for a remote repository
+    pub repository: String,
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub ssh_key: Option<String>,
+    pub encryption: String,
+    pub compression: String,
+    pub retention: RetentionPreset,
+    pub sources: Vec<String>,
+    pub email: Option<String>,
+    pub passphrase_file: String,
+    pub key_backup: String,
+}
+
+impl Answers {
+    /// The bundled default config with the answers filled in. Comments
+    /// are lost on the way; `generate-config` has them.
+    pub fn to_config(&self) -> Result<String, String> {
+        let mut doc = ConfigFormat::Yaml.parse(&Config::example(ConfigFormat::Yaml)?)?;
+        let set = |doc: &mut Value, section: &str, key: &str, value: Value| {
+            if let Some(section) = doc.get_mut(section).and_then(Value::as_mapping_mut) {
+                section.insert(key.into(), value);
+            }
+        };
+
+        set(
+            &mut doc,
+            "repository",
+            "path",
+            self.repository.clone().into(),
+        );
+        set(
+            &mut doc,
+            "repository",
+            "encryption",
+            self.encryption.clone().into(),
+        );
+        if let Some(host) = &self.host {
+            let mut remote = Mapping::new();
+            if let Some(user) = &self.user {
+                remote.insert("user".into(), user.clone().into());
+            }
+            remote.insert("host".into(), host.clone().into());
+            if let Some(port) = self.port {
+                remote.insert("port".into(), port.into());
+            }
+            if let Some(ssh_key) = &self.ssh_key {
+                remote.insert("ssh_key".into(), ssh_key.clone().into());
+            }
+            set(&mut doc, "repository", "remote", Value::Mapping(remote));
+        }
+
+        doc["compression"] = self.compression.clone().into();
+
+        let (within, [hourly, daily, weekly, monthly, yearly]) = self.retention.settings();
+        set(&mut doc, "retention", "within", within.into());
+        for (key, value) in [
+            ("hourly", hourly),
+            ("daily", daily),
+            ("weekly", weekly),
+            ("monthly", monthly),
+            ("yearly", yearly),
+        ] {
+            set(&mut doc, "retention", key, value.into());
+        }
+
+        let jobs = self
+            .sources
+            .iter()
+            .map(|source| {
+                let destination = source.trim_matches('/');
+                let name = match destination.rsplit('/').next() {
+                    Some("") | None => "root",
+                    Some(name) => name,
+                };
+                let mut job = Mapping::new();
+                job.insert("name".into(), name.into());
+                job.insert("source".into(), source.clone().into());
+                job.insert(
+                    "destination".into(),
+                    if destination.is_empty() {
+                        "root"
+                    } else {
+                        destination
+                    }
+                    .into(),
+                );
+                job.insert("enabled".into(), true.into());
+                Value::Mapping(job)
+            })
+            .collect();
+        doc["jobs"] = Value::Sequence(jobs);
+
+        set(
+            &mut doc,
+            "notifications",
+            "enabled",
+            self.email.is_some().into(),
+        );
+        set(
+            &mut doc,
+            "notifications",
+            "email",
+            self.email.clone().map_or(Value::Null, Value::from),
+        );
+
+        set(
+            &mut doc,
+            "security",
+            "passphrase_file",
+            self.passphrase_file.clone().into(),
+        );
+        set(
+            &mut doc,
+            "security",
+            "key_backup",
+            self.key_backup.clone().into(),
+        );
+
+        let body = ConfigFormat::Yaml.render(&doc)?;
+        Ok(format!(
+            "# Borg Time Machine Configuration, written by `init --interactive`.\n\
+             # `generate-config` writes the default config with every option explained.\n\n{}",
+            body
+        ))
+    }
+}
+
+/// Asks questions on `output` and reads the answers from `input`; an
+/// empty answer takes the default shown in brackets.
+pub struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    pub fn ask(&mut self, question: &str, default: Option<&str>) -> Result<String, String> {
+        match default {
+            Some(default) if !default.is_empty() => {
+                write!(self.output, "{} [{}]: ", question, default)
+            }
+            _ => write!(self.output, "{}: ", question),
+        }
+        .and_then(|_| self.output.flush())
+        .map_err(|e| format!("Failed to write prompt: {}", e))?;
+
+        let mut answer = String::new();
+        let read = self
+            .input
+            .read_line(&mut answer)
+            .map_err(|e| format!("Failed to read answer: {}", e))?;
+        if read == 0 {
+            return Err("Input ended before the questions did".to_string());
+        }
+        match answer.trim() {
+            "" => Ok(default.unwrap_or("").to_string()),
+            answer => Ok(answer.to_string()),
+        }
+    }
+
+    /// Ask until `check` accepts the answer, showing why it didn't.
+    pub fn ask_valid<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        check: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<T, String> {
+        loop {
+            let answer = self.ask(question, default)?;
+            match check(&answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => self.say(&format!("  {}", e))?,
+            }
+        }
+    }
+
+    pub fn say(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.output, "{}", line).map_err(|e| format!("Failed to write prompt: {}", e))
+    }
+
+    /// Walk through the settings a new repository needs.
+    pub fn run(&mut self) -> Result<Answers, String> {
+        self.say("Where should backups go? Give a local path (e.g. on a backup disk),")?;
+        self.say("or a host name as well for a repository reached over SSH.")?;
+        let host = Some(self.ask("Remote host (empty for a local repository)", None)?)
+            .filter(|host| !host.is_empty());
+        let (user, port, ssh_key) = match host {
+            Some(_) => {
+                let user = self.ask("SSH user", Some("borg"))?;
+                let port = self.ask_valid("SSH port", Some("22"), |port| {
+                    port.parse::<u16>()
+                        .map_err(|_| format!("{} is not a port number", port))
+                })?;
+                let ssh_key = self.ask("SSH key (empty for the default)", None)?;
+                (
+                    Some(user),
+                    (port != 22).then_some(port),
+                    Some(ssh_key).filter(|key| !key.is_empty()),
+                )
+            }
+            None => (None, None, None),
+        };
+        let repository = self.ask_valid(
+            "Repository path",
+            Some("/mnt/backup/borg-timemachine"),
+            |path| match path {
+                "" => Err("The repository needs a path".to_string()),
+                path => Ok(path.to_string()),
+            },
+        )?;
+
+        let encryption = self.ask_valid(
+            &format!("Encryption ({})", ENCRYPTION_MODES.join(", ")),
+            Some(ENCRYPTION_MODES[0]),
+            |mode| match ENCRYPTION_MODES.contains(&mode) {
+                true => Ok(mode.to_string()),
+                false => Err(format!("Unknown encryption mode {}", mode)),
+            },
+        )?;
+
+        let compression = self.ask_valid(
+            "Compression (lz4 is fast, zstd,3 smaller, auto,zstd,10 smallest)",
+            Some("lz4"),
+            |spec| match compression_problem(spec) {
+                None => Ok(spec.to_string()),
+                Some(problem) => Err(problem),
+            },
+        )?;
+
+        self.say("Retention presets:")?;
+        for preset in RetentionPreset::ALL {
+            self.say(&format!("  {:<13} {}", preset.name(), preset.description()))?;
+        }
+        let retention = self.ask_valid(
+            "Retention",
+            Some(RetentionPreset::TimeMachine.name()),
+            |name| {
+                RetentionPreset::ALL
+                    .into_iter()
+                    .find(|preset| preset.name() == name)
+                    .ok_or_else(|| format!("Unknown preset {}", name))
+            },
+        )?;
+
+        let sources = self.ask_valid(
+            "Directories to back up, separated by spaces",
+            Some("/etc /home"),
+            |answer| {
+                let sources: Vec<String> = answer.split_whitespace().map(String::from).collect();
+                match sources.iter().find(|source| !source.starts_with('/')) {
+                    _ if sources.is_empty() => Err("Name at least one directory".to_string()),
+                    Some(source) => Err(format!("{} is not an absolute path", source)),
+                    None => Ok(sources),
+                }
+            },
+        )?;
+
+        let email = Some(self.ask(
+            "Email address for failure notifications (empty for none)",
+            None,
+        )?)
+        .filter(|email| !email.is_empty());
+
+        let passphrase_file = self.ask(
+            "Passphrase file (a random passphrase is written there)",
+            Some("/root/.borg-passphrase"),
+        )?;
+        let key_backup = self.ask(
+            "Export the repository key to (keep a copy off this machine)",
+            Some("/root/borg-key-backup.txt"),
+        )?;
+
+        Ok(Answers {
+            repository,
+            host,
+            user,
+            port,
+            ssh_key,
+            encryption,
+            compression,
+            retention,
+            sources,
+            email,
+            passphrase_file,
+            key_backup,
+        })
+    }
+}
+
+/// Write a random passphrase to `path`, readable by the owner only.
+/// Refuses to replace an existing file, which may already unlock a
+/// repository.
+pub fn write_passphrase_file(path: &Path) -> Result<(), String> {
+    let mut random = [0u8; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .map_err(|e| format!("Failed to read /dev/urandom: {}", e))?;
+    let mut passphrase: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{}\n", passphrase).as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e));
+    crate::passphrase::zeroize(&mut passphrase);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wizard_answers() {
+        let input = "\n/srv/borg\nrepokey-sha9\nrepokey\ngzip\nzstd,3\nminimal\n\
+                     home\n/ /srv\nops@example.com\n/root/pp\n\n";
+        let mut output = Vec::new();
+        let answers = Prompt::new(Cursor::new(input), &mut output).run().unwrap();
+        assert_eq!(answers.host, None);
+        assert_eq!(answers.repository, "/srv/borg");
+        assert_eq!(answers.encryption, "repokey");
+        assert_eq!(answers.compression, "zstd,3");
+        assert_eq!(answers.retention, RetentionPreset::Minimal);
+        assert_eq!(answers.sources, ["/", "/srv"]);
+        assert_eq!(answers.email.as_deref(), Some("ops@example.com"));
+        assert_eq!(answers.key_backup, "/root/borg-key-backup.txt");
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Unknown encryption mode repokey-sha9"));
+        assert!(output.contains("home is not an absolute path"));
+
+        let config: Config = serde_yaml::from_str(&answers.to_config().unwrap()).unwrap();
+        assert_eq!(config.repository.path, "/srv/borg");
+        assert_eq!(config.compression, "zstd,3");
+        assert_eq!(config.retention.daily, 7);
+        assert_eq!(config.retention.hourly, 0);
+        let jobs: Vec<(&str, &str)> = config
+            .jobs
+            .iter()
+            .map(|job| (job.name.as_str(), job.destination.as_str()))
+            .collect();
+        assert_eq!(jobs, [("root", "root"), ("srv", "srv")]);
+        assert_eq!(config.security.passphrase_file, "/root/pp");
+        assert!(config.lint().is_ok());
+
+        let incomplete = Prompt::new(Cursor::new("host\n"), Vec::new()).run();
+        assert!(incomplete.is_err());
+    }
+
+    #[test]
+    fn test_remote_answers() {
+        let input = "nas\nbackup\n2222\n\nborg/laptop\n\n\n\n\n\n\n\n";
+        let answers = Prompt::new(Cursor::new(input), Vec::new()).run().unwrap();
+        let config: Config = serde_yaml::from_str(&answers.to_config().unwrap()).unwrap();
+        assert_eq!(
+            config.repository.url(),
+            "ssh://backup@nas:2222/./borg/laptop"
+        );
+        assert!(!config.notifications.enabled);
+    }
+
+    #[test]
+    fn test_write_passphrase_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passphrase");
+        write_passphrase_file(&path).unwrap();
+        let passphrase = fs::read_to_string(&path).unwrap();
+        assert_eq!(passphrase.trim().len(), 64);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(write_passphrase_file(&path).is_err());
+    }
+}